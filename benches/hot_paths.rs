@@ -0,0 +1,147 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+// We like writing sizes as 1*KB, 1*MB for readability.
+#![allow(clippy::identity_op)]
+
+// Micro-benchmarks of the hot paths. Run with `cargo bench`.
+// Shard pipes are drained by background threads that discard the data.
+
+use std::{
+    io::{self, Write},
+    thread,
+};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use criu_image_streamer::{
+    capture::{ImageFile, ImageSerializer, Shard},
+    image::{self, marker},
+    image_store::{mem, ImageFile as _},
+    test_utils::new_pipe,
+    unix_pipe::{UnixPipe, UnixPipeImpl},
+    util::{pb_read, pb_write, KB, MB},
+};
+
+/// Returns the write end of a pipe whose content is discarded.
+fn new_sink_pipe() -> UnixPipe {
+    let (mut pipe_r, pipe_w) = new_pipe();
+    thread::spawn(move || io::copy(&mut pipe_r, &mut io::sink()));
+    pipe_w
+}
+
+fn new_shards(num_shards: usize) -> (Vec<Shard>, i32) {
+    let mut pipes: Vec<UnixPipe> = (0..num_shards).map(|_| new_sink_pipe()).collect();
+    let capacity = UnixPipe::increase_capacity(&mut pipes, 1*MB as i32).unwrap();
+    let shards = pipes.into_iter().map(|pipe| Shard::new(pipe).unwrap()).collect();
+    (shards, capacity)
+}
+
+fn bench_pb(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pb");
+
+    let markers = [
+        ("file_data", marker::Body::FileData(256*KB as u32)),
+        ("filename", marker::Body::Filename("pages-1234.img".to_string())),
+    ];
+
+    for (name, body) in markers.iter() {
+        let marker = image::Marker { seq: 123_456, body: Some(body.clone()), padding: 0, file_size: 0 };
+
+        let mut buf = Vec::new();
+        group.bench_function(BenchmarkId::new("write", name), |b| b.iter(|| {
+            buf.clear();
+            pb_write(&mut buf, &marker).unwrap()
+        }));
+
+        group.bench_function(BenchmarkId::new("read", name), |b| b.iter(|| {
+            let mut src = &buf[..];
+            pb_read::<_, image::Marker>(&mut src).unwrap()
+        }));
+    }
+
+    group.finish();
+}
+
+fn bench_mem_file(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mem_file");
+
+    for &size in &[4*KB, 1*MB, 32*MB] {
+        let data = vec![0xAA; size];
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("write_all_from_slice", size), &data, |b, data| {
+            b.iter(|| {
+                let mut file = mem::File::new_small();
+                file.write_all_from_slice(data).unwrap();
+                file
+            })
+        });
+
+        let mut file = mem::File::new_small();
+        file.write_all(&data).unwrap();
+        group.bench_with_input(BenchmarkId::new("reader", size), &file, |b, file| {
+            b.iter(|| io::copy(&mut file.reader(), &mut io::sink()).unwrap())
+        });
+
+        let mut dst = new_sink_pipe();
+        group.bench_with_input(BenchmarkId::new("drain", size), &data, |b, data| {
+            b.iter_batched(|| {
+                let mut file = mem::File::new_small();
+                file.write_all(data).unwrap();
+                file
+            }, |file| file.drain(&mut dst, |_| Ok(())).unwrap(), BatchSize::LargeInput)
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_serializer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serializer");
+
+    // Many small files stress the marker writes and the shard selection.
+    const NUM_SMALL_FILES: usize = 1000;
+    let (mut shards, capacity) = new_shards(4);
+    let mut img_serializer = ImageSerializer::new(&mut shards, capacity);
+    let small_file = vec![0xAA; 4*KB];
+    group.throughput(Throughput::Elements(NUM_SMALL_FILES as u64));
+    group.bench_function("small_files_from_buf", |b| b.iter(|| {
+        for i in 0..NUM_SMALL_FILES {
+            img_serializer.write_file_from_buf(&format!("core-{}.img", i), &small_file).unwrap();
+        }
+    }));
+    drop(img_serializer);
+
+    // Data spliced from a CRIU pipe gets chunked across shards.
+    // We stay below the default pipe capacity to avoid blocking on our own pipe.
+    const SPLICED_SIZE: usize = 60*KB;
+    let (mut shards, capacity) = new_shards(4);
+    let mut img_serializer = ImageSerializer::new(&mut shards, capacity);
+    let data = vec![0xAA; SPLICED_SIZE];
+    group.throughput(Throughput::Bytes(SPLICED_SIZE as u64));
+    group.bench_function("drain_img_file", |b| {
+        b.iter_batched(|| {
+            let (pipe_r, mut pipe_w) = new_pipe();
+            pipe_w.write_all(&data).unwrap();
+            (ImageFile::new("pages-1.img".to_string(), pipe_r, false), pipe_w)
+        }, |(mut img_file, _pipe_w)| {
+            img_serializer.drain_img_file(&mut img_file, false).unwrap()
+        }, BatchSize::SmallInput)
+    });
+    drop(img_serializer);
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_pb, bench_mem_file, bench_serializer);
+criterion_main!(benches);
@@ -29,4 +29,9 @@ fn main() {
     prost_build::compile_protos(&get_proto_files("proto/criu"),
                                 &[PathBuf::from("proto/criu")])
         .expect("Failed to generate protobuf wrappers for ./proto/criu/*.proto");
+
+    #[cfg(feature = "grpc")]
+    tonic_build::configure()
+        .compile(&get_proto_files("proto/grpc"), &[PathBuf::from("proto/grpc")])
+        .expect("Failed to generate the gRPC service ./proto/grpc/*.proto");
 }
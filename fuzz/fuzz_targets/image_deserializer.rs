@@ -0,0 +1,28 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![no_main]
+
+// The image is reassembled from shard data, which comes from untrusted storage.
+
+use libfuzzer_sys::fuzz_target;
+use criu_image_streamer::{
+    extract::deserialize_img_from_slice,
+    image_store,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let mut mem_store = image_store::mem::Store::default();
+    let _ = deserialize_img_from_slice(&mut mem_store, data);
+});
@@ -0,0 +1,31 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![no_main]
+
+// files.img is parsed when remapping TCP listen ports during serve.
+
+use libfuzzer_sys::fuzz_target;
+use criu_image_streamer::{
+    image_patcher::patch_img,
+    image_store::{self, ImageStore, ImageFile},
+};
+
+fuzz_target!(|data: &[u8]| {
+    let mut mem_store = image_store::mem::Store::default();
+    let mut files = mem_store.create("files.img").unwrap();
+    files.write_all_from_slice(data).unwrap();
+    mem_store.insert("files.img", files);
+    let _ = patch_img(&mut mem_store, vec![(80, 8080)]);
+});
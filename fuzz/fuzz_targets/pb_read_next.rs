@@ -0,0 +1,32 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![no_main]
+
+// Markers from the shards and requests from CRIU are decoded with pb_read_next().
+
+use libfuzzer_sys::fuzz_target;
+use criu_image_streamer::{
+    util::pb_read_next,
+    image,
+    criu,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let mut src = data;
+    while let Ok(Some(_)) = pb_read_next::<_, image::Marker>(&mut src) {}
+
+    let mut src = data;
+    while let Ok(Some(_)) = pb_read_next::<_, criu::ImgStreamerRequestEntry>(&mut src) {}
+});
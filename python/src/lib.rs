@@ -0,0 +1,222 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+
+// Python bindings of the library API. The file descriptors passed from Python are duplicated, so
+// Python keeps ownership of its own. Operations release the GIL while streaming, so that other
+// Python threads can feed or drain the shards.
+
+// pyo3 macros expand the returned `PyResult` with a conversion that clippy finds useless.
+#![allow(clippy::useless_conversion)]
+
+use std::{
+    fs,
+    os::unix::io::{FromRawFd, RawFd},
+    path::PathBuf,
+};
+use nix::unistd::dup;
+use pyo3::{
+    prelude::*,
+    exceptions::PyRuntimeError,
+    types::PyBytes,
+};
+use ::criu_image_streamer::{
+    CaptureBuilder,
+    ExtractBuilder,
+    unix_pipe::{UnixPipe, UnixPipeImpl},
+    manifest::{load_signing_key, load_verifying_key},
+    audit::AuditLog,
+    image::marker::Body,
+    show::show_img,
+};
+
+fn to_py_err(e: anyhow::Error) -> PyErr {
+    PyRuntimeError::new_err(format!("{:#}", e))
+}
+
+fn dup_fd(fd: RawFd) -> anyhow::Result<RawFd> {
+    Ok(dup(fd)?)
+}
+
+fn pipes(fds: Vec<RawFd>) -> anyhow::Result<Vec<UnixPipe>> {
+    fds.into_iter().map(|fd| UnixPipe::new(dup_fd(fd)?)).collect()
+}
+
+fn ext_file_pipes(ext_files: Vec<(String, RawFd)>) -> anyhow::Result<Vec<(String, UnixPipe)>> {
+    ext_files.into_iter().map(|(filename, fd)| Ok((filename, UnixPipe::new(dup_fd(fd)?)?))).collect()
+}
+
+fn progress_pipe(fd: RawFd) -> anyhow::Result<fs::File> {
+    Ok(unsafe { fs::File::from_raw_fd(dup_fd(fd)?) })
+}
+
+fn audit_log(path: Option<PathBuf>) -> anyhow::Result<Option<AuditLog>> {
+    path.as_deref().map(AuditLog::open).transpose()
+}
+
+/// Captures a CRIU image into the shards. See `criu-image-streamer capture`.
+#[pyfunction]
+#[pyo3(signature = (images_dir, shard_fds, progress_fd=None, ext_files=vec![], sign_key=None,
+                    audit_log_path=None, containers=vec![], job_id=None))]
+#[allow(clippy::too_many_arguments)]
+fn capture(
+    py: Python<'_>,
+    images_dir: PathBuf,
+    shard_fds: Vec<RawFd>,
+    progress_fd: Option<RawFd>,
+    ext_files: Vec<(String, RawFd)>,
+    sign_key: Option<PathBuf>,
+    audit_log_path: Option<PathBuf>,
+    containers: Vec<String>,
+    job_id: Option<String>,
+) -> PyResult<()> {
+    let run = || -> anyhow::Result<()> {
+        let mut capture = CaptureBuilder::new(images_dir, pipes(shard_fds)?)
+            .ext_files(ext_file_pipes(ext_files)?)
+            .sign_key(sign_key.as_deref().map(load_signing_key).transpose()?)
+            .audit_log(audit_log(audit_log_path)?)
+            .containers(containers)
+            .job_id(job_id);
+        if let Some(progress_fd) = progress_fd {
+            capture = capture.progress_pipe(progress_pipe(progress_fd)?);
+        }
+        capture.run()
+    };
+    py.allow_threads(run).map_err(to_py_err)
+}
+
+/// Serves a captured image to CRIU. See `criu-image-streamer serve`.
+#[pyfunction]
+#[pyo3(signature = (images_dir, shard_fds, progress_fd=None, ext_files=vec![], tcp_listen_remaps=vec![],
+                    verify_key=None, audit_log_path=None, containers=vec![], job_id=None))]
+#[allow(clippy::too_many_arguments)]
+fn serve(
+    py: Python<'_>,
+    images_dir: PathBuf,
+    shard_fds: Vec<RawFd>,
+    progress_fd: Option<RawFd>,
+    ext_files: Vec<(String, RawFd)>,
+    tcp_listen_remaps: Vec<(u16, u16)>,
+    verify_key: Option<PathBuf>,
+    audit_log_path: Option<PathBuf>,
+    containers: Vec<String>,
+    job_id: Option<String>,
+) -> PyResult<()> {
+    let run = || -> anyhow::Result<()> {
+        let mut extract = ExtractBuilder::new(images_dir, pipes(shard_fds)?)
+            .ext_files(ext_file_pipes(ext_files)?)
+            .tcp_listen_remaps(tcp_listen_remaps)
+            .verify_key(verify_key.as_deref().map(load_verifying_key).transpose()?)
+            .audit_log(audit_log(audit_log_path)?)
+            .containers(containers)
+            .job_id(job_id);
+        if let Some(progress_fd) = progress_fd {
+            extract = extract.progress_pipe(progress_pipe(progress_fd)?);
+        }
+        extract.serve()
+    };
+    py.allow_threads(run).map_err(to_py_err)
+}
+
+/// Extracts a captured image to `images_dir`, or only checks it with `dry_run`.
+/// See `criu-image-streamer extract`.
+#[pyfunction]
+#[pyo3(signature = (images_dir, shard_fds, progress_fd=None, ext_files=vec![], verify_key=None,
+                    audit_log_path=None, dry_run=false))]
+#[allow(clippy::too_many_arguments)]
+fn extract(
+    py: Python<'_>,
+    images_dir: PathBuf,
+    shard_fds: Vec<RawFd>,
+    progress_fd: Option<RawFd>,
+    ext_files: Vec<(String, RawFd)>,
+    verify_key: Option<PathBuf>,
+    audit_log_path: Option<PathBuf>,
+    dry_run: bool,
+) -> PyResult<()> {
+    let run = || -> anyhow::Result<()> {
+        let mut extract = ExtractBuilder::new(images_dir, pipes(shard_fds)?)
+            .verify_key(verify_key.as_deref().map(load_verifying_key).transpose()?);
+        if let Some(progress_fd) = progress_fd {
+            extract = extract.progress_pipe(progress_pipe(progress_fd)?);
+        }
+        if dry_run {
+            extract.extract_dry_run()
+        } else {
+            extract.ext_files(ext_file_pipes(ext_files)?)
+                .audit_log(audit_log(audit_log_path)?)
+                .extract()
+        }
+    };
+    py.allow_threads(run).map_err(to_py_err)
+}
+
+/// Returns the entries of a CRIU image file as text. See `criu-image-streamer show`.
+#[pyfunction]
+fn show(filename: &str, img: &[u8]) -> PyResult<String> {
+    let mut out = Vec::new();
+    show_img(filename, img, &mut out).map_err(to_py_err)?;
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+/// Iterates over the markers of an image, in sequence order. Each item is a tuple
+/// `(seq, kind, value, data)`, where `kind` is one of "filename", "file_data", "file_eof", and
+/// "image_eof". `data` holds the payload of "file_data" markers.
+#[pyclass(unsendable)]
+struct ShardReader {
+    inner: ::criu_image_streamer::ShardReader,
+}
+
+#[pymethods]
+impl ShardReader {
+    #[new]
+    fn new(shard_fds: Vec<RawFd>) -> PyResult<Self> {
+        let inner = ::criu_image_streamer::ShardReader::new(pipes(shard_fds).map_err(to_py_err)?);
+        Ok(Self { inner })
+    }
+
+    /// Returns the UUID of the image, once the shard headers have been read.
+    fn image_uuid(&self) -> Option<String> {
+        self.inner.image_uuid().map(String::from)
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<(u64, &'static str, PyObject, PyObject)>> {
+        let (marker, data) = match self.inner.next() {
+            Some(item) => item.map_err(to_py_err)?,
+            None => return Ok(None),
+        };
+        let (kind, value) = match marker.body {
+            Some(Body::Filename(filename)) => ("filename", filename.into_py(py)),
+            Some(Body::FileData(size)) => ("file_data", size.into_py(py)),
+            Some(Body::FileEof(eof)) => ("file_eof", eof.into_py(py)),
+            Some(Body::ImageEof(eof)) => ("image_eof", eof.into_py(py)),
+            _ => return Err(PyRuntimeError::new_err("Malformed image marker")),
+        };
+        Ok(Some((marker.seq, kind, value, PyBytes::new_bound(py, &data).into_py(py))))
+    }
+}
+
+#[pymodule]
+fn criu_image_streamer(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(capture, m)?)?;
+    m.add_function(wrap_pyfunction!(serve, m)?)?;
+    m.add_function(wrap_pyfunction!(extract, m)?)?;
+    m.add_function(wrap_pyfunction!(show, m)?)?;
+    m.add_class::<ShardReader>()?;
+    Ok(())
+}
@@ -0,0 +1,95 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    io::Write,
+    time::{SystemTime, UNIX_EPOCH},
+    path::Path,
+    fs,
+};
+use serde::Serialize;
+use crate::{
+    manifest::FileDigest,
+    util::{progress_line, ProgressFormat},
+};
+use anyhow::{Result, Context};
+
+/// `AuditLog` emits a record for each image file that goes in or out of the streamer.
+/// Records are JSON formatted, one per line. Each record is emitted with a single write() so
+/// that records are not interleaved when multiple streamers append to the same file.
+/// Failing to write an audit record fails the whole operation, as required for compliance.
+pub struct AuditLog {
+    file: fs::File,
+    /// Set when the records go on the progress fd, framed in its format.
+    progress_format: Option<ProgressFormat>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// The file was captured into the image
+    In,
+    /// The file was served to CRIU, or extracted from the image
+    Out,
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    filename: &'a str,
+    size: u64,
+    sha256: &'a str,
+    timestamp_millis: u128,
+    direction: Direction,
+}
+
+impl AuditLog {
+    pub fn new(file: fs::File) -> Self {
+        Self { file, progress_format: None }
+    }
+
+    /// Emits the records on the progress fd. Each record is a progress message, so that it
+    /// doesn't corrupt the length-delimited messages of the proto format.
+    pub fn to_progress(progress_pipe: fs::File, format: ProgressFormat) -> Self {
+        Self { file: progress_pipe, progress_format: Some(format) }
+    }
+
+    /// Returns another handle on the same audit log. Records are not interleaved, as each is
+    /// written with a single write().
+    pub fn try_clone(&self) -> Result<Self> {
+        Ok(Self { file: self.file.try_clone()?, progress_format: self.progress_format })
+    }
+
+    /// Opens the audit log in append mode, creating the file if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = fs::OpenOptions::new().append(true).create(true).open(path)
+            .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+        Ok(Self::new(file))
+    }
+
+    pub fn record(&mut self, direction: Direction, filename: &str, digest: &FileDigest) -> Result<()> {
+        let timestamp_millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        let record = AuditRecord {
+            filename,
+            size: digest.size,
+            sha256: &digest.sha256,
+            timestamp_millis,
+            direction,
+        };
+
+        let line = serde_json::to_string(&record)?;
+        let buf = progress_line(self.progress_format.unwrap_or(ProgressFormat::Json), &line);
+        self.file.write_all(&buf).context("Failed to write to the audit log")?;
+        Ok(())
+    }
+}
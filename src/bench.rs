@@ -0,0 +1,227 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    os::unix::net::UnixStream,
+    os::unix::io::AsRawFd,
+    io::{self, BufRead, BufReader, Write},
+    iter::repeat_n,
+    time::{Duration, Instant},
+    path::Path,
+    thread,
+    fs,
+};
+use nix::unistd::pipe;
+use serde::Serialize;
+use crate::{
+    capture::CaptureBuilder,
+    criu,
+    criu_connection::IMG_STREAMER_CAPTURE_SOCKET_NAME,
+    unix_pipe::{UnixPipe, UnixPipeImpl},
+    util::*,
+};
+use anyhow::{Result, Context};
+
+// The bench operation measures the performance of the capture operation without a real CRIU dump.
+// We play the role of CRIU by sending a synthetic mix of files to the capture socket, and discard
+// what comes out of the shards. Resource usage is measured on the thread running capture(), which
+// excludes the cost of generating and discarding the data.
+
+const SMALL_FILE_SIZE: usize = 4*KB;
+#[allow(clippy::identity_op)]
+const MEDIUM_FILE_SIZE: usize = 1*MB;
+const LARGE_FILE_SIZE: usize = 64*MB;
+
+/// Data is written to CRIU file pipes in blocks of this size.
+#[allow(clippy::identity_op)]
+const WRITE_BLOCK_SIZE: usize = 1*MB;
+
+pub struct Workload {
+    pub small_files: usize,
+    pub medium_files: usize,
+    pub large_files: usize,
+    /// Target rate in bytes per second at which files are sent. `None` means unlimited.
+    pub rate: Option<u64>,
+}
+
+impl Workload {
+    fn file_sizes(&self) -> impl Iterator<Item = usize> {
+        // Small files come first, as the first files of a CRIU dump are small metadata files.
+        repeat_n(SMALL_FILE_SIZE, self.small_files)
+            .chain(repeat_n(MEDIUM_FILE_SIZE, self.medium_files))
+            .chain(repeat_n(LARGE_FILE_SIZE, self.large_files))
+    }
+}
+
+#[derive(Serialize)]
+struct BenchReport {
+    files: usize,
+    bytes: u64,
+    duration_millis: u128,
+    throughput_mb_per_sec: f64,
+    /// read() and write() like syscalls performed by capture(), splice() excluded. Missing when
+    /// the kernel doesn't support per task I/O accounting.
+    read_syscalls: Option<u64>,
+    write_syscalls: Option<u64>,
+    cpu_user_millis: u64,
+    cpu_system_millis: u64,
+    cpu_usage_percent: f64,
+}
+
+#[derive(Default)]
+struct ThreadUsage {
+    syscalls: Option<(u64, u64)>,
+    cpu_user: Duration,
+    cpu_system: Duration,
+}
+
+impl ThreadUsage {
+    fn current() -> Self {
+        let syscalls = Self::io_syscalls();
+
+        let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+        unsafe { libc::getrusage(libc::RUSAGE_THREAD, &mut usage) };
+        let to_duration = |tv: libc::timeval|
+            Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64);
+
+        Self { syscalls, cpu_user: to_duration(usage.ru_utime), cpu_system: to_duration(usage.ru_stime) }
+    }
+
+    /// Returns the number of read and write syscalls of the current thread.
+    fn io_syscalls() -> Option<(u64, u64)> {
+        let io = fs::read_to_string("/proc/thread-self/io").ok()?;
+        let get = |key: &str| io.lines()
+            .find_map(|line| line.strip_prefix(key))
+            .and_then(|v| v.trim().parse().ok());
+        Some((get("syscr:")?, get("syscw:")?))
+    }
+
+    fn since(&self, start: &Self) -> Self {
+        let syscalls = match (self.syscalls, start.syscalls) {
+            (Some((r, w)), Some((r0, w0))) => Some((r - r0, w - w0)),
+            _ => None,
+        };
+        Self {
+            syscalls,
+            cpu_user: self.cpu_user - start.cpu_user,
+            cpu_system: self.cpu_system - start.cpu_system,
+        }
+    }
+}
+
+fn new_pipe() -> Result<(UnixPipe, UnixPipe)> {
+    let (fd_r, fd_w) = pipe()?;
+    Ok((UnixPipe::new(fd_r)?, UnixPipe::new(fd_w)?))
+}
+
+/// Sends the workload files to the capture socket, the same way CRIU does.
+fn send_files(images_dir: &Path, workload: &Workload) -> Result<(usize, u64)> {
+    let socket_path = images_dir.join(IMG_STREAMER_CAPTURE_SOCKET_NAME);
+    let mut socket = UnixStream::connect(&socket_path)
+        .with_context(|| format!("Failed to connect to {}", socket_path.display()))?;
+
+    let block = vec![0xAA; WRITE_BLOCK_SIZE];
+    let start_time = Instant::now();
+    let mut num_files = 0;
+    let mut total_bytes = 0;
+
+    for size in workload.file_sizes() {
+        let filename = format!("bench-{}.img", num_files);
+        pb_write(&mut socket, &criu::ImgStreamerRequestEntry { filename })?;
+        let (pipe_r, mut pipe_w) = new_pipe()?;
+        send_fd(&mut socket, pipe_r.as_raw_fd())?;
+        drop(pipe_r);
+
+        let mut remaining = size;
+        while remaining > 0 {
+            let len = remaining.min(WRITE_BLOCK_SIZE);
+            pipe_w.write_all(&block[..len]).context("Failed to write to the capture")?;
+            remaining -= len;
+            total_bytes += len as u64;
+
+            if let Some(rate) = workload.rate {
+                let duration_goal = Duration::from_secs_f64(total_bytes as f64 / rate as f64);
+                if let Some(ahead) = duration_goal.checked_sub(start_time.elapsed()) {
+                    thread::sleep(ahead);
+                }
+            }
+        }
+        num_files += 1;
+    }
+
+    Ok((num_files, total_bytes))
+}
+
+/// The description of arguments can be found in main.rs
+pub fn bench(
+    images_dir: &Path,
+    mut progress_pipe: fs::File,
+    num_shards: usize,
+    workload: Workload,
+) -> Result<()>
+{
+    ensure!(num_shards > 0, "At least one shard is needed");
+
+    let (shard_pipes_r, shard_pipes_w): (Vec<UnixPipe>, Vec<UnixPipe>) =
+        (0..num_shards).map(|_| new_pipe()).collect::<Result<Vec<_>>>()?.into_iter().unzip();
+
+    // Shards are discarded.
+    let shard_threads: Vec<_> = shard_pipes_r.into_iter()
+        .map(|mut pipe| thread::spawn(move || io::copy(&mut pipe, &mut io::sink())))
+        .collect();
+
+    let (capture_progress_r, capture_progress_w) = new_pipe()?;
+    let mut capture_progress = BufReader::new(capture_progress_r);
+
+    let capture_thread = {
+        let images_dir = images_dir.to_path_buf();
+        thread::spawn(move || -> Result<ThreadUsage> {
+            let start_usage = ThreadUsage::current();
+            CaptureBuilder::new(images_dir, shard_pipes_w)
+                .progress_pipe(capture_progress_w)
+                .run()?;
+            Ok(ThreadUsage::current().since(&start_usage))
+        })
+    };
+
+    let mut line = String::new();
+    capture_progress.read_line(&mut line)?;
+    ensure!(line == "socket-init\n", "The capture failed to start");
+
+    let start_time = Instant::now();
+    let (files, bytes) = send_files(images_dir, &workload)?;
+    let usage = capture_thread.join().map_err(|_| anyhow!("capture() panicked"))??;
+    let duration = start_time.elapsed();
+
+    for shard_thread in shard_threads {
+        shard_thread.join().map_err(|_| anyhow!("Shard reader panicked"))??;
+    }
+
+    let duration_secs = duration.as_secs_f64();
+    let cpu = usage.cpu_user + usage.cpu_system;
+    let report = BenchReport {
+        files,
+        bytes,
+        duration_millis: duration.as_millis(),
+        throughput_mb_per_sec: bytes as f64 / MB as f64 / duration_secs,
+        read_syscalls: usage.syscalls.map(|(r, _)| r),
+        write_syscalls: usage.syscalls.map(|(_, w)| w),
+        cpu_user_millis: usage.cpu_user.as_millis() as u64,
+        cpu_system_millis: usage.cpu_system.as_millis() as u64,
+        cpu_usage_percent: 100.0 * cpu.as_secs_f64() / duration_secs,
+    };
+    emit_progress(&mut progress_pipe, &serde_json::to_string(&report)?);
+
+    Ok(())
+}
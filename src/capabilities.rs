@@ -0,0 +1,95 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+
+use serde::Serialize;
+use crate::{
+    kernel_caps::{KernelCaps, KERNEL_CAPS},
+    codec::{gzip_backends, is_in_path},
+};
+
+// Orchestration layers may run against different versions of the streamer. Instead of parsing
+// --help or comparing version numbers, they can check what the installed streamer supports with
+// the capabilities operation.
+
+/// Version of the image format written on the shards. Version 1 images have no shard headers.
+/// Images of all versions can be read.
+pub const IMAGE_FORMAT_VERSION: u32 = 2;
+
+/// The operations of the command line.
+pub const OPERATIONS: &[&str] = &[
+    "capture", "serve", "extract", "bench", "replay", "show", "list", "filter", "merge", "import-stream",
+    #[cfg(feature = "kubelet")]
+    "kubelet-import",
+    #[cfg(feature = "kubelet")]
+    "kubelet-export",
+    #[cfg(feature = "grpc")]
+    "grpc-server",
+    "doctor", "capabilities", "completions",
+];
+
+/// Optional features, and the options enabling them.
+pub const FEATURES: &[&str] = &[
+    "ext-files", "tcp-listen-remap", "audit-log", "expected-size",
+    "shard-spill", "cpuset", "nice", "criu-trace", "containers", "job-id", "dry-run", "to-stdout",
+    "codec-cmd", "codec", "chunk-alignment", "shard-devices", "request-stats", "shard-cmd", "rclone", "ssh", "mux-shards", "file-table", "reflink-from", "snapshot-cmd", "chown", "chmod", "min-pipe-capacity", "max-rate", "rate-control", "progress-format", "log-target", "audit-log-to-progress",
+    #[cfg(feature = "config")]
+    "config",
+    #[cfg(feature = "signing")]
+    "sign-key",
+    #[cfg(feature = "signing")]
+    "verify-key",
+    #[cfg(feature = "grpc")]
+    "grpc",
+    #[cfg(feature = "otel")]
+    "otlp-endpoint",
+];
+
+#[derive(Serialize)]
+pub struct ImageFormat {
+    pub version: u32,
+    pub readable_versions: Vec<u32>,
+}
+
+#[derive(Serialize)]
+pub struct Capabilities {
+    pub version: &'static str,
+    pub image_format: ImageFormat,
+    pub operations: &'static [&'static str],
+    pub features: &'static [&'static str],
+    /// The gzip backends available for --codec gzip, the preferred first, e.g., `qat`, `isa-l`.
+    /// Otherwise, compression is left to the programs at the other end of the shards, or to
+    /// --codec-cmd.
+    pub codecs: Vec<String>,
+    /// The remote storage available with --rclone, i.e., `rclone` when it is in the PATH.
+    /// Otherwise, remote storage is left to the programs at the other end of the shards, or to
+    /// --shard-cmd.
+    pub remotes: Vec<String>,
+    pub kernel: KernelCaps,
+}
+
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        image_format: ImageFormat {
+            version: IMAGE_FORMAT_VERSION,
+            readable_versions: (1..=IMAGE_FORMAT_VERSION).collect(),
+        },
+        operations: OPERATIONS,
+        features: FEATURES,
+        codecs: gzip_backends().into_iter().map(String::from).collect(),
+        remotes: is_in_path("rclone").then(|| "rclone".to_string()).into_iter().collect(),
+        kernel: KERNEL_CAPS.clone(),
+    }
+}
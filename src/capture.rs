@@ -13,25 +13,45 @@
 //  limitations under the License.
 
 use std::{
-    collections::{BinaryHeap},
+    collections::{BinaryHeap, VecDeque},
     os::unix::io::AsRawFd,
-    time::Instant,
+    time::{Duration, Instant},
     cmp::{min, max},
-    path::Path,
+    path::PathBuf,
     sync::Once,
     rc::Rc,
+    os::unix::net::UnixListener,
+    thread,
+    io::{Read, Write},
+    mem::size_of,
     fs,
 };
 use crate::{
-    poller::{Poller, EpollFlags},
-    criu_connection::{CriuListener, CriuConnection},
-    unix_pipe::{UnixPipe, UnixPipeImpl},
+    poller::{Poller, PollResult, EpollFlags},
+    criu_connection::{CriuListener, CriuConnection, criu_socket_dirs, bind_control_socket},
+    criu_watchdog::{CriuWatchdog, WATCHDOG_INTERVAL},
+    unix_pipe::{UnixPipe, UnixPipeImpl, ensure_min_capacity},
     util::*,
     image,
     image::marker,
     impl_ord_by,
+    manifest::{Manifest, FileHasher, SigningKey, MANIFEST_FILENAME, MANIFEST_SIG_FILENAME,
+               is_reserved_filename},
+    audit::{AuditLog, Direction},
+    kernel_caps::KERNEL_CAPS,
+    page_stats::{PageStats, is_pagemap_filename},
+    criu_trace::{CriuTrace, TraceEvent, TraceOperation},
+    events::{Event, EventCallback},
+    marker_log::MarkerLog,
+    rate_limit::{RateLimit, Throttle, handle_rate_control, MAX_THROTTLE_PAUSE},
+    file_table::{FileTableBuilder, encode_file_table},
 };
-use anyhow::Result;
+use prost::Message;
+use nix::{
+    poll::{poll, PollFd, PollFlags},
+    unistd::Pid,
+};
+use anyhow::{Result, Context};
 
 // When CRIU dumps an application, it first connects to our UNIX socket. CRIU will send us many
 // image files during the dumping process. To send an image file, it sends a protobuf request that
@@ -61,34 +81,70 @@ use anyhow::Result;
 /// Note that the following pipe buffers are not actually using memory. The content of the pipe is
 /// just a list of pointers to the application memory page, which is already allocated as CRIU does
 /// a vmsplice(..., SPLICE_F_GIFT) when providing data.
-const CRIU_PIPE_DESIRED_CAPACITY: i32 = 4*MB as i32;
+pub const CRIU_PIPE_DESIRED_CAPACITY: i32 = 4*MB as i32;
 
 /// Large buffers size improves performance as it allows us to increase the size of our chunks.
 /// 1MB provides excellent performance.
 #[allow(clippy::identity_op)]
-const SHARD_PIPE_DESIRED_CAPACITY: i32 = 1*MB as i32;
+pub const SHARD_PIPE_DESIRED_CAPACITY: i32 = 1*MB as i32;
+
+/// Chunks up to this size are copied in userspace instead of being spliced. This way, the marker
+/// and its data are written to the shard with a single writev(). When checkpointing many small
+/// files, the per-file syscall overhead dominates, which is what this reduces.
+const SMALL_CHUNK_MAX_SIZE: i32 = 16*KB as i32;
+
+/// Number of events returned by a single epoll_wait(). This doesn't matter much as the number of
+/// concurrent image files is typically at most 2, but a larger capacity reduces syscalls when
+/// many external files are streamed.
+pub const DEFAULT_EPOLL_CAPACITY: usize = 8;
+
+/// Maximum number of bytes drained from an image file before giving other ready image files their
+/// turn. This prevents a large file from monopolizing the capture when CRIU interleaves files
+/// (e.g., pages-X.img and pagemap-X.img), lowering the latency of small files.
+#[allow(clippy::identity_op)]
+const DRAIN_BURST_BUDGET: i32 = 1*MB as i32;
 
 /// An `ImageFile` represents a file coming from CRIU.
 /// The complete CRIU image is comprised of many of these files.
-struct ImageFile {
+pub struct ImageFile {
     /// Incoming pipe from CRIU
     pipe: UnixPipe,
     /// Associated filename (e.g., "pages-3.img")
     filename: Rc<str>,
+    /// When signing or auditing the image, we compute the digest of the file as it streams
+    /// through.
+    hasher: Option<FileHasher>,
+    /// Pagemap images are kept in memory to compute the page statistics once fully received.
+    pagemap: Option<Vec<u8>>,
+    /// Number of bytes received so far
+    size: u64,
 }
 
 impl ImageFile {
-    pub fn new(filename: String, mut pipe: UnixPipe) -> Self {
+    pub fn new(filename: String, mut pipe: UnixPipe, with_digest: bool) -> Self {
         // Try setting the pipe capacity. Failing is okay, it's just for better performance.
         let _ = pipe.set_capacity(CRIU_PIPE_DESIRED_CAPACITY);
         let filename = Rc::from(filename);
-        Self { pipe, filename }
+        let hasher = if with_digest { Some(FileHasher::default()) } else { None };
+        let pagemap = if is_pagemap_filename(&filename) { Some(Vec::new()) } else { None };
+        Self { pipe, filename, hasher, pagemap, size: 0 }
     }
 }
 
+/// Outcome of `ImageSerializer::drain_img_file()`
+#[derive(Debug, PartialEq)]
+pub enum DrainStatus {
+    /// The image file pipe is empty, more data may come.
+    Empty,
+    /// The burst budget is exhausted, but the image file pipe has more data to drain.
+    Pending,
+    /// EOF of the image file is reached.
+    Eof,
+}
+
 /// A `Shard` is a pipe whose endpoint goes to the upload process (e.g., aws s3)
 /// We keep track of the in-kernel buffer capacity to optimize performance.
-struct Shard {
+pub struct Shard {
     /// Outgoing pipe to the uploader
     pipe: UnixPipe,
     /// `remaining_space` is a lower bound of the in-kernel pipe remaining space. As the upload
@@ -98,18 +154,105 @@ struct Shard {
     remaining_space: i32,
     /// Total bytes written to the pipe, useful for producing stats.
     bytes_written: u64,
+    /// Data that didn't fit in the pipe when spilling is enabled. It is written to the pipe
+    /// before any other data.
+    spill: VecDeque<u8>,
+    /// Set while a chunk is written. If the write fails midway, the shard ends with a partial
+    /// chunk, and can't carry any more markers.
+    torn: bool,
+    /// Position of the shard in the provided shards, as in its header. Set by `ImageSerializer`.
+    index: usize,
+    /// Time spent writing to the shard while it was full, and waiting for its spilled data to be
+    /// written.
+    blocked_duration: Duration,
+    /// Time spent splicing data into the shard.
+    splice_duration: Duration,
+    /// Bytes of markers and padding written to the shard, the rest of the shard being file data.
+    marker_bytes: u64,
 }
 
 impl Shard {
     pub fn new(pipe: UnixPipe) -> Result<Self> {
-        Ok(Self { pipe, remaining_space: 0, bytes_written: 0 })
+        Ok(Self { pipe, remaining_space: 0, bytes_written: 0, spill: VecDeque::new(), torn: false, index: 0,
+                  blocked_duration: Duration::ZERO, splice_duration: Duration::ZERO, marker_bytes: 0 })
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Returns the stats of the shard, written over `transfer_duration_millis`.
+    pub fn stat(&self, transfer_duration_millis: u128) -> ShardStat {
+        ShardStat {
+            size: self.bytes_written,
+            transfer_duration_millis,
+            blocked_millis: Some(self.blocked_duration.as_millis()),
+            splice_millis: Some(self.splice_duration.as_millis()),
+            marker_bytes: Some(self.marker_bytes),
+            pipe_capacity: self.pipe.capacity().ok(),
+        }
+    }
+
+    /// Writes the shard header. This must be done before any other data is written.
+    fn write_header(&mut self, header: image::ShardHeader, chunk_alignment: usize) -> Result<()> {
+        let mut marker = image::Marker { seq: 0, body: Some(marker::Body::ShardHeader(header)), padding: 0, file_size: 0 };
+        set_padding(&mut marker, 0, 0, chunk_alignment);
+        let mut buf = Vec::new();
+        pb_write(&mut buf, &marker)?;
+        buf.resize(buf.len() + marker.padding as usize, 0);
+        self.pipe.write_all(&buf).context("Failed to write to shard")?;
+        self.bytes_written += buf.len() as u64;
+        self.marker_bytes += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Writes the image aborted marker, unless it would block. A shard that is torn, or that has
+    /// spilled data, can't carry it. The extraction then finds out at EOF that the image is
+    /// incomplete.
+    fn write_abort(&mut self) {
+        // POLLOUT means that the pipe has a free slot, so writing the few bytes of the marker
+        // doesn't block.
+        let mut poll_fds = [PollFd::new(self.pipe.as_raw_fd(), PollFlags::POLLOUT)];
+        let writable = matches!(poll(&mut poll_fds, 0), Ok(1)) &&
+                       poll_fds[0].revents() == Some(PollFlags::POLLOUT);
+        if writable && !self.torn && self.spill.is_empty() {
+            let marker = image::Marker { seq: 0, body: Some(marker::Body::ImageAborted(true)), padding: 0, file_size: 0 };
+            let _ = pb_write(&mut self.pipe, &marker);
+        }
     }
 
     pub fn refresh_remaining_space(&mut self, pipe_capacity: i32) -> Result<()> {
         let pipe_len = self.pipe.fionread()?;
-        self.remaining_space = pipe_capacity - pipe_len;
+        self.remaining_space = pipe_capacity - pipe_len - self.spill.len() as i32;
+        Ok(())
+    }
+
+    /// Writes as much of the spilled data as the pipe accepts without blocking.
+    fn flush_spill(&mut self) -> Result<()> {
+        let (front, back) = self.spill.as_slices();
+        let written = self.pipe.try_writev(&[front, back]).context("Failed to write to shard")?;
+        self.spill.drain(..written);
         Ok(())
     }
+
+    /// Writes the chunk without blocking. What doesn't fit in the pipe is spilled in memory.
+    /// Returns the number of bytes spilled.
+    fn write_or_spill(&mut self, bufs: &[&[u8]]) -> Result<usize> {
+        let mut written = if self.spill.is_empty() {
+            self.pipe.try_writev(bufs).context("Failed to write to shard")?
+        } else {
+            0 // Data must be written in order
+        };
+
+        let mut spilled = 0;
+        for buf in bufs {
+            let skip = min(written, buf.len());
+            written -= skip;
+            self.spill.extend(&buf[skip..]);
+            spilled += buf.len() - skip;
+        }
+        Ok(spilled)
+    }
 }
 
 // This gives ordering to `Shard` over its `remaining_space` field, useful for the binary heap
@@ -124,50 +267,238 @@ impl_ord_by!(Shard, |a: &Self, b: &Self| a.remaining_space.cmp(&b.remaining_spac
 /// Chunks are ordered by a sequence number. Semantically, the sequence number should be per image
 /// file, but for simplicity, we use a global sequence number. It makes the implementation easier,
 /// esp. on the deserializer side.
-struct ImageSerializer<'a> {
+/// It is public so that it can be exercised by the benchmarks in benches/.
+pub struct ImageSerializer<'a> {
     shards: BinaryHeap<&'a mut Shard>,
     shard_pipe_capacity: i32, // constant
     seq: u64,
     current_filename: Option<Rc<str>>,
+    /// Buffer used when the chunk data must be copied in userspace instead of being spliced.
+    copy_buf: Vec<u8>,
+    /// Markers are encoded in this buffer before being written along with their chunk data.
+    /// A filename marker stays in this buffer until the next chunk is written.
+    marker_buf: Vec<u8>,
+    /// When non-zero, shard pipes are non-blocking, and chunks that don't fit in a shard pipe are
+    /// buffered in memory, up to this many bytes across all shards. This prevents a stalled
+    /// uploader from freezing CRIU while other shards could take the data.
+    spill_max_size: usize,
+    /// Total bytes spilled across all shards
+    spill_size: usize,
+    /// Total bytes of file data written, markers excluded
+    data_size: u64,
+    /// Set once the image EOF marker is written. See the `Drop` implementation.
+    image_eof: bool,
+    /// Logs the markers as they are written, see `log_markers()`.
+    marker_log: Option<&'a mut MarkerLog>,
+    /// The filename marker waiting in `marker_buf`, kept to be logged with the shard it goes to.
+    pending_filename_marker: Option<image::Marker>,
+    /// Size of the next file, recorded in its filename marker. See `hint_file_size()`.
+    file_size_hint: Option<(Rc<str>, u64)>,
+    /// When non-zero, chunks are padded so that each marker starts on a multiple of this many
+    /// bytes in the shard stream.
+    chunk_alignment: usize,
+    /// Markers larger than this are rejected, see `limit_marker_size()`.
+    max_marker_size: usize,
+    /// Zeros, written as padding.
+    padding_buf: Vec<u8>,
+    /// Time spent writing to shards that were all full. See `saturated_duration()`.
+    saturated_duration: Duration,
+    /// Records the files written, see `record_file_table()`.
+    file_table: Option<FileTableBuilder>,
+    /// High-water mark of the bytes in the shard pipes and spilled. See `max_buffered_bytes()`.
+    max_buffered_bytes: u64,
 }
 
 struct Chunk<'a> {
     marker: image::Marker,
-    data: Option<(&'a mut ImageFile, i32)>,
+    data: ChunkData<'a>,
+}
+
+enum ChunkData<'a> {
+    None,
+    /// Data is spliced from the image file pipe
+    Pipe(&'a mut ImageFile, i32),
+    /// Data is already in memory
+    Buf(&'a [u8]),
+}
+
+impl ChunkData<'_> {
+    fn len(&self) -> i32 {
+        match self {
+            ChunkData::None => 0,
+            ChunkData::Pipe(_, size) => *size,
+            ChunkData::Buf(buf) => buf.len() as i32,
+        }
+    }
 }
 
 /// Chunks are preceded by a header that we call marker. Chunk markers take an entire page in
 /// kernel space as it is followed by spliced data.
 static CHUNK_MARKER_KERNEL_SIZE: &PAGE_SIZE = &PAGE_SIZE;
 
+/// Larger alignments waste too much of the shard pipes in padding.
+pub const MAX_CHUNK_ALIGNMENT: usize = 64*KB;
+
+/// Sets the padding of `marker` so that `prefix_len` bytes, the marker, `data_len` bytes of data,
+/// and the padding end on a multiple of `alignment`. The padding is recorded in the marker, which
+/// changes the size of the marker, hence the loop. It converges in a few rounds, as the padding
+/// only grows, and the size of its encoding is bounded.
+fn set_padding(marker: &mut image::Marker, prefix_len: usize, data_len: usize, alignment: usize) {
+    marker.padding = 0;
+    if alignment == 0 {
+        return;
+    }
+    loop {
+        let len = prefix_len + size_of::<u32>() + marker.encoded_len() + data_len + marker.padding as usize;
+        let missing = (alignment - len % alignment) % alignment;
+        if missing == 0 {
+            return;
+        }
+        marker.padding += missing as u32;
+    }
+}
+
 impl<'a> ImageSerializer<'a> {
     pub fn new(shards: &'a mut [Shard], shard_pipe_capacity: i32) -> Self {
         assert!(!shards.is_empty());
         Self {
             shard_pipe_capacity,
-            shards: shards.iter_mut().collect(),
+            shards: shards.iter_mut().enumerate()
+                .map(|(index, shard)| { shard.index = index; shard })
+                .collect(),
             current_filename: None,
             seq: 0,
+            copy_buf: Vec::new(),
+            marker_buf: Vec::new(),
+            spill_max_size: 0,
+            spill_size: 0,
+            data_size: 0,
+            image_eof: false,
+            marker_log: None,
+            pending_filename_marker: None,
+            file_size_hint: None,
+            chunk_alignment: 0,
+            max_marker_size: DEFAULT_MAX_PB_SIZE,
+            padding_buf: Vec::new(),
+            saturated_duration: Duration::default(),
+            file_table: None,
+            max_buffered_bytes: 0,
         }
     }
 
+    /// Time spent blocked on the shards. Chunks go to the shard with the most room, so a write
+    /// blocks only when all the shards are full, meaning that the uploaders are not keeping up.
+    pub fn saturated_duration(&self) -> Duration {
+        self.saturated_duration
+    }
+
+    /// The most bytes that were in the shard pipes and spilled at once. The shard pipes are only
+    /// measured when their remaining space is refreshed, which happens when the shards fill up,
+    /// so this is a sample of the high-water mark, not an exact figure.
+    pub fn max_buffered_bytes(&self) -> u64 {
+        self.max_buffered_bytes
+    }
+
+    /// Pads the chunks so that each marker starts on a multiple of `chunk_alignment` bytes in the
+    /// shard stream, e.g., for uploaders writing with O_DIRECT. The shard headers must have been
+    /// written with the same alignment.
+    pub fn align_chunks(&mut self, chunk_alignment: usize) {
+        self.chunk_alignment = chunk_alignment;
+    }
+
+    /// Fails instead of writing markers larger than `max_marker_size` bytes, which the extract
+    /// side would reject. `DEFAULT_MAX_PB_SIZE` by default.
+    pub fn limit_marker_size(&mut self, max_marker_size: usize) {
+        self.max_marker_size = max_marker_size;
+    }
+
+    /// Records the files written, so that the file table can be written at the end of the first
+    /// shard with `write_file_table()`.
+    pub fn record_file_table(&mut self) {
+        self.file_table = Some(FileTableBuilder::new(self.shards.len()));
+    }
+
+    /// Logs every marker written to the shards, along with the shard it goes to.
+    pub fn log_markers(&mut self, marker_log: &'a mut MarkerLog) {
+        self.marker_log = Some(marker_log);
+    }
+
+    /// Makes the shard pipes non-blocking, and buffers up to `spill_max_size` bytes in memory
+    /// when shards are full. Spilled data must be flushed with `flush_spills()` and
+    /// `wait_spills()`.
+    pub fn enable_spill(&mut self, spill_max_size: usize) -> Result<()> {
+        for shard in self.shards.iter() {
+            shard.pipe.set_nonblocking()?;
+        }
+        self.spill_max_size = spill_max_size;
+        Ok(())
+    }
+
     fn refresh_all_shard_remaining_space(&mut self) -> Result<()> {
         // We wish to mutate all the elements of the BinaryHeap.
         // We tear the existing one down and build a fresh one to reduce insertion cost.
+        // It's also a good time to flush spilled data.
         let shard_pipe_capacity = self.shard_pipe_capacity;
+        let flush_spills = self.spill_size > 0;
         self.shards = self.shards.drain()
             .map(|shard| {
+                if flush_spills && !shard.spill.is_empty() {
+                    shard.flush_spill()?;
+                }
                 shard.refresh_remaining_space(shard_pipe_capacity)?;
                 Ok(shard)
             })
             .collect::<Result<_>>()?;
+        if flush_spills {
+            self.spill_size = self.shards.iter().map(|shard| shard.spill.len()).sum();
+        }
+        // `remaining_space` accounts for both the pipe content and the spilled data.
+        let buffered_bytes: i64 = self.shards.iter()
+            .map(|shard| max(shard_pipe_capacity - shard.remaining_space, 0) as i64)
+            .sum();
+        self.max_buffered_bytes = max(self.max_buffered_bytes, buffered_bytes as u64);
+        Ok(())
+    }
+
+    /// Writes to the shards what they accept of the spilled data, without blocking.
+    pub fn flush_spills(&mut self) -> Result<()> {
+        if self.spill_size > 0 {
+            self.refresh_all_shard_remaining_space()?;
+        }
+        Ok(())
+    }
+
+    /// Blocks until at most `max_size` bytes remain spilled.
+    pub fn wait_spills(&mut self, max_size: usize) -> Result<()> {
+        while self.spill_size > max_size {
+            let mut poll_fds: Vec<PollFd> = self.shards.iter()
+                .filter(|shard| !shard.spill.is_empty())
+                .map(|shard| PollFd::new(shard.pipe.as_raw_fd(), PollFlags::POLLOUT))
+                .collect();
+            let timeout = -1;
+            let poll_start = Instant::now();
+            let result = poll(&mut poll_fds, timeout);
+            let blocked_duration = poll_start.elapsed();
+            self.saturated_duration += blocked_duration;
+            // Each shard that holds spilled data held the capture back.
+            let mut shards = std::mem::take(&mut self.shards).into_vec();
+            for shard in shards.iter_mut().filter(|shard| !shard.spill.is_empty()) {
+                shard.blocked_duration += blocked_duration;
+            }
+            self.shards = shards.into();
+            match result {
+                Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+                result => result.context("Failed to poll shards")?,
+            };
+            self.refresh_all_shard_remaining_space()?;
+        }
         Ok(())
     }
 
     fn gen_marker(&mut self, body: marker::Body) -> image::Marker {
         let seq = self.seq;
         self.seq += 1;
-        image::Marker { seq, body: Some(body) }
+        image::Marker { seq, body: Some(body), padding: 0, file_size: 0 }
     }
 
     /// When transferring bytes from the CRIU pipe to one of the shards, we do so with large chunks
@@ -178,14 +509,19 @@ impl<'a> ImageSerializer<'a> {
         max(self.shard_pipe_capacity/4 - **CHUNK_MARKER_KERNEL_SIZE as i32, *PAGE_SIZE as i32)
     }
 
-    fn write_chunk(&mut self, chunk: Chunk) -> Result<()> {
-        let data_size = match chunk.data {
-            None => 0,
-            Some((_, size)) => size,
-        };
+    fn write_chunk(&mut self, mut chunk: Chunk) -> Result<()> {
+        let data_size = chunk.data.len();
+        self.data_size += data_size as u64;
+        // The pending filename marker in `marker_buf` is written along, it counts in the padding.
+        set_padding(&mut chunk.marker, self.marker_buf.len(), data_size as usize, self.chunk_alignment);
+        pb_write_max(&mut self.marker_buf, &chunk.marker, self.max_marker_size)?;
+        let padding_size = chunk.marker.padding as usize;
+        if self.padding_buf.len() < padding_size {
+            self.padding_buf.resize(padding_size, 0);
+        }
 
         // Estimate the space required in the shard pipe to write the marker and its data.
-        let space_required = **CHUNK_MARKER_KERNEL_SIZE as i32 + data_size;
+        let space_required = **CHUNK_MARKER_KERNEL_SIZE as i32 + data_size + padding_size as i32;
 
         // Check if the shard with the most remaining space is likely to block.
         // If so, refresh other pipes' remaining space to check for a better candidate.
@@ -199,124 +535,703 @@ impl<'a> ImageSerializer<'a> {
         // Pick the shard with the greatest remaining space for our write. We might block when we
         // write, but that's inevitable, and that's how our output is throttled.
         let mut shard = self.shards.peek_mut().unwrap();
+        // Spilled chunks don't block. The time spent waiting on the spills is accounted instead.
+        let saturated_start = (shard.remaining_space < space_required && self.spill_max_size == 0)
+            .then(Instant::now);
+
+        // Markers are logged before being written, so that a write that blocks forever shows up.
+        if let Some(marker_log) = self.marker_log.as_mut() {
+            if let Some(filename_marker) = self.pending_filename_marker.take() {
+                marker_log.record(shard.index, &filename_marker, None)?;
+            }
+            marker_log.record(shard.index, &chunk.marker, self.current_filename.as_deref())?;
+        }
 
-        // 1) Write the chunk marker
-        let marker_size = pb_write(&mut shard.pipe, &chunk.marker)?;
+        if let (Some(file_table), Some(filename)) = (self.file_table.as_mut(), self.current_filename.as_ref()) {
+            match chunk.marker.body {
+                Some(marker::Body::FileData(size)) => file_table.add_chunk(filename, shard.index, size),
+                Some(marker::Body::FileEof(_)) => file_table.add_file(filename),
+                _ => {}
+            }
+        }
 
-        // 2) and its associated data, if specified
-        if let Some((img_file, _)) = chunk.data {
-            img_file.pipe.splice_all(&mut shard.pipe, data_size as usize)?;
+        // Write the chunk marker (and the pending filename marker), and its associated data.
+        let marker_buf = &self.marker_buf[..];
+        let padding = &self.padding_buf[..padding_size];
+        shard.torn = true;
+        match chunk.data {
+            _ if self.spill_max_size > 0 => {
+                let data = match chunk.data {
+                    ChunkData::None => &[],
+                    ChunkData::Buf(buf) => buf,
+                    ChunkData::Pipe(..) => unreachable!("Chunks are copied when spilling"),
+                };
+                self.spill_size += shard.write_or_spill(&[marker_buf, data, padding])?;
+            }
+            ChunkData::None => {
+                shard.pipe.writev_all(&[marker_buf, padding])?;
+            }
+            ChunkData::Pipe(img_file, _) => {
+                shard.pipe.write_all(marker_buf).context("Failed to write to shard")?;
+                let splice_start = Instant::now();
+                img_file.pipe.splice_all(&mut shard.pipe, data_size as usize)?;
+                shard.splice_duration += splice_start.elapsed();
+                shard.pipe.write_all(padding).context("Failed to write to shard")?;
+            }
+            ChunkData::Buf(buf) => {
+                shard.pipe.writev_all(&[marker_buf, buf, padding])?;
+            }
         }
 
-        shard.bytes_written += marker_size as u64 + data_size as u64;
+        shard.torn = false;
+        if let Some(saturated_start) = saturated_start {
+            let blocked_duration = saturated_start.elapsed();
+            self.saturated_duration += blocked_duration;
+            shard.blocked_duration += blocked_duration;
+        }
+        shard.bytes_written += (marker_buf.len() + data_size as usize + padding_size) as u64;
+        shard.marker_bytes += (marker_buf.len() + padding_size) as u64;
         shard.remaining_space -= space_required;
         // As the shard reference drops, the binary heap gets reordered. nice.
+        drop(shard);
 
-        Ok(())
+        self.marker_buf.clear();
+
+        // This is how our output is throttled when spilling.
+        self.wait_spills(self.spill_max_size)
     }
 
-    fn maybe_write_filename_marker(&mut self, img_file: &ImageFile) -> Result<()> {
+    fn maybe_write_filename_marker(&mut self, filename: &Rc<str>) -> Result<()> {
         // We avoid repeating the filename on sequential data chunks of the same file for
         // performance. We write the filename only when needed.
-        let filename = &img_file.filename;
+        // The filename marker is always followed by a chunk, so we save a write by writing it
+        // with that chunk.
         match &self.current_filename {
             Some(current_filename) if current_filename == filename => {},
             _ => {
                 self.current_filename = Some(Rc::clone(filename));
-                let marker = self.gen_marker(marker::Body::Filename(filename.to_string()));
-                self.write_chunk(Chunk { marker, data: None })?;
+                let mut marker = self.gen_marker(marker::Body::Filename(filename.to_string()));
+                if matches!(&self.file_size_hint, Some((hinted_filename, _)) if hinted_filename == filename) {
+                    marker.file_size = self.file_size_hint.take().unwrap().1;
+                }
+                pb_write_max(&mut self.marker_buf, &marker, self.max_marker_size)?;
+                if self.marker_log.is_some() {
+                    self.pending_filename_marker = Some(marker);
+                }
             }
         }
 
         Ok(())
     }
 
-    /// Returns false if EOF of img_file is reached, true otherwise.
-    pub fn drain_img_file(&mut self, img_file: &mut ImageFile) -> Result<bool> {
-        let mut readable_len = img_file.pipe.fionread()?;
+    /// Drains the image file pipe until it is empty, or until the burst budget is exhausted.
+    /// The pipe is registered as edge-triggered in the poller, and the kernel may only signal a
+    /// new edge when an empty pipe gets written to. When the pipe is not empty, the caller must
+    /// call us again later, which is what `DrainStatus::Pending` indicates.
+    /// `hup` indicates that the poller reported that the writer closed the pipe, in which case no
+    /// more data can come once the pipe is drained, and we have reached EOF.
+    pub fn drain_img_file(&mut self, img_file: &mut ImageFile, hup: bool) -> Result<DrainStatus> {
+        // Detecting EOF with the hangup event saves an additional poll round per image file
+        // compared to waiting for fionread() to return 0 in a subsequent round.
+        let is_eof = hup;
 
-        // This code is only invoked when the poller reports that the image file's pipe is readable
-        // (or errored), which is why we can detect EOF when fionread() returns 0.
-        let is_eof = readable_len == 0;
+        self.maybe_write_filename_marker(&img_file.filename)?;
 
-        self.maybe_write_filename_marker(img_file)?;
+        let mut budget = DRAIN_BURST_BUDGET;
+        loop {
+            let mut readable_len = img_file.pipe.fionread()?;
+            if readable_len == 0 {
+                break;
+            }
+            if budget == 0 {
+                return Ok(DrainStatus::Pending);
+            }
+            readable_len = min(readable_len, budget);
+            budget -= readable_len;
+            img_file.size += readable_len as u64;
 
-        while readable_len > 0 {
-            let data_size = min(readable_len, self.chunk_max_data_size());
-            let marker = self.gen_marker(marker::Body::FileData(data_size as u32));
-            self.write_chunk(Chunk { marker, data: Some((img_file, data_size)) })?;
-            readable_len -= data_size;
+            while readable_len > 0 {
+                let data_size = min(readable_len, self.chunk_max_data_size());
+                let marker = self.gen_marker(marker::Body::FileData(data_size as u32));
+                if img_file.hasher.is_some() || img_file.pagemap.is_some() ||
+                   data_size <= SMALL_CHUNK_MAX_SIZE || self.spill_max_size > 0 {
+                    self.copy_chunk(marker, img_file, data_size)?;
+                } else {
+                    self.write_chunk(Chunk { marker, data: ChunkData::Pipe(img_file, data_size) })?;
+                }
+                readable_len -= data_size;
+            }
         }
 
         if is_eof {
             let marker = self.gen_marker(marker::Body::FileEof(true));
-            self.write_chunk(Chunk { marker, data: None })?;
+            self.write_chunk(Chunk { marker, data: ChunkData::None })?;
+            Ok(DrainStatus::Eof)
+        } else {
+            Ok(DrainStatus::Empty)
         }
+    }
 
-        Ok(!is_eof)
+    /// Brings the chunk data in userspace, giving up on zero-copy. We do so to compute the digest
+    /// of the image file, to parse pagemap images, to be able to spill the chunk, or when the
+    /// chunk is small enough that a copy is cheaper than an additional syscall.
+    fn copy_chunk(&mut self, marker: image::Marker, img_file: &mut ImageFile,
+                              data_size: i32) -> Result<()> {
+        let mut buf = std::mem::take(&mut self.copy_buf);
+        buf.resize(data_size as usize, 0);
+        img_file.pipe.read_exact(&mut buf)
+            .with_context(|| format!("Failed to read from image file {}", img_file.filename))?;
+        if let Some(hasher) = img_file.hasher.as_mut() {
+            hasher.update(&buf);
+        }
+        if let Some(pagemap) = img_file.pagemap.as_mut() {
+            pagemap.extend_from_slice(&buf);
+        }
+        let result = self.write_chunk(Chunk { marker, data: ChunkData::Buf(&buf) });
+        self.copy_buf = buf;
+        result
+    }
+
+    /// Records the size of a file about to be written, when known before its data, so that the
+    /// extraction can preallocate it. Only the importers know it. CRIU streams its files, the
+    /// pages files included, without telling their size, and they are not preallocated.
+    pub fn hint_file_size(&mut self, filename: &Rc<str>, size: u64) {
+        self.file_size_hint = Some((Rc::clone(filename), size));
+    }
+
+    /// Writes a file whose content we hold in memory, such as the image manifest.
+    pub fn write_file_from_buf(&mut self, filename: &str, data: &[u8]) -> Result<()> {
+        let filename = Rc::from(filename);
+        self.write_file_data(&filename, data)?;
+        self.write_file_eof(&filename)
+    }
+
+    /// Writes a piece of a file. Pieces of different files may be interleaved, as long as each
+    /// file ends with `write_file_eof()`.
+    pub fn write_file_data(&mut self, filename: &Rc<str>, data: &[u8]) -> Result<()> {
+        self.maybe_write_filename_marker(filename)?;
+
+        let chunk_max_data_size = self.chunk_max_data_size() as usize;
+        for buf in data.chunks(chunk_max_data_size) {
+            let marker = self.gen_marker(marker::Body::FileData(buf.len() as u32));
+            self.write_chunk(Chunk { marker, data: ChunkData::Buf(buf) })?;
+        }
+        Ok(())
+    }
+
+    pub fn write_file_eof(&mut self, filename: &Rc<str>) -> Result<()> {
+        self.maybe_write_filename_marker(filename)?;
+        let marker = self.gen_marker(marker::Body::FileEof(true));
+        self.write_chunk(Chunk { marker, data: ChunkData::None })
+    }
+
+    /// Returns the number of bytes of file data written so far.
+    pub fn data_size(&self) -> u64 {
+        self.data_size
     }
 
     pub fn write_image_eof(&mut self) -> Result<()> {
         let marker = self.gen_marker(image::marker::Body::ImageEof(true));
-        self.write_chunk(Chunk { marker, data: None })
+        self.image_eof = true;
+        self.write_chunk(Chunk { marker, data: ChunkData::None })
+    }
+
+    /// Writes the file table at the end of the first shard, after the image EOF marker. See
+    /// file_table.rs.
+    pub fn write_file_table(&mut self) -> Result<()> {
+        ensure!(self.image_eof, "The file table must be written after the image EOF marker");
+        let file_table = self.file_table.take()
+            .ok_or_else(|| anyhow!("The files were not recorded for the file table"))?
+            .build();
+        let chunk_alignment = self.chunk_alignment;
+        let (buf, markers) = encode_file_table(&file_table, |marker, data_len| {
+            set_padding(marker, 0, data_len, chunk_alignment);
+        })?;
+        if let Some(marker_log) = self.marker_log.as_mut() {
+            for marker in &markers {
+                marker_log.record(0, marker, None)?;
+            }
+        }
+
+        // The shards are not ordered by index in the heap.
+        let mut shards = std::mem::take(&mut self.shards).into_vec();
+        let shard = shards.iter_mut().find(|shard| shard.index == 0).unwrap();
+        let result = if self.spill_max_size > 0 {
+            shard.write_or_spill(&[&buf]).map(|spilled| self.spill_size += spilled)
+        } else {
+            shard.pipe.write_all(&buf).context("Failed to write to shard")
+        };
+        shard.bytes_written += buf.len() as u64;
+        shard.marker_bytes += buf.len() as u64;
+        self.shards = shards.into();
+        result
+    }
+}
+
+/// When the serializer goes away before the image is complete, the capture has failed. On a live
+/// migration, the extraction reads the shards as they are written. We tell it that the image is
+/// invalid, so it fails right away instead of waiting for the shards to be closed.
+impl Drop for ImageSerializer<'_> {
+    fn drop(&mut self) {
+        if !self.image_eof {
+            for shard in std::mem::take(&mut self.shards) {
+                shard.write_abort();
+            }
+        }
     }
 }
 
 
-/// The description of arguments can be found in main.rs
-pub fn capture(
-    images_dir: &Path,
-    mut progress_pipe: fs::File,
+/// Estimates how far along the capture is, compared to an expected image size. The expected size
+/// typically comes from a previous checkpoint of the same application, so the captured size may
+/// exceed it. The ETA assumes that the transfer speed observed so far remains the same.
+struct ProgressEstimator {
+    expected_size: u64,
+    last_percent: Option<u8>,
+}
+
+impl ProgressEstimator {
+    fn new(expected_size: u64) -> Self {
+        Self { expected_size, last_percent: None }
+    }
+
+    /// Returns a progress report when the completed percentage has increased since the last one.
+    fn update(&mut self, size: u64, start_time: Instant) -> Option<Progress> {
+        let percent = match self.expected_size {
+            0 => 100,
+            expected_size => (min(size, expected_size) * 100 / expected_size) as u8,
+        };
+        if self.last_percent >= Some(percent) {
+            return None;
+        }
+        self.last_percent = Some(percent);
+
+        let remaining_size = self.expected_size.saturating_sub(size);
+        let eta_millis = match size {
+            0 => None,
+            _ => Some(start_time.elapsed().as_millis() * remaining_size as u128 / size as u128),
+        };
+
+        Some(Progress { size, expected_size: self.expected_size, percent, eta_millis })
+    }
+}
+
+/// Suggests how many shards would keep up with a workload that had `num_shards` shards saturated
+/// for the `saturation` fraction of the transfer. Uploaders are assumed to be independent, so
+/// that the throughput of the shards grows with their number. The capture produced its data in
+/// the unsaturated part of the transfer, which scales the number of shards by
+/// `1 / (1 - saturation)`. Low saturations are noise, e.g., a chunk that blocked for a moment,
+/// and keep the number of shards. Shards that never saturate may be more than needed, but how
+/// fast their uploaders could go is unknown, so the number of shards is kept as well.
+pub fn suggest_num_shards(num_shards: usize, saturation: f64) -> usize {
+    const MIN_SATURATION: f64 = 0.1;
+    // Bounds the suggestion to 10x the number of shards.
+    const MAX_SATURATION: f64 = 0.9;
+    const MAX_SUGGESTED_SHARDS: usize = 32;
+
+    if saturation < MIN_SATURATION {
+        return num_shards;
+    }
+    let suggested = (num_shards as f64 / (1.0 - saturation.min(MAX_SATURATION))).round() as usize;
+    max(num_shards, min(suggested, MAX_SUGGESTED_SHARDS))
+}
+
+/// Prepares the shard pipes for writing a new image. Returns the shards, their pipe capacity, and
+/// the UUID of the image.
+/// Creates the shards, and writes their headers. `chunk_alignment` pads the headers, as with
+/// `ImageSerializer::align_chunks()`. 0 disables the alignment. Fails when the shard pipes can't
+/// be given `min_pipe_capacity`.
+pub fn init_shards(
     mut shard_pipes: Vec<UnixPipe>,
-    ext_file_pipes: Vec<(String, UnixPipe)>,
-) -> Result<()>
+    chunk_alignment: usize,
+    min_pipe_capacity: Option<i32>,
+) -> Result<(Vec<Shard>, i32, String)>
 {
-    // First, we need to listen on the unix socket and notify the progress pipe that
+    let shard_pipe_capacity = UnixPipe::increase_capacity(&mut shard_pipes, SHARD_PIPE_DESIRED_CAPACITY)?;
+    if let Some(min_pipe_capacity) = min_pipe_capacity {
+        ensure_min_capacity(shard_pipe_capacity, min_pipe_capacity)?;
+    }
+    let mut shards: Vec<Shard> = shard_pipes.into_iter().map(Shard::new).collect::<Result<_>>()?;
+
+    // The shard headers identify the image, so that the extraction can tell which shards are
+    // missing, or don't belong to the image.
+    let image_uuid = new_uuid()?;
+    let num_shards = shards.len() as u32;
+    for (shard_index, shard) in shards.iter_mut().enumerate() {
+        let image_uuid = image_uuid.clone();
+        shard.write_header(image::ShardHeader { image_uuid, shard_index: shard_index as u32, num_shards },
+                           chunk_alignment)?;
+    }
+
+    Ok((shards, shard_pipe_capacity, image_uuid))
+}
+
+/// `CaptureBuilder` configures the capture of a CRIU image into shards. Only the images directory
+/// and the shards are required, other options have the same defaults as the command line.
+/// The description of the options can be found in main.rs.
+pub struct CaptureBuilder {
+    images_dir: PathBuf,
+    shard_pipes: Vec<UnixPipe>,
+    progress_pipe: Option<fs::File>,
+    ext_file_pipes: Vec<(String, UnixPipe)>,
+    sign_key: Option<SigningKey>,
+    audit_log: Option<AuditLog>,
+    epoll_capacity: usize,
+    shard_spill_size: usize,
+    chunk_alignment: usize,
+    max_marker_size: usize,
+    min_pipe_capacity: Option<i32>,
+    expected_size: Option<u64>,
+    criu_trace: Option<CriuTrace>,
+    marker_log: Option<MarkerLog>,
+    containers: Vec<String>,
+    job_id: Option<String>,
+    criu_timeout: Option<Duration>,
+    ext_file_digests: bool,
+    file_digests: bool,
+    ordered_ext_files: bool,
+    rate_limit: RateLimit,
+    rate_control: bool,
+    file_table: bool,
+    on_event: Option<EventCallback>,
+}
+
+impl CaptureBuilder {
+    pub fn new(images_dir: impl Into<PathBuf>, shard_pipes: Vec<UnixPipe>) -> Self {
+        Self {
+            images_dir: images_dir.into(),
+            shard_pipes,
+            progress_pipe: None,
+            ext_file_pipes: Vec::new(),
+            sign_key: None,
+            audit_log: None,
+            epoll_capacity: DEFAULT_EPOLL_CAPACITY,
+            shard_spill_size: 0,
+            chunk_alignment: 0,
+            max_marker_size: DEFAULT_MAX_PB_SIZE,
+            min_pipe_capacity: None,
+            expected_size: None,
+            criu_trace: None,
+            marker_log: None,
+            containers: Vec::new(),
+            job_id: None,
+            criu_timeout: None,
+            ext_file_digests: false,
+            file_digests: false,
+            ordered_ext_files: false,
+            rate_limit: RateLimit::default(),
+            rate_control: false,
+            file_table: false,
+            on_event: None,
+        }
+    }
+
+    /// Where to report progress. Progress is discarded by default.
+    pub fn progress_pipe(mut self, progress_pipe: fs::File) -> Self {
+        self.progress_pipe = Some(progress_pipe);
+        self
+    }
+
+    /// Invokes `on_event` as the files are captured. See `events.rs`.
+    pub fn on_event(mut self, on_event: impl FnMut(Event<'_>) + Send + 'static) -> Self {
+        self.on_event = Some(Box::new(on_event));
+        self
+    }
+
+    pub fn ext_files(mut self, ext_file_pipes: Vec<(String, UnixPipe)>) -> Self {
+        self.ext_file_pipes = ext_file_pipes;
+        self
+    }
+
+    /// Reports the sha256 digest of the external files in the stats. Digests are reported
+    /// regardless when the image is signed or audited.
+    pub fn ext_file_digests(mut self, ext_file_digests: bool) -> Self {
+        self.ext_file_digests = ext_file_digests;
+        self
+    }
+
+    /// Reports the sha256 digest of every file in the stats. When the image is not signed, the
+    /// image carries an unsigned manifest of the files, checked by `extract_dry_run()`.
+    pub fn file_digests(mut self, file_digests: bool) -> Self {
+        self.file_digests = file_digests;
+        self
+    }
+
+    /// Captures the external files one after the other, in the order they are provided, instead
+    /// of concurrently. The producer of an external file must not wait on the following ones.
+    pub fn ordered_ext_files(mut self, ordered_ext_files: bool) -> Self {
+        self.ordered_ext_files = ordered_ext_files;
+        self
+    }
+
+    pub fn sign_key(mut self, sign_key: Option<SigningKey>) -> Self {
+        self.sign_key = sign_key;
+        self
+    }
+
+    pub fn audit_log(mut self, audit_log: Option<AuditLog>) -> Self {
+        self.audit_log = audit_log;
+        self
+    }
+
+    pub fn epoll_capacity(mut self, epoll_capacity: usize) -> Self {
+        self.epoll_capacity = epoll_capacity;
+        self
+    }
+
+    /// 0 disables spilling.
+    pub fn shard_spill_size(mut self, shard_spill_size: usize) -> Self {
+        self.shard_spill_size = shard_spill_size;
+        self
+    }
+
+    /// Pads the chunks so that each marker starts on a multiple of `chunk_alignment` bytes in the
+    /// shard streams. 0 disables the alignment.
+    pub fn chunk_alignment(mut self, chunk_alignment: usize) -> Self {
+        self.chunk_alignment = chunk_alignment;
+        self
+    }
+
+    /// Fails on the markers larger than `max_marker_size` bytes, from CRIU and to the shards,
+    /// as they are most likely the sign of a corrupted stream. See `DEFAULT_MAX_PB_SIZE`.
+    pub fn max_marker_size(mut self, max_marker_size: usize) -> Self {
+        self.max_marker_size = max_marker_size;
+        self
+    }
+
+    /// Fails when the shard pipes can't be given `min_pipe_capacity` bytes, instead of streaming
+    /// slowly through the smaller pipes that the host limits allow. Disabled by default.
+    pub fn min_pipe_capacity(mut self, min_pipe_capacity: Option<i32>) -> Self {
+        self.min_pipe_capacity = min_pipe_capacity;
+        self
+    }
+
+    pub fn expected_size(mut self, expected_size: Option<u64>) -> Self {
+        self.expected_size = expected_size;
+        self
+    }
+
+    pub fn criu_trace(mut self, criu_trace: Option<CriuTrace>) -> Self {
+        self.criu_trace = criu_trace;
+        self
+    }
+
+    /// Logs every marker written to the shards. See `MarkerLog`.
+    pub fn marker_log(mut self, marker_log: Option<MarkerLog>) -> Self {
+        self.marker_log = marker_log;
+        self
+    }
+
+    pub fn containers(mut self, containers: Vec<String>) -> Self {
+        self.containers = containers;
+        self
+    }
+
+    pub fn job_id(mut self, job_id: Option<String>) -> Self {
+        self.job_id = job_id;
+        self
+    }
+
+    /// Caps the rate at which the image is written to the shards. The caller may keep a clone of
+    /// `rate_limit` to change it while the capture runs. Unlimited by default.
+    pub fn rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    /// Lets controllers change the rate limit with commands on the control socket
+    /// (images_dir/streamer-control.sock) while the capture runs. See rate_limit.rs.
+    pub fn rate_control(mut self, rate_control: bool) -> Self {
+        self.rate_control = rate_control;
+        self
+    }
+
+    /// Writes the table of the files of the image at the end of the first shard, so that the
+    /// image can be listed with `read_file_table()` without reading it. See file_table.rs.
+    pub fn file_table(mut self, file_table: bool) -> Self {
+        self.file_table = file_table;
+        self
+    }
+
+    /// Fails when CRIU makes no progress for `criu_timeout` while being captured. A CRIU that dies
+    /// is detected regardless. See `CriuWatchdog`.
+    pub fn criu_timeout(mut self, criu_timeout: Option<Duration>) -> Self {
+        self.criu_timeout = criu_timeout;
+        self
+    }
+
+    /// Captures the image. Returns once CRIU is done, and all the files have been written to the
+    /// shards.
+    pub fn run(self) -> Result<()> {
+        capture(self)
+    }
+}
+
+fn capture(opts: CaptureBuilder) -> Result<()> {
+    let CaptureBuilder {
+        images_dir, shard_pipes, progress_pipe, ext_file_pipes, sign_key, mut audit_log,
+        epoll_capacity, shard_spill_size, chunk_alignment, max_marker_size, min_pipe_capacity, expected_size, mut criu_trace, mut marker_log, containers,
+        job_id, criu_timeout, ext_file_digests, file_digests, ordered_ext_files, rate_limit, rate_control,
+        file_table, mut on_event,
+    } = opts;
+    let images_dir = images_dir.as_path();
+    let mut progress_pipe = match progress_pipe {
+        Some(progress_pipe) => progress_pipe,
+        None => null_progress_pipe()?,
+    };
+
+    // First, we need to listen on the unix sockets and notify the progress pipe that
     // we are ready. We do this ASAP because our controller is blocking on us to start CRIU.
-    create_dir_all(images_dir)?;
-    let listener = CriuListener::bind_for_capture(images_dir)?;
+    // There is one socket per container, or a single one when containers are not used.
+    let listeners = criu_socket_dirs(images_dir, &containers).into_iter()
+        .map(|(socket_dir, prefix)| {
+            create_dir_all(&socket_dir)?;
+            let listener = CriuListener::bind_for_capture(&socket_dir, job_id.as_deref())?
+                .criu_timeout(criu_timeout)
+                .max_request_size(max_marker_size);
+            Ok((listener, prefix))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let control_listener = rate_control
+        .then(|| bind_control_socket(images_dir, job_id.as_deref()))
+        .transpose()?;
 
     emit_progress(&mut progress_pipe, "socket-init");
 
     // The kernel may limit the number of allocated pages for pipes, we must do it before setting
     // the pipe size of external file pipes as shard pipes are more performance sensitive.
-    let shard_pipe_capacity = UnixPipe::increase_capacity(&mut shard_pipes, SHARD_PIPE_DESIRED_CAPACITY)?;
-    let mut shards: Vec<Shard> = shard_pipes.into_iter().map(Shard::new).collect::<Result<_>>()?;
+    let (mut shards, shard_pipe_capacity, image_uuid) = init_shards(shard_pipes, chunk_alignment, min_pipe_capacity)?;
+    if let Some(marker_log) = marker_log.as_mut() {
+        let num_shards = shards.len() as u32;
+        for shard_index in 0..shards.len() {
+            let image_uuid = image_uuid.clone();
+            let header = image::ShardHeader { image_uuid, shard_index: shard_index as u32, num_shards };
+            let marker = image::Marker { seq: 0, body: Some(marker::Body::ShardHeader(header)), padding: 0, file_size: 0 };
+            marker_log.record(shard_index, &marker, None)?;
+        }
+    }
 
-    // We are ready to get to work. Accept CRIU's connection.
-    let criu = listener.into_accept()?;
+    // The image serializer reads data from the image files, and writes it in chunks into shards.
+    // From now on, failing aborts the image on the shards.
+    let mut img_serializer = ImageSerializer::new(&mut shards, shard_pipe_capacity);
+    if shard_spill_size > 0 {
+        img_serializer.enable_spill(shard_spill_size)?;
+    }
+    if chunk_alignment > 0 {
+        img_serializer.align_chunks(chunk_alignment);
+    }
+    img_serializer.limit_marker_size(max_marker_size);
+    if file_table {
+        img_serializer.record_file_table();
+    }
+    if let Some(marker_log) = marker_log.as_mut() {
+        img_serializer.log_markers(marker_log);
+    }
 
-    // Setup the poller to monitor the server socket and image files' pipes
+    // Setup the poller to monitor the server sockets and image files' pipes.
+    // The names of the files that CRIU sends are prefixed with the container name.
+    // Image files come with the pid of the CRIU sending them, and None for external files.
     enum PollType {
-        Criu(CriuConnection),
-        ImageFile(ImageFile),
+        Listener(CriuListener, String),
+        Criu(CriuConnection, String),
+        ImageFile(ImageFile, Option<Pid>),
+        Control(UnixListener),
     }
+    // Image file pipes are edge-triggered: drain_img_file() consumes all that the pipe holds, and
+    // each write or close from the other end generates a new edge. When it stops early to let
+    // other image files have their turn, the poller is asked to reschedule it. The CRIU socket stays
+    // level-triggered as it may hold many file requests, and we read one at a time.
+    const IMAGE_FILE_EPOLL_FLAGS: EpollFlags = EpollFlags::from_bits_truncate(
+        EpollFlags::EPOLLIN.bits() | EpollFlags::EPOLLET.bits());
     let mut poller = Poller::new()?;
-    poller.add(criu.as_raw_fd(), PollType::Criu(criu), EpollFlags::EPOLLIN)?;
+    for (listener, prefix) in listeners {
+        poller.add(listener.as_raw_fd(), PollType::Listener(listener, prefix), EpollFlags::EPOLLIN)?;
+    }
+    // The control socket is removed once it is all that is left, so that it doesn't keep the
+    // capture going.
+    let mut control_key = control_listener
+        .map(|listener| poller.add(listener.as_raw_fd(), PollType::Control(listener), EpollFlags::EPOLLIN))
+        .transpose()?;
+    let mut throttle = Throttle::new(rate_limit.clone());
+
+    let with_digest = sign_key.is_some() || audit_log.is_some() || file_digests;
+    let mut manifest = Manifest::default();
 
+    let start_ext_file = |poller: &mut Poller<PollType>, img_file: ImageFile,
+                          on_event: Option<&mut EventCallback>| {
+        if let Some(on_event) = on_event {
+            on_event(Event::FileStart { filename: &img_file.filename });
+        }
+        poller.add(img_file.pipe.as_raw_fd(), PollType::ImageFile(img_file, None), IMAGE_FILE_EPOLL_FLAGS)
+    };
+
+    // Ordered external files (e.g., the layers of a root filesystem) are captured one after the
+    // other. Each is complete in the image before the next one starts, so the consumers on the
+    // extract side receive them in the same order. Otherwise, they are all captured concurrently.
+    let mut pending_ext_files = VecDeque::new();
     for (filename, pipe) in ext_file_pipes {
-        let img_file = ImageFile::new(filename, pipe);
-        poller.add(img_file.pipe.as_raw_fd(), PollType::ImageFile(img_file), EpollFlags::EPOLLIN)?;
+        ensure!(!is_reserved_filename(&filename), "The ext file name `{}` is reserved", filename);
+        pending_ext_files.push_back(ImageFile::new(filename, pipe, with_digest || ext_file_digests));
+    }
+    let num_started = if ordered_ext_files { min(1, pending_ext_files.len()) } else { pending_ext_files.len() };
+    for img_file in pending_ext_files.drain(..num_started) {
+        start_ext_file(&mut poller, img_file, on_event.as_mut())?;
     }
 
     // Used to compute transfer speed. But the real start is when we call
     // `notify_checkpoint_start_once()`
     let mut start_time = Instant::now();
     let notify_checkpoint_start_once = Once::new();
+    let mut progress_estimator = expected_size.map(ProgressEstimator::new);
+    let mut page_stats = None;
+    let mut ext_file_stats = Vec::new();
 
-    // The image serializer reads data from the image files, and writes it in chunks into shards.
-    let mut img_serializer = ImageSerializer::new(&mut shards, shard_pipe_capacity);
+    // The watchdog fails the capture when CRIU dies, or stalls, while we wait on it.
+    let mut watchdog = CriuWatchdog::new(criu_timeout);
 
     // Process all inputs (ext files, CRIU's connection, and CRIU's files) until they reach EOF.
     // As CRIU requests to write files, we receive new unix pipes that are added to the poller.
-    // We use an epoll_capacity of 8. This doesn't really matter as the number of concurrent
-    // connection is typically at most 2.
-    let epoll_capacity = 8;
-    while let Some((poll_key, poll_obj)) = poller.poll(epoll_capacity)? {
+    while let Some(poll_result) = poller.poll(epoll_capacity, Some(WATCHDOG_INTERVAL))? {
+        let (poll_key, poll_obj, poll_events) = match poll_result {
+            PollResult::Ready(poll_key, poll_obj, poll_events) => (poll_key, poll_obj, poll_events),
+            PollResult::TimedOut => {
+                let criu_pids = poller.values().filter_map(|poll_obj| match poll_obj {
+                    PollType::Listener(..) | PollType::Control(_) => None,
+                    PollType::Criu(criu, _) => Some(criu.pid()),
+                    PollType::ImageFile(_, criu_pid) => *criu_pid,
+                });
+                watchdog.check(criu_pids)?;
+                continue;
+            }
+        };
+        // Controllers are not CRIU, they don't count as progress.
+        if !matches!(poll_obj, PollType::Control(_)) {
+            watchdog.progress();
+        }
+
         match poll_obj {
-            PollType::Criu(criu) => {
+            PollType::Control(control_listener) => {
+                handle_rate_control(control_listener, &rate_limit)?;
+                continue;
+            }
+            PollType::Listener(..) => {
+                // CRIU is connecting. There is no need for more connections on this socket.
+                if let PollType::Listener(listener, prefix) = poller.remove(poll_key)? {
+                    let mut criu = listener.into_accept()?;
+                    if let Some(criu_trace) = criu_trace.as_ref() {
+                        criu.set_trace(criu_trace.try_clone()?, TraceOperation::Capture)?;
+                    }
+                    poller.add(criu.as_raw_fd(), PollType::Criu(criu, prefix), EpollFlags::EPOLLIN)?;
+                }
+                // No data has moved, there is no progress to report.
+                continue;
+            }
+            PollType::Criu(criu, prefix) => {
                 match criu.read_next_file_request()? {
                     Some(filename) => {
+                        ensure!(!is_reserved_filename(&filename),
+                                "CRIU sent the reserved filename `{}`", filename);
+
                         if filename != "cpuinfo.img" {
                             // Once the checkpoint has started, we must notify the controller.
                             // This is useful for our controller to kick tarring the file system as
@@ -330,9 +1245,13 @@ pub fn capture(
                         }
 
                         let pipe = criu.recv_pipe()?;
-                        let img_file = ImageFile::new(filename, pipe);
-                        poller.add(img_file.pipe.as_raw_fd(), PollType::ImageFile(img_file),
-                                   EpollFlags::EPOLLIN)?;
+                        let criu_pid = criu.pid();
+                        let img_file = ImageFile::new(format!("{}{}", prefix, filename), pipe, with_digest);
+                        if let Some(on_event) = on_event.as_mut() {
+                            on_event(Event::FileStart { filename: &img_file.filename });
+                        }
+                        poller.add(img_file.pipe.as_raw_fd(), PollType::ImageFile(img_file, Some(criu_pid)),
+                                   IMAGE_FILE_EPOLL_FLAGS)?;
                     }
                     None => {
                         // We are done receiving file requests. We can close the socket.
@@ -341,28 +1260,136 @@ pub fn capture(
                     }
                 }
             }
-            PollType::ImageFile(img_file) => {
-                if !img_serializer.drain_img_file(img_file)? {
-                    // EOF of the image file is reached. Note that the image file pipe file
-                    // descriptor is closed automatically as it is owned by the poller.
-                    poller.remove(poll_key)?;
+            PollType::ImageFile(img_file, _) => {
+                let hup = poll_events.contains(EpollFlags::EPOLLHUP);
+                match img_serializer.drain_img_file(img_file, hup)? {
+                    DrainStatus::Empty => {}
+                    DrainStatus::Pending => {
+                        // Other image files get their turn before we resume draining this one.
+                        poller.reschedule(poll_key, poll_events);
+                    }
+                    DrainStatus::Eof => {
+                        // EOF of the image file is reached. Note that the image file pipe file
+                        // descriptor is closed automatically as it is owned by the poller.
+                        if let PollType::ImageFile(img_file, criu_pid) = poller.remove(poll_key)? {
+                            if let Some(on_event) = on_event.as_mut() {
+                                on_event(Event::FileComplete { filename: &img_file.filename, size: img_file.size });
+                            }
+                            if let Some(criu_trace) = criu_trace.as_mut() {
+                                let filename = img_file.filename.to_string();
+                                criu_trace.record(TraceEvent::FileEof { filename, size: img_file.size })?;
+                            }
+                            if let Some(pagemap) = img_file.pagemap {
+                                page_stats.get_or_insert_with(PageStats::default)
+                                    .add_pagemap(&img_file.filename, &pagemap)?;
+                            }
+                            let digest = img_file.hasher.map(FileHasher::finalize);
+                            if criu_pid.is_none() {
+                                // External files are not sent by CRIU.
+                                ext_file_stats.push(ExtFileStat {
+                                    filename: img_file.filename.to_string(),
+                                    size: img_file.size,
+                                    sha256: digest.as_ref().map(|digest| digest.sha256.clone()),
+                                });
+                                if let Some(img_file) = pending_ext_files.pop_front() {
+                                    start_ext_file(&mut poller, img_file, on_event.as_mut())?;
+                                }
+                            }
+                            if let Some(digest) = digest {
+                                if let Some(audit_log) = audit_log.as_mut() {
+                                    audit_log.record(Direction::In, &img_file.filename, &digest)?;
+                                }
+                                manifest.add_file(&img_file.filename, digest);
+                            }
+                        }
+                    }
                 }
             }
         }
+
+        img_serializer.flush_spills()?;
+
+        if let Some(progress_estimator) = progress_estimator.as_mut() {
+            if let Some(progress) = progress_estimator.update(img_serializer.data_size(), start_time) {
+                emit_progress(&mut progress_pipe, &serde_json::to_string(&progress)?);
+            }
+        }
+
+        // Pausing leaves the data in the pipes of CRIU, which blocks once they are full.
+        let pause = throttle.pause(img_serializer.data_size());
+        if !pause.is_zero() {
+            thread::sleep(min(pause, MAX_THROTTLE_PAUSE));
+        }
+
+        if let Some(key) = control_key {
+            if poller.values().all(|poll_obj| matches!(poll_obj, PollType::Control(_))) {
+                poller.remove(key)?;
+                control_key = None;
+            }
+        }
+    }
+
+    if let Some(sign_key) = sign_key {
+        let (manifest, sig) = manifest.sign(&sign_key)?;
+        img_serializer.write_file_from_buf(MANIFEST_FILENAME, &manifest)?;
+        img_serializer.write_file_from_buf(MANIFEST_SIG_FILENAME, &sig)?;
+    } else if file_digests {
+        // The manifest detects corruption, but not tampering, without a signature.
+        img_serializer.write_file_from_buf(MANIFEST_FILENAME, &manifest.serialize()?)?;
     }
 
     img_serializer.write_image_eof()?;
+    if file_table {
+        img_serializer.write_file_table()?;
+    }
+    img_serializer.wait_spills(0)?;
+    let saturated_duration = img_serializer.saturated_duration();
+    let max_buffered_bytes = img_serializer.max_buffered_bytes();
+    drop(img_serializer);
 
     let stats = {
-        let transfer_duration_millis = start_time.elapsed().as_millis();
+        let transfer_duration = start_time.elapsed();
+        let transfer_duration_millis = transfer_duration.as_millis();
+        // The checkpoint may start after some of the data was written, e.g., external files.
+        let saturation = match transfer_duration.as_secs_f64() {
+            d if d > 0.0 => (saturated_duration.as_secs_f64() / d).min(1.0),
+            _ => 0.0,
+        };
         Stats {
-            shards: shards.iter().map(|s| ShardStat {
-                size: s.bytes_written,
-                transfer_duration_millis,
-            }).collect(),
+            schema_version: STATS_SCHEMA_VERSION,
+            image_uuid: Some(image_uuid),
+            shards: shards.iter().map(|s| s.stat(transfer_duration_millis)).collect(),
+            kernel: KERNEL_CAPS.clone(),
+            pages: page_stats,
+            ext_files: ext_file_stats,
+            files: if file_digests { manifest.into_sorted_files() } else { Vec::new() },
+            shard_saturation: Some(ShardSaturation {
+                saturation,
+                suggested_num_shards: suggest_num_shards(shards.len(), saturation),
+            }),
+            max_buffered_bytes: Some(max_buffered_bytes),
         }
     };
-    emit_progress(&mut progress_pipe, &serde_json::to_string(&stats)?);
+    report_stats(&mut progress_pipe, &stats)?;
+    if let Some(on_event) = on_event.as_mut() {
+        on_event(Event::ImageComplete { stats: &stats });
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suggest_num_shards() {
+        assert_eq!(suggest_num_shards(4, 0.0), 4);
+        assert_eq!(suggest_num_shards(4, 0.05), 4);
+        assert_eq!(suggest_num_shards(4, 0.5), 8);
+        assert_eq!(suggest_num_shards(4, 0.75), 16);
+        assert_eq!(suggest_num_shards(4, 1.0), 32);
+        assert_eq!(suggest_num_shards(1, 1.0), 10);
+        assert_eq!(suggest_num_shards(40, 0.5), 40);
+    }
+}
@@ -0,0 +1,253 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    fs,
+    env,
+    fmt,
+    path::Path,
+    os::unix::process::{CommandExt, ExitStatusExt},
+    process::{Command, ExitStatus, Stdio},
+};
+use crate::unix_pipe::{UnixPipe, new_cloexec_pipe};
+use anyhow::{Result, Context};
+
+// Compression is normally done by the programs at the other end of the shards (e.g.,
+// `lz4 | aws s3 cp - s3://destination`). When the shards go straight to an uploader that doesn't
+// compress, or when a proprietary or hardware codec is wanted, a codec can sit between the
+// streamer and each shard instead. It encodes what we write to the shard when capturing, and
+// decodes what we read from the shard when extracting. Codecs see each shard as an opaque byte
+// stream, the image format on the shards is unchanged.
+//
+// The streamer talks to a codec through a pipe, as it does with shards. This keeps splice() and
+// the load-balancing on fionread() working as is.
+
+/// A streaming codec, applied to each shard.
+pub trait Codec {
+    /// Returns the pipe to write to, whose data gets encoded into `shard`.
+    fn encoder(&self, shard: UnixPipe) -> Result<(UnixPipe, CodecStage)>;
+
+    /// Returns the pipe to read from, which gets the decoded data of `shard`.
+    fn decoder(&self, shard: UnixPipe) -> Result<(UnixPipe, CodecStage)>;
+}
+
+/// A running codec stage. It finishes once the data of its input is exhausted.
+pub struct CodecStage {
+    wait: Box<dyn FnOnce() -> Result<()> + Send>,
+}
+
+impl CodecStage {
+    pub fn new(wait: impl FnOnce() -> Result<()> + Send + 'static) -> Self {
+        Self { wait: Box::new(wait) }
+    }
+
+    /// Waits for the stage to finish, and fails if it didn't succeed.
+    pub fn wait(self) -> Result<()> {
+        (self.wait)()
+    }
+}
+
+/// The failure of a codec process.
+#[derive(Debug)]
+pub struct CodecFailed {
+    pub cmd: String,
+    pub status: ExitStatus,
+}
+
+impl CodecFailed {
+    /// A codec killed by SIGPIPE was stopped by its reader closing the pipe early. It is the
+    /// consequence of another failure, not its cause.
+    pub fn is_broken_pipe(&self) -> bool {
+        self.status.signal() == Some(libc::SIGPIPE)
+    }
+}
+
+impl fmt::Display for CodecFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The codec command `{}` failed: {}", self.cmd, self.status)
+    }
+}
+
+impl std::error::Error for CodecFailed {}
+
+/// `ExternalCodec` pipes the data of each shard through a shell command, one process per shard.
+/// The command is a filter reading stdin and writing stdout, e.g., `lz4` to encode and `lz4 -d`
+/// to decode.
+pub struct ExternalCodec {
+    encode_cmd: String,
+    decode_cmd: String,
+}
+
+impl ExternalCodec {
+    /// The same command runs in both directions, the caller picks the one matching the operation.
+    pub fn new(cmd: impl Into<String>) -> Self {
+        let cmd = cmd.into();
+        Self { encode_cmd: cmd.clone(), decode_cmd: cmd }
+    }
+
+    pub fn with_commands(encode_cmd: impl Into<String>, decode_cmd: impl Into<String>) -> Self {
+        Self { encode_cmd: encode_cmd.into(), decode_cmd: decode_cmd.into() }
+    }
+
+    fn spawn(cmd: &str, stdin: fs::File, stdout: fs::File) -> Result<CodecStage> {
+        let mut command = Command::new("sh");
+        command.arg("-c")
+            .arg(cmd)
+            .stdin(Stdio::from(stdin))
+            .stdout(Stdio::from(stdout));
+        unsafe {
+            command.pre_exec(|| {
+                cloexec_inherited_fds();
+                Ok(())
+            });
+        }
+        let mut child = command.spawn()
+            .with_context(|| format!("Failed to spawn the codec command `{}`", cmd))?;
+
+        let cmd = cmd.to_string();
+        Ok(CodecStage::new(move || {
+            let status = child.wait()
+                .with_context(|| format!("Failed to wait for the codec command `{}`", cmd))?;
+            ensure!(status.success(), CodecFailed { cmd, status });
+            Ok(())
+        }))
+    }
+}
+
+impl Codec for ExternalCodec {
+    fn encoder(&self, shard: UnixPipe) -> Result<(UnixPipe, CodecStage)> {
+        let (pipe_r, pipe_w) = new_cloexec_pipe()?;
+        Ok((pipe_w, Self::spawn(&self.encode_cmd, pipe_r, shard)?))
+    }
+
+    fn decoder(&self, shard: UnixPipe) -> Result<(UnixPipe, CodecStage)> {
+        let (pipe_r, pipe_w) = new_cloexec_pipe()?;
+        Ok((pipe_r, Self::spawn(&self.decode_cmd, shard, pipe_w)?))
+    }
+}
+
+/// A gzip implementation, run as an external filter. All of them read and write the gzip format,
+/// so an image encoded with one can be decoded with any other.
+struct GzipBackend {
+    name: &'static str,
+    binary: &'static str,
+    /// The device that must be present for the backend to be used.
+    device: Option<&'static str>,
+    encode_cmd: &'static str,
+    decode_cmd: &'static str,
+}
+
+/// The gzip backends, the preferred first. Hardware compression offload backends go here.
+const GZIP_BACKENDS: &[GzipBackend] = &[
+    // Intel QuickAssist, through QATzip. The device is managed by the QAT driver.
+    GzipBackend { name: "qat", binary: "qzip", device: Some("/dev/qat_adf_ctl"),
+                  encode_cmd: "qzip -c", decode_cmd: "qzip -d -c" },
+    // Intel ISA-L, which uses the SIMD instructions of the CPU.
+    GzipBackend { name: "isa-l", binary: "igzip", device: None,
+                  encode_cmd: "igzip -c", decode_cmd: "igzip -d -c" },
+    GzipBackend { name: "gzip", binary: "gzip", device: None,
+                  encode_cmd: "gzip -c", decode_cmd: "gzip -d -c" },
+];
+
+pub(crate) fn is_in_path(binary: &str) -> bool {
+    env::var_os("PATH")
+        .map(|paths| env::split_paths(&paths).any(|dir| dir.join(binary).is_file()))
+        .unwrap_or(false)
+}
+
+impl GzipBackend {
+    fn is_available(&self) -> bool {
+        self.device.iter().all(|device| Path::new(device).exists()) && is_in_path(self.binary)
+    }
+}
+
+/// Returns the names of the gzip backends available on this host, the preferred first.
+pub fn gzip_backends() -> Vec<&'static str> {
+    GZIP_BACKENDS.iter()
+        .filter(|backend| backend.is_available())
+        .map(|backend| backend.name)
+        .collect()
+}
+
+/// Returns the gzip codec of the preferred backend available on this host. The backend is
+/// selected at runtime, so the same binary offloads compression to the hardware of the hosts
+/// that have it.
+pub fn gzip_codec() -> Result<ExternalCodec> {
+    let backend = GZIP_BACKENDS.iter()
+        .find(|backend| backend.is_available())
+        .ok_or_else(|| anyhow!("No gzip implementation found in the PATH (qzip, igzip, or gzip)"))?;
+    Ok(ExternalCodec::with_commands(backend.encode_cmd, backend.decode_cmd))
+}
+
+/// A codec process, or a shard command, only needs its stdin, stdout, and stderr. It must not inherit the other
+/// shards, which are often opened without O_CLOEXEC, otherwise the shards would not see EOF until
+/// all the codec processes exit. When the other end of the shards is in our process, the codecs
+/// would wait on each other. This runs between fork() and exec(), so it only makes
+/// async-signal-safe calls.
+pub(crate) fn cloexec_inherited_fds() {
+    // CLOSE_RANGE_CLOEXEC needs Linux 5.11. Older kernels get the fds flagged one by one.
+    const CLOSE_RANGE_CLOEXEC: libc::c_uint = 1 << 2;
+    unsafe {
+        if libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, CLOSE_RANGE_CLOEXEC) == 0 {
+            return;
+        }
+        for fd in 3..libc::sysconf(libc::_SC_OPEN_MAX) as libc::c_int {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+    }
+}
+
+/// Puts an encoder in front of each shard. Returns the pipes to write to in place of the shards.
+pub fn encode_shards(codec: &dyn Codec, shards: Vec<UnixPipe>) -> Result<(Vec<UnixPipe>, Vec<CodecStage>)> {
+    shards.into_iter().map(|shard| codec.encoder(shard)).collect::<Result<Vec<_>>>()
+        .map(|stages| stages.into_iter().unzip())
+}
+
+/// Puts a decoder after each shard. Returns the pipes to read from in place of the shards.
+pub fn decode_shards(codec: &dyn Codec, shards: Vec<UnixPipe>) -> Result<(Vec<UnixPipe>, Vec<CodecStage>)> {
+    shards.into_iter().map(|shard| codec.decoder(shard)).collect::<Result<Vec<_>>>()
+        .map(|stages| stages.into_iter().unzip())
+}
+
+/// Waits for all the codec stages to finish. Their pipes must have been closed beforehand.
+/// See `finish_codec_stages()` to combine their outcome with the result of the operation.
+pub fn wait_codec_stages(stages: Vec<CodecStage>) -> Result<()> {
+    let mut result = Ok(());
+    for stage in stages {
+        // All stages are waited for, so that no codec process is left behind.
+        let stage_result = stage.wait();
+        if result.is_ok() {
+            result = stage_result;
+        }
+    }
+    result
+}
+
+/// Waits for the codec stages, and combines their outcome with the `result` of the operation
+/// that used them. A decoder that fails cuts its shard short, which the operation reports as a
+/// truncated shard, and an encoder that fails makes the operation report a broken pipe. The error
+/// of the codec tells what went wrong, so it comes first. A codec killed by SIGPIPE is the
+/// exception, as it only tells that the operation failed and stopped reading from it.
+pub fn finish_codec_stages(result: Result<()>, stages: Vec<CodecStage>) -> Result<()> {
+    let codec_result = wait_codec_stages(stages);
+    let codec_is_cause = match &codec_result {
+        Err(e) => e.downcast_ref::<CodecFailed>().is_none_or(|e| !e.is_broken_pipe()),
+        Ok(()) => false,
+    };
+    if codec_is_cause {
+        codec_result.context("The shard codec failed")
+    } else {
+        result.and(codec_result)
+    }
+}
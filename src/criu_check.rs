@@ -0,0 +1,68 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    path::Path,
+    process::Command,
+};
+use anyhow::{Result, Context};
+
+// A CRIU that doesn't support image streaming ignores the streamer, and the streamer waits
+// forever for CRIU to connect to its socket. With --check-criu, we check the installed CRIU
+// before binding the socket, and fail with guidance instead.
+
+/// CRIU 3.15 is the first version supporting --stream.
+pub const MIN_CRIU_VERSION: (u32, u32) = (3, 15);
+
+/// Returns the (major, minor) version of the provided CRIU binary, parsed from `criu --version`.
+pub fn criu_version(criu: &Path) -> Result<(u32, u32)> {
+    let output = Command::new(criu).arg("--version").output()
+        .with_context(|| format!("Failed to run `{} --version`. Is CRIU installed?", criu.display()))?;
+    ensure!(output.status.success(), "`{} --version` failed: {}", criu.display(), output.status);
+
+    // The output looks like "Version: 3.15\n", possibly followed by the git id.
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    parse_criu_version(&stdout)
+        .ok_or_else(|| anyhow!("Failed to parse the version of CRIU from `{}`", stdout.trim()))
+}
+
+fn parse_criu_version(output: &str) -> Option<(u32, u32)> {
+    let version = output.lines().find_map(|line| line.strip_prefix("Version: "))?;
+    let mut parts = version.trim().split('.');
+    Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+}
+
+/// Checks that the provided CRIU binary supports image streaming, and the provided `features`
+/// with `criu check --feature`. Returns the version of CRIU.
+pub fn check_criu(criu: &Path, features: &[String]) -> Result<(u32, u32)> {
+    let version = criu_version(criu)?;
+    ensure!(version >= MIN_CRIU_VERSION,
+            "CRIU {}.{} does not support image streaming, CRIU >= {}.{} is required. \
+             Upgrade CRIU, or make the newer CRIU come first in the PATH",
+            version.0, version.1, MIN_CRIU_VERSION.0, MIN_CRIU_VERSION.1);
+
+    for feature in features {
+        let output = Command::new(criu).args(["check", "--feature", feature]).output()
+            .with_context(|| format!("Failed to run `{} check`", criu.display()))?;
+        // CRIU reports the missing bits on stdout or stderr depending on its version.
+        let report = format!("{}{}", String::from_utf8_lossy(&output.stdout),
+                                     String::from_utf8_lossy(&output.stderr));
+        ensure!(output.status.success(),
+                "CRIU {}.{} does not support the `{}` feature on this host. \
+                 `criu check --feature {}` reported: {}",
+                version.0, version.1, feature, feature, report.trim());
+    }
+
+    Ok(version)
+}
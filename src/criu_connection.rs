@@ -15,76 +15,196 @@
 use std::{
     os::unix::net::{UnixStream, UnixListener},
     os::unix::io::{RawFd, AsRawFd},
-    path::Path,
+    path::{Path, PathBuf},
+    io::{BufRead, BufReader},
+    time::Duration,
     fs,
 };
+use nix::{
+    sys::socket::{getsockopt, sockopt::PeerCredentials},
+    poll::PollFlags,
+    unistd::Pid,
+};
 use crate::{
     criu,
-    util::{pb_write, recv_fd, pb_read_next},
+    util::{pb_write, recv_fd, pb_read_next_max, DEFAULT_MAX_PB_SIZE},
     unix_pipe::{UnixPipe, UnixPipeImpl},
+    criu_trace::{CriuTrace, TraceEvent, TraceOperation},
+    criu_watchdog::CriuWatchdog,
 };
 use anyhow::{Result, Context};
 
-const IMG_STREAMER_CAPTURE_SOCKET_NAME: &str = "streamer-capture.sock";
-const IMG_STREAMER_SERVE_SOCKET_NAME: &str = "streamer-serve.sock";
+pub const IMG_STREAMER_CAPTURE_SOCKET_NAME: &str = "streamer-capture.sock";
+pub const IMG_STREAMER_SERVE_SOCKET_NAME: &str = "streamer-serve.sock";
+/// Used by controllers to activate a serve in warm standby. See `wait_for_go()`.
+pub const IMG_STREAMER_CONTROL_SOCKET_NAME: &str = "streamer-control.sock";
+
+/// Returns the name of the socket, suffixed with the job id when provided, e.g.,
+/// `streamer-capture-<job_id>.sock`. This lets concurrent streamers share an images directory
+/// without clobbering each other's sockets.
+pub fn socket_name(base_name: &str, job_id: Option<&str>) -> String {
+    match job_id {
+        Some(job_id) => format!("{}-{}.sock", base_name.trim_end_matches(".sock"), job_id),
+        None => base_name.to_string(),
+    }
+}
+
+/// When streaming the images of several containers (e.g., the containers of a pod), each container
+/// has its own CRIU socket in `images_dir/<container>`, and the names of its files are prefixed
+/// with `<container>/` in the image. Without containers, the socket is in `images_dir`, and
+/// filenames are not prefixed.
+/// Returns the directories of the sockets, along with the filename prefixes.
+pub fn criu_socket_dirs(images_dir: &Path, containers: &[String]) -> Vec<(PathBuf, String)> {
+    if containers.is_empty() {
+        return vec![(images_dir.to_path_buf(), String::new())];
+    }
+    containers.iter()
+        .map(|container| (images_dir.join(container), format!("{}/", container)))
+        .collect()
+}
+
+fn bind_unix_socket(socket_path: &Path) -> Result<UnixListener> {
+    // 1) We unlink the socket path to avoid EADDRINUSE on bind() if it already exists.
+    // 2) We ignore the unlink error because we are most likely getting a -ENOENT.
+    //    It is safe to do so as correctness is not impacted by unlink() failing.
+    let _ = fs::remove_file(socket_path);
+    UnixListener::bind(socket_path)
+        .with_context(|| format!("Failed to bind socket to {}", socket_path.display()))
+}
+
+/// Binds the control socket of a serve in warm standby.
+pub fn bind_control_socket(images_dir: &Path, job_id: Option<&str>) -> Result<UnixListener> {
+    bind_unix_socket(&images_dir.join(socket_name(IMG_STREAMER_CONTROL_SOCKET_NAME, job_id)))
+}
+
+/// Waits until a controller sends `go\n` on the control socket. Connections closed without
+/// sending a command are ignored, which lets controllers probe the socket.
+pub fn wait_for_go(control_listener: UnixListener) -> Result<()> {
+    loop {
+        let (socket, _) = control_listener.accept()?;
+        let mut command = String::new();
+        BufReader::new(socket).read_line(&mut command)?;
+        match command.trim_end_matches('\n') {
+            "go" => return Ok(()),
+            "" => continue,
+            command => bail!("Unexpected control command `{}`", command),
+        }
+    }
+}
 
 /// The role of the `CriuListener` and `CriuConnection` is to handle communication with CRIU over
 /// the image socket.
-
 pub struct CriuListener {
     listener: UnixListener,
+    criu_timeout: Option<Duration>,
+    max_request_size: usize,
 }
 
 impl CriuListener {
     fn bind(socket_path: &Path) -> Result<Self> {
-        // 1) We unlink the socket path to avoid EADDRINUSE on bind() if it already exists.
-        // 2) We ignore the unlink error because we are most likely getting a -ENOENT.
-        //    It is safe to do so as correctness is not impacted by unlink() failing.
-        let _ = fs::remove_file(socket_path);
-        let listener = UnixListener::bind(socket_path)
-            .with_context(|| format!("Failed to bind socket to {}", socket_path.display()))?;
+        let listener = bind_unix_socket(socket_path)?;
+        Ok(Self { listener, criu_timeout: None, max_request_size: DEFAULT_MAX_PB_SIZE })
+    }
 
-        Ok(Self { listener })
+    pub fn bind_for_capture(images_dir: &Path, job_id: Option<&str>) -> Result<Self> {
+        Self::bind(&images_dir.join(socket_name(IMG_STREAMER_CAPTURE_SOCKET_NAME, job_id)))
     }
 
-    pub fn bind_for_capture(images_dir: &Path) -> Result<Self> {
-        Self::bind(&images_dir.join(IMG_STREAMER_CAPTURE_SOCKET_NAME))
+    pub fn bind_for_restore(images_dir: &Path, job_id: Option<&str>) -> Result<Self> {
+        Self::bind(&images_dir.join(socket_name(IMG_STREAMER_SERVE_SOCKET_NAME, job_id)))
     }
 
-    pub fn bind_for_restore(images_dir: &Path) -> Result<Self> {
-        Self::bind(&images_dir.join(IMG_STREAMER_SERVE_SOCKET_NAME))
+    /// Fail when the accepted CRIU makes no progress for `criu_timeout`. See `CriuWatchdog`.
+    pub fn criu_timeout(mut self, criu_timeout: Option<Duration>) -> Self {
+        self.criu_timeout = criu_timeout;
+        self
+    }
+
+    /// Fail when the accepted CRIU sends a file request larger than `max_request_size` bytes.
+    pub fn max_request_size(mut self, max_request_size: usize) -> Self {
+        self.max_request_size = max_request_size;
+        self
     }
 
     // into_accept() drops the listener. There is no need for having multiple CRIU connections,
     // so we close the listener here.
     pub fn into_accept(self) -> Result<CriuConnection> {
         let (socket, _) = self.listener.accept()?;
-        Ok(CriuConnection { socket })
+        // The pid lets the watchdog notice when CRIU dies. It is 0 when CRIU is in a pid
+        // namespace that we can't see.
+        let pid = getsockopt(socket.as_raw_fd(), PeerCredentials)
+            .context("Failed to get the credentials of CRIU")?
+            .pid();
+        let watchdog = CriuWatchdog::new(self.criu_timeout);
+        Ok(CriuConnection { socket, trace: None, pid: Pid::from_raw(pid), watchdog,
+                            max_request_size: self.max_request_size })
+    }
+
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
     }
 }
 
 pub struct CriuConnection {
     socket: UnixStream,
+    trace: Option<CriuTrace>,
+    pid: Pid,
+    watchdog: CriuWatchdog,
+    max_request_size: usize,
 }
 
 impl CriuConnection {
+    /// Records the traffic with CRIU from now on.
+    pub fn set_trace(&mut self, mut trace: CriuTrace, operation: TraceOperation) -> Result<()> {
+        trace.record(TraceEvent::Connect { operation })?;
+        self.trace = Some(trace);
+        Ok(())
+    }
+
+    fn record(&mut self, event: TraceEvent) -> Result<()> {
+        match self.trace.as_mut() {
+            Some(trace) => trace.record(event),
+            None => Ok(()),
+        }
+    }
+
     /// Read and return the next file request. If reached EOF, returns Ok(None).
     pub fn read_next_file_request(&mut self) -> Result<Option<String>> {
-        Ok(pb_read_next(&mut self.socket)?
-            .map(|(req, _): (criu::ImgStreamerRequestEntry, _)| req.filename))
+        self.watchdog.wait_fd(self.socket.as_raw_fd(), PollFlags::POLLIN, self.pid)?;
+        let filename = pb_read_next_max(&mut self.socket, self.max_request_size)?
+            .map(|(req, _): (criu::ImgStreamerRequestEntry, _)| req.filename);
+        self.record(match &filename {
+            Some(filename) => TraceEvent::Request { filename: filename.clone() },
+            None => TraceEvent::Disconnect,
+        })?;
+        Ok(filename)
     }
 
     /// Returns the data pipe that is used to transfer the file.
     pub fn recv_pipe(&mut self) -> Result<UnixPipe> {
-        UnixPipe::new(recv_fd(&mut self.socket)?)
+        // CRIU sends the pipe right after its request, or its reply. One that doesn't is stalled.
+        self.watchdog.wait_fd(self.socket.as_raw_fd(), PollFlags::POLLIN, self.pid)?;
+        let pipe = UnixPipe::new(recv_fd(&mut self.socket)?)?;
+        self.record(TraceEvent::RecvPipe)?;
+        Ok(pipe)
     }
 
     /// During restore, CRIU requests image files that may or may not exist.
     /// We must let CRIU know if we hold has the requested file in question.
     /// It is done via `send_file_reply()`. Not used during checkpointing.
     pub fn send_file_reply(&mut self, exists: bool) -> Result<()> {
+        self.watchdog.wait_fd(self.socket.as_raw_fd(), PollFlags::POLLOUT, self.pid)?;
         pb_write(&mut self.socket, &criu::ImgStreamerReplyEntry { exists })?;
-        Ok(())
+        self.record(TraceEvent::Reply { exists })
+    }
+
+    /// Blocks until CRIU can take more data from the pipe of a file being served.
+    pub fn wait_writable(&mut self, pipe: &UnixPipe) -> Result<()> {
+        self.watchdog.wait_fd(pipe.as_raw_fd(), PollFlags::POLLOUT, self.pid)
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
     }
 
     pub fn as_raw_fd(&self) -> RawFd {
@@ -0,0 +1,107 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    time::Instant,
+    path::Path,
+    fs,
+};
+use serde::{Serialize, Deserialize};
+use anyhow::{Result, Context};
+
+// To reproduce protocol issues seen in production, the traffic on the CRIU socket can be recorded
+// in a trace file with --criu-trace. The trace can be replayed later with the replay operation,
+// which plays the role of CRIU against a streamer (see replay.rs).
+// The content of the image files is not recorded, only their size, so traces are small and don't
+// contain application data.
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceOperation {
+    Capture,
+    Serve,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TraceEvent {
+    /// CRIU connected to the capture or serve socket
+    Connect { operation: TraceOperation },
+    /// CRIU sent an ImgStreamerRequestEntry
+    Request { filename: String },
+    /// We sent an ImgStreamerReplyEntry (serve only)
+    Reply { exists: bool },
+    /// CRIU sent the pipe of the requested file
+    RecvPipe,
+    /// The file sent by CRIU reached EOF (capture only)
+    FileEof { filename: String, size: u64 },
+    /// CRIU closed the connection
+    Disconnect,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TraceRecord {
+    elapsed_millis: u128,
+    #[serde(flatten)]
+    event: TraceEvent,
+}
+
+/// `CriuTrace` records events, one JSON record per line.
+/// Failing to write a record fails the operation, as an incomplete trace would be misleading.
+pub struct CriuTrace {
+    file: fs::File,
+    start_time: Instant,
+}
+
+impl CriuTrace {
+    pub fn new(file: fs::File) -> Self {
+        Self { file, start_time: Instant::now() }
+    }
+
+    /// Creates the trace file, truncating it if it exists.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = fs::File::create(path)
+            .with_context(|| format!("Failed to create CRIU trace {}", path.display()))?;
+        Ok(Self::new(file))
+    }
+
+    /// Returns a handle to the same trace. Records of both handles are appended in order.
+    pub fn try_clone(&self) -> Result<Self> {
+        let file = self.file.try_clone().context("Failed to clone the CRIU trace")?;
+        Ok(Self { file, start_time: self.start_time })
+    }
+
+    pub fn record(&mut self, event: TraceEvent) -> Result<()> {
+        let elapsed_millis = self.start_time.elapsed().as_millis();
+        let mut line = serde_json::to_string(&TraceRecord { elapsed_millis, event })?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes()).context("Failed to write to the CRIU trace")?;
+        Ok(())
+    }
+
+    /// Reads the events of a recorded trace.
+    pub fn load(path: &Path) -> Result<Vec<TraceEvent>> {
+        let file = fs::File::open(path)
+            .with_context(|| format!("Failed to open CRIU trace {}", path.display()))?;
+        BufReader::new(file).lines().enumerate()
+            .map(|(i, line)| {
+                let record: TraceRecord = serde_json::from_str(&line?)
+                    .with_context(|| format!("Malformed record at line {} of {}", i+1, path.display()))?;
+                Ok(record.event)
+            })
+            .collect()
+    }
+}
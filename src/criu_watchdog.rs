@@ -0,0 +1,129 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    os::unix::io::RawFd,
+    time::{Duration, Instant},
+    fmt,
+    fs,
+};
+use nix::{
+    poll::{poll, PollFd, PollFlags},
+    sys::signal::kill,
+    unistd::Pid,
+    errno::Errno,
+    Error,
+};
+use anyhow::{Result, Context};
+
+// When CRIU is killed mid-checkpoint or mid-restore, its socket and pipes normally hang up, and
+// we notice. But the processes that CRIU forks (e.g., the restored tasks) may inherit them and
+// keep them open, in which case we would wait on CRIU forever. The watchdog wakes up
+// periodically while we wait on CRIU, and fails when CRIU is gone, or when it made no progress
+// for longer than the configured timeout.
+
+/// How often we check on CRIU while waiting on it.
+pub const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Why the watchdog gave up on CRIU. Library users can tell it apart from other failures with
+/// `anyhow::Error::downcast_ref()`, e.g., to retry a restore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CriuWatchdogError {
+    /// CRIU died, but its socket or pipes are kept open by other processes
+    Died { pid: Pid },
+    /// CRIU made no progress for the configured timeout
+    Stalled { timeout: Duration },
+}
+
+impl fmt::Display for CriuWatchdogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Died { pid } => write!(f, "CRIU (pid {}) died while streaming the image, \
+                                             its socket or pipes are kept open by other processes", pid),
+            Self::Stalled { timeout } => write!(f, "CRIU made no progress for {}s, giving up", timeout.as_secs()),
+        }
+    }
+}
+
+impl std::error::Error for CriuWatchdogError {}
+
+pub struct CriuWatchdog {
+    /// Fail when CRIU makes no progress for this long. Disabled when None.
+    timeout: Option<Duration>,
+    last_progress: Instant,
+}
+
+impl CriuWatchdog {
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self { timeout, last_progress: Instant::now() }
+    }
+
+    /// Records that CRIU sent a request, or moved data.
+    pub fn progress(&mut self) {
+        self.last_progress = Instant::now();
+    }
+
+    /// Fails when one of the CRIU processes we are waiting on is dead, or when none of them made
+    /// progress within the timeout. `criu_pids` is empty when we are not waiting on CRIU.
+    pub fn check(&self, criu_pids: impl IntoIterator<Item = Pid>) -> Result<()> {
+        let mut waiting_on_criu = false;
+        for pid in criu_pids {
+            ensure!(is_alive(pid), CriuWatchdogError::Died { pid });
+            waiting_on_criu = true;
+        }
+
+        if let Some(timeout) = self.timeout {
+            ensure!(!waiting_on_criu || self.last_progress.elapsed() < timeout,
+                    CriuWatchdogError::Stalled { timeout });
+        }
+
+        Ok(())
+    }
+
+    /// Blocks until `fd` is ready for `flags`, checking on the CRIU process `criu_pid` meanwhile.
+    pub fn wait_fd(&mut self, fd: RawFd, flags: PollFlags, criu_pid: Pid) -> Result<()> {
+        loop {
+            let mut poll_fds = [PollFd::new(fd, flags)];
+            match poll(&mut poll_fds, WATCHDOG_INTERVAL.as_millis() as i32) {
+                Err(Error::Sys(Errno::EINTR)) => continue,
+                // Errors and hang ups count as ready, the following read or write reports them.
+                result => if result.context("Failed to poll CRIU")? > 0 {
+                    self.progress();
+                    return Ok(());
+                }
+            }
+            self.check(Some(criu_pid))?;
+        }
+    }
+}
+
+/// Returns whether the process is still running. A pid of 0 means that the process lives in a
+/// pid namespace that we can't see, and we assume it's alive.
+pub fn is_alive(pid: Pid) -> bool {
+    if pid == Pid::from_raw(0) {
+        return true;
+    }
+    if let Err(Error::Sys(Errno::ESRCH)) = kill(pid, None) {
+        return false;
+    }
+
+    // A killed process remains a zombie until its parent reaps it, and kill() still finds it.
+    // When /proc is not readable, we assume the process is alive.
+    match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        // The state comes after the command name, which is in parentheses and may contain spaces.
+        Ok(stat) => !matches!(stat.rsplit(')').next().map(str::trim_start).and_then(|s| s.chars().next()),
+                              Some('Z') | Some('X')),
+        Err(_) => true,
+    }
+}
@@ -0,0 +1,210 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    fs,
+    fmt,
+    io::Write,
+    path::Path,
+};
+use nix::unistd::geteuid;
+use crate::{
+    capture::{CRIU_PIPE_DESIRED_CAPACITY, SHARD_PIPE_DESIRED_CAPACITY},
+    criu_check::{criu_version, MIN_CRIU_VERSION},
+    kernel_caps::KERNEL_CAPS,
+    util::{PAGE_SIZE, MB},
+};
+use anyhow::Result;
+
+// When the host limits pipe sizes, the streamer falls back to smaller pipes, and is slower than it
+// could be. The only signs are a reduced `max_pipe_capacity`, and reduced shard `pipe_capacity`
+// in the stats. The doctor operation inspects the host settings that matter to the streamer, and
+// prints how to fix them.
+
+/// Pipe buffers a single streamer may want: a few shards, and a few CRIU and external file pipes.
+/// This is the default soft limit of the kernel with 4KB pages.
+const RECOMMENDED_PIPE_USER_BYTES: u64 = 64*MB as u64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Ok,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Ok => "ok",
+            Status::Warning => "warning",
+            Status::Error => "error",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+    /// How to fix the issue, when there is one.
+    pub remediation: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &'static str, detail: String) -> Self {
+        Self { name, status: Status::Ok, detail, remediation: None }
+    }
+
+    fn issue(name: &'static str, status: Status, detail: String, remediation: String) -> Self {
+        Self { name, status, detail, remediation: Some(remediation) }
+    }
+}
+
+fn read_sysctl(path: &str) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Returns MemAvailable from /proc/meminfo, in bytes.
+fn mem_available() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find_map(|line| line.strip_prefix("MemAvailable:"))?;
+    let kb: u64 = line.trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// Pipe limits don't apply to processes with CAP_SYS_RESOURCE or CAP_SYS_ADMIN. We approximate
+/// this with root.
+fn pipe_limits_apply() -> bool {
+    !geteuid().is_root()
+}
+
+/// The largest pipe capacity an unprivileged streamer can ask for. None when the limit doesn't
+/// apply, or can't be read.
+pub fn pipe_max_size() -> Option<u64> {
+    pipe_limits_apply().then(|| read_sysctl("/proc/sys/fs/pipe-max-size")).flatten()
+}
+
+pub fn check_pipe_max_size(pipe_max_size: Option<u64>, limits_apply: bool) -> Check {
+    const NAME: &str = "pipe-max-size";
+    let wanted = CRIU_PIPE_DESIRED_CAPACITY as u64;
+    match pipe_max_size {
+        None => Check::issue(NAME, Status::Warning,
+            "/proc/sys/fs/pipe-max-size is not readable".to_string(),
+            "Check that /proc is mounted".to_string()),
+        Some(size) if size < wanted && limits_apply => Check::issue(NAME, Status::Warning,
+            format!("{} bytes, pipes are limited below the {} bytes the streamer asks for", size, wanted),
+            format!("sysctl -w fs.pipe-max-size={}", wanted)),
+        Some(size) if size < wanted => Check::ok(NAME, format!("{} bytes, not enforced for root", size)),
+        Some(size) => Check::ok(NAME, format!("{} bytes", size)),
+    }
+}
+
+/// `limit` is in pages, and 0 means unlimited.
+pub fn check_pipe_user_pages(name: &'static str, limit: Option<u64>, limits_apply: bool) -> Check {
+    let wanted_pages = RECOMMENDED_PIPE_USER_BYTES / *PAGE_SIZE as u64;
+    // Beyond the soft limit, new pipes get a single page. Beyond the hard limit, pipes can't grow.
+    let consequence = if name.ends_with("soft") {
+        "new pipes are shrunk to a single page"
+    } else {
+        "pipes can no longer be resized"
+    };
+    match limit {
+        None => Check::issue(name, Status::Warning,
+            format!("/proc/sys/fs/{} is not readable", name),
+            "Check that /proc is mounted".to_string()),
+        Some(0) => Check::ok(name, "unlimited".to_string()),
+        Some(pages) if pages < wanted_pages && limits_apply => Check::issue(name, Status::Warning,
+            format!("{} pages, once the pipes of the user reach this limit, {}", pages, consequence),
+            format!("sysctl -w fs.{}={} (or 0 for unlimited)", name, wanted_pages)),
+        Some(pages) if pages < wanted_pages =>
+            Check::ok(name, format!("{} pages, not enforced for root", pages)),
+        Some(pages) => Check::ok(name, format!("{} pages", pages)),
+    }
+}
+
+/// `image_size` is the size of the images to serve, which are buffered in memory.
+pub fn check_memory(mem_available: Option<u64>, image_size: Option<u64>) -> Check {
+    const NAME: &str = "memory";
+    match (mem_available, image_size) {
+        (None, _) => Check::issue(NAME, Status::Warning,
+            "MemAvailable is not reported in /proc/meminfo".to_string(),
+            "Check that /proc is mounted".to_string()),
+        (Some(available), Some(size)) if available < size => Check::issue(NAME, Status::Error,
+            format!("{} MB available, serving a {} MB image buffers it in memory",
+                    available / MB as u64, size / MB as u64),
+            "Free up memory, or extract the image to disk and restore from images_dir".to_string()),
+        (Some(available), _) => Check::ok(NAME, format!("{} MB available", available / MB as u64)),
+    }
+}
+
+pub fn check_criu(criu: &Path) -> Check {
+    const NAME: &str = "criu";
+    match criu_version(criu) {
+        Ok(version) if version >= MIN_CRIU_VERSION =>
+            Check::ok(NAME, format!("CRIU {}.{}", version.0, version.1)),
+        Ok(version) => Check::issue(NAME, Status::Error,
+            format!("CRIU {}.{} does not support image streaming", version.0, version.1),
+            format!("Install CRIU >= {}.{}, and make it come first in the PATH",
+                    MIN_CRIU_VERSION.0, MIN_CRIU_VERSION.1)),
+        Err(e) => Check::issue(NAME, Status::Error, format!("{:#}", e),
+            format!("Install CRIU >= {}.{}", MIN_CRIU_VERSION.0, MIN_CRIU_VERSION.1)),
+    }
+}
+
+pub fn check_kernel() -> Check {
+    const NAME: &str = "kernel";
+    let caps = &*KERNEL_CAPS;
+    if let Some(warning) = caps.splice_bug_warning() {
+        return Check::issue(NAME, Status::Warning, warning, "Upgrade the kernel".to_string());
+    }
+    match caps.max_pipe_capacity {
+        None => Check::issue(NAME, Status::Warning,
+            format!("{}, pipes cannot be resized", caps.release),
+            "Run on a kernel (or container runtime) supporting F_SETPIPE_SZ".to_string()),
+        Some(capacity) if capacity < SHARD_PIPE_DESIRED_CAPACITY => Check::issue(NAME, Status::Warning,
+            format!("{}, pipes can't grow beyond {} bytes", caps.release, capacity),
+            "See the pipe-max-size and pipe-user-pages checks".to_string()),
+        Some(_) if !caps.splice => Check::issue(NAME, Status::Warning,
+            format!("{}, splice() is not supported, data is copied through userspace", caps.release),
+            "Run on a kernel (or container runtime) supporting splice()".to_string()),
+        Some(_) => Check::ok(NAME, caps.release.clone()),
+    }
+}
+
+/// Inspects the host. `image_size` is the size of the images to serve, when known.
+pub fn doctor(image_size: Option<u64>) -> Vec<Check> {
+    let limits_apply = pipe_limits_apply();
+    let pipe_user_pages = |name| check_pipe_user_pages(name, read_sysctl(&format!("/proc/sys/fs/{}", name)),
+                                                       limits_apply);
+    vec![
+        check_pipe_max_size(read_sysctl("/proc/sys/fs/pipe-max-size"), limits_apply),
+        pipe_user_pages("pipe-user-pages-soft"),
+        pipe_user_pages("pipe-user-pages-hard"),
+        check_memory(mem_available(), image_size),
+        check_criu(Path::new("criu")),
+        check_kernel(),
+    ]
+}
+
+/// Prints one line per check, followed by the remediation of the issues.
+pub fn print_report(checks: &[Check], out: &mut impl Write) -> Result<()> {
+    for check in checks {
+        writeln!(out, "[{}] {}: {}", check.status, check.name, check.detail)?;
+        if let Some(remediation) = &check.remediation {
+            writeln!(out, "    fix: {}", remediation)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,36 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+
+use crate::util::{Stats, Phase};
+
+// Library users can follow the image as it streams through, for example to drive a progress UI,
+// without parsing the progress pipe. The callback is invoked on the streaming thread, so it should
+// return quickly. Events can be forwarded to another thread with a channel.
+
+#[derive(Debug, Clone, Copy)]
+pub enum Event<'a> {
+    /// A file starts streaming. When capturing, the file comes from CRIU or is an external file.
+    /// When extracting, the file is decoded from the shards.
+    FileStart { filename: &'a str },
+    /// A file has been fully streamed.
+    FileComplete { filename: &'a str, size: u64 },
+    /// The image has been fully streamed. These are the stats emitted on the progress pipe.
+    ImageComplete { stats: &'a Stats },
+    /// Serving the image enters a new phase, whether or not the phases are reported on the
+    /// progress pipe. `Phase::Done` ends the last phase.
+    PhaseStart { phase: Phase },
+}
+
+pub type EventCallback = Box<dyn FnMut(Event<'_>) + Send>;
@@ -13,24 +13,44 @@
 //  limitations under the License.
 
 use std::{
+    cmp::max,
     collections::{BinaryHeap, HashMap, HashSet},
     os::unix::io::AsRawFd,
-    time::Instant,
-    path::Path,
+    time::{Duration, Instant},
+    path::{Path, PathBuf},
+    io::{Read, Write, BufRead, BufReader},
+    cell::RefCell,
+    rc::Rc,
+    sync::{Arc, Mutex, Condvar},
+    thread,
     fs,
 };
 use crate::{
-    criu_connection::CriuListener,
-    unix_pipe::{UnixPipe, UnixPipeImpl},
+    criu_connection::{CriuListener, CriuConnection, criu_socket_dirs, bind_control_socket, wait_for_go},
+    unix_pipe::{UnixPipe, UnixPipeImpl, ensure_min_capacity},
     util::*,
     image,
     image::marker,
     impl_ord_by,
     image_store,
-    image_store::{ImageStore, ImageFile},
+    image_store::{ImageStore, ImageFile, DynImageStore, fs::Ownership},
     image_patcher::patch_img,
+    manifest::{Manifest, ManifestFile, FileHasher, FileDigest, SigningKey, VerifyingKey, is_reserved_filename,
+               MANIFEST_FILENAME, MANIFEST_SIG_FILENAME},
+    audit::{AuditLog, Direction},
+    kernel_caps::KERNEL_CAPS,
+    criu_trace::{CriuTrace, TraceOperation},
+    marker_log::MarkerLog,
+    reflink::link_unchanged_files,
+    snapshot::run_snapshot_cmd,
+    capture::{self, ImageSerializer},
+    events::{Event, EventCallback},
 };
-use nix::poll::{poll, PollFd, PollFlags};
+use nix::{
+    poll::{poll, PollFd, PollFlags},
+    sys::mman::{mlockall, munlockall, MlockAllFlags},
+};
+use serde::Serialize;
 use anyhow::{Result, Context};
 
 // The serialized image is received via multiple data streams (`Shard`). The data streams are
@@ -69,13 +89,68 @@ struct Shard {
     pipe: UnixPipe,
     transfer_duration_millis: u128,
     bytes_read: u64,
+    /// When the shard carries successive images, the header of the next image, read while the
+    /// current image was not complete yet.
+    next_header: Option<image::ShardHeader>,
+    /// Position of the shard in the provided shards. Set by `ImageDeserializer`.
+    index: usize,
 }
 
 impl Shard {
     fn new(mut pipe: UnixPipe) -> Self {
         // Try setting the pipe capacity. Failing is okay, it's just for better performance.
         let _ = pipe.set_capacity(SHARD_PIPE_DESIRED_CAPACITY);
-        Self { pipe, bytes_read: 0, transfer_duration_millis: 0 }
+        Self { pipe, bytes_read: 0, transfer_duration_millis: 0, next_header: None, index: 0 }
+    }
+
+    /// Creates the shards, failing when their pipes can't be given `min_pipe_capacity`.
+    fn new_all(shard_pipes: Vec<UnixPipe>, min_pipe_capacity: Option<i32>) -> Result<Vec<Self>> {
+        let shards: Vec<Self> = shard_pipes.into_iter().map(Shard::new).collect();
+        if let Some(min_pipe_capacity) = min_pipe_capacity {
+            for shard in &shards {
+                ensure_min_capacity(shard.pipe.capacity()?, min_pipe_capacity)?;
+            }
+        }
+        Ok(shards)
+    }
+}
+
+/// The data payload following a `FileData` marker is read from a shard pipe. It can also be read
+/// from a byte slice, which is useful for fuzzing the deserializer.
+pub trait MarkerDataSource {
+    fn write_data_into(&mut self, img_file: &mut impl ImageFile, size: usize) -> Result<()>;
+    /// Discards the padding following a marker and its data.
+    fn skip(&mut self, size: usize) -> Result<()>;
+}
+
+impl MarkerDataSource for UnixPipe {
+    fn write_data_into(&mut self, img_file: &mut impl ImageFile, size: usize) -> Result<()> {
+        img_file.write_all_from_pipe(self, size)
+    }
+
+    fn skip(&mut self, size: usize) -> Result<()> {
+        if size > 0 {
+            let skipped = std::io::copy(&mut self.take(size as u64), &mut std::io::sink())
+                .context("Failed to read from shard")?;
+            ensure!(skipped == size as u64, EOF_ERR_MSG);
+        }
+        Ok(())
+    }
+}
+
+impl MarkerDataSource for &[u8] {
+    fn write_data_into(&mut self, img_file: &mut impl ImageFile, size: usize) -> Result<()> {
+        ensure!(self.len() >= size, EOF_ERR_MSG);
+        let (data, rest) = self.split_at(size);
+        img_file.write_all_from_slice(data)?;
+        *self = rest;
+        Ok(())
+    }
+
+    fn skip(&mut self, size: usize) -> Result<()> {
+        ensure!(self.len() >= size, EOF_ERR_MSG);
+        *self = &self[size..];
+        Ok(())
     }
 }
 
@@ -88,6 +163,15 @@ struct PendingMarker<'a> {
 // hence the `reverse()`. Note that sequence numbers are unique, giving us a total order.
 impl_ord_by!(PendingMarker<'a>, |a: &Self, b: &Self| a.marker.seq.cmp(&b.marker.seq).reverse());
 
+/// Returns the number of data and padding bytes following the marker.
+fn marker_data_size(marker: &image::Marker) -> u64 {
+    let data_size = match marker.body {
+        Some(marker::Body::FileData(size)) => size as u64,
+        _ => 0,
+    };
+    data_size + marker.padding as u64
+}
+
 struct ImageDeserializer<'a, ImgStore: ImageStore> {
     // Shards are located in three different collections:
     // 1) `shards` stores shards that may not be readable yet. `poll()` is used to determine when a
@@ -103,6 +187,10 @@ struct ImageDeserializer<'a, ImgStore: ImageStore> {
     readable_shards: Vec<&'a mut Shard>,
     pending_markers: BinaryHeap<PendingMarker<'a>>,
     seq: u64,
+    // The data of the pending markers waits in the shard pipes. We track how much of it there is
+    // at once, for the stats.
+    buffered_bytes: u64,
+    max_buffered_bytes: u64,
 
     // The following fields relate to the output.
     // When receiving a `Filename(filename)` marker, the `img_files` map is examined to see if we
@@ -119,24 +207,116 @@ struct ImageDeserializer<'a, ImgStore: ImageStore> {
     // `start_time` is used for stats, image_eof is used for safety checks.
     start_time: Instant,
     image_eof: bool,
+
+    // The shard headers tell us which image the shards belong to, and how many shards the image
+    // was captured with. Images captured by older versions have no shard headers.
+    num_shards: usize,
+    image_uuid: Option<String>,
+    expected_num_shards: Option<usize>,
+
+    // When the shards carry successive images, we stop at the image EOF, and the shard headers
+    // of the next image are kept in the shards for the next deserializer. See `drain_next()`.
+    rolling: bool,
+
+    // Logs the markers as they are processed, see `log_markers()`.
+    marker_log: Option<&'a mut MarkerLog>,
+
+    // Markers larger than this are rejected, see `limit_marker_size()`.
+    max_marker_size: usize,
 }
 
 impl<'a, ImgStore: ImageStore> ImageDeserializer<'a, ImgStore> {
     pub fn new(img_store: &'a mut ImgStore, shards: &'a mut [Shard]) -> Self {
         let num_shards = shards.len();
         Self {
-            shards: shards.iter_mut().collect(),
+            shards: shards.iter_mut().enumerate()
+                .map(|(index, shard)| { shard.index = index; shard })
+                .collect(),
             readable_shards: Vec::with_capacity(num_shards),
             pending_markers: BinaryHeap::with_capacity(num_shards),
             seq: 0,
+            buffered_bytes: 0,
+            max_buffered_bytes: 0,
             img_store,
             img_files: HashMap::new(),
             current_img_file: None,
             start_time: Instant::now(),
             image_eof: false,
+            num_shards,
+            image_uuid: None,
+            expected_num_shards: None,
+            rolling: false,
+            marker_log: None,
+            max_marker_size: DEFAULT_MAX_PB_SIZE,
         }
     }
 
+    /// Logs every marker read from the shards, along with the shard it comes from.
+    pub fn log_markers(&mut self, marker_log: &'a mut MarkerLog) {
+        self.marker_log = Some(marker_log);
+    }
+
+    /// Fails on the markers larger than `max_marker_size` bytes, as they are most likely the
+    /// sign of a corrupted shard. `DEFAULT_MAX_PB_SIZE` by default.
+    pub fn limit_marker_size(&mut self, max_marker_size: usize) {
+        self.max_marker_size = max_marker_size;
+    }
+
+    pub fn image_uuid(&self) -> Option<&str> {
+        self.image_uuid.as_deref()
+    }
+
+    /// The most bytes of chunk data that were waiting in the shard pipes for their turn at once.
+    pub fn max_buffered_bytes(&self) -> u64 {
+        self.max_buffered_bytes
+    }
+
+    fn add_shard_header(&mut self, header: image::ShardHeader) -> Result<()> {
+        match &self.image_uuid {
+            Some(image_uuid) => ensure!(*image_uuid == header.image_uuid,
+                "The provided shards belong to different images: {} and {}",
+                image_uuid, header.image_uuid),
+            None => self.image_uuid = Some(header.image_uuid),
+        }
+
+        let expected_num_shards = header.num_shards as usize;
+        ensure!(self.num_shards <= expected_num_shards,
+                "The image {} was captured with {} shards, but {} shards were provided",
+                self.image_uuid.as_ref().unwrap(), expected_num_shards, self.num_shards);
+        self.expected_num_shards = Some(expected_num_shards);
+        Ok(())
+    }
+
+    /// When all the shards have reached EOF, or are waiting for a marker that no shard has, the
+    /// image is incomplete. Each remaining shard has a pending marker, and markers come in
+    /// increasing order on a shard. So the sequence numbers between the pending markers are
+    /// missing, as well as the ones after, which we can't tell precisely.
+    fn incomplete_image_error(&self) -> anyhow::Error {
+        let mut pending_seqs: Vec<u64> = self.pending_markers.iter().map(|p| p.marker.seq).collect();
+        pending_seqs.sort_unstable();
+
+        let mut missing_ranges = Vec::new();
+        let mut next_seq = self.seq;
+        for seq in pending_seqs {
+            match seq.checked_sub(next_seq) {
+                None | Some(0) => {}
+                Some(1) => missing_ranges.push(format!("{}", next_seq)),
+                Some(_) => missing_ranges.push(format!("{}-{}", next_seq, seq - 1)),
+            }
+            next_seq = max(next_seq, seq + 1);
+        }
+        missing_ranges.push(format!("{} onwards", next_seq));
+
+        let shards = match (&self.image_uuid, self.expected_num_shards) {
+            (Some(image_uuid), Some(expected_num_shards)) =>
+                format!("The image {} was captured with {} shards, and {} shards were provided",
+                        image_uuid, expected_num_shards, self.num_shards),
+            _ => format!("{} shards were provided", self.num_shards),
+        };
+        anyhow!("The image is incomplete. {}. Missing marker sequence numbers: {}",
+                shards, missing_ranges.join(", "))
+    }
+
     fn mark_image_eof(&mut self) -> Result<()> {
         ensure!(self.img_files.is_empty() && self.pending_markers.is_empty(),
                 "Image EOF marker came unexpectedly");
@@ -145,7 +325,8 @@ impl<'a, ImgStore: ImageStore> ImageDeserializer<'a, ImgStore> {
         Ok(())
     }
 
-    fn select_img_file(&mut self, filename: Box<str>) -> Result<()> {
+    /// `file_size` is the size of the file when the image tells it, 0 otherwise.
+    fn select_img_file(&mut self, filename: Box<str>, file_size: u64) -> Result<()> {
         // First, put the current image file back in the hashmap.
         // This avoids creating the same image file twice.
         if let Some((filename, output)) = self.current_img_file.take() {
@@ -157,7 +338,10 @@ impl<'a, ImgStore: ImageStore> ImageDeserializer<'a, ImgStore> {
         let (filename, img_file) = match self.img_files.remove_entry(&filename) {
             Some((filename, img_file)) => (filename, img_file),
             None => {
-                let img_file = self.img_store.create(&filename)?;
+                let mut img_file = self.img_store.create(&filename)?;
+                if file_size > 0 {
+                    img_file.preallocate(file_size)?;
+                }
                 (filename, img_file)
             }
         };
@@ -166,31 +350,36 @@ impl<'a, ImgStore: ImageStore> ImageDeserializer<'a, ImgStore> {
         Ok(())
     }
 
-    fn process_marker(&mut self, marker: image::Marker, shard: &mut Shard) -> Result<()> {
+    /// Returns the number of data and padding bytes read from `src`.
+    fn process_marker(&mut self, marker: image::Marker, src: &mut impl MarkerDataSource) -> Result<u64> {
         use marker::Body::*;
 
-        match marker.body {
+        let data_size = match marker.body {
             Some(Filename(filename)) => {
-                self.select_img_file(filename.into_boxed_str())?;
+                self.select_img_file(filename.into_boxed_str(), marker.file_size)?;
+                0
             }
             Some(FileData(size)) => {
                 let (_filename, img_file) = self.current_img_file.as_mut()
                     .ok_or_else(|| anyhow!("Unexpected FileData marker"))?;
-                img_file.write_all_from_pipe(&mut shard.pipe, size as usize)?;
-                shard.bytes_read += size as u64;
+                src.write_data_into(img_file, size as usize)?;
+                size as u64
             }
             Some(FileEof(true)) => {
                 let (filename, img_file) = self.current_img_file.take()
                     .ok_or_else(|| anyhow!("Unexpected FileEof marker"))?;
                 self.img_store.insert(filename, img_file);
+                0
             }
             Some(ImageEof(true)) => {
                 self.mark_image_eof()?;
+                0
             }
             _ => bail!("Malformed image marker"),
-        }
+        };
 
-        Ok(())
+        src.skip(marker.padding as usize)?;
+        Ok(data_size + marker.padding as u64)
     }
 
     fn get_next_in_order_marker(&mut self) -> Option<PendingMarker<'a>> {
@@ -203,8 +392,13 @@ impl<'a, ImgStore: ImageStore> ImageDeserializer<'a, ImgStore> {
     }
 
     fn process_pending_markers(&mut self) -> Result<()> {
-        while let Some(PendingMarker { marker, mut shard }) = self.get_next_in_order_marker() {
-            self.process_marker(marker, &mut shard)?;
+        while let Some(PendingMarker { marker, shard }) = self.get_next_in_order_marker() {
+            self.buffered_bytes -= marker_data_size(&marker);
+            if let Some(marker_log) = self.marker_log.as_mut() {
+                let filename = self.current_img_file.as_ref().map(|(filename, _)| &**filename);
+                marker_log.record(shard.index, &marker, filename)?;
+            }
+            shard.bytes_read += self.process_marker(marker, &mut shard.pipe)?;
             self.seq += 1;
             self.shards.push(shard);
         }
@@ -216,14 +410,65 @@ impl<'a, ImgStore: ImageStore> ImageDeserializer<'a, ImgStore> {
     }
 
     fn drain_shard(&mut self, shard: &'a mut Shard) -> Result<()> {
-        match pb_read_next(&mut shard.pipe)? {
+        match pb_read_next_max::<_, image::Marker>(&mut shard.pipe, self.max_marker_size)? {
             None => {
                 // EOF of that shard is reached
                 self.mark_shard_eof(shard);
             }
             Some((marker, marker_size)) => {
-                ensure!(!self.image_eof, "Unexpected data after image EOF");
                 shard.bytes_read += marker_size as u64;
+                // Sequenced markers are logged in order, when processed.
+                let is_sequenced = !matches!(marker.body,
+                    Some(marker::Body::ShardHeader(_)) | Some(marker::Body::ImageAborted(_)) |
+                    Some(marker::Body::FileTable(_)) | Some(marker::Body::FileTableSize(_)));
+                if !is_sequenced {
+                    if let Some(marker_log) = self.marker_log.as_mut() {
+                        marker_log.record(shard.index, &marker, None)?;
+                    }
+                }
+                // Non-sequenced markers are handled before checking for data after the image
+                // EOF. A shard that only carries its header may be read after the image EOF
+                // marker came on another shard.
+                match marker.body {
+                    // The file table follows the image EOF marker on the first shard, and is only
+                    // read by listings. See file_table.rs.
+                    Some(marker::Body::FileTable(size)) => {
+                        let skipped = size as usize + marker.padding as usize;
+                        shard.pipe.skip(skipped)?;
+                        shard.bytes_read += skipped as u64;
+                        self.shards.push(shard);
+                        return Ok(());
+                    }
+                    Some(marker::Body::FileTableSize(_)) => {
+                        self.shards.push(shard);
+                        return Ok(());
+                    }
+                    // The abort marker is not sequenced, it comes after whatever the shard carried.
+                    Some(marker::Body::ImageAborted(true)) =>
+                        bail!("The capture failed, the image is invalid"),
+                    Some(marker::Body::ShardHeader(header)) => {
+                        shard.pipe.skip(marker.padding as usize)?;
+                        shard.bytes_read += marker.padding as u64;
+                        let is_next_image = self.image_uuid.as_ref()
+                            .is_some_and(|image_uuid| *image_uuid != header.image_uuid);
+                        if self.rolling && is_next_image {
+                            // The shard is done with the current image, this is the next one.
+                            shard.next_header = Some(header);
+                        } else {
+                            self.add_shard_header(header)?;
+                            self.shards.push(shard);
+                        }
+                        return Ok(());
+                    }
+                    _ => {}
+                }
+                ensure!(!self.image_eof, "Unexpected data after image EOF");
+                ensure!(marker.seq >= self.seq,
+                        "Unexpected marker sequence number {}, already processed", marker.seq);
+                ensure!(!self.pending_markers.iter().any(|p| p.marker.seq == marker.seq),
+                        "Duplicate marker sequence number {}", marker.seq);
+                self.buffered_bytes += marker_data_size(&marker);
+                self.max_buffered_bytes = max(self.max_buffered_bytes, self.buffered_bytes);
                 self.pending_markers.push(PendingMarker { marker, shard });
                 self.process_pending_markers()?;
             }
@@ -285,35 +530,177 @@ impl<'a, ImgStore: ImageStore> ImageDeserializer<'a, ImgStore> {
         while let Some(shard) = self.get_next_readable_shard()? {
             self.drain_shard(shard)?;
         }
-        ensure!(self.image_eof, "No shards to read from");
+        if !self.image_eof {
+            return Err(self.incomplete_image_error());
+        }
+        Ok(())
+    }
+
+    /// Returns successfully when the next image of shards carrying successive images has been
+    /// fully deserialized. The shards are left at the start of the image that follows. Returns
+    /// false when the shards reached EOF before the next image started.
+    pub fn drain_next(&mut self) -> Result<bool> {
+        self.rolling = true;
+        let headers: Vec<_> = self.shards.iter_mut()
+            .filter_map(|shard| shard.next_header.take())
+            .collect();
+        for header in headers {
+            self.add_shard_header(header)?;
+        }
+
+        while !self.image_eof {
+            match self.get_next_readable_shard()? {
+                Some(shard) => self.drain_shard(shard)?,
+                None if self.seq == 0 && self.pending_markers.is_empty() &&
+                        self.image_uuid.is_none() => return Ok(false),
+                None => return Err(self.incomplete_image_error()),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Processes the markers of an image that comes in a single byte slice. The markers must be
+    /// in sequence. This exists so that fuzzers can exercise the marker processing without
+    /// setting up shard pipes.
+    pub fn drain_slice(&mut self, mut data: &[u8]) -> Result<()> {
+        while let Some((marker, _)) = pb_read_next::<_, image::Marker>(&mut data)? {
+            ensure!(!self.image_eof, "Unexpected data after image EOF");
+            ensure!(marker.seq == self.seq, "Unexpected marker sequence number");
+            self.process_marker(marker, &mut data)?;
+            self.seq += 1;
+        }
+        ensure!(self.image_eof, "Image EOF marker is missing");
         Ok(())
     }
 }
 
-/// `serve_img()` serves the in-memory image store to CRIU.
-fn serve_img(
+/// Deserializes an image contained in a byte slice into `img_store`, as if it was received via
+/// a single shard.
+pub fn deserialize_img_from_slice<ImgStore: ImageStore>(
+    img_store: &mut ImgStore,
+    data: &[u8],
+) -> Result<()>
+{
+    ImageDeserializer::new(img_store, &mut []).drain_slice(data)
+}
+
+/// A CRIU socket, with the files it serves. `prefix` was stripped from their names.
+struct CriuListenerStore {
+    listener: CriuListener,
+    prefix: String,
+    mem_store: image_store::mem::Store,
+}
+
+/// Binds the CRIU sockets, and splits the in-memory image store between them. There is one
+/// socket per container, or a single one when containers are not used. Each container gets the
+/// files prefixed with its name, with the prefix stripped.
+fn bind_criu_listeners(
     images_dir: &Path,
+    mut mem_store: image_store::mem::Store,
+    containers: &[String],
+    job_id: Option<&str>,
+    criu_timeout: Option<Duration>,
+    max_marker_size: usize,
+) -> Result<Vec<CriuListenerStore>>
+{
+    criu_socket_dirs(images_dir, containers).into_iter()
+        .map(|(socket_dir, prefix)| {
+            create_dir_all(&socket_dir)?;
+            let listener = CriuListener::bind_for_restore(&socket_dir, job_id)?
+                .criu_timeout(criu_timeout)
+                .max_request_size(max_marker_size);
+            let mem_store = mem_store.split_off_prefix(&prefix);
+            Ok(CriuListenerStore { listener, prefix, mem_store })
+        })
+        .collect()
+}
+
+/// `serve_img()` serves the in-memory image stores to CRIU. Returns the file requests of CRIU,
+/// in the order in which they were made.
+fn serve_img(
     progress_pipe: &mut fs::File,
-    mem_store: &mut image_store::mem::Store,
-) -> Result<()>
+    listeners: Vec<CriuListenerStore>,
+    audit_log: Option<AuditLog>,
+    criu_trace: Option<CriuTrace>,
+) -> Result<Vec<CriuRequestStat>>
 {
-    let listener = CriuListener::bind_for_restore(images_dir)?;
     emit_progress(progress_pipe, "socket-init");
-    let mut criu = listener.into_accept()?;
+    let start_time = Instant::now();
+
+    // Containers are restored concurrently, so each CRIU connection is served from its own thread.
+    // Without containers, we serve CRIU from the current thread.
+    if listeners.len() == 1 {
+        let listener = listeners.into_iter().next().unwrap();
+        return serve_criu(listener, audit_log, criu_trace, start_time);
+    }
+
+    let criu_threads = listeners.into_iter().map(|listener| {
+        let audit_log = audit_log.as_ref().map(AuditLog::try_clone).transpose()?;
+        let criu_trace = criu_trace.as_ref().map(CriuTrace::try_clone).transpose()?;
+        Ok(thread::spawn(move || serve_criu(listener, audit_log, criu_trace, start_time)))
+    }).collect::<Result<Vec<_>>>()?;
+
+    let mut requests = Vec::new();
+    for t in criu_threads {
+        requests.extend(t.join().map_err(|_| anyhow!("CRIU serving thread panicked"))??);
+    }
+    requests.sort_by_key(|r| r.requested_at_millis);
+    Ok(requests)
+}
+
+fn serve_criu(
+    listener: CriuListenerStore,
+    audit_log: Option<AuditLog>,
+    criu_trace: Option<CriuTrace>,
+    start_time: Instant,
+) -> Result<Vec<CriuRequestStat>>
+{
+    let CriuListenerStore { listener, prefix, mem_store } = listener;
+    serve_criu_connection(listener.into_accept()?, mem_store, &prefix, audit_log, criu_trace, start_time)
+}
+
+/// Serves the files of `mem_store` to CRIU. Returns the file requests of CRIU. Their filenames
+/// get `prefix` back, and their times are relative to `start_time`.
+fn serve_criu_connection(
+    mut criu: CriuConnection,
+    mut mem_store: image_store::mem::Store,
+    prefix: &str,
+    mut audit_log: Option<AuditLog>,
+    criu_trace: Option<CriuTrace>,
+    start_time: Instant,
+) -> Result<Vec<CriuRequestStat>>
+{
+    if let Some(criu_trace) = criu_trace {
+        criu.set_trace(criu_trace, TraceOperation::Serve)?;
+    }
 
     let mut filenames_of_sent_files = HashSet::new();
+    let mut requests = Vec::new();
 
     // XXX Currently, CRIU reads image files sequentially. If it were to read files in an
     // interleaved fashion, we would have to use the Poller to avoid deadlocks.
     while let Some(filename) = criu.read_next_file_request()? {
-        match mem_store.remove(&filename) {
+        let request_time = Instant::now();
+        let memory_file = mem_store.remove(&filename);
+        let exists = memory_file.is_some();
+        let size = memory_file.as_ref().map_or(0, |f| f.size());
+        match memory_file {
             Some(memory_file) => {
                 filenames_of_sent_files.insert(filename.clone());
+                if let Some(audit_log) = audit_log.as_mut() {
+                    // We audit the content that CRIU is getting, which may have been patched.
+                    let mut hasher = FileHasher::default();
+                    std::io::copy(&mut memory_file.reader(), &mut hasher)?;
+                    audit_log.record(Direction::Out, &filename, &hasher.finalize())?;
+                }
                 criu.send_file_reply(true)?; // true means that the file exists.
                 let mut pipe = criu.recv_pipe()?;
                 // Try setting the pipe capacity. Failing is okay.
                 let _ = pipe.set_capacity(CRIU_PIPE_DESIRED_CAPACITY);
-                memory_file.drain(&mut pipe)
+                // The pipe is non-blocking so that the watchdog can check on CRIU while we wait
+                // for it to consume the file.
+                pipe.set_nonblocking()?;
+                memory_file.drain(&mut pipe, |pipe| criu.wait_writable(pipe))
                     .with_context(|| format!("while serving file {}", &filename))?;
             }
             None => {
@@ -327,24 +714,88 @@ fn serve_img(
                 criu.send_file_reply(false)?; // false means that the file does not exist.
             }
         }
+        requests.push(CriuRequestStat {
+            filename: format!("{}{}", prefix, filename),
+            exists,
+            size,
+            requested_at_millis: request_time.duration_since(start_time).as_millis(),
+            wait_millis: request_time.elapsed().as_millis(),
+        });
     }
 
-    Ok(())
+    Ok(requests)
+}
+
+/// Returns the UUID of the image, when the shards carry one, and the high-water mark of the
+/// buffered chunk bytes.
+fn drain_image<Store: ImageStore>(
+    img_store: &mut Store,
+    shards: &mut [Shard],
+    marker_log: Option<&mut MarkerLog>,
+    max_marker_size: usize,
+) -> Result<(Option<String>, u64)>
+{
+    let mut img_deserializer = ImageDeserializer::new(img_store, shards);
+    if let Some(marker_log) = marker_log {
+        img_deserializer.log_markers(marker_log);
+    }
+    img_deserializer.limit_marker_size(max_marker_size);
+    img_deserializer.drain_all()?;
+    Ok((img_deserializer.image_uuid().map(String::from), img_deserializer.max_buffered_bytes()))
 }
 
+/// How `drain_shards_into_img_store()` handles the received files.
+struct DrainOptions {
+    /// Computes the digests of all the files, e.g., to verify the image.
+    digests: bool,
+    /// Reports the digests of all the files in the stats. Implies `digests`.
+    file_digests: bool,
+    /// Computes the digests of the external files for the stats.
+    ext_file_digests: bool,
+    /// Fails when the external files are not in order in the image.
+    ordered_ext_files: bool,
+    /// Logs every marker read from the shards.
+    marker_log: Option<MarkerLog>,
+    /// Rejects the markers larger than this.
+    max_marker_size: usize,
+    /// Fails when the shard pipes can't be given this capacity.
+    min_pipe_capacity: Option<i32>,
+}
+
+impl Default for DrainOptions {
+    fn default() -> Self {
+        Self {
+            digests: false,
+            file_digests: false,
+            ext_file_digests: false,
+            ordered_ext_files: false,
+            marker_log: None,
+            max_marker_size: DEFAULT_MAX_PB_SIZE,
+            min_pipe_capacity: None,
+        }
+    }
+}
+
+/// Returns the digests of all the received files when digests are computed.
 fn drain_shards_into_img_store<Store: ImageStore>(
     img_store: &mut Store,
     progress_pipe: &mut fs::File,
     shard_pipes: Vec<UnixPipe>,
     ext_file_pipes: Vec<(String, UnixPipe)>,
-) -> Result<()>
+    mut opts: DrainOptions,
+    mut on_event: Option<&mut EventCallback>,
+) -> Result<HashMap<Box<str>, FileDigest>>
 {
-    let mut shards: Vec<Shard> = shard_pipes.into_iter().map(Shard::new).collect();
+    let with_digests = opts.digests || opts.file_digests;
+    let mut shards = Shard::new_all(shard_pipes, opts.min_pipe_capacity)?;
 
     // The content of the `ext_file_pipes` are streamed out directly, and not buffered in memory.
     // This is important to avoid blowing up our memory budget. These external files typically
     // contain a checkpointed filesystem, which is large.
     let mut overlayed_img_store = image_store::fs_overlay::Store::new(img_store);
+    if opts.ordered_ext_files {
+        overlayed_img_store.enable_ordering();
+    }
     for (filename, mut pipe) in ext_file_pipes {
         // Despite the misleading name, the pipe is not for CRIU, it's most likely for `tar`, but
         // it gets to enjoy the same pipe capacity. If we fail to increase the pipe capacity,
@@ -352,51 +803,1112 @@ fn drain_shards_into_img_store<Store: ImageStore>(
         let _ = pipe.set_capacity(CRIU_PIPE_DESIRED_CAPACITY);
         overlayed_img_store.add_overlay(filename, pipe);
     }
+    // When all digests are computed, the ones of the external files are taken from there.
+    if opts.ext_file_digests && !with_digests {
+        overlayed_img_store.enable_digests();
+    }
 
-    let mut img_deserializer = ImageDeserializer::new(&mut overlayed_img_store, &mut shards);
-    img_deserializer.drain_all()?;
+    let mut events_img_store = image_store::events::Store::new(&mut overlayed_img_store,
+                                                                on_event.as_deref_mut());
+
+    let (digests, (image_uuid, max_buffered_bytes)) = if with_digests {
+        let mut digest_img_store = image_store::digest::Store::new(&mut events_img_store);
+        let drained = drain_image(&mut digest_img_store, &mut shards, opts.marker_log.as_mut(),
+                                  opts.max_marker_size)?;
+        (digest_img_store.into_digests(), drained)
+    } else {
+        let drained = drain_image(&mut events_img_store, &mut shards, opts.marker_log.as_mut(),
+                                  opts.max_marker_size)?;
+        (HashMap::new(), drained)
+    };
+
+    let mut ext_file_stats = overlayed_img_store.into_ext_file_stats();
+    for stat in &mut ext_file_stats {
+        if stat.sha256.is_none() {
+            stat.sha256 = digests.get(stat.filename.as_str()).map(|digest| digest.sha256.clone());
+        }
+    }
+
+    // Listed as in the manifest, which doesn't include itself, nor its signature.
+    let mut file_stats = Vec::new();
+    if opts.file_digests {
+        file_stats = digests.iter()
+            .filter(|(filename, _)| !is_reserved_filename(filename))
+            .map(|(filename, digest)| ManifestFile { filename: filename.to_string(), digest: digest.clone() })
+            .collect();
+        file_stats.sort_by(|a, b| a.filename.cmp(&b.filename));
+    }
 
+    emit_stats(progress_pipe, &shards, image_uuid, max_buffered_bytes, ext_file_stats, file_stats, on_event)?;
+    Ok(digests)
+}
+
+fn emit_stats(
+    progress_pipe: &mut fs::File,
+    shards: &[Shard],
+    image_uuid: Option<String>,
+    max_buffered_bytes: u64,
+    ext_files: Vec<ExtFileStat>,
+    files: Vec<ManifestFile>,
+    on_event: Option<&mut EventCallback>,
+) -> Result<()>
+{
     let stats = Stats {
+        schema_version: STATS_SCHEMA_VERSION,
         shards: shards.iter().map(|s| ShardStat {
             size: s.bytes_read,
             transfer_duration_millis: s.transfer_duration_millis,
+            blocked_millis: None,
+            splice_millis: None,
+            marker_bytes: None,
+            pipe_capacity: s.pipe.capacity().ok(),
         }).collect(),
+        kernel: KERNEL_CAPS.clone(),
+        pages: None,
+        image_uuid,
+        ext_files,
+        files,
+        shard_saturation: None,
+        max_buffered_bytes: Some(max_buffered_bytes),
     };
-    emit_progress(progress_pipe, &serde_json::to_string(&stats)?);
-
+    report_stats(progress_pipe, &stats)?;
+    if let Some(on_event) = on_event {
+        on_event(Event::ImageComplete { stats: &stats });
+    }
     Ok(())
 }
 
-/// Description of the arguments can be found in main.rs
-pub fn serve(images_dir: &Path,
-    mut progress_pipe: fs::File,
+/// Reports the phases of serving an image on the progress pipe, when enabled. The duration of
+/// each phase is emitted once done.
+struct PhaseTracker {
+    enabled: bool,
+    current: Option<(Phase, Instant)>,
+    phases: Vec<PhaseStat>,
+}
+
+impl PhaseTracker {
+    fn new(enabled: bool) -> Self {
+        Self { enabled, current: None, phases: Vec::new() }
+    }
+
+    fn enter(&mut self, progress_pipe: &mut fs::File, on_event: Option<&mut EventCallback>,
+             phase: Phase) -> Result<()> {
+        if let Some(on_event) = on_event {
+            on_event(Event::PhaseStart { phase });
+        }
+        if !self.enabled {
+            return Ok(());
+        }
+        if let Some((phase, start_time)) = self.current.take() {
+            let duration_millis = start_time.elapsed().as_millis();
+            self.phases.push(PhaseStat { phase, duration_millis });
+        }
+        emit_progress(progress_pipe, &serde_json::to_string(&PhaseTransition { phase })?);
+        if phase != Phase::Done {
+            self.current = Some((phase, Instant::now()));
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, progress_pipe: &mut fs::File, on_event: Option<&mut EventCallback>) -> Result<()> {
+        self.enter(progress_pipe, on_event, Phase::Done)?;
+        if self.enabled {
+            let stats = PhaseStats { phases: self.phases };
+            emit_progress(progress_pipe, &serde_json::to_string(&stats)?);
+        }
+        Ok(())
+    }
+}
+
+fn verify_img(
+    manifest: &[u8],
+    sig: &[u8],
+    verify_key: &VerifyingKey,
+    digests: &HashMap<Box<str>, FileDigest>,
+) -> Result<()>
+{
+    Manifest::from_signed(manifest, sig, verify_key)?
+        .check_digests(digests)
+        .context("Image verification failed")
+}
+
+fn read_mem_file(mem_store: &mut image_store::mem::Store, filename: &str) -> Result<Vec<u8>> {
+    let file = mem_store.remove(filename)
+        .ok_or_else(|| anyhow!("{} is missing from the image. Was the image signed?", filename))?;
+    let mut buf = Vec::new();
+    file.reader().read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// `ExtractBuilder` configures the decoding of an image from shards, to either serve it to CRIU,
+/// extract it to disk, or check it with a dry run. Only the images directory and the shards are
+/// required, other options have the same defaults as the command line.
+/// The description of the options can be found in main.rs.
+pub struct ExtractBuilder {
+    images_dir: PathBuf,
     shard_pipes: Vec<UnixPipe>,
+    progress_pipe: Option<fs::File>,
     ext_file_pipes: Vec<(String, UnixPipe)>,
     tcp_listen_remaps: Vec<(u16, u16)>,
-) -> Result<()>
-{
+    verify_key: Option<VerifyingKey>,
+    audit_log: Option<AuditLog>,
+    criu_trace: Option<CriuTrace>,
+    marker_log: Option<MarkerLog>,
+    containers: Vec<String>,
+    job_id: Option<String>,
+    phases: bool,
+    request_stats: bool,
+    standby: bool,
+    rolling: bool,
+    criu_timeout: Option<Duration>,
+    ext_file_digests: bool,
+    file_digests: bool,
+    ordered_ext_files: bool,
+    ghost_files_dir: Option<PathBuf>,
+    reflink_from: Option<PathBuf>,
+    snapshot_cmd: Option<String>,
+    ownership: Ownership,
+    max_marker_size: usize,
+    min_pipe_capacity: Option<i32>,
+    on_event: Option<EventCallback>,
+}
+
+impl ExtractBuilder {
+    pub fn new(images_dir: impl Into<PathBuf>, shard_pipes: Vec<UnixPipe>) -> Self {
+        Self {
+            images_dir: images_dir.into(),
+            shard_pipes,
+            progress_pipe: None,
+            ext_file_pipes: Vec::new(),
+            tcp_listen_remaps: Vec::new(),
+            verify_key: None,
+            audit_log: None,
+            criu_trace: None,
+            marker_log: None,
+            containers: Vec::new(),
+            job_id: None,
+            phases: false,
+            request_stats: false,
+            standby: false,
+            rolling: false,
+            criu_timeout: None,
+            ext_file_digests: false,
+            file_digests: false,
+            ordered_ext_files: false,
+            ghost_files_dir: None,
+            reflink_from: None,
+            snapshot_cmd: None,
+            ownership: Ownership::default(),
+            max_marker_size: DEFAULT_MAX_PB_SIZE,
+            min_pipe_capacity: None,
+            on_event: None,
+        }
+    }
+
+    /// Where to report progress. Progress is discarded by default.
+    pub fn progress_pipe(mut self, progress_pipe: fs::File) -> Self {
+        self.progress_pipe = Some(progress_pipe);
+        self
+    }
+
+    /// Invokes `on_event` as the files are decoded from the shards. See `events.rs`.
+    pub fn on_event(mut self, on_event: impl FnMut(Event<'_>) + Send + 'static) -> Self {
+        self.on_event = Some(Box::new(on_event));
+        self
+    }
+
+    pub fn ext_files(mut self, ext_file_pipes: Vec<(String, UnixPipe)>) -> Self {
+        self.ext_file_pipes = ext_file_pipes;
+        self
+    }
+
+    /// Reports the sha256 digest of the external files in the stats. The external files are then
+    /// copied through memory instead of being spliced.
+    pub fn ext_file_digests(mut self, ext_file_digests: bool) -> Self {
+        self.ext_file_digests = ext_file_digests;
+        self
+    }
+
+    /// Reports the sha256 digest of every file in the stats, as listed in the manifest of the
+    /// image. The files are then copied through memory instead of being spliced.
+    pub fn file_digests(mut self, file_digests: bool) -> Self {
+        self.file_digests = file_digests;
+        self
+    }
+
+    /// Fails when the external files don't come in the image in the order they are provided.
+    /// Each external file is then complete before the next one starts. The image must be
+    /// captured with `CaptureBuilder::ordered_ext_files()`.
+    pub fn ordered_ext_files(mut self, ordered_ext_files: bool) -> Self {
+        self.ordered_ext_files = ordered_ext_files;
+        self
+    }
+
+    /// Extracts the ghost files (ghost-file-*.img) into `ghost_files_dir` instead of the images
+    /// directory, e.g., to store them separately from the rest of the image.
+    pub fn ghost_files_dir(mut self, ghost_files_dir: Option<PathBuf>) -> Self {
+        self.ghost_files_dir = ghost_files_dir;
+        self
+    }
+
+    /// Once extracted, the files that are the same as in `reflink_from`, the extraction of a
+    /// previous checkpoint, are replaced by reflinks (or hard links) of them. See `reflink.rs`.
+    /// The files are then copied through memory instead of being spliced, to compute their digest.
+    pub fn reflink_from(mut self, reflink_from: Option<PathBuf>) -> Self {
+        self.reflink_from = reflink_from;
+        self
+    }
+
+    /// Once the image is extracted and verified, runs `snapshot_cmd` with `sh -c` to snapshot the
+    /// images directory, e.g., a btrfs subvolume or a ZFS dataset. See `snapshot.rs`.
+    pub fn snapshot_cmd(mut self, snapshot_cmd: Option<String>) -> Self {
+        self.snapshot_cmd = snapshot_cmd;
+        self
+    }
+
+    /// Gives the extracted files, and the directories created, the provided owner and
+    /// permissions. See `image_store::fs::Ownership`.
+    pub fn ownership(mut self, ownership: Ownership) -> Self {
+        self.ownership = ownership;
+        self
+    }
+
+    /// Fails on the markers larger than `max_marker_size` bytes, from the shards and from CRIU,
+    /// as they are most likely the sign of a corrupted stream. See `DEFAULT_MAX_PB_SIZE`.
+    pub fn max_marker_size(mut self, max_marker_size: usize) -> Self {
+        self.max_marker_size = max_marker_size;
+        self
+    }
+
+    /// Fails when the shard pipes can't be given `min_pipe_capacity` bytes, instead of streaming
+    /// slowly through the smaller pipes that the host limits allow. Disabled by default.
+    pub fn min_pipe_capacity(mut self, min_pipe_capacity: Option<i32>) -> Self {
+        self.min_pipe_capacity = min_pipe_capacity;
+        self
+    }
+
+    fn drain_opts(&mut self) -> DrainOptions {
+        DrainOptions {
+            digests: false,
+            file_digests: self.file_digests,
+            ext_file_digests: self.ext_file_digests,
+            ordered_ext_files: self.ordered_ext_files,
+            marker_log: self.marker_log.take(),
+            max_marker_size: self.max_marker_size,
+            min_pipe_capacity: self.min_pipe_capacity,
+        }
+    }
+
+    pub fn tcp_listen_remaps(mut self, tcp_listen_remaps: Vec<(u16, u16)>) -> Self {
+        self.tcp_listen_remaps = tcp_listen_remaps;
+        self
+    }
+
+    pub fn verify_key(mut self, verify_key: Option<VerifyingKey>) -> Self {
+        self.verify_key = verify_key;
+        self
+    }
+
+    pub fn audit_log(mut self, audit_log: Option<AuditLog>) -> Self {
+        self.audit_log = audit_log;
+        self
+    }
+
+    pub fn criu_trace(mut self, criu_trace: Option<CriuTrace>) -> Self {
+        self.criu_trace = criu_trace;
+        self
+    }
+
+    /// Logs every marker read from the shards. See `MarkerLog`.
+    pub fn marker_log(mut self, marker_log: Option<MarkerLog>) -> Self {
+        self.marker_log = marker_log;
+        self
+    }
+
+    pub fn containers(mut self, containers: Vec<String>) -> Self {
+        self.containers = containers;
+        self
+    }
+
+    pub fn job_id(mut self, job_id: Option<String>) -> Self {
+        self.job_id = job_id;
+        self
+    }
+
+    /// Reports the phase transitions on the progress pipe, and the duration of each phase once
+    /// CRIU is done.
+    pub fn phases(mut self, phases: bool) -> Self {
+        self.phases = phases;
+        self
+    }
+
+    /// Reports the file requests of CRIU on the progress pipe once CRIU is done, with their
+    /// sizes and how long CRIU waited on them. See `ServeStats`.
+    pub fn request_stats(mut self, request_stats: bool) -> Self {
+        self.request_stats = request_stats;
+        self
+    }
+
+    /// Waits in warm standby once the image is received, with the CRIU sockets bound and the
+    /// image locked in memory, until a controller sends `go` on the control socket.
+    pub fn standby(mut self, standby: bool) -> Self {
+        self.standby = standby;
+        self
+    }
+
+    /// Receives successive images from the shards, and serves the last complete one when CRIU
+    /// connects. See `serve_rolling()`.
+    pub fn rolling(mut self, rolling: bool) -> Self {
+        self.rolling = rolling;
+        self
+    }
+
+    /// Fails when CRIU makes no progress for `criu_timeout` while being served. A CRIU that dies
+    /// is detected regardless. See `CriuWatchdog`.
+    pub fn criu_timeout(mut self, criu_timeout: Option<Duration>) -> Self {
+        self.criu_timeout = criu_timeout;
+        self
+    }
+
+    /// Serves the image to CRIU. Returns once CRIU is done.
+    pub fn serve(self) -> Result<()> {
+        self.ensure_no_extract_to_disk_options()?;
+        if self.rolling {
+            ensure!(self.ext_file_pipes.is_empty() && self.containers.is_empty() &&
+                    !self.phases && !self.standby,
+                    "External files, containers, phases, and standby are not supported with \
+                     rolling images");
+            return serve_rolling(self);
+        }
+        serve(self)
+    }
+
+    /// Extracts the image to the images directory.
+    pub fn extract(self) -> Result<()> {
+        self.ensure_no_serve_options()?;
+        extract(self)
+    }
+
+    /// Extracts the image into a store provided by the library user. The image can't be verified,
+    /// as the files can't be read back from the store.
+    pub fn extract_into(self, mut img_store: Box<dyn DynImageStore>) -> Result<()> {
+        self.ensure_no_serve_options()?;
+        self.ensure_no_extract_to_disk_options()?;
+        ensure!(self.verify_key.is_none(), "Verifying the image is not supported with a custom image store");
+        extract_into(self, &mut img_store)
+    }
+
+    /// Extracts the image into `out`, one file after the other, each framed as
+    /// `filename\0size\0data`. See `image_store::stream::Store`. The image can't be verified, as
+    /// the files are gone by the time the manifest is received.
+    pub fn extract_to_stream(self, out: impl Write) -> Result<()> {
+        self.ensure_no_serve_options()?;
+        self.ensure_no_extract_to_disk_options()?;
+        ensure!(self.verify_key.is_none(), "Verifying the image is not supported when streaming it out");
+        let mut stream_store = image_store::stream::Store::new(out);
+        extract_into(self, &mut stream_store)?;
+        stream_store.finish()
+    }
+
+    /// Checks the image without writing it anywhere.
+    pub fn extract_dry_run(self) -> Result<()> {
+        self.ensure_no_serve_options()?;
+        self.ensure_no_extract_to_disk_options()?;
+        ensure!(self.ext_file_pipes.is_empty() && self.audit_log.is_none(),
+                "External files and the audit log are not supported with a dry run");
+        extract_dry_run(self)
+    }
+
+    fn ensure_no_serve_options(&self) -> Result<()> {
+        ensure!(self.tcp_listen_remaps.is_empty() && self.criu_trace.is_none() &&
+                self.containers.is_empty() && self.job_id.is_none() && !self.phases &&
+                !self.request_stats && !self.standby && !self.rolling && self.criu_timeout.is_none(),
+                "TCP listen remaps, CRIU traces, containers, job ids, phases, request stats, \
+                 standby, rolling images, and CRIU timeouts are only supported when serving the \
+                 image");
+        Ok(())
+    }
+
+    fn ensure_no_extract_to_disk_options(&self) -> Result<()> {
+        ensure!(self.ghost_files_dir.is_none(),
+                "Diverting ghost files is only supported when extracting the image to disk");
+        Ok(())
+    }
+
+    fn progress_pipe_or_null(&mut self) -> Result<fs::File> {
+        match self.progress_pipe.take() {
+            Some(progress_pipe) => Ok(progress_pipe),
+            None => null_progress_pipe(),
+        }
+    }
+}
+
+fn serve(mut opts: ExtractBuilder) -> Result<()> {
+    let mut progress_pipe = opts.progress_pipe_or_null()?;
+    let drain_opts = opts.drain_opts();
+    let ExtractBuilder {
+        images_dir, shard_pipes, ext_file_pipes, tcp_listen_remaps, verify_key, mut audit_log,
+        criu_trace, containers, job_id, phases, request_stats, standby, criu_timeout, max_marker_size,
+        mut on_event, ..
+    } = opts;
+    let images_dir = images_dir.as_path();
+    let mut phases = PhaseTracker::new(phases);
+
     create_dir_all(images_dir)?;
 
+    let ext_filenames: Vec<String> = ext_file_pipes.iter().map(|(f, _)| f.clone()).collect();
+    let with_digests = verify_key.is_some() || audit_log.is_some();
+
+    phases.enter(&mut progress_pipe, on_event.as_mut(), Phase::Buffering)?;
     let mut mem_store = image_store::mem::Store::default();
-    drain_shards_into_img_store(&mut mem_store, &mut progress_pipe, shard_pipes, ext_file_pipes)?;
+    let digests = drain_shards_into_img_store(&mut mem_store, &mut progress_pipe,
+                                              shard_pipes, ext_file_pipes,
+                                              DrainOptions { digests: with_digests, ..drain_opts },
+                                              on_event.as_mut())?;
+    if let Some(verify_key) = verify_key {
+        // The image must be verified before CRIU gets to see any of it.
+        phases.enter(&mut progress_pipe, on_event.as_mut(), Phase::Verifying)?;
+        let manifest = read_mem_file(&mut mem_store, MANIFEST_FILENAME)?;
+        let sig = read_mem_file(&mut mem_store, MANIFEST_SIG_FILENAME)?;
+        verify_img(&manifest, &sig, &verify_key, &digests)?;
+    }
+    if let Some(audit_log) = audit_log.as_mut() {
+        // External files are not served to CRIU. They have been streamed out already.
+        for filename in &ext_filenames {
+            if let Some(digest) = digests.get(filename.as_str()) {
+                audit_log.record(Direction::Out, filename, digest)?;
+            }
+        }
+    }
+    phases.enter(&mut progress_pipe, on_event.as_mut(), Phase::Patching)?;
     patch_img(&mut mem_store, tcp_listen_remaps)?;
-    serve_img(images_dir, &mut progress_pipe, &mut mem_store)?;
+    let listeners = bind_criu_listeners(images_dir, mem_store, &containers, job_id.as_deref(),
+                                        criu_timeout, max_marker_size)?;
+
+    if standby {
+        phases.enter(&mut progress_pipe, on_event.as_mut(), Phase::Standby)?;
+        let control_listener = bind_control_socket(images_dir, job_id.as_deref())?;
+        // Keeping the image resident avoids page faults on activation. Locking memory requires
+        // privileges, failing is okay.
+        let _ = mlockall(MlockAllFlags::MCL_CURRENT);
+        emit_progress(&mut progress_pipe, "standby");
+        wait_for_go(control_listener)?;
+    }
+
+    phases.enter(&mut progress_pipe, on_event.as_mut(), Phase::Serving)?;
+    let result = serve_img(&mut progress_pipe, listeners, audit_log, criu_trace);
+    if standby {
+        let _ = munlockall();
+    }
+    let requests = result?;
+    if request_stats {
+        emit_progress(&mut progress_pipe, &serde_json::to_string(&ServeStats { requests })?);
+    }
+    phases.finish(&mut progress_pipe, on_event.as_mut())?;
 
     Ok(())
 }
 
-/// Description of the arguments can be found in main.rs
-pub fn extract(images_dir: &Path,
+/// State shared between the thread receiving the images of a rolling serve, and the thread
+/// serving CRIU.
+struct RollingState {
+    /// The last complete image, ready to be served
+    image: Option<image_store::mem::Store>,
+    /// Cleared when the receiving thread is done
+    receiving: bool,
+    /// Set once CRIU has connected. The image being received is then discarded.
+    taken: bool,
+}
+
+type SharedRollingState = Arc<(Mutex<RollingState>, Condvar)>;
+
+/// Receives the successive images carried by the shards. Each image replaces the previous one
+/// once it is fully received, verified, and patched. Of the `drain_opts`, only the marker log and
+/// the marker size limit, and the minimum pipe capacity apply.
+fn receive_rolling_images(
+    rolling_state: &SharedRollingState,
     mut progress_pipe: fs::File,
     shard_pipes: Vec<UnixPipe>,
-    ext_file_pipes: Vec<(String, UnixPipe)>,
+    tcp_listen_remaps: Vec<(u16, u16)>,
+    verify_key: Option<VerifyingKey>,
+    drain_opts: DrainOptions,
+    mut on_event: Option<EventCallback>,
 ) -> Result<()>
 {
+    let DrainOptions { mut marker_log, max_marker_size, min_pipe_capacity, .. } = drain_opts;
+    let mut shards = Shard::new_all(shard_pipes, min_pipe_capacity)?;
+
+    loop {
+        for shard in &mut shards {
+            shard.bytes_read = 0;
+        }
+        let start_time = Instant::now();
+
+        let mut mem_store = image_store::mem::Store::default();
+        let mut events_img_store = image_store::events::Store::new(&mut mem_store,
+                                                                    on_event.as_mut());
+        let (image_uuid, max_buffered_bytes, digests) = if verify_key.is_some() {
+            let mut digest_img_store = image_store::digest::Store::new(&mut events_img_store);
+            let mut img_deserializer = ImageDeserializer::new(&mut digest_img_store, &mut shards);
+            if let Some(marker_log) = marker_log.as_mut() {
+                img_deserializer.log_markers(marker_log);
+            }
+            img_deserializer.limit_marker_size(max_marker_size);
+            if !img_deserializer.drain_next()? {
+                return Ok(());
+            }
+            let image_uuid = img_deserializer.image_uuid().map(String::from);
+            let max_buffered_bytes = img_deserializer.max_buffered_bytes();
+            (image_uuid, max_buffered_bytes, digest_img_store.into_digests())
+        } else {
+            let mut img_deserializer = ImageDeserializer::new(&mut events_img_store, &mut shards);
+            if let Some(marker_log) = marker_log.as_mut() {
+                img_deserializer.log_markers(marker_log);
+            }
+            img_deserializer.limit_marker_size(max_marker_size);
+            if !img_deserializer.drain_next()? {
+                return Ok(());
+            }
+            (img_deserializer.image_uuid().map(String::from), img_deserializer.max_buffered_bytes(),
+             HashMap::new())
+        };
+
+        // The shards don't reach EOF between images, the transfer duration is the one of the image.
+        let transfer_duration_millis = start_time.elapsed().as_millis();
+        for shard in &mut shards {
+            shard.transfer_duration_millis = transfer_duration_millis;
+        }
+        emit_stats(&mut progress_pipe, &shards, image_uuid, max_buffered_bytes, Vec::new(), Vec::new(),
+                   on_event.as_mut())?;
+
+        if let Some(verify_key) = verify_key.as_ref() {
+            let manifest = read_mem_file(&mut mem_store, MANIFEST_FILENAME)?;
+            let sig = read_mem_file(&mut mem_store, MANIFEST_SIG_FILENAME)?;
+            verify_img(&manifest, &sig, verify_key, &digests)?;
+        }
+        patch_img(&mut mem_store, tcp_listen_remaps.clone())?;
+
+        let (state, cvar) = &**rolling_state;
+        let mut state = state.lock().unwrap();
+        if state.taken {
+            return Ok(());
+        }
+        let previous_image = state.image.replace(mem_store);
+        drop(state);
+        cvar.notify_all();
+        emit_progress(&mut progress_pipe, "image-ready");
+        // The previous image is released outside of the lock, as it can take a while.
+        drop(previous_image);
+    }
+}
+
+/// Serves CRIU while receiving successive images from the shards, e.g., the periodic checkpoints
+/// of an application. The shards carry the images back to back. The last complete image is kept
+/// while the next one is received, and replaces it once complete. The CRIU socket is bound once
+/// the first image is complete, and CRIU gets the last complete image when it connects. If an
+/// image fails to be received, receiving stops, and the last complete image remains served.
+fn serve_rolling(mut opts: ExtractBuilder) -> Result<()> {
+    let mut progress_pipe = opts.progress_pipe_or_null()?;
+    let drain_opts = opts.drain_opts();
+    let ExtractBuilder {
+        images_dir, shard_pipes, tcp_listen_remaps, verify_key, audit_log, criu_trace, job_id,
+        request_stats, criu_timeout, max_marker_size, on_event, ..
+    } = opts;
+    let images_dir = images_dir.as_path();
+
     create_dir_all(images_dir)?;
 
+    let rolling_state: SharedRollingState = Arc::new((Mutex::new(RollingState {
+        image: None,
+        receiving: true,
+        taken: false,
+    }), Condvar::new()));
+
+    let receiving_thread = {
+        let rolling_state = Arc::clone(&rolling_state);
+        let progress_pipe = progress_pipe.try_clone()?;
+        thread::spawn(move || {
+            let result = receive_rolling_images(&rolling_state, progress_pipe, shard_pipes,
+                                                tcp_listen_remaps, verify_key, drain_opts, on_event);
+            let (state, cvar) = &*rolling_state;
+            state.lock().unwrap().receiving = false;
+            cvar.notify_all();
+            result
+        })
+    };
+
+    {
+        let (state, cvar) = &*rolling_state;
+        let state = cvar.wait_while(state.lock().unwrap(),
+                                    |state| state.image.is_none() && state.receiving).unwrap();
+        if state.image.is_none() {
+            drop(state);
+            receiving_thread.join().map_err(|_| anyhow!("Image receiving thread panicked"))??;
+            bail!("The shards reached EOF before an image was complete");
+        }
+    }
+
+    let listener = CriuListener::bind_for_restore(images_dir, job_id.as_deref())?
+        .criu_timeout(criu_timeout)
+        .max_request_size(max_marker_size);
+    emit_progress(&mut progress_pipe, "socket-init");
+    let start_time = Instant::now();
+    let criu = listener.into_accept()?;
+
+    let mem_store = {
+        let mut state = rolling_state.0.lock().unwrap();
+        state.taken = true;
+        state.image.take().expect("The image should be present")
+    };
+    let requests = serve_criu_connection(criu, mem_store, "", audit_log, criu_trace, start_time)?;
+    if request_stats {
+        emit_progress(&mut progress_pipe, &serde_json::to_string(&ServeStats { requests })?);
+    }
+    Ok(())
+}
+
+fn extract(mut opts: ExtractBuilder) -> Result<()> {
+    let mut progress_pipe = opts.progress_pipe_or_null()?;
+    let drain_opts = opts.drain_opts();
+    let ExtractBuilder {
+        images_dir, shard_pipes, ext_file_pipes, verify_key, mut audit_log, ghost_files_dir,
+        reflink_from, snapshot_cmd, ownership, mut on_event, ..
+    } = opts;
+    let images_dir = images_dir.as_path();
+
+    ownership.create_dir_all(images_dir)?;
+
+    let with_digests = verify_key.is_some() || audit_log.is_some() || reflink_from.is_some();
+
     // extract on disk
     let mut file_store = image_store::fs::Store::new(images_dir);
-    drain_shards_into_img_store(&mut file_store, &mut progress_pipe, shard_pipes, ext_file_pipes)?;
+    file_store.set_ownership(ownership);
+    if let Some(ghost_files_dir) = ghost_files_dir {
+        ownership.create_dir_all(&ghost_files_dir)?;
+        file_store.divert_ghost_files(ghost_files_dir);
+    }
+    let digests = drain_shards_into_img_store(&mut file_store, &mut progress_pipe,
+                                              shard_pipes, ext_file_pipes,
+                                              DrainOptions { digests: with_digests, ..drain_opts },
+                                              on_event.as_mut())
+        .and_then(|digests| {
+            if let Some(verify_key) = verify_key.as_ref() {
+                let read_file = |filename| {
+                    let path = images_dir.join(filename);
+                    fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))
+                };
+                let manifest = read_file(MANIFEST_FILENAME)?;
+                let sig = read_file(MANIFEST_SIG_FILENAME)?;
+                verify_img(&manifest, &sig, verify_key, &digests)?;
+            }
+            Ok(digests)
+        });
+    // The files of an image that can't be verified must not be left for CRIU to restore.
+    let digests = match digests {
+        Err(e) if verify_key.is_some() => return Err(match file_store.remove_created_files() {
+            Ok(()) => e,
+            Err(remove_err) => e.context(format!("The unverified files are left in images_dir: {:#}", remove_err)),
+        }),
+        digests => digests?,
+    };
+    if let Some(audit_log) = audit_log.as_mut() {
+        for (filename, digest) in &digests {
+            audit_log.record(Direction::Out, filename, digest)?;
+        }
+    }
+    if let Some(reflink_from) = reflink_from {
+        link_unchanged_files(images_dir, &reflink_from, &digests, &ownership)?;
+    }
+    if let Some(snapshot_cmd) = snapshot_cmd {
+        run_snapshot_cmd(&snapshot_cmd, images_dir)?;
+    }
+
+    Ok(())
+}
+
+fn extract_into(mut opts: ExtractBuilder, img_store: &mut impl ImageStore) -> Result<()> {
+    let mut progress_pipe = opts.progress_pipe_or_null()?;
+    let drain_opts = opts.drain_opts();
+    let ExtractBuilder { shard_pipes, ext_file_pipes, mut audit_log, mut on_event, .. } = opts;
+
+    let with_digests = audit_log.is_some();
+    let digests = drain_shards_into_img_store(img_store, &mut progress_pipe,
+                                              shard_pipes, ext_file_pipes,
+                                              DrainOptions { digests: with_digests, ..drain_opts },
+                                              on_event.as_mut())?;
+    if let Some(audit_log) = audit_log.as_mut() {
+        for (filename, digest) in &digests {
+            audit_log.record(Direction::Out, filename, digest)?;
+        }
+    }
 
     Ok(())
 }
+
+/// How the content of the files was checked by `extract_dry_run()`
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Verification {
+    /// Against the manifest, whose signature was verified
+    Signature,
+    /// Against the manifest, whose signature was not verified as no key was provided. This
+    /// detects corruption, but not tampering.
+    Manifest,
+    /// The image has no manifest, only the image structure was checked
+    None,
+}
+
+#[derive(Serialize)]
+struct DryRunReport<'a> {
+    files: Vec<DryRunFile<'a>>,
+    verification: Verification,
+}
+
+#[derive(Serialize)]
+struct DryRunFile<'a> {
+    filename: &'a str,
+    size: u64,
+    sha256: &'a str,
+}
+
+fn extract_dry_run(mut opts: ExtractBuilder) -> Result<()> {
+    let mut progress_pipe = opts.progress_pipe_or_null()?;
+    let ExtractBuilder {
+        shard_pipes, verify_key, marker_log, max_marker_size, min_pipe_capacity, mut on_event, ..
+    } = opts;
+
+    // The deserializer checks the markers and the file sizes as the shards are drained. We
+    // discard the file content, except for the manifest.
+    let mut null_store = image_store::null::Store::default();
+    null_store.retain(MANIFEST_FILENAME);
+    null_store.retain(MANIFEST_SIG_FILENAME);
+    let digests = drain_shards_into_img_store(&mut null_store, &mut progress_pipe,
+                                              shard_pipes, vec![],
+                                              DrainOptions { digests: true, marker_log, max_marker_size, min_pipe_capacity,
+                                                             ..Default::default() },
+                                              on_event.as_mut())?;
+
+    let verification = match (verify_key, null_store.remove_retained(MANIFEST_FILENAME)) {
+        (Some(verify_key), manifest) => {
+            let read_retained = |filename, data: Option<Vec<u8>>| data.ok_or_else(||
+                anyhow!("{} is missing from the image. Was the image signed?", filename));
+            let manifest = read_retained(MANIFEST_FILENAME, manifest)?;
+            let sig = read_retained(MANIFEST_SIG_FILENAME,
+                                    null_store.remove_retained(MANIFEST_SIG_FILENAME))?;
+            verify_img(&manifest, &sig, &verify_key, &digests)?;
+            Verification::Signature
+        }
+        (None, Some(manifest)) => {
+            let manifest: Manifest = serde_json::from_slice(&manifest)
+                .context("The image manifest is malformed")?;
+            manifest.check_digests(&digests).context("Image verification failed")?;
+            Verification::Manifest
+        }
+        (None, None) => Verification::None,
+    };
+
+    let files = null_store.files().iter().map(|(filename, size)| DryRunFile {
+        filename,
+        size: *size,
+        sha256: &digests[filename].sha256,
+    }).collect();
+    let report = DryRunReport { files, verification };
+    emit_progress(&mut progress_pipe, &serde_json::to_string(&report)?);
+
+    Ok(())
+}
+
+/// Decodes the image from the shards, and returns the content of a single file. The other files
+/// are discarded.
+pub fn extract_img_file(
+    mut progress_pipe: fs::File,
+    shard_pipes: Vec<UnixPipe>,
+    filename: &str,
+) -> Result<Vec<u8>>
+{
+    let mut null_store = image_store::null::Store::default();
+    null_store.retain(filename);
+    drain_shards_into_img_store(&mut null_store, &mut progress_pipe, shard_pipes, vec![],
+                                DrainOptions::default(), None)?;
+    null_store.remove_retained(filename)
+        .ok_or_else(|| anyhow!("{} is missing from the image", filename))
+}
+
+/// Writes a new image into `output_shard_pipes`, with the files that `write_files` puts in the
+/// image store. Manifests of the source images no longer match the new image, so they are
+/// skipped, and a new one is signed with `sign_key` when provided.
+fn write_new_image(
+    progress_pipe: &mut fs::File,
+    output_shard_pipes: Vec<UnixPipe>,
+    sign_key: Option<SigningKey>,
+    write_files: impl FnOnce(&mut image_store::serializer::Store) -> Result<()>,
+) -> Result<()>
+{
+    let start_time = Instant::now();
+
+    let (mut output_shards, shard_pipe_capacity, image_uuid) = capture::init_shards(output_shard_pipes, 0, None)?;
+    let img_serializer = Rc::new(RefCell::new(ImageSerializer::new(&mut output_shards, shard_pipe_capacity)));
+
+    let mut img_store = image_store::serializer::Store::new(Rc::clone(&img_serializer), sign_key.is_some());
+    img_store.skip(MANIFEST_FILENAME);
+    img_store.skip(MANIFEST_SIG_FILENAME);
+    write_files(&mut img_store)?;
+
+    let manifest = img_store.finish()?;
+    let mut img_serializer = Rc::try_unwrap(img_serializer).ok()
+        .expect("The image serializer is still in use").into_inner();
+    if let (Some(manifest), Some(sign_key)) = (manifest, sign_key) {
+        let (manifest, sig) = manifest.sign(&sign_key)?;
+        img_serializer.write_file_from_buf(MANIFEST_FILENAME, &manifest)?;
+        img_serializer.write_file_from_buf(MANIFEST_SIG_FILENAME, &sig)?;
+    }
+    img_serializer.write_image_eof()?;
+    let max_buffered_bytes = img_serializer.max_buffered_bytes();
+    drop(img_serializer);
+
+    let transfer_duration_millis = start_time.elapsed().as_millis();
+    let stats = Stats {
+        schema_version: STATS_SCHEMA_VERSION,
+        image_uuid: Some(image_uuid),
+        shards: output_shards.iter().map(|s| s.stat(transfer_duration_millis)).collect(),
+        kernel: KERNEL_CAPS.clone(),
+        pages: None,
+        ext_files: Vec::new(),
+        files: Vec::new(),
+        shard_saturation: None,
+        max_buffered_bytes: Some(max_buffered_bytes),
+    };
+    report_stats(progress_pipe, &stats)?;
+
+    Ok(())
+}
+
+/// Rewrites the image from `shard_pipes` into `output_shard_pipes`, without the `removed` files,
+/// and with the `replaced` files taking their content from the provided local files. The other
+/// files are copied as they are received.
+pub fn filter(
+    mut progress_pipe: fs::File,
+    shard_pipes: Vec<UnixPipe>,
+    output_shard_pipes: Vec<UnixPipe>,
+    removed: Vec<String>,
+    replaced: Vec<(String, PathBuf)>,
+    sign_key: Option<SigningKey>,
+) -> Result<()>
+{
+    // We open the replacement files first to fail early.
+    let replaced = replaced.into_iter().map(|(filename, path)| {
+        let file = fs::File::open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let size = file.metadata().map(|metadata| metadata.len()).ok();
+        Ok((filename, file, size))
+    }).collect::<Result<Vec<_>>>()?;
+
+    write_new_image(&mut progress_pipe, output_shard_pipes, sign_key, |img_store| {
+        let filenames = removed.iter().chain(replaced.iter().map(|(filename, _, _)| filename));
+        for filename in filenames.clone() {
+            img_store.skip(filename);
+        }
+
+        let mut shards: Vec<Shard> = shard_pipes.into_iter().map(Shard::new).collect();
+        drain_image(img_store, &mut shards, None, DEFAULT_MAX_PB_SIZE)?;
+
+        for filename in filenames {
+            ensure!(img_store.skipped_files().contains(filename.as_str()),
+                    "{} is not in the image", filename);
+        }
+        for (filename, file, size) in replaced {
+            img_store.add_file(&filename, file, size)
+                .with_context(|| format!("Failed to write the replacement of {}", filename))?;
+        }
+        Ok(())
+    })
+}
+
+/// Combines independently captured images, each coming from its own set of shards, into a
+/// single image written into `output_shard_pipes`. The images are read one after the other, and
+/// must not have files in common.
+pub fn merge(
+    mut progress_pipe: fs::File,
+    shard_pipe_sets: Vec<Vec<UnixPipe>>,
+    output_shard_pipes: Vec<UnixPipe>,
+    sign_key: Option<SigningKey>,
+) -> Result<()>
+{
+    write_new_image(&mut progress_pipe, output_shard_pipes, sign_key, |img_store| {
+        for (i, shard_pipes) in shard_pipe_sets.into_iter().enumerate() {
+            let mut shards: Vec<Shard> = shard_pipes.into_iter().map(Shard::new).collect();
+            drain_image(img_store, &mut shards, None, DEFAULT_MAX_PB_SIZE)
+                .with_context(|| format!("Failed to read image #{}", i+1))?;
+            img_store.next_image();
+        }
+        Ok(())
+    })
+}
+
+/// Reads a field of the framed file stream of `import_stream()`, terminated by a NUL byte.
+/// Returns None at the end of the stream.
+fn read_stream_field(stream: &mut impl BufRead, field: &str) -> Result<Option<String>> {
+    let mut buf = Vec::new();
+    stream.read_until(0, &mut buf).context("Failed to read the file stream")?;
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    ensure!(buf.pop() == Some(0), "The file stream ends in the middle of a {}", field);
+    String::from_utf8(buf).map(Some)
+        .map_err(|_| anyhow!("The file stream has a non UTF-8 {}", field))
+}
+
+/// Writes the files read from `stream` as an image into `output_shard_pipes`. Each file is
+/// framed as `filename\0size\0data`, the size being in decimal, as written by
+/// `ExtractBuilder::extract_to_stream()`. This lets producers other than CRIU (e.g., GPU state
+/// dumpers) use the shards. Files are streamed as they are read.
+pub fn import_stream(
+    mut progress_pipe: fs::File,
+    stream: impl Read,
+    output_shard_pipes: Vec<UnixPipe>,
+    sign_key: Option<SigningKey>,
+) -> Result<()>
+{
+    write_new_image(&mut progress_pipe, output_shard_pipes, sign_key, |img_store| {
+        let mut stream = BufReader::new(stream);
+        while let Some(filename) = read_stream_field(&mut stream, "filename")? {
+            ensure!(!filename.is_empty(), "The file stream has a file with an empty name");
+            ensure!(!is_reserved_filename(&filename), "The file stream has the reserved file `{}`", filename);
+            let size = read_stream_field(&mut stream, "size")?
+                .ok_or_else(|| anyhow!("The file stream ends before the size of {}", filename))?;
+            let size: u64 = size.parse()
+                .map_err(|_| anyhow!("The file stream has an invalid size for {}: `{}`", filename, size))?;
+
+            let mut data = (&mut stream).take(size);
+            img_store.add_file(&filename, &mut data, Some(size))
+                .with_context(|| format!("Failed to write {}", filename))?;
+            ensure!(data.limit() == 0, "The file stream ends in the middle of {}", filename);
+        }
+        Ok(())
+    })
+}
+
+/// Writes the kubelet checkpoint archive read from `archive` as an image into
+/// `output_shard_pipes`. The CRIU image files of the archive can be served to CRIU directly.
+/// Files are streamed as they are read from the archive.
+#[cfg(feature = "kubelet")]
+pub fn import_kubelet_checkpoint(
+    mut progress_pipe: fs::File,
+    archive: impl Read,
+    output_shard_pipes: Vec<UnixPipe>,
+    sign_key: Option<SigningKey>,
+) -> Result<()>
+{
+    write_new_image(&mut progress_pipe, output_shard_pipes, sign_key, |img_store| {
+        let mut archive = tar::Archive::new(archive);
+        for entry in archive.entries().context("Failed to read the checkpoint archive")? {
+            let entry = entry.context("Failed to read the checkpoint archive")?;
+            let path = entry.path()?.into_owned();
+            match entry.header().entry_type() {
+                tar::EntryType::Directory => continue,
+                tar::EntryType::Regular | tar::EntryType::Continuous => {}
+                _ => bail!("{} in the checkpoint archive is not a regular file", path.display()),
+            }
+            let filename = image_store::kubelet::image_filename(&path)?;
+            let path = path.display();
+            ensure!(!is_reserved_filename(&filename),
+                    "The checkpoint archive has the reserved file `{}`", path);
+            let size = entry.header().size().ok();
+            img_store.add_file(&filename, entry, size)
+                .with_context(|| format!("Failed to write {}", path))?;
+        }
+        Ok(())
+    })
+}
+
+/// Writes the image from `shard_pipes` as a kubelet checkpoint archive into `archive`. This is
+/// the reverse of `import_kubelet_checkpoint()`. The manifest of the image is dropped.
+#[cfg(feature = "kubelet")]
+pub fn export_kubelet_checkpoint(
+    mut progress_pipe: fs::File,
+    shard_pipes: Vec<UnixPipe>,
+    archive: impl std::io::Write,
+) -> Result<()>
+{
+    let mut kubelet_store = image_store::kubelet::Store::new(archive)?;
+    drain_shards_into_img_store(&mut kubelet_store, &mut progress_pipe, shard_pipes, vec![],
+                                DrainOptions::default(), None)?;
+    kubelet_store.finish()?.flush().context("Failed to write the checkpoint archive")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::new_pipe;
+
+    fn write_marker(pipe: &mut UnixPipe, seq: u64, body: marker::Body) -> Result<()> {
+        pb_write(pipe, &image::Marker { seq, body: Some(body), padding: 0, file_size: 0 })?;
+        Ok(())
+    }
+
+    // The image fits in the first shard, and the other shards only carry their header. When the
+    // headers come after the image EOF marker, the image is still valid.
+    #[test]
+    fn test_shard_headers_after_image_eof() -> Result<()> {
+        const NUM_SHARDS: usize = 16;
+        let (readers, mut writers): (Vec<_>, Vec<_>) = (0..NUM_SHARDS).map(|_| new_pipe()).unzip();
+        let mut shards: Vec<Shard> = readers.into_iter().map(Shard::new).collect();
+        let header = |shard_index| marker::Body::ShardHeader(image::ShardHeader {
+            image_uuid: "uuid".to_string(), shard_index, num_shards: NUM_SHARDS as u32,
+        });
+
+        let mut first_shard = writers.remove(0);
+        write_marker(&mut first_shard, 0, header(0))?;
+        write_marker(&mut first_shard, 0, marker::Body::Filename("file.img".to_string()))?;
+        write_marker(&mut first_shard, 1, marker::Body::FileData(4))?;
+        first_shard.write_all(b"data")?;
+        write_marker(&mut first_shard, 2, marker::Body::FileEof(true))?;
+        write_marker(&mut first_shard, 3, marker::Body::ImageEof(true))?;
+        drop(first_shard);
+
+        let mut mem_store = image_store::mem::Store::default();
+        let mut img_deserializer = ImageDeserializer::new(&mut mem_store, &mut shards);
+        // The other shards are empty, and not readable, until the image EOF marker is processed.
+        while !img_deserializer.image_eof {
+            let shard = img_deserializer.get_next_readable_shard()?.unwrap();
+            img_deserializer.drain_shard(shard)?;
+        }
+        for (i, mut shard) in writers.into_iter().enumerate() {
+            write_marker(&mut shard, 0, header(i as u32 + 1))?;
+        }
+        img_deserializer.drain_all()?;
+        assert_eq!(img_deserializer.image_uuid(), Some("uuid"));
+        drop(img_deserializer);
+        assert_eq!(read_mem_file(&mut mem_store, "file.img")?, b"data");
+        Ok(())
+    }
+
+    // A hostile stream may repeat a sequence number, on the same shard or on another one.
+    #[test]
+    fn test_stale_and_duplicate_seqs() -> Result<()> {
+        let header = |shard_index| marker::Body::ShardHeader(image::ShardHeader {
+            image_uuid: "uuid".to_string(), shard_index, num_shards: 2,
+        });
+
+        for &(first_seqs, second_seqs, expected_err) in &[
+            (&[0, 0][..], &[][..], "Unexpected marker sequence number 0, already processed"),
+            (&[1][..], &[1][..], "Duplicate marker sequence number 1"),
+        ] {
+            let (readers, mut writers): (Vec<_>, Vec<_>) = (0..2).map(|_| new_pipe()).unzip();
+            let mut shards: Vec<Shard> = readers.into_iter().map(Shard::new).collect();
+            for (i, seqs) in [first_seqs, second_seqs].iter().enumerate() {
+                write_marker(&mut writers[i], 0, header(i as u32))?;
+                for &seq in seqs.iter() {
+                    write_marker(&mut writers[i], seq, marker::Body::Filename("file.img".to_string()))?;
+                }
+            }
+            drop(writers);
+
+            let mut mem_store = image_store::mem::Store::default();
+            let err = ImageDeserializer::new(&mut mem_store, &mut shards).drain_all().unwrap_err();
+            assert_eq!(err.to_string(), expected_err);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    cell::RefCell,
+    io::{Read, Result as IoResult, Error as IoError, ErrorKind},
+    time::Duration,
+    thread,
+};
+
+// This module is only compiled with the `fault-injection` feature. It is used by tests to
+// exercise the error paths and the resilience of the streamer. Faults are injected in pipe
+// operations: splice()/vmsplice() in unix_pipe.rs, and protobuf reads in util.rs.
+//
+// Faults are configured per thread. The capture and extract operations are single threaded,
+// which lets tests inject faults in one of them without disturbing other tests running in
+// parallel.
+
+/// Probabilities (between 0.0 and 1.0) of injecting a given fault on each pipe operation.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct Faults {
+    /// Seed of the pseudo random generator, making fault sequences reproducible.
+    pub seed: u64,
+    /// The operation is delayed by up to 1ms.
+    pub delay: f64,
+    /// The operation transfers fewer bytes than requested.
+    pub short_io: f64,
+    /// The operation fails with EINTR.
+    pub eintr: f64,
+    /// The operation hits an unexpected EOF.
+    pub eof: f64,
+    /// splice() and vmsplice() fail with EINVAL, as they do on fds that don't support them.
+    pub unsupported: f64,
+}
+
+enum Fault {
+    ShortIo,
+    Eintr,
+    Eof,
+}
+
+struct FaultInjector {
+    faults: Faults,
+    rng_state: u64,
+}
+
+impl FaultInjector {
+    fn new(faults: Faults) -> Self {
+        // The xorshift state must not be zero
+        let rng_state = faults.seed | 1;
+        Self { faults, rng_state }
+    }
+
+    /// xorshift64*, good enough for testing purposes.
+    fn next_u64(&mut self) -> u64 {
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        self.rng_state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && (self.next_u64() as f64 / u64::MAX as f64) < probability
+    }
+
+    fn next_fault(&mut self, eof_allowed: bool) -> Option<Fault> {
+        if self.chance(self.faults.delay) {
+            let delay_micros = self.next_u64() % 1000;
+            thread::sleep(Duration::from_micros(delay_micros));
+        }
+
+        if eof_allowed && self.chance(self.faults.eof) {
+            Some(Fault::Eof)
+        } else if self.chance(self.faults.eintr) {
+            Some(Fault::Eintr)
+        } else if self.chance(self.faults.short_io) {
+            Some(Fault::ShortIo)
+        } else {
+            None
+        }
+    }
+}
+
+thread_local! {
+    static FAULT_INJECTOR: RefCell<Option<FaultInjector>> = const { RefCell::new(None) };
+}
+
+/// Injects faults in the pipe operations performed by the current thread.
+pub fn inject_faults(faults: Faults) {
+    FAULT_INJECTOR.with(|fi| *fi.borrow_mut() = Some(FaultInjector::new(faults)));
+}
+
+fn next_fault(eof_allowed: bool) -> Option<Fault> {
+    FAULT_INJECTOR.with(|fi| fi.borrow_mut().as_mut().and_then(|fi| fi.next_fault(eof_allowed)))
+}
+
+/// Returns a length between 1 and `len` to simulate a short read or write.
+fn short_len(len: usize) -> usize {
+    FAULT_INJECTOR.with(|fi| match fi.borrow_mut().as_mut() {
+        Some(fi) if len > 1 => 1 + (fi.next_u64() as usize % (len - 1)),
+        _ => len,
+    })
+}
+
+/// Called before a pipe operation of `len` bytes. Returns the number of bytes the operation
+/// should transfer, 0 to simulate an EOF, or EINTR. Delays are applied directly.
+pub fn pipe_fault(len: usize, eof_allowed: bool) -> nix::Result<usize> {
+    match next_fault(eof_allowed) {
+        Some(Fault::Eof) => Ok(0),
+        Some(Fault::Eintr) => Err(nix::Error::Sys(nix::errno::Errno::EINTR)),
+        Some(Fault::ShortIo) => Ok(short_len(len)),
+        None => Ok(len),
+    }
+}
+
+/// Called before a splice() or vmsplice(). Fails with EINVAL to simulate an environment where
+/// splicing is not supported.
+pub fn splice_fault() -> nix::Result<()> {
+    let unsupported = FAULT_INJECTOR.with(|fi| match fi.borrow_mut().as_mut() {
+        Some(fi) => fi.chance(fi.faults.unsupported),
+        None => false,
+    });
+    if unsupported {
+        return Err(nix::Error::Sys(nix::errno::Errno::EINVAL));
+    }
+    Ok(())
+}
+
+/// `FaultyReader` injects faults in the reads of the underlying reader.
+pub struct FaultyReader<R>(pub R);
+
+impl<R: Read> Read for FaultyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        match pipe_fault(buf.len(), true) {
+            Ok(0) => Ok(0),
+            Ok(len) => self.0.read(&mut buf[..len]),
+            Err(_) => Err(IoError::from(ErrorKind::Interrupted)),
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom, Write},
+    mem::size_of,
+    rc::Rc,
+};
+use prost::Message;
+use crate::{
+    image::{self, marker, file_table},
+    util::{pb_read, pb_write},
+};
+use anyhow::{Result, Context};
+
+// Listing the files of an image normally means reading all of its shards. Stored images are
+// often listed, e.g., to pick the image to restore, or to check what an image holds before
+// downloading it. With --file-table, the capture writes the table of the files of the image at
+// the end of the first shard, once the image is complete. The table gives the size of each file,
+// and how many of its chunks went on each shard.
+//
+// The file table comes after the image EOF marker, and ends with a marker of fixed size giving
+// the size of the table. The table is found by reading the tail of the shard, as with the central
+// directory of a zip archive. Readers of the image skip both markers.
+
+const NO_FILE_TABLE: &str = "The shard has no file table. The image may have been captured without --file-table, \
+                             or this is not the first shard of the image";
+
+fn file_table_size_marker(file_table_size: u64) -> image::Marker {
+    image::Marker { seq: 0, body: Some(marker::Body::FileTableSize(file_table_size)), padding: 0, file_size: 0 }
+}
+
+/// Size of the marker ending a shard that carries a file table, its length prefix included.
+pub fn file_table_size_marker_len() -> usize {
+    size_of::<u32>() + file_table_size_marker(0).encoded_len()
+}
+
+/// Records the files of an image as its chunks are written.
+pub struct FileTableBuilder {
+    num_shards: usize,
+    files: Vec<file_table::File>,
+    /// Index of each file in `files`
+    file_indexes: HashMap<Rc<str>, usize>,
+}
+
+impl FileTableBuilder {
+    pub fn new(num_shards: usize) -> Self {
+        Self { num_shards, files: Vec::new(), file_indexes: HashMap::new() }
+    }
+
+    fn file(&mut self, filename: &Rc<str>) -> &mut file_table::File {
+        let files = &mut self.files;
+        let num_shards = self.num_shards;
+        let index = *self.file_indexes.entry(Rc::clone(filename)).or_insert_with(|| {
+            files.push(file_table::File {
+                filename: filename.to_string(),
+                size: 0,
+                shard_chunks: vec![0; num_shards],
+            });
+            files.len() - 1
+        });
+        &mut files[index]
+    }
+
+    /// Records a chunk of `size` bytes of the file, written on `shard_index`.
+    pub fn add_chunk(&mut self, filename: &Rc<str>, shard_index: usize, size: u32) {
+        let file = self.file(filename);
+        file.size += size as u64;
+        file.shard_chunks[shard_index] += 1;
+    }
+
+    /// Records the file, which may have no chunks.
+    pub fn add_file(&mut self, filename: &Rc<str>) {
+        self.file(filename);
+    }
+
+    pub fn build(self) -> image::FileTable {
+        image::FileTable { files: self.files }
+    }
+}
+
+/// Encodes the file table, along with its markers, as written at the end of the first shard.
+/// `set_padding` pads the file table marker, given the size of what follows it, so that the
+/// shard still ends on its chunk alignment. The markers are returned as well, for the marker log.
+pub fn encode_file_table(file_table: &image::FileTable, set_padding: impl FnOnce(&mut image::Marker, usize))
+    -> Result<(Vec<u8>, [image::Marker; 2])>
+{
+    let table = file_table.encode_to_vec();
+    let mut marker = image::Marker { seq: 0, body: Some(marker::Body::FileTable(table.len() as u32)), padding: 0, file_size: 0 };
+    set_padding(&mut marker, table.len() + file_table_size_marker_len());
+
+    let mut buf = Vec::new();
+    pb_write(&mut buf, &marker)?;
+    buf.extend_from_slice(&table);
+    buf.resize(buf.len() + marker.padding as usize, 0);
+    let size_marker = file_table_size_marker(buf.len() as u64);
+    pb_write(&mut buf, &size_marker)?;
+    Ok((buf, [marker, size_marker]))
+}
+
+/// Reads the file table at the end of the first shard of an image, without reading the rest of
+/// the shard.
+pub fn read_file_table(shard: &mut (impl Read + Seek)) -> Result<image::FileTable> {
+    let marker_len = file_table_size_marker_len() as u64;
+    let shard_size = shard.seek(SeekFrom::End(0)).context("Failed to seek the shard")?;
+    ensure!(shard_size >= marker_len, NO_FILE_TABLE);
+
+    shard.seek(SeekFrom::Start(shard_size - marker_len))?;
+    let file_table_size = match pb_read::<_, image::Marker>(shard).ok().and_then(|m| m.body) {
+        Some(marker::Body::FileTableSize(size)) if size <= shard_size - marker_len => size,
+        _ => bail!(NO_FILE_TABLE),
+    };
+
+    shard.seek(SeekFrom::Start(shard_size - marker_len - file_table_size))?;
+    let table_len = match pb_read::<_, image::Marker>(shard)?.body {
+        Some(marker::Body::FileTable(len)) => len,
+        _ => bail!("The file table of the shard is corrupted"),
+    };
+    let mut table = vec![0; table_len as usize];
+    shard.read_exact(&mut table).context("The file table of the shard is truncated")?;
+    image::FileTable::decode(&table[..]).context("The file table of the shard is corrupted")
+}
+
+/// Prints a line per file of the table: the filename, the size, and the number of chunks on each
+/// shard, separated by tabs.
+pub fn print_file_table(file_table: &image::FileTable, out: &mut impl Write) -> Result<()> {
+    for file in &file_table.files {
+        let shard_chunks: Vec<String> = file.shard_chunks.iter().map(u32::to_string).collect();
+        writeln!(out, "{}\t{}\t{}", file.filename, file.size, shard_chunks.join(","))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_file_table() -> Result<()> {
+        let mut builder = FileTableBuilder::new(2);
+        let (core, pages) = (Rc::from("core-1.img"), Rc::from("pages-1.img"));
+        builder.add_chunk(&pages, 0, 100);
+        builder.add_chunk(&core, 1, 10);
+        builder.add_chunk(&pages, 1, 50);
+        builder.add_file(&Rc::from("empty.img"));
+
+        let mut shard = b"the image".to_vec();
+        shard.extend(encode_file_table(&builder.build(), |_, _| {})?.0);
+        let file_table = read_file_table(&mut Cursor::new(shard))?;
+
+        let mut out = Vec::new();
+        print_file_table(&file_table, &mut out)?;
+        assert_eq!(String::from_utf8(out)?, "pages-1.img\t150\t1,1\ncore-1.img\t10\t0,1\nempty.img\t0\t0,0\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_file_table() {
+        let err = read_file_table(&mut Cursor::new(vec![0xff; 64])).unwrap_err();
+        assert!(err.to_string().contains("has no file table"), "{}", err);
+        assert!(read_file_table(&mut Cursor::new(vec![])).is_err());
+    }
+}
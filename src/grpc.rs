@@ -0,0 +1,449 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    io::{self, BufRead, BufReader, Read},
+    os::unix::{
+        fs::OpenOptionsExt,
+        io::{AsRawFd, FromRawFd, RawFd},
+        process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    pin::Pin,
+    process::{Command, Stdio},
+    sync::{Arc, Mutex},
+    task::{Context as TaskContext, Poll},
+    thread,
+    time::{Duration, Instant},
+};
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag, OFlag},
+    poll::{poll, PollFd, PollFlags},
+    sys::{signal::{kill, Signal}, stat::{umask, Mode}},
+    unistd::{pipe2, Pid},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_stream::{wrappers::UnixListenerStream, StreamExt};
+use tonic::{transport::server::Connected, Request, Response, Status};
+use anyhow::{Context, Result};
+
+// The control plane lets a controller (e.g., a checkpointing daemon) drive the streamer over
+// gRPC, instead of spawning it and passing file descriptors. Shards and external files are named
+// pipes that the streamer opens by path.
+//
+// Each operation runs in its own streamer process, re-executing the current binary with the
+// command line options matching the request. This reuses the capture and serve operations as they
+// are, keeps a failing operation from taking down the server, and makes aborting an operation
+// a matter of killing its process.
+//
+// The server listens on a Unix socket that only its user can connect to. Operations open paths,
+// and read keys, with the privileges of the server, so they must not be reachable by others.
+
+#[allow(clippy::all)]
+pub mod control {
+    tonic::include_proto!("control");
+}
+
+use control::{
+    control_server::{Control, ControlServer},
+    StartCaptureRequest, StartRestoreRequest, StartResponse, GetProgressRequest,
+    GetProgressResponse, AbortRequest, AbortResponse, ExtFile, State,
+};
+
+/// The prefix of the error line that the streamer writes on stderr when an operation fails.
+const ERROR_PREFIX: &str = "criu-image-streamer Error: ";
+
+/// How long the outcome of a finished operation can be queried with GetProgress.
+const FINISHED_OPERATION_RETENTION: Duration = Duration::from_secs(10*60);
+
+/// How often the named pipes being opened check whether their operation was aborted.
+const OPEN_PIPE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+struct Operation {
+    state: State,
+    progress: Vec<String>,
+    error: String,
+    /// Set while the streamer process runs, and has not been reaped.
+    pid: Option<Pid>,
+    /// Set once the operation is over, whether it succeeded, failed, or was aborted.
+    finished_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Operations {
+    next_id: u64,
+    operations: HashMap<u64, Operation>,
+}
+
+impl Operations {
+    /// Forgets the operations that finished long ago.
+    fn prune(&mut self) {
+        self.operations.retain(|_, operation| match operation.finished_at {
+            Some(finished_at) => finished_at.elapsed() < FINISHED_OPERATION_RETENTION,
+            None => true,
+        });
+    }
+
+    fn is_aborted(&self, id: u64) -> bool {
+        self.operations[&id].state == State::Aborted
+    }
+}
+
+type SharedOperations = Arc<Mutex<Operations>>;
+
+/// Options of a streamer process, before its pipes are opened.
+struct Spawn {
+    args: Vec<OsString>,
+    /// Opened for writing when capturing, and for reading when restoring.
+    shard_paths: Vec<String>,
+    /// Opened for reading when capturing, and for writing when restoring.
+    ext_files: Vec<ExtFile>,
+    capture: bool,
+}
+
+pub struct ControlService {
+    images_dir: PathBuf,
+    streamer_exe: PathBuf,
+    operations: SharedOperations,
+}
+
+impl ControlService {
+    pub fn new(images_dir: PathBuf) -> Result<Self> {
+        let streamer_exe = std::env::current_exe()
+            .context("Failed to locate the streamer executable")?;
+        Ok(Self { images_dir, streamer_exe, operations: SharedOperations::default() })
+    }
+
+    fn images_dir_arg(&self, images_dir: String) -> Vec<OsString> {
+        let images_dir = match images_dir {
+            images_dir if images_dir.is_empty() => self.images_dir.clone(),
+            images_dir => PathBuf::from(images_dir),
+        };
+        vec!["--images-dir".into(), images_dir.into()]
+    }
+
+    fn start(&self, spawn: Spawn) -> u64 {
+        let id = {
+            let mut operations = self.operations.lock().unwrap();
+            operations.prune();
+            let id = operations.next_id;
+            operations.next_id += 1;
+            operations.operations.insert(id, Operation {
+                state: State::Running,
+                progress: Vec::new(),
+                error: String::new(),
+                pid: None,
+                finished_at: None,
+            });
+            id
+        };
+
+        // Opening the named pipes waits until the controller opens the other ends, so the
+        // operation is started on its own thread.
+        let streamer_exe = self.streamer_exe.clone();
+        let operations = Arc::clone(&self.operations);
+        thread::spawn(move || {
+            let result = run_operation(id, &streamer_exe, spawn, &operations);
+            let mut operations = operations.lock().unwrap();
+            let operation = operations.operations.get_mut(&id).unwrap();
+            operation.pid = None;
+            operation.finished_at = Some(Instant::now());
+            if operation.state == State::Running {
+                match result {
+                    Ok(()) => operation.state = State::Succeeded,
+                    Err(e) => {
+                        operation.state = State::Failed;
+                        operation.error = format!("{:#}", e);
+                    }
+                }
+            }
+        });
+
+        id
+    }
+}
+
+fn add_arg(args: &mut Vec<OsString>, name: &str, value: impl Into<OsString>) {
+    args.push(name.into());
+    args.push(value.into());
+}
+
+fn add_str_arg(args: &mut Vec<OsString>, name: &str, value: String) {
+    if !value.is_empty() {
+        add_arg(args, name, value);
+    }
+}
+
+fn add_list_arg(args: &mut Vec<OsString>, name: &str, values: Vec<String>) {
+    if !values.is_empty() {
+        add_arg(args, name, values.join(","));
+    }
+}
+
+/// Opens the named pipe at `path`, once the controller opens its other end. A blocking open()
+/// could not be interrupted when the operation is aborted, so the pipe is opened non-blocking,
+/// and `is_aborted()` is checked while waiting. The pipe is made blocking before being returned.
+fn open_pipe(path: &str, write: bool, is_aborted: impl Fn() -> bool) -> Result<fs::File> {
+    let open = || fs::OpenOptions::new().read(!write).write(write)
+        .custom_flags(libc::O_NONBLOCK).open(path);
+    let pipe = loop {
+        ensure!(!is_aborted(), "Aborted while opening {}", path);
+        match open() {
+            // Opening the write end fails until there is a reader.
+            Err(e) if write && e.raw_os_error() == Some(libc::ENXIO) => thread::sleep(OPEN_PIPE_POLL_INTERVAL),
+            result => break result.with_context(|| format!("Failed to open {}", path))?,
+        }
+    };
+    // Opening the read end succeeds right away. The writer is there once data comes, or once it
+    // closes its end. Before any writer, the pipe is not reported as hung up.
+    if !write {
+        let timeout = OPEN_PIPE_POLL_INTERVAL.as_millis() as libc::c_int;
+        loop {
+            ensure!(!is_aborted(), "Aborted while opening {}", path);
+            let mut poll_fds = [PollFd::new(pipe.as_raw_fd(), PollFlags::POLLIN)];
+            match poll(&mut poll_fds, timeout) {
+                Ok(0) | Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+                result => { result.with_context(|| format!("Failed to poll {}", path))?; break; }
+            }
+        }
+    }
+    fcntl(pipe.as_raw_fd(), FcntlArg::F_SETFL(OFlag::empty()))
+        .with_context(|| format!("Failed to make {} blocking", path))?;
+    Ok(pipe)
+}
+
+fn fd_list(fds: impl Iterator<Item=RawFd>) -> String {
+    fds.map(|fd| fd.to_string()).collect::<Vec<_>>().join(",")
+}
+
+/// Opens the pipes of the operation, and runs its streamer process to completion.
+fn run_operation(id: u64, streamer_exe: &Path, spawn: Spawn, operations: &SharedOperations) -> Result<()> {
+    let Spawn { mut args, shard_paths, ext_files, capture } = spawn;
+
+    let is_aborted = || operations.lock().unwrap().is_aborted(id);
+    let shards = shard_paths.iter()
+        .map(|path| open_pipe(path, capture, is_aborted))
+        .collect::<Result<Vec<_>>>()?;
+    let ext_files = ext_files.iter()
+        .map(|ext_file| Ok((ext_file.filename.as_str(), open_pipe(&ext_file.path, !capture, is_aborted)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let (progress_r, progress_w) = pipe2(OFlag::O_CLOEXEC)?;
+    let (progress_r, progress_w) = unsafe { (fs::File::from_raw_fd(progress_r), fs::File::from_raw_fd(progress_w)) };
+
+    // The pipes are opened with O_CLOEXEC. The streamer process inherits them as
+    // we clear the flag after forking.
+    let mut inherited_fds = vec![progress_w.as_raw_fd()];
+    inherited_fds.extend(shards.iter().map(|shard| shard.as_raw_fd()));
+    inherited_fds.extend(ext_files.iter().map(|(_, pipe)| pipe.as_raw_fd()));
+
+    add_arg(&mut args, "--progress-fd", progress_w.as_raw_fd().to_string());
+    // The progress is collected line by line, regardless of the environment of the server.
+    add_arg(&mut args, "--progress-format", "json");
+    // The error of the operation is read from stderr.
+    add_arg(&mut args, "--log-target", "stderr");
+    add_arg(&mut args, "--shard-fds", fd_list(shards.iter().map(|shard| shard.as_raw_fd())));
+    if !ext_files.is_empty() {
+        let ext_file_fds = ext_files.iter()
+            .map(|(filename, pipe)| format!("{}:{}", filename, pipe.as_raw_fd()))
+            .collect::<Vec<_>>();
+        add_arg(&mut args, "--ext-file-fds", ext_file_fds.join(","));
+    }
+    args.push(if capture { "capture" } else { "serve" }.into());
+
+    let mut command = Command::new(streamer_exe);
+    command.args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    unsafe {
+        command.pre_exec(move || {
+            for fd in &inherited_fds {
+                fcntl(*fd, FcntlArg::F_SETFD(FdFlag::empty()))
+                    .map_err(|_| std::io::Error::last_os_error())?;
+            }
+            Ok(())
+        });
+    }
+
+    let mut child = {
+        let mut operations = operations.lock().unwrap();
+        let operation = operations.operations.get_mut(&id).unwrap();
+        if operation.state != State::Running {
+            // Aborted while opening the pipes
+            return Ok(());
+        }
+        let child = command.spawn().context("Failed to spawn the streamer")?;
+        operation.pid = Some(Pid::from_raw(child.id() as i32));
+        child
+    };
+
+    // Our copies of the pipes must be closed for the other ends to see EOF.
+    drop(progress_w);
+    drop(shards);
+    drop(ext_files);
+
+    // stderr is read along with the progress, so that a streamer filling up its stderr pipe
+    // doesn't block forever.
+    let mut child_stderr = child.stderr.take().unwrap();
+    let stderr_thread = thread::spawn(move || -> io::Result<String> {
+        let mut stderr = String::new();
+        child_stderr.read_to_string(&mut stderr)?;
+        Ok(stderr)
+    });
+
+    for line in BufReader::new(progress_r).lines() {
+        let line = line?;
+        let mut operations = operations.lock().unwrap();
+        operations.operations.get_mut(&id).unwrap().progress.push(line);
+    }
+
+    let stderr = stderr_thread.join().unwrap()?;
+
+    // The process must not be reaped while Abort may still kill it, otherwise its pid could be
+    // reused. We wait for its exit without reaping it, and forget its pid before reaping it.
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let rc = unsafe { libc::waitid(libc::P_PID, child.id(), &mut info, libc::WEXITED | libc::WNOWAIT) };
+    ensure!(rc == 0, "waitid() failed: {}", std::io::Error::last_os_error());
+    operations.lock().unwrap().operations.get_mut(&id).unwrap().pid = None;
+    let status = child.wait()?;
+
+    if let Some(error) = stderr.lines().find_map(|line| line.strip_prefix(ERROR_PREFIX)) {
+        bail!("{}", error);
+    }
+    ensure!(status.success(), "The streamer exited with {}", status);
+    Ok(())
+}
+
+#[tonic::async_trait]
+impl Control for ControlService {
+    async fn start_capture(&self, request: Request<StartCaptureRequest>) -> Result<Response<StartResponse>, Status> {
+        let r = request.into_inner();
+        if r.shard_paths.is_empty() {
+            return Err(Status::invalid_argument("shard_paths must not be empty"));
+        }
+        let mut args = self.images_dir_arg(r.images_dir);
+        add_str_arg(&mut args, "--sign-key", r.sign_key);
+        add_str_arg(&mut args, "--audit-log", r.audit_log);
+        add_list_arg(&mut args, "--containers", r.containers);
+        add_str_arg(&mut args, "--job-id", r.job_id);
+        if r.expected_size > 0 {
+            add_arg(&mut args, "--expected-size", r.expected_size.to_string());
+        }
+        let id = self.start(Spawn { args, shard_paths: r.shard_paths, ext_files: r.ext_files, capture: true });
+        Ok(Response::new(StartResponse { id }))
+    }
+
+    async fn start_restore(&self, request: Request<StartRestoreRequest>) -> Result<Response<StartResponse>, Status> {
+        let r = request.into_inner();
+        if r.shard_paths.is_empty() {
+            return Err(Status::invalid_argument("shard_paths must not be empty"));
+        }
+        let mut args = self.images_dir_arg(r.images_dir);
+        add_str_arg(&mut args, "--verify-key", r.verify_key);
+        add_str_arg(&mut args, "--audit-log", r.audit_log);
+        add_list_arg(&mut args, "--containers", r.containers);
+        add_str_arg(&mut args, "--job-id", r.job_id);
+        let remaps = r.tcp_listen_remaps.iter()
+            .map(|remap| format!("{}:{}", remap.old_port, remap.new_port))
+            .collect();
+        add_list_arg(&mut args, "--tcp-listen-remap", remaps);
+        let id = self.start(Spawn { args, shard_paths: r.shard_paths, ext_files: r.ext_files, capture: false });
+        Ok(Response::new(StartResponse { id }))
+    }
+
+    async fn get_progress(&self, request: Request<GetProgressRequest>) -> Result<Response<GetProgressResponse>, Status> {
+        let id = request.into_inner().id;
+        let mut operations = self.operations.lock().unwrap();
+        operations.prune();
+        let operation = operations.operations.get(&id)
+            .ok_or_else(|| Status::not_found(format!("Unknown operation {}", id)))?;
+        Ok(Response::new(GetProgressResponse {
+            state: operation.state as i32,
+            progress: operation.progress.clone(),
+            error: operation.error.clone(),
+        }))
+    }
+
+    async fn abort(&self, request: Request<AbortRequest>) -> Result<Response<AbortResponse>, Status> {
+        let id = request.into_inner().id;
+        let mut operations = self.operations.lock().unwrap();
+        let operation = operations.operations.get_mut(&id)
+            .ok_or_else(|| Status::not_found(format!("Unknown operation {}", id)))?;
+        // Aborting a completed operation has no effect.
+        if operation.state == State::Running {
+            operation.state = State::Aborted;
+            if let Some(pid) = operation.pid {
+                kill(pid, Signal::SIGKILL)
+                    .map_err(|e| Status::internal(format!("Failed to kill the streamer: {}", e)))?;
+            }
+        }
+        Ok(Response::new(AbortResponse {}))
+    }
+}
+
+/// A connection to the Unix socket of the server. tonic only knows about TCP connections.
+struct UnixStream(tokio::net::UnixStream);
+
+impl Connected for UnixStream {
+    type ConnectInfo = ();
+
+    fn connect_info(&self) -> Self::ConnectInfo {}
+}
+
+impl AsyncRead for UnixStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UnixStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// Serves the control plane on the Unix socket `listen` until the process is terminated. The
+/// socket is only accessible to the user of the server. Requests that don't specify an images
+/// directory use `images_dir`.
+pub fn serve_grpc(images_dir: &Path, listen: &Path) -> Result<()> {
+    let service = ControlService::new(images_dir.to_path_buf())?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        // A socket left over by a previous server is replaced.
+        let _ = fs::remove_file(listen);
+        // The socket is created with no access for others, rather than restricted afterwards.
+        let old_umask = umask(Mode::from_bits_truncate(0o177));
+        let listener = tokio::net::UnixListener::bind(listen);
+        umask(old_umask);
+        let listener = listener.with_context(|| format!("Failed to bind {}", listen.display()))?;
+        let incoming = UnixListenerStream::new(listener).map(|stream| stream.map(UnixStream));
+        tonic::transport::Server::builder()
+            .add_service(ControlServer::new(service))
+            .serve_with_incoming(incoming)
+            .await
+            .context("gRPC server failed")
+    })
+}
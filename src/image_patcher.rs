@@ -34,7 +34,7 @@ const FILES_MAGIC: u32 = 0x56303138;
 // From #include <netinet/tcp.h>
 const TCP_LISTEN: u32 = 10;
 
-fn read_criu_img_header(reader: &mut impl Read, expected_header_magic: u32) -> Result<()>
+pub fn read_criu_img_header(reader: &mut impl Read, expected_header_magic: u32) -> Result<()>
 {
     let mut header = read_bytes_next(reader, 2*size_of::<u32>())?
         .ok_or_else(|| anyhow!("Failed to read the CRIU image header"))?;
@@ -49,7 +49,7 @@ fn read_criu_img_header(reader: &mut impl Read, expected_header_magic: u32) -> R
     Ok(())
 }
 
-fn write_criu_img_header(writer: &mut impl Write, header_magic: u32) -> Result<()>
+pub fn write_criu_img_header(writer: &mut impl Write, header_magic: u32) -> Result<()>
 {
     writer.write_all(&IMG_COMMON_MAGIC.to_le_bytes())?;
     writer.write_all(&header_magic.to_le_bytes())?;
@@ -0,0 +1,81 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use super::{ImageStore, ImageFile};
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    io::Read,
+};
+use crate::{
+    unix_pipe::UnixPipe,
+    manifest::{FileHasher, FileDigest},
+};
+
+/// `Store` computes the digest of every file written to the underlying store. We can no longer
+/// splice() data from the shards as we need to see the data, which is why this store is only used
+/// when verifying or auditing the image.
+pub struct Store<'a, UnderlyingStore> {
+    underlying_store: &'a mut UnderlyingStore,
+    digests: HashMap<Box<str>, FileDigest>,
+}
+
+impl<'a, UnderlyingStore: ImageStore> Store<'a, UnderlyingStore> {
+    pub fn new(underlying_store: &'a mut UnderlyingStore) -> Self {
+        let digests = HashMap::new();
+        Self { underlying_store, digests }
+    }
+
+    /// Returns the digests of the files that have been fully received.
+    pub fn into_digests(self) -> HashMap<Box<str>, FileDigest> {
+        self.digests
+    }
+}
+
+impl<UnderlyingStore: ImageStore> ImageStore for Store<'_, UnderlyingStore> {
+    type File = File<UnderlyingStore::File>;
+
+    fn create(&mut self, filename: &str) -> Result<Self::File> {
+        let file = self.underlying_store.create(filename)?;
+        Ok(File { file, hasher: FileHasher::default() })
+    }
+
+    fn insert(&mut self, filename: impl Into<Box<str>>, file: Self::File) {
+        let filename = filename.into();
+        self.digests.insert(filename.clone(), file.hasher.finalize());
+        self.underlying_store.insert(filename, file.file);
+    }
+}
+
+pub struct File<UnderlyingFile> {
+    file: UnderlyingFile,
+    hasher: FileHasher,
+}
+
+impl<UnderlyingFile: ImageFile> ImageFile for File<UnderlyingFile> {
+    fn write_all_from_pipe(&mut self, shard_pipe: &mut UnixPipe, size: usize) -> Result<()> {
+        let mut buf = vec![0; size];
+        shard_pipe.read_exact(&mut buf).context("Failed to read from shard")?;
+        self.write_all_from_slice(&buf)
+    }
+
+    fn write_all_from_slice(&mut self, buf: &[u8]) -> Result<()> {
+        self.hasher.update(buf);
+        self.file.write_all_from_slice(buf)
+    }
+
+    fn preallocate(&mut self, size: u64) -> Result<()> {
+        self.file.preallocate(size)
+    }
+}
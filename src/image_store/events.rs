@@ -0,0 +1,77 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+
+use super::{ImageStore, ImageFile};
+use anyhow::Result;
+use crate::{
+    unix_pipe::UnixPipe,
+    events::{Event, EventCallback},
+};
+
+/// `Store` reports the files written to the underlying store to the user's callback. Without a
+/// callback, files go straight through.
+pub struct Store<'a, UnderlyingStore> {
+    underlying_store: &'a mut UnderlyingStore,
+    on_event: Option<&'a mut EventCallback>,
+}
+
+impl<'a, UnderlyingStore: ImageStore> Store<'a, UnderlyingStore> {
+    pub fn new(underlying_store: &'a mut UnderlyingStore, on_event: Option<&'a mut EventCallback>) -> Self {
+        Self { underlying_store, on_event }
+    }
+}
+
+impl<UnderlyingStore: ImageStore> ImageStore for Store<'_, UnderlyingStore> {
+    type File = File<UnderlyingStore::File>;
+
+    fn create(&mut self, filename: &str) -> Result<Self::File> {
+        let file = self.underlying_store.create(filename)?;
+        if let Some(on_event) = self.on_event.as_mut() {
+            on_event(Event::FileStart { filename });
+        }
+        Ok(File { file, size: 0 })
+    }
+
+    fn insert(&mut self, filename: impl Into<Box<str>>, file: Self::File) {
+        let filename = filename.into();
+        if let Some(on_event) = self.on_event.as_mut() {
+            on_event(Event::FileComplete { filename: &filename, size: file.size });
+        }
+        self.underlying_store.insert(filename, file.file);
+    }
+}
+
+pub struct File<UnderlyingFile> {
+    file: UnderlyingFile,
+    size: u64,
+}
+
+impl<UnderlyingFile: ImageFile> ImageFile for File<UnderlyingFile> {
+    fn write_all_from_pipe(&mut self, shard_pipe: &mut UnixPipe, size: usize) -> Result<()> {
+        self.file.write_all_from_pipe(shard_pipe, size)?;
+        self.size += size as u64;
+        Ok(())
+    }
+
+    fn write_all_from_slice(&mut self, buf: &[u8]) -> Result<()> {
+        self.file.write_all_from_slice(buf)?;
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+
+    fn preallocate(&mut self, size: u64) -> Result<()> {
+        self.file.preallocate(size)
+    }
+}
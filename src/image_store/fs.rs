@@ -16,19 +16,127 @@ use super::{ImageStore, ImageFile};
 use anyhow::{Context, Result};
 use std::{
     fs,
-    path::Path,
+    path::{Path, PathBuf},
+    io::{self, Write},
+    os::unix::{
+        fs::{MetadataExt, PermissionsExt, chown, fchown},
+        io::AsRawFd,
+    },
+};
+use nix::{
+    errno::Errno,
+    fcntl::{fallocate, FallocateFlags},
 };
 use crate::{
     unix_pipe::{UnixPipe, UnixPipeImpl},
+    util::create_dir_all,
 };
 
+/// CRIU saves the content of the files that were deleted while still open into
+/// ghost-file-<id>.img. These can be as large as the rest of the image.
+pub fn is_ghost_filename(filename: &str) -> bool {
+    let basename = filename.rsplit('/').next().unwrap_or(filename);
+    basename.starts_with("ghost-file-") && basename.ends_with(".img")
+}
+
+/// Filenames come from the image, which is untrusted. Only plain names, and names of files of
+/// containers, e.g., "container/pages-1.img", are accepted, so that files are never written
+/// outside of the images directory.
+fn ensure_safe_filename(filename: &str) -> Result<()> {
+    let components: Vec<&str> = filename.split('/').collect();
+    ensure!(components.len() <= 2 &&
+            components.iter().all(|c| !c.is_empty() && *c != "." && *c != ".."),
+            "Invalid filename `{}` in the image", filename);
+    Ok(())
+}
+
+/// Owner and permissions of the files and directories created when extracting, when they must
+/// differ from the streamer's, e.g., when the streamer runs as root, but CRIU restores in a user
+/// namespace.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Ownership {
+    /// uid and gid
+    pub owner: Option<(u32, u32)>,
+    /// Permission bits of the files. Directories also get the execute bits of their read bits.
+    pub mode: Option<u32>,
+}
+
+impl Ownership {
+    pub fn apply_to_file(&self, file: &fs::File, path: &Path) -> Result<()> {
+        if let Some((uid, gid)) = self.owner {
+            fchown(file, Some(uid), Some(gid))
+                .with_context(|| format!("Failed to chown {}", path.display()))?;
+        }
+        if let Some(mode) = self.mode {
+            file.set_permissions(fs::Permissions::from_mode(mode))
+                .with_context(|| format!("Failed to chmod {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether a file with `metadata` already has the ownership.
+    pub fn is_applied_to(&self, metadata: &fs::Metadata) -> bool {
+        self.owner.is_none_or(|owner| owner == (metadata.uid(), metadata.gid())) &&
+            self.mode.is_none_or(|mode| mode == metadata.mode() & 0o7777)
+    }
+
+    fn apply_to_dir(&self, dir: &Path) -> Result<()> {
+        if let Some((uid, gid)) = self.owner {
+            chown(dir, Some(uid), Some(gid))
+                .with_context(|| format!("Failed to chown {}", dir.display()))?;
+        }
+        if let Some(mode) = self.mode {
+            fs::set_permissions(dir, fs::Permissions::from_mode(mode | ((mode & 0o444) >> 2)))
+                .with_context(|| format!("Failed to chmod {}", dir.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Creates `dir` along with its missing parents. Only the directories created are given the
+    /// ownership.
+    pub fn create_dir_all(&self, dir: &Path) -> Result<()> {
+        let missing_dirs: Vec<&Path> = dir.ancestors().take_while(|dir| !dir.exists()).collect();
+        create_dir_all(dir)?;
+        for dir in missing_dirs.into_iter().rev() {
+            self.apply_to_dir(dir)?;
+        }
+        Ok(())
+    }
+}
+
 pub struct Store<'a> {
     images_dir: &'a Path,
+    ghost_files_dir: Option<PathBuf>,
+    ownership: Ownership,
+    created_files: Vec<PathBuf>,
 }
 
 impl<'a> Store<'a> {
     pub fn new(images_dir: &'a Path) -> Self {
-        Self { images_dir }
+        Self { images_dir, ghost_files_dir: None, ownership: Ownership::default(), created_files: Vec::new() }
+    }
+
+    /// Removes the files written so far, e.g., when the image fails verification, so that they
+    /// can't be mistaken for a valid image.
+    pub fn remove_created_files(&mut self) -> Result<()> {
+        for path in self.created_files.drain(..) {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound =>
+                    return Err(e).with_context(|| format!("Failed to remove {}", path.display())),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives the files, and the directories of containers, the provided ownership.
+    pub fn set_ownership(&mut self, ownership: Ownership) {
+        self.ownership = ownership;
+    }
+
+    /// Writes the ghost files in `ghost_files_dir` instead of the images directory.
+    pub fn divert_ghost_files(&mut self, ghost_files_dir: PathBuf) {
+        self.ghost_files_dir = Some(ghost_files_dir);
     }
 }
 
@@ -36,10 +144,24 @@ impl ImageStore for Store<'_> {
     type File = fs::File;
 
     fn create(&mut self, filename: &str) -> Result<Self::File> {
-        let full_path = &self.images_dir.join(&filename);
+        ensure_safe_filename(filename)?;
+        let dir = match self.ghost_files_dir.as_deref() {
+            Some(ghost_files_dir) if is_ghost_filename(filename) => ghost_files_dir,
+            _ => self.images_dir,
+        };
+        let full_path = &dir.join(filename);
+
+        // Files of containers are in their own directory, e.g., "container/pages-1.img".
+        if filename.contains('/') {
+            if let Some(dir) = full_path.parent() {
+                self.ownership.create_dir_all(dir)?;
+            }
+        }
 
         let file = fs::File::create(full_path)
             .with_context(|| format!("Failed to create file {}", full_path.display()))?;
+        self.created_files.push(full_path.clone());
+        self.ownership.apply_to_file(&file, full_path)?;
 
         Ok(file)
     }
@@ -54,4 +176,33 @@ impl ImageFile for fs::File {
         shard_pipe.splice_all(self, size)?;
         Ok(())
     }
+
+    fn write_all_from_slice(&mut self, buf: &[u8]) -> Result<()> {
+        self.write_all(buf).context("Failed to write file")?;
+        Ok(())
+    }
+
+    /// Reserves the blocks of the file upfront, so that large imported files (e.g., multi-GB GPU
+    /// state dumps) are laid out in few extents. The size of the file is left to the data written.
+    fn preallocate(&mut self, size: u64) -> Result<()> {
+        match fallocate(self.as_raw_fd(), FallocateFlags::FALLOC_FL_KEEP_SIZE, 0, size as libc::off_t) {
+            // Not all filesystems support it, preallocating is only an optimization.
+            Err(nix::Error::Sys(Errno::EOPNOTSUPP)) => Ok(()),
+            result => result.map(|_| ()).context("Failed to preallocate file"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ensure_safe_filename() {
+        assert!(ensure_safe_filename("pages-1.img").is_ok());
+        assert!(ensure_safe_filename("container/pages-1.img").is_ok());
+        for filename in &["", "/etc/passwd", "../x", "c/../x", "c/..", "./x", "c//x", "c/", "a/b/c"] {
+            assert!(ensure_safe_filename(filename).is_err(), "{}", filename);
+        }
+    }
 }
@@ -13,28 +13,61 @@
 //  limitations under the License.
 
 use super::{ImageStore, ImageFile};
-use anyhow::Result;
+use anyhow::{Context, Result};
 use std::{
     fs,
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    io::Read,
 };
 use crate::{
     unix_pipe::UnixPipe,
+    manifest::FileHasher,
+    util::ExtFileStat,
 };
 
+/// `Store` streams the external files out to their pipes, and the other files to the underlying
+/// store. The size, and optionally the digest, of the external files are recorded for the stats.
 pub struct Store<'a, UnderlyingStore> {
     underlying_store: &'a mut UnderlyingStore,
     overlayed_files: HashMap<Box<str>, fs::File>,
+    with_digests: bool,
+    ext_file_stats: Vec<ExtFileStat>,
+    /// When the overlayed files are ordered, the ones not fully received yet, in order.
+    ordered_files: Option<VecDeque<Box<str>>>,
 }
 
 impl<'a, UnderlyingStore: ImageStore> Store<'a, UnderlyingStore> {
     pub fn new (underlying_store: &'a mut UnderlyingStore) -> Self {
         let overlayed_files = HashMap::new();
-        Self { underlying_store, overlayed_files }
+        Self {
+            underlying_store, overlayed_files, with_digests: false, ext_file_stats: Vec::new(),
+            ordered_files: None,
+        }
     }
 
     pub fn add_overlay(&mut self, filename: String, file: fs::File) {
-        self.overlayed_files.insert(filename.into_boxed_str(), file);
+        let filename = filename.into_boxed_str();
+        if let Some(ordered_files) = self.ordered_files.as_mut() {
+            ordered_files.push_back(filename.clone());
+        }
+        self.overlayed_files.insert(filename, file);
+    }
+
+    /// Fails when an overlayed file starts before the previously added ones are complete. This
+    /// guarantees that the consumers receive the overlayed files in the order they are added.
+    /// Must be called before adding overlays.
+    pub fn enable_ordering(&mut self) {
+        self.ordered_files = Some(VecDeque::new());
+    }
+
+    /// Computes the digests of the overlayed files. We can no longer splice() their data.
+    pub fn enable_digests(&mut self) {
+        self.with_digests = true;
+    }
+
+    /// Returns the stats of the overlayed files that have been fully received.
+    pub fn into_ext_file_stats(self) -> Vec<ExtFileStat> {
+        self.ext_file_stats
     }
 }
 
@@ -43,24 +76,72 @@ impl<UnderlyingStore: ImageStore> ImageStore for Store<'_, UnderlyingStore> {
 
     fn create(&mut self, filename: &str) -> Result<Self::File> {
         Ok(match self.overlayed_files.remove(filename) {
-            Some(file) => File::Overlayed(file),
-            None       => File::Underlying(self.underlying_store.create(filename)?),
+            Some(file) => {
+                if let Some(ordered_files) = self.ordered_files.as_ref() {
+                    let expected = ordered_files.front().map(|f| &**f).unwrap_or_default();
+                    ensure!(expected == filename,
+                            "The external file `{}` comes before `{}` is complete in the image. \
+                             Ordered external files must be captured with --ordered-ext-files",
+                            filename, expected);
+                }
+                let hasher = if self.with_digests { Some(FileHasher::default()) } else { None };
+                File::Overlayed(OverlayedFile { file, size: 0, hasher })
+            }
+            None => File::Underlying(self.underlying_store.create(filename)?),
         })
     }
 
     fn insert(&mut self, filename: impl Into<Box<str>>, output: Self::File) {
         match output {
-            File::Overlayed(_) => {},
+            File::Overlayed(file) => {
+                let filename = filename.into();
+                if let Some(ordered_files) = self.ordered_files.as_mut() {
+                    ordered_files.retain(|f| *f != filename);
+                }
+                self.ext_file_stats.push(ExtFileStat {
+                    filename: filename.into(),
+                    size: file.size,
+                    sha256: file.hasher.map(|hasher| hasher.finalize().sha256),
+                });
+            }
             File::Underlying(file) => self.underlying_store.insert(filename, file),
         }
     }
 }
 
+pub struct OverlayedFile {
+    file: fs::File,
+    size: u64,
+    hasher: Option<FileHasher>,
+}
+
 pub enum File<UnderlyingFile> {
-    Overlayed(fs::File),
+    Overlayed(OverlayedFile),
     Underlying(UnderlyingFile),
 }
 
+impl ImageFile for OverlayedFile {
+    fn write_all_from_pipe(&mut self, shard_pipe: &mut UnixPipe, size: usize) -> Result<()> {
+        if self.hasher.is_none() {
+            self.size += size as u64;
+            return self.file.write_all_from_pipe(shard_pipe, size);
+        }
+
+        // We need to see the data to compute its digest.
+        let mut buf = vec![0; size];
+        shard_pipe.read_exact(&mut buf).context("Failed to read from shard")?;
+        self.write_all_from_slice(&buf)
+    }
+
+    fn write_all_from_slice(&mut self, buf: &[u8]) -> Result<()> {
+        self.size += buf.len() as u64;
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(buf);
+        }
+        self.file.write_all_from_slice(buf)
+    }
+}
+
 impl<UnderlyingFile: ImageFile> ImageFile for File<UnderlyingFile> {
     fn write_all_from_pipe(&mut self, shard_pipe: &mut UnixPipe, size: usize) -> Result<()> {
         match self {
@@ -68,4 +149,19 @@ impl<UnderlyingFile: ImageFile> ImageFile for File<UnderlyingFile> {
             File::Underlying(file) => file.write_all_from_pipe(shard_pipe, size),
         }
     }
+
+    fn write_all_from_slice(&mut self, buf: &[u8]) -> Result<()> {
+        match self {
+            File::Overlayed(file)  => file.write_all_from_slice(buf),
+            File::Underlying(file) => file.write_all_from_slice(buf),
+        }
+    }
+
+    fn preallocate(&mut self, size: u64) -> Result<()> {
+        match self {
+            // External files are pipes, or files provided by the caller.
+            File::Overlayed(_)     => Ok(()),
+            File::Underlying(file) => file.preallocate(size),
+        }
+    }
 }
@@ -0,0 +1,150 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+
+use super::{ImageStore, ImageFile, mem};
+use anyhow::{Context, Result};
+use std::{
+    io::Write,
+    path::{Component, Path},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tar::{Builder, Header, EntryType};
+use crate::{
+    unix_pipe::UnixPipe,
+    manifest::is_reserved_filename,
+};
+
+// A kubelet checkpoint archive (as produced by the kubelet checkpoint API, and consumed by the
+// container runtime on restore) is a tar file holding the CRIU image files in `checkpoint/`,
+// next to the runtime's own files (e.g., config.dump, spec.dump, rootfs-diff.tar).
+// In the streamed image, the CRIU image files are named as CRIU knows them, so that the image
+// can be served to CRIU directly. The runtime's files are prefixed with `kubelet/`.
+
+const CHECKPOINT_DIR: &str = "checkpoint/";
+const KUBELET_PREFIX: &str = "kubelet/";
+
+/// Returns the name in the image of the file at `path` in the archive. The archive is untrusted,
+/// so paths that are absolute, or that have `.` or `..` components, are rejected.
+pub fn image_filename(path: &Path) -> Result<String> {
+    let components = path.components().map(|component| match component {
+        Component::Normal(name) => name.to_str()
+            .ok_or_else(|| anyhow!("The checkpoint archive has a non UTF-8 path")),
+        _ => bail!("The checkpoint archive has the invalid path `{}`", path.display()),
+    }).collect::<Result<Vec<_>>>()?;
+    let path = components.join("/");
+    Ok(match path.strip_prefix(CHECKPOINT_DIR) {
+        Some(filename) => filename.to_string(),
+        None => format!("{}{}", KUBELET_PREFIX, path),
+    })
+}
+
+/// Returns the path in the archive of the image file `filename`.
+pub fn archive_path(filename: &str) -> String {
+    match filename.strip_prefix(KUBELET_PREFIX) {
+        Some(path) => path.to_string(),
+        None => format!("{}{}", CHECKPOINT_DIR, filename),
+    }
+}
+
+/// `Store` writes the files it receives into a kubelet checkpoint archive. Each file is buffered
+/// in memory until it is complete, as tar needs its size upfront. The manifest of the image is
+/// not part of the archive, and is dropped.
+pub struct Store<W: Write> {
+    builder: Builder<W>,
+    mtime: u64,
+    /// `insert()` can't fail, errors are reported by `finish()`.
+    insert_result: Result<()>,
+}
+
+impl<W: Write> Store<W> {
+    pub fn new(archive: W) -> Result<Self> {
+        let mtime = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut builder = Builder::new(archive);
+        let mut header = new_header(EntryType::Directory, 0o755, mtime, 0);
+        builder.append_data(&mut header, CHECKPOINT_DIR, std::io::empty())
+            .context("Failed to write the checkpoint archive")?;
+        Ok(Self { builder, mtime, insert_result: Ok(()) })
+    }
+
+    /// Terminates the archive, and returns the underlying writer.
+    pub fn finish(self) -> Result<W> {
+        self.insert_result?;
+        self.builder.into_inner().context("Failed to write the checkpoint archive")
+    }
+
+    fn append(&mut self, filename: &str, file: File) -> Result<()> {
+        let mut header = new_header(EntryType::Regular, 0o644, self.mtime, file.size);
+        self.builder.append_data(&mut header, archive_path(filename), file.data.reader())
+            .with_context(|| format!("Failed to write {} into the checkpoint archive", filename))
+    }
+}
+
+fn new_header(entry_type: EntryType, mode: u32, mtime: u64, size: u64) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_mode(mode);
+    header.set_mtime(mtime);
+    header.set_size(size);
+    header
+}
+
+impl<W: Write> ImageStore for Store<W> {
+    type File = File;
+
+    fn create(&mut self, _filename: &str) -> Result<Self::File> {
+        Ok(File { data: mem::File::new_small(), size: 0 })
+    }
+
+    fn insert(&mut self, filename: impl Into<Box<str>>, file: Self::File) {
+        let filename = filename.into();
+        if self.insert_result.is_ok() && !is_reserved_filename(&filename) {
+            self.insert_result = self.append(&filename, file);
+        }
+    }
+}
+
+pub struct File {
+    data: mem::File,
+    size: u64,
+}
+
+impl ImageFile for File {
+    fn write_all_from_pipe(&mut self, shard_pipe: &mut UnixPipe, size: usize) -> Result<()> {
+        self.data.write_all_from_pipe(shard_pipe, size)?;
+        self.size += size as u64;
+        Ok(())
+    }
+
+    fn write_all_from_slice(&mut self, buf: &[u8]) -> Result<()> {
+        self.data.write_all_from_slice(buf)?;
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_filename() -> Result<()> {
+        assert_eq!(image_filename(Path::new("checkpoint/pages-1.img"))?, "pages-1.img");
+        assert_eq!(image_filename(Path::new("config.dump"))?, "kubelet/config.dump");
+        for path in &["../../etc/x", "/etc/x", "checkpoint/../x", "./config.dump"] {
+            assert!(image_filename(Path::new(path)).is_err(), "{}", path);
+        }
+        Ok(())
+    }
+}
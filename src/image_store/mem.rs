@@ -15,7 +15,6 @@
 use super::{ImageStore, ImageFile};
 use anyhow::{Context, Result};
 use std::{
-    fs,
     collections::{VecDeque, HashMap},
     io::{Read, Write, Result as IoResult},
     cmp::min,
@@ -40,7 +39,6 @@ use crate::{
 /// The large chunk size should not be too large (e.g., 100MB) as the chunk size directly
 /// increases our memory overhead while transferring data to CRIU: while CRIU is duplicates the
 /// chunk data into its memory space, the chunk remains allocated until we get to the next chunk.
-
 const MAX_LARGE_CHUNK_SIZE: usize = 10*MB;
 static MAX_SMALL_CHUNK_SIZE: &PAGE_SIZE = &PAGE_SIZE;
 
@@ -53,6 +51,21 @@ impl Store {
     pub fn remove(&mut self, filename: &str) -> Option<File> {
         self.files.remove(filename)
     }
+
+    /// Moves the files whose name starts with `prefix` into a new store, with the prefix stripped
+    /// from their name.
+    pub fn split_off_prefix(&mut self, prefix: &str) -> Self {
+        if prefix.is_empty() {
+            return std::mem::take(self);
+        }
+        let (files, others) = self.files.drain()
+            .partition::<HashMap<_, _>, _>(|(filename, _)| filename.starts_with(prefix));
+        self.files = others;
+        let files = files.into_iter()
+            .map(|(filename, file)| (filename[prefix.len()..].into(), file))
+            .collect();
+        Self { files }
+    }
 }
 
 impl ImageStore for Store {
@@ -86,6 +99,21 @@ impl File {
         Small(Vec::new())
     }
 
+    /// Reserves the memory of a file of `size` bytes, on an empty file. Small files get their
+    /// exact capacity in one allocation, instead of growing as chunks come. Large files get their
+    /// first chunk, sized for the file when it fits.
+    fn reserve_exact(&mut self, size: usize) {
+        match self {
+            Small(chunk) if chunk.is_empty() && size > **MAX_SMALL_CHUNK_SIZE => {
+                let mut chunks = VecDeque::new();
+                chunks.push_back(MmapBuf::with_capacity(min(size, MAX_LARGE_CHUNK_SIZE)));
+                *self = Large(chunks);
+            }
+            Small(chunk) => chunk.reserve_exact(size),
+            Large(_) => {}
+        }
+    }
+
     fn large_from_slice(init_data: &[u8]) -> Self {
         // This function is always used to convert a small file into a large
         // file. There's no panic as `init_data` is a most PAGE_SIZE=4KB, which
@@ -147,16 +175,41 @@ impl File {
         })
     }
 
-    pub fn drain(self, dst: &mut fs::File) -> Result<()> {
+    /// Size of the file, in bytes.
+    pub fn size(&self) -> u64 {
+        match self {
+            Small(chunk) => chunk.len() as u64,
+            Large(chunks) => chunks.iter().map(|chunk| chunk.len() as u64).sum(),
+        }
+    }
+
+    /// Writes the file into `dst`, which must be a non-blocking pipe. `wait_writable` is called
+    /// before each write, and blocks until the pipe has room.
+    pub fn drain(
+        self,
+        dst: &mut UnixPipe,
+        mut wait_writable: impl FnMut(&UnixPipe) -> Result<()>,
+    ) -> Result<()>
+    {
         match self {
-            Small(chunk) => dst.write_all(&chunk)?,
+            Small(chunk) => {
+                let mut data = &chunk[..];
+                while !data.is_empty() {
+                    wait_writable(dst)?;
+                    data = &data[dst.try_writev(&[data])?..];
+                }
+            }
             Large(chunks) => {
                 for chunk in chunks {
                     // We can vmsplice() because the chunk is backed by our mmap buffer.
                     // It will be unmapped after the vmsplice guaranteeing that memory pages
                     // are not going to be recycled and modified, which is a problem for
                     // vmsplice().
-                    dst.vmsplice_all(&chunk)?;
+                    let mut data = &chunk[..];
+                    while !data.is_empty() {
+                        wait_writable(dst)?;
+                        data = &data[dst.try_vmsplice(data)?..];
+                    }
                 }
             }
         };
@@ -164,7 +217,7 @@ impl File {
         Ok(())
     }
 
-    pub fn reader(&self) -> FileReader {
+    pub fn reader(&self) -> FileReader<'_> {
         let chunks = match self {
             Small(chunk) => vec![&chunk[..]].into_iter().collect(),
             Large(chunks) => chunks.iter().map(|chunk| &chunk[..]).collect(),
@@ -182,6 +235,21 @@ impl ImageFile for File {
 
         Ok(())
     }
+
+    fn write_all_from_slice(&mut self, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            let len = buf.len();
+            // copy_from_reader() advances `buf` as it reads from it.
+            self.copy_from_reader(&mut buf, len)?;
+        }
+
+        Ok(())
+    }
+
+    fn preallocate(&mut self, size: u64) -> Result<()> {
+        self.reserve_exact(size as usize);
+        Ok(())
+    }
 }
 
 pub struct FileReader<'a> {
@@ -13,8 +13,15 @@
 //  limitations under the License.
 
 pub mod fs_overlay;
+pub mod digest;
+pub mod events;
 pub mod fs;
+#[cfg(feature = "kubelet")]
+pub mod kubelet;
 pub mod mem;
+pub mod null;
+pub mod serializer;
+pub mod stream;
 
 use anyhow::Result;
 use crate::unix_pipe::UnixPipe;
@@ -22,12 +29,21 @@ use crate::unix_pipe::UnixPipe;
 // The `ImageStore` is only used during image extraction.
 //
 // `ImageDeserializer` in extract.rs outputs the image into an image store, defined here.
-// We have three image stores:
+// We have the following image stores:
 // * `fs::Store`, used to store an image on disk.
 // * `mem::Store`, used to store an image in memory. This is useful to stream the image to
 //   CRIU without touching disk.
 // * `fs_overlay::Store`, used for bypassing certain files (like fs.tar) when extracting to memory.
 //   These special files are passed via the "--ext-files-fds" option on the CLI.
+// * `digest::Store`, used for computing the digest of all files passing through, to verify the
+//   image against its signed manifest.
+// * `null::Store`, used for validating an image without extracting it (`extract --dry-run`).
+// * `serializer::Store`, used for rewriting an image into new shards (`filter`).
+// * `events::Store`, used for reporting the files to the library user's event callback.
+// * `stream::Store`, used for writing the files one after the other to a stream
+//   (`extract --to-stdout`).
+// * `kubelet::Store`, used for writing an image as a kubelet checkpoint archive (`kubelet-export`).
+// Library users can also provide their own store by implementing `DynImageStore`.
 
 // We use a `Box<str>` instead of `String` for filenames to reduce memory usage by 8 bytes per
 // filename. CRIU can generate a lot of files (e.g., one per checkpointed application thread).
@@ -45,4 +61,42 @@ pub trait ImageStore {
 
 pub trait ImageFile {
     fn write_all_from_pipe(&mut self, shard_pipe: &mut UnixPipe, size: usize) -> Result<()>;
+    fn write_all_from_slice(&mut self, buf: &[u8]) -> Result<()>;
+    /// Called on a newly created file when the image tells its size, which only imported files
+    /// do. The file may still receive a different amount of data.
+    fn preallocate(&mut self, _size: u64) -> Result<()> { Ok(()) }
+}
+
+/// `DynImageStore` is the object-safe version of `ImageStore`, for library users streaming the
+/// image files into their own sinks (e.g., a database, or a network service) with
+/// `ExtractBuilder::extract_into()`. `insert()` is called once the file is complete.
+pub trait DynImageStore {
+    fn create(&mut self, filename: &str) -> Result<Box<dyn ImageFile>>;
+    fn insert(&mut self, filename: Box<str>, file: Box<dyn ImageFile>);
+}
+
+impl ImageStore for Box<dyn DynImageStore> {
+    type File = Box<dyn ImageFile>;
+
+    fn create(&mut self, filename: &str) -> Result<Self::File> {
+        (**self).create(filename)
+    }
+
+    fn insert(&mut self, filename: impl Into<Box<str>>, file: Self::File) {
+        (**self).insert(filename.into(), file)
+    }
+}
+
+impl ImageFile for Box<dyn ImageFile> {
+    fn write_all_from_pipe(&mut self, shard_pipe: &mut UnixPipe, size: usize) -> Result<()> {
+        (**self).write_all_from_pipe(shard_pipe, size)
+    }
+
+    fn write_all_from_slice(&mut self, buf: &[u8]) -> Result<()> {
+        (**self).write_all_from_slice(buf)
+    }
+
+    fn preallocate(&mut self, size: u64) -> Result<()> {
+        (**self).preallocate(size)
+    }
 }
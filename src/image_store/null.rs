@@ -0,0 +1,91 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+
+use super::{ImageStore, ImageFile};
+use anyhow::{Context, Result};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Read},
+};
+use crate::unix_pipe::UnixPipe;
+
+/// `Store` discards the content of files, and only records their size. It is used to validate
+/// an image without extracting it. The content of a few files that we need to inspect (e.g., the
+/// manifest) can be retained in memory.
+#[derive(Default)]
+pub struct Store {
+    /// Received files and their size, in the order in which they were completed
+    files: Vec<(Box<str>, u64)>,
+    retained_filenames: HashSet<Box<str>>,
+    retained_files: HashMap<Box<str>, Vec<u8>>,
+}
+
+impl Store {
+    /// Keeps the content of the file with the given name in memory, if it is received.
+    pub fn retain(&mut self, filename: &str) {
+        self.retained_filenames.insert(filename.into());
+    }
+
+    pub fn files(&self) -> &[(Box<str>, u64)] {
+        &self.files
+    }
+
+    pub fn remove_retained(&mut self, filename: &str) -> Option<Vec<u8>> {
+        self.retained_files.remove(filename)
+    }
+}
+
+impl ImageStore for Store {
+    type File = File;
+
+    fn create(&mut self, filename: &str) -> Result<Self::File> {
+        let data = if self.retained_filenames.contains(filename) { Some(Vec::new()) } else { None };
+        Ok(File { size: 0, data })
+    }
+
+    fn insert(&mut self, filename: impl Into<Box<str>>, file: Self::File) {
+        let filename = filename.into();
+        if let Some(data) = file.data {
+            self.retained_files.insert(filename.clone(), data);
+        }
+        self.files.push((filename, file.size));
+    }
+}
+
+pub struct File {
+    size: u64,
+    data: Option<Vec<u8>>,
+}
+
+impl ImageFile for File {
+    fn write_all_from_pipe(&mut self, shard_pipe: &mut UnixPipe, size: usize) -> Result<()> {
+        let mut src = shard_pipe.take(size as u64);
+        let copied = match self.data.as_mut() {
+            Some(data) => src.read_to_end(data).map(|n| n as u64),
+            None => io::copy(&mut src, &mut io::sink()),
+        }.context("Failed to read from shard")?;
+        ensure!(copied == size as u64, "Unexpected EOF while reading from shard");
+        self.size += copied;
+        Ok(())
+    }
+
+    fn write_all_from_slice(&mut self, buf: &[u8]) -> Result<()> {
+        if let Some(data) = self.data.as_mut() {
+            data.extend_from_slice(buf);
+        }
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+}
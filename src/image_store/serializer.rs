@@ -0,0 +1,160 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use super::{ImageStore, ImageFile};
+use anyhow::{Context, Result};
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    io::Read,
+    rc::Rc,
+};
+use crate::{
+    capture::ImageSerializer,
+    unix_pipe::UnixPipe,
+    manifest::{Manifest, FileHasher},
+    util::MB,
+};
+
+/// `Store` writes the files it receives into new shards with an `ImageSerializer`, as they are
+/// received. This is how an image is rewritten without extracting it. Files can be skipped, in
+/// which case their content is discarded.
+/// Files may be written concurrently, so they share the serializer.
+pub struct Store<'a> {
+    img_serializer: Rc<RefCell<ImageSerializer<'a>>>,
+    skipped_filenames: HashSet<Box<str>>,
+    /// The skipped files that were found in the image
+    skipped_files: HashSet<Box<str>>,
+    /// When merging images, the files of the current image, and the files of the previous images
+    filenames: HashSet<Box<str>>,
+    previous_filenames: HashSet<Box<str>>,
+    /// When signing the new image, the digest of each file written is added to the manifest.
+    manifest: Option<Manifest>,
+    /// `insert()` can't fail, errors are reported by `finish()`.
+    insert_result: Result<()>,
+}
+
+impl<'a> Store<'a> {
+    pub fn new(img_serializer: Rc<RefCell<ImageSerializer<'a>>>, with_manifest: bool) -> Self {
+        Self {
+            img_serializer,
+            skipped_filenames: HashSet::new(),
+            skipped_files: HashSet::new(),
+            filenames: HashSet::new(),
+            previous_filenames: HashSet::new(),
+            manifest: if with_manifest { Some(Manifest::default()) } else { None },
+            insert_result: Ok(()),
+        }
+    }
+
+    /// Discards the file with the given name, if it is received.
+    pub fn skip(&mut self, filename: &str) {
+        self.skipped_filenames.insert(filename.into());
+    }
+
+    pub fn skipped_files(&self) -> &HashSet<Box<str>> {
+        &self.skipped_files
+    }
+
+    /// Called when the next files come from another image. Images must not have files in common.
+    pub fn next_image(&mut self) {
+        self.previous_filenames.extend(self.filenames.drain());
+    }
+
+    /// Writes a file that doesn't come from the image, such as a replacement file. Its `size`,
+    /// when known, is recorded in the image for the extraction to preallocate the file.
+    pub fn add_file(&mut self, filename: &str, mut src: impl Read, size: Option<u64>) -> Result<()> {
+        let mut file = self.new_file(filename);
+        if let Some(size) = size {
+            self.img_serializer.borrow_mut().hint_file_size(&file.filename, size);
+        }
+        let mut buf = vec![0; MB];
+        loop {
+            match src.read(&mut buf)? {
+                0 => break,
+                n => file.write_all_from_slice(&buf[..n])?,
+            }
+        }
+        self.insert(filename, file);
+        std::mem::replace(&mut self.insert_result, Ok(()))
+    }
+
+    /// Returns the manifest of the files written, when requested.
+    pub fn finish(self) -> Result<Option<Manifest>> {
+        self.insert_result?;
+        Ok(self.manifest)
+    }
+
+    fn new_file(&self, filename: &str) -> File<'a> {
+        File {
+            filename: Rc::from(filename),
+            img_serializer: Some(Rc::clone(&self.img_serializer)),
+            hasher: self.manifest.as_ref().map(|_| FileHasher::default()),
+        }
+    }
+}
+
+impl<'a> ImageStore for Store<'a> {
+    type File = File<'a>;
+
+    fn create(&mut self, filename: &str) -> Result<Self::File> {
+        if self.skipped_filenames.contains(filename) {
+            self.skipped_files.insert(filename.into());
+            return Ok(File { filename: Rc::from(filename), img_serializer: None, hasher: None });
+        }
+        ensure!(!self.previous_filenames.contains(filename),
+                "{} is in more than one image", filename);
+        self.filenames.insert(filename.into());
+        Ok(self.new_file(filename))
+    }
+
+    fn insert(&mut self, filename: impl Into<Box<str>>, file: Self::File) {
+        let img_serializer = match file.img_serializer {
+            Some(img_serializer) => img_serializer,
+            None => return,
+        };
+        if self.insert_result.is_ok() {
+            self.insert_result = img_serializer.borrow_mut().write_file_eof(&file.filename);
+        }
+        if let (Some(manifest), Some(hasher)) = (self.manifest.as_mut(), file.hasher) {
+            let filename: Box<str> = filename.into();
+            manifest.add_file(&filename, hasher.finalize());
+        }
+    }
+}
+
+pub struct File<'a> {
+    filename: Rc<str>,
+    /// None when the file is skipped
+    img_serializer: Option<Rc<RefCell<ImageSerializer<'a>>>>,
+    hasher: Option<FileHasher>,
+}
+
+impl ImageFile for File<'_> {
+    fn write_all_from_pipe(&mut self, shard_pipe: &mut UnixPipe, size: usize) -> Result<()> {
+        let mut buf = vec![0; size];
+        shard_pipe.read_exact(&mut buf).context("Failed to read from shard")?;
+        self.write_all_from_slice(&buf)
+    }
+
+    fn write_all_from_slice(&mut self, buf: &[u8]) -> Result<()> {
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(buf);
+        }
+        match self.img_serializer.as_ref() {
+            Some(img_serializer) => img_serializer.borrow_mut().write_file_data(&self.filename, buf),
+            None => Ok(()),
+        }
+    }
+}
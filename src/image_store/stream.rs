@@ -0,0 +1,86 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use super::{ImageStore, ImageFile, mem};
+use anyhow::{Context, Result};
+use std::io::{self, Write};
+use crate::unix_pipe::UnixPipe;
+
+/// `Store` writes the files to a stream, one after the other, each framed as
+/// `filename\0size\0data`, where `size` is the length of `data` in decimal. This lets another
+/// process (e.g., a custom storage backend) consume the whole image from a pipe.
+///
+/// The files of the image come interleaved in the shards, so each file is buffered in memory
+/// until it is complete, and written in the order in which the files are completed.
+pub struct Store<W: Write> {
+    out: W,
+    /// The first error writing to `out`. The files that follow are discarded, and the error is
+    /// reported by `finish()`, as `insert()` can't fail.
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Store<W> {
+    pub fn new(out: W) -> Self {
+        Self { out, error: None }
+    }
+
+    /// Flushes the stream. Fails when one of the files could not be written.
+    pub fn finish(mut self) -> Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e).context("Failed to write the image stream");
+        }
+        self.out.flush().context("Failed to write the image stream")
+    }
+
+    fn write_file(&mut self, filename: &str, file: &File) -> io::Result<()> {
+        write!(self.out, "{}\0{}\0", filename, file.size)?;
+        io::copy(&mut file.data.reader(), &mut self.out)?;
+        Ok(())
+    }
+}
+
+impl<W: Write> ImageStore for Store<W> {
+    type File = File;
+
+    fn create(&mut self, _filename: &str) -> Result<Self::File> {
+        Ok(File { data: mem::File::new_small(), size: 0 })
+    }
+
+    fn insert(&mut self, filename: impl Into<Box<str>>, file: Self::File) {
+        if self.error.is_none() {
+            if let Err(e) = self.write_file(&filename.into(), &file) {
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+pub struct File {
+    data: mem::File,
+    size: u64,
+}
+
+impl ImageFile for File {
+    fn write_all_from_pipe(&mut self, shard_pipe: &mut UnixPipe, size: usize) -> Result<()> {
+        self.data.write_all_from_pipe(shard_pipe, size)?;
+        self.size += size as u64;
+        Ok(())
+    }
+
+    fn write_all_from_slice(&mut self, buf: &[u8]) -> Result<()> {
+        self.data.write_all_from_slice(buf)?;
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+}
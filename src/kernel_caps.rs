@@ -0,0 +1,151 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    fs,
+    io::{Read, Write},
+    os::unix::io::{AsRawFd, FromRawFd},
+};
+use nix::{
+    fcntl::{fcntl, FcntlArg, splice, vmsplice, SpliceFFlags},
+    sys::{uio::IoVec, utsname::uname},
+    unistd::pipe,
+    errno::Errno,
+    Error,
+};
+use serde::{Serialize, Deserialize};
+use crate::util::{PAGE_SIZE, MB};
+
+// The streamer relies on a few pipe features that are not available everywhere. Some container
+// runtimes (e.g., gVisor) don't implement splice() or F_SETPIPE_SZ, and some kernels are known to
+// corrupt data going through splice(). Instead of discovering this in the middle of a checkpoint,
+// we probe the kernel once, on first use, and pick safe fallbacks (e.g., copying data through
+// userspace instead of splicing). The decisions are reported in the stats output.
+
+lazy_static::lazy_static! {
+    pub static ref KERNEL_CAPS: KernelCaps = KernelCaps::probe();
+}
+
+/// The largest pipe capacity we'd ever ask for. We don't probe beyond this to avoid holding on to
+/// more pipe buffer pages than necessary.
+const PROBE_MAX_PIPE_CAPACITY: i32 = 4*MB as i32;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KernelCaps {
+    /// Kernel release, as reported by uname()
+    pub release: String,
+    /// Largest pipe capacity that F_SETPIPE_SZ accepts, capped to a few MBs.
+    /// None when pipes cannot be resized, in which case pipes are used with their default size.
+    pub max_pipe_capacity: Option<i32>,
+    /// The kernel release is known to corrupt data going through splice()
+    pub splice_bug: bool,
+    /// Data is spliced between pipes and files. Otherwise, it is copied through userspace.
+    pub splice: bool,
+    /// Data is vmspliced from memory into pipes. Otherwise, it is copied with write().
+    pub vmsplice: bool,
+}
+
+impl KernelCaps {
+    pub fn probe() -> Self {
+        let release = uname().release().to_string();
+        let splice_bug = is_splice_bug_release(&release);
+        Self {
+            max_pipe_capacity: probe_max_pipe_capacity(),
+            splice: !splice_bug && probe_splice(),
+            vmsplice: !splice_bug && probe_vmsplice(),
+            splice_bug,
+            release,
+        }
+    }
+}
+
+impl KernelCaps {
+    /// Returns a warning when the kernel is known to corrupt data going through splice(). We copy
+    /// data through userspace instead, but CRIU may still splice data in and out of our pipes.
+    pub fn splice_bug_warning(&self) -> Option<String> {
+        if !self.splice_bug {
+            return None;
+        }
+        Some(format!("Kernel {} is known to corrupt data going through splice(). \
+                      The streamer copies data through userspace instead, at reduced performance, \
+                      but CRIU's own use of splice() remains exposed. Upgrade to a kernel release with the fix",
+                     self.release))
+    }
+}
+
+fn new_pipe() -> Option<(fs::File, fs::File)> {
+    let (fd_r, fd_w) = pipe().ok()?;
+    unsafe { Some((fs::File::from_raw_fd(fd_r), fs::File::from_raw_fd(fd_w))) }
+}
+
+fn probe_max_pipe_capacity() -> Option<i32> {
+    let (pipe_r, _pipe_w) = new_pipe()?;
+
+    // /proc/sys/fs/pipe-max-size is the limit for unprivileged users. Privileged users may go
+    // beyond, which is why we start from our own maximum.
+    let mut capacity = PROBE_MAX_PIPE_CAPACITY;
+    loop {
+        match fcntl(pipe_r.as_raw_fd(), FcntlArg::F_SETPIPE_SZ(capacity)) {
+            Ok(actual_capacity) => return Some(actual_capacity),
+            // EPERM is returned when exceeding pipe-max-size, or the pipe-user-pages limits.
+            Err(Error::Sys(Errno::EPERM)) if capacity > *PAGE_SIZE as i32 => capacity /= 2,
+            Err(_) => return None,
+        }
+    }
+}
+
+/// Moves a few bytes from one pipe to another with splice(), and checks that they come out intact.
+fn probe_splice() -> bool {
+    let probe = || -> Option<bool> {
+        let (src_r, mut src_w) = new_pipe()?;
+        let (mut dst_r, dst_w) = new_pipe()?;
+        let data = b"splice probe";
+        src_w.write_all(data).ok()?;
+        let n = splice(src_r.as_raw_fd(), None, dst_w.as_raw_fd(), None,
+                       data.len(), SpliceFFlags::empty()).ok()?;
+        drop(dst_w);
+        let mut buf = Vec::new();
+        dst_r.read_to_end(&mut buf).ok()?;
+        Some(n == data.len() && buf == data)
+    };
+    probe().unwrap_or(false)
+}
+
+/// Moves a few bytes from memory into a pipe with vmsplice(), and checks that they come out intact.
+fn probe_vmsplice() -> bool {
+    let probe = || -> Option<bool> {
+        let (mut pipe_r, pipe_w) = new_pipe()?;
+        let data = b"vmsplice probe";
+        let n = vmsplice(pipe_w.as_raw_fd(), &[IoVec::from_slice(data)], SpliceFFlags::empty()).ok()?;
+        drop(pipe_w);
+        let mut buf = Vec::new();
+        pipe_r.read_to_end(&mut buf).ok()?;
+        Some(n == data.len() && buf == data)
+    };
+    probe().unwrap_or(false)
+}
+
+/// Data corruption with splice() has been observed with 4.14.67 and 4.15.0-1037 kernels, but
+/// not with 4.14.121. The fix appears to be https://github.com/torvalds/linux/commit/1bdc347.
+/// The corruption is intermittent, so it can't be reliably probed; we go by the release instead.
+fn is_splice_bug_release(release: &str) -> bool {
+    let mut parts = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|n| n.parse::<u32>().ok());
+    match (parts.next().flatten(), parts.next().flatten(), parts.next().flatten()) {
+        (Some(4), Some(14), Some(sublevel)) => sublevel < 121,
+        (Some(4), Some(15), _) => true,
+        _ => false,
+    }
+}
@@ -27,10 +27,48 @@ pub mod extract;
 pub mod poller;
 pub mod unix_pipe;
 pub mod criu_connection;
+pub mod criu_trace;
+pub mod criu_check;
+pub mod criu_watchdog;
 pub mod ord_by;
 pub mod image_patcher;
 pub mod image_store;
 pub mod mmap_buf;
+pub mod manifest;
+pub mod audit;
+pub mod bench;
+pub mod replay;
+pub mod kernel_caps;
+pub mod page_stats;
+pub mod show;
+pub mod events;
+pub mod shard_reader;
+pub mod shard_fifos;
+pub mod shard_devices;
+pub mod shard_mux;
+pub mod shard_cmd;
+pub mod rate_limit;
+pub mod capabilities;
+pub mod doctor;
+pub mod marker_log;
+pub mod file_table;
+pub mod reflink;
+pub mod snapshot;
+pub mod logger;
+pub mod codec;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "fault-injection")]
+pub mod fault_injection;
+#[cfg(feature = "otel")]
+pub mod telemetry;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
+
+// The entry points for embedding the streamer.
+pub use capture::CaptureBuilder;
+pub use extract::ExtractBuilder;
+pub use shard_reader::ShardReader;
 
 // Protobufs definitions are defined in ../proto/
 #[allow(clippy::all)]
@@ -41,3 +79,7 @@ pub mod criu {
 pub mod image {
     include!(concat!(env!("OUT_DIR"), "/image.rs"));
 }
+#[allow(clippy::all)]
+pub mod stats {
+    include!(concat!(env!("OUT_DIR"), "/stats.rs"));
+}
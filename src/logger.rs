@@ -0,0 +1,271 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    io::Write,
+    os::unix::net::UnixDatagram,
+    path::Path,
+    str::FromStr,
+    sync::Mutex,
+};
+use crate::{
+    events::{Event, EventCallback},
+    util::Phase,
+};
+use anyhow::{Result, Context};
+
+// The streamer logs its warnings and errors on stderr by default. Production hosts collect logs
+// with journald or syslog, where messages from concurrent streamers are told apart by their
+// fields. With --log-target, messages are sent to the local journald or syslog socket instead,
+// along with the operation, the job id, the image UUID, and the phase, as far as they are known.
+// journald gets them as fields of its native protocol, and syslog as key=value pairs following
+// the message.
+//
+// The progress pipe defaults to stderr, so informational messages (phases, image complete) are
+// only logged to journald and syslog.
+
+pub const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+pub const SYSLOG_SOCKET: &str = "/dev/log";
+
+const IDENTIFIER: &str = "criu-image-streamer";
+/// The syslog facility of the messages, LOG_USER.
+const SYSLOG_FACILITY: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogTarget {
+    Stderr,
+    Journald,
+    Syslog,
+}
+
+impl FromStr for LogTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "stderr" => LogTarget::Stderr,
+            "journald" => LogTarget::Journald,
+            "syslog" => LogTarget::Syslog,
+            _ => bail!("Unknown log target `{}`", s),
+        })
+    }
+}
+
+/// Severity of a message, with the values of syslog.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Error = 3,
+    Warning = 4,
+    Info = 6,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "Error",
+            Level::Warning => "Warning",
+            Level::Info => "Info",
+        }
+    }
+}
+
+/// What the messages are about, reported with each message.
+#[derive(Default, Debug, Clone)]
+pub struct LogContext {
+    pub operation: Option<String>,
+    pub job_id: Option<String>,
+    pub image_uuid: Option<String>,
+    pub phase: Option<Phase>,
+}
+
+impl LogContext {
+    /// Returns the fields of the context that are known, named as in journald.
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = Vec::new();
+        if let Some(operation) = &self.operation {
+            fields.push(("OPERATION", operation.clone()));
+        }
+        if let Some(job_id) = &self.job_id {
+            fields.push(("JOB_ID", job_id.clone()));
+        }
+        if let Some(image_uuid) = &self.image_uuid {
+            fields.push(("IMAGE_UUID", image_uuid.clone()));
+        }
+        if let Some(phase) = self.phase {
+            fields.push(("PHASE", phase.name().to_string()));
+        }
+        fields
+    }
+}
+
+pub struct Logger {
+    target: LogTarget,
+    /// Connected to the journald or syslog socket
+    socket: Option<UnixDatagram>,
+    pub context: LogContext,
+}
+
+impl Logger {
+    pub fn stderr() -> Self {
+        Self { target: LogTarget::Stderr, socket: None, context: LogContext::default() }
+    }
+
+    /// Returns a logger sending to the journald or syslog socket at `socket_path`. The socket
+    /// paths of the host are `JOURNALD_SOCKET` and `SYSLOG_SOCKET`.
+    pub fn connect(target: LogTarget, socket_path: &Path) -> Result<Self> {
+        ensure!(target != LogTarget::Stderr, "stderr has no socket");
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(socket_path)
+            .with_context(|| format!("Failed to connect to the {:?} socket {}", target, socket_path.display()))?;
+        Ok(Self { target, socket: Some(socket), context: LogContext::default() })
+    }
+
+    /// Returns the logger of the host for `target`.
+    pub fn new(target: LogTarget) -> Result<Self> {
+        match target {
+            LogTarget::Stderr => Ok(Self::stderr()),
+            LogTarget::Journald => Self::connect(target, Path::new(JOURNALD_SOCKET)),
+            LogTarget::Syslog => Self::connect(target, Path::new(SYSLOG_SOCKET)),
+        }
+    }
+
+    pub fn log(&self, level: Level, message: &str) {
+        let fields = self.context.fields();
+        let datagram = match self.target {
+            LogTarget::Stderr => {
+                if level != Level::Info {
+                    eprintln!("{} {}: {}", IDENTIFIER, level.name(), message);
+                }
+                return;
+            }
+            LogTarget::Journald => encode_journald(level, message, &fields),
+            LogTarget::Syslog => encode_syslog(level, message, &fields),
+        };
+        // Logging must not fail the operation. A message that can't be logged goes to stderr.
+        let sent = self.socket.as_ref().map(|socket| socket.send(&datagram));
+        if !matches!(sent, Some(Ok(_))) && level != Level::Info {
+            eprintln!("{} {}: {}", IDENTIFIER, level.name(), message);
+        }
+    }
+}
+
+/// Appends a field in the native journald protocol. Values containing a newline are written with
+/// their length, as they can't be terminated by a newline.
+fn push_journald_field(datagram: &mut Vec<u8>, name: &str, value: &str) {
+    datagram.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        datagram.push(b'\n');
+        datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        datagram.push(b'=');
+    }
+    datagram.extend_from_slice(value.as_bytes());
+    datagram.push(b'\n');
+}
+
+fn encode_journald(level: Level, message: &str, fields: &[(&str, String)]) -> Vec<u8> {
+    let mut datagram = Vec::new();
+    push_journald_field(&mut datagram, "MESSAGE", message);
+    push_journald_field(&mut datagram, "PRIORITY", &(level as u8).to_string());
+    push_journald_field(&mut datagram, "SYSLOG_IDENTIFIER", IDENTIFIER);
+    for (name, value) in fields {
+        push_journald_field(&mut datagram, name, value);
+    }
+    datagram
+}
+
+/// Encodes the message in the RFC 3164 format, which the local syslog daemons accept without a
+/// timestamp. Fields follow the message as key=value pairs.
+fn encode_syslog(level: Level, message: &str, fields: &[(&str, String)]) -> Vec<u8> {
+    let mut datagram = Vec::new();
+    let priority = SYSLOG_FACILITY * 8 + level as u8;
+    let _ = write!(datagram, "<{}>{}[{}]: {}", priority, IDENTIFIER, std::process::id(), message.replace('\n', " "));
+    for (name, value) in fields {
+        let _ = write!(datagram, " {}={}", name.to_lowercase(), value);
+    }
+    datagram
+}
+
+lazy_static::lazy_static! {
+    static ref LOGGER: Mutex<Logger> = Mutex::new(Logger::stderr());
+}
+
+/// Replaces the logger of the process, stderr by default.
+pub fn set_logger(logger: Logger) {
+    *LOGGER.lock().unwrap() = logger;
+}
+
+/// Logs with the logger of the process.
+pub fn log(level: Level, message: &str) {
+    LOGGER.lock().unwrap().log(level, message);
+}
+
+/// Returns the event callback that follows the phase and the image UUID for the log context, and
+/// logs the phases and the completion of the image.
+pub fn log_event_callback() -> EventCallback {
+    Box::new(|event| {
+        let mut logger = LOGGER.lock().unwrap();
+        match event {
+            Event::PhaseStart { phase } => {
+                logger.context.phase = Some(phase);
+                logger.log(Level::Info, &format!("Entering the {} phase", phase.name()));
+            }
+            Event::ImageComplete { stats } => {
+                logger.context.image_uuid = stats.image_uuid.clone();
+                let size: u64 = stats.shards.iter().map(|s| s.size).sum();
+                let pipe_capacities: Vec<String> = stats.shards.iter()
+                    .map(|s| s.pipe_capacity.map_or_else(|| "?".to_string(), |c| c.to_string()))
+                    .collect();
+                logger.log(Level::Info, &format!("Image complete, {} bytes in {} shards, shard pipe capacities: {}",
+                                                 size, stats.shards.len(), pipe_capacities.join(", ")));
+            }
+            Event::FileStart { .. } | Event::FileComplete { .. } => {}
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journald() -> Result<()> {
+        let socket_path = Path::new("/tmp/test-criu-image-streamer-journald.sock");
+        let _ = std::fs::remove_file(socket_path);
+        let journald = UnixDatagram::bind(socket_path)?;
+
+        let mut logger = Logger::connect(LogTarget::Journald, socket_path)?;
+        logger.context.job_id = Some("job-1".to_string());
+        logger.context.phase = Some(Phase::Serving);
+        logger.log(Level::Error, "two\nlines");
+
+        let mut buf = vec![0; 4096];
+        let len = journald.recv(&mut buf)?;
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"two\nlines\n");
+        expected.extend_from_slice(b"PRIORITY=3\nSYSLOG_IDENTIFIER=criu-image-streamer\nJOB_ID=job-1\nPHASE=serving\n");
+        assert_eq!(&buf[..len], expected.as_slice());
+        std::fs::remove_file(socket_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_syslog() {
+        let fields = [("IMAGE_UUID", "1234".to_string())];
+        let datagram = encode_syslog(Level::Warning, "slow shard", &fields);
+        assert_eq!(String::from_utf8(datagram).unwrap(),
+                   format!("<12>criu-image-streamer[{}]: slow shard image_uuid=1234", std::process::id()));
+    }
+}
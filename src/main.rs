@@ -23,15 +23,57 @@ extern crate anyhow;
 
 use std::{
     os::unix::io::FromRawFd,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    ops::RangeInclusive,
+    collections::HashSet,
+    time::Duration,
+    io::BufWriter,
+    ffi::OsString,
+    cmp::min,
     fs,
 };
-use structopt::{StructOpt, clap::AppSettings};
+#[cfg(feature = "config")]
+use std::ffi::OsStr;
+use structopt::{StructOpt, clap::{AppSettings, Shell}};
 use criu_image_streamer::{
     unix_pipe::{UnixPipe, UnixPipeImpl},
-    capture::capture,
-    extract::{serve, extract},
+    capture::{CaptureBuilder, DEFAULT_EPOLL_CAPACITY, MAX_CHUNK_ALIGNMENT},
+    extract::{ExtractBuilder, extract_img_file, filter, merge, import_stream},
+    bench::{bench, Workload},
+    replay::replay,
+    show::show_img,
+    capabilities::capabilities,
+    doctor::{doctor, print_report, pipe_max_size, Status},
+    criu_trace::CriuTrace,
+    marker_log::MarkerLog,
+    file_table::{read_file_table, print_file_table},
+    codec::{ExternalCodec, gzip_codec, encode_shards, decode_shards, finish_codec_stages},
+    criu_check::check_criu,
+    kernel_caps::KERNEL_CAPS,
+    shard_fifos::{open_shard_fifos, FifoDirection},
+    shard_devices::{open_shard_devices, wait_shard_devices},
+    shard_mux::{mux_shards, demux_shards, wait_shard_mux},
+    shard_cmd::{ShardCmd, SshUrl, spawn_shard_cmds, finish_shard_cmds},
+    rate_limit::{RateLimit, mb_per_sec},
+    manifest::Manifest,
+    audit::AuditLog,
+    image_store::fs::Ownership,
+    events::{Event, EventCallback},
+    logger::{Logger, LogTarget, Level, set_logger, log, log_event_callback},
+    util::{set_progress_format, set_cpu_affinity, set_nice, ProgressFormat,
+           DEFAULT_MAX_PB_SIZE, MIN_MAX_PB_SIZE},
 };
+#[cfg(feature = "grpc")]
+use criu_image_streamer::grpc::serve_grpc;
+#[cfg(feature = "kubelet")]
+use criu_image_streamer::extract::{import_kubelet_checkpoint, export_kubelet_checkpoint};
+#[cfg(feature = "signing")]
+use criu_image_streamer::manifest::{load_signing_key, load_verifying_key};
+#[cfg(not(feature = "signing"))]
+use criu_image_streamer::manifest::{SigningKey, VerifyingKey};
+#[cfg(feature = "otel")]
+use criu_image_streamer::telemetry::{init_telemetry, telemetry_event_callback, start_shard_cmd_spans,
+                                    finish_telemetry};
 use nix::unistd::dup;
 use anyhow::{Result, Context};
 
@@ -47,6 +89,32 @@ fn parse_ext_fd(s: &str) -> Result<(String, i32)> {
     })
 }
 
+fn parse_replace(s: &str) -> Result<(String, PathBuf)> {
+    let mut parts = s.splitn(2, ':');
+    Ok(match (parts.next(), parts.next()) {
+        (Some(filename), Some(path)) if !filename.is_empty() && !path.is_empty() =>
+            (filename.to_string(), PathBuf::from(path)),
+        _ => bail!("Format is filename:path")
+    })
+}
+
+fn parse_fds(s: &str) -> Result<Vec<i32>> {
+    s.split(',')
+        .map(|fd| fd.parse().context("Provided fd is not an integer"))
+        .collect()
+}
+
+fn parse_container(s: &str) -> Result<String> {
+    ensure!(!s.is_empty() && s != "." && s != ".." && !s.contains('/'),
+            "Container names must be valid directory names");
+    Ok(s.to_string())
+}
+
+fn parse_job_id(s: &str) -> Result<String> {
+    ensure!(!s.is_empty() && !s.contains('/'), "The job id must be a valid filename");
+    Ok(s.to_string())
+}
+
 fn parse_port_remap(s: &str) -> Result<(u16, u16)> {
     let mut parts = s.split(':');
     Ok(match (parts.next(), parts.next(), parts.next()) {
@@ -59,6 +127,162 @@ fn parse_port_remap(s: &str) -> Result<(u16, u16)> {
     })
 }
 
+fn parse_owner(s: &str) -> Result<(u32, u32)> {
+    let mut parts = s.split(':');
+    Ok(match (parts.next(), parts.next(), parts.next()) {
+        (Some(uid), Some(gid), None) => {
+            let uid = uid.parse().context("Provided uid is not an integer")?;
+            let gid = gid.parse().context("Provided gid is not an integer")?;
+            (uid, gid)
+        },
+        _ => bail!("Format is uid:gid")
+    })
+}
+
+fn parse_mode(s: &str) -> Result<u32> {
+    let mode = u32::from_str_radix(s, 8).context("Provided mode is not an octal integer")?;
+    ensure!(mode <= 0o7777, "Provided mode must be at most 7777");
+    Ok(mode)
+}
+
+fn parse_cpu_range(s: &str) -> Result<RangeInclusive<usize>> {
+    let mut parts = s.split('-');
+    Ok(match (parts.next(), parts.next(), parts.next()) {
+        (Some(cpu), None, None) => {
+            let cpu = cpu.parse().context("Provided cpu is not an integer")?;
+            cpu..=cpu
+        },
+        (Some(first), Some(last), None) => {
+            let first = first.parse().context("Provided first cpu is not an integer")?;
+            let last = last.parse().context("Provided last cpu is not an integer")?;
+            ensure!(first <= last, "The first cpu of a range must not exceed the last one");
+            first..=last
+        },
+        _ => bail!("Format is cpu or first_cpu-last_cpu")
+    })
+}
+
+/// Returns the environment variable of an option, e.g., CRIU_IMG_STREAMER_IMAGES_DIR for
+/// --images-dir.
+fn option_env_var(option: &str) -> String {
+    format!("CRIU_IMG_STREAMER_{}", option.to_uppercase().replace('-', "_"))
+}
+
+/// Options taking a value that can be provided by the config file. These are the options that
+/// apply to all operations and read their environment variable, which leaves out --config, and
+/// the OpenTelemetry options that use the standard variables.
+// clap has no public way to list the arguments of an app, its parser is reachable nonetheless.
+#[cfg(feature = "config")]
+fn config_options() -> Vec<&'static str> {
+    Opts::clap().p.opts()
+        .filter_map(|opt| opt.s.long.filter(|&long| long != "config" &&
+            opt.v.env.as_ref().is_some_and(|(env, _)| *env == OsStr::new(&option_env_var(long)))))
+        .collect()
+}
+
+/// Flags that apply to all operations, which can be provided by the config file, or the
+/// environment. clap only reads the environment of options taking a value, the flags are read
+/// with `env_flag_args()`.
+fn config_flags() -> Vec<&'static str> {
+    Opts::clap().p.flags()
+        .filter_map(|flag| flag.s.long)
+        .filter(|&long| long != "help" && long != "version")
+        .collect()
+}
+
+/// Returns the flags set to true in the environment, e.g., CRIU_IMG_STREAMER_MUX_SHARDS=true for
+/// --mux-shards, as command line arguments. Flags that are already in `args` are skipped.
+fn env_flag_args(args: &[OsString], env_var: impl Fn(&str) -> Option<OsString>) -> Result<Vec<OsString>> {
+    let mut flag_args = Vec::new();
+    for flag in config_flags() {
+        let name = option_env_var(flag);
+        let set = match env_var(&name) {
+            None => continue,
+            Some(value) => match value.to_str() {
+                Some("true") => true,
+                Some("false") => false,
+                _ => bail!("{} must be true or false", name),
+            },
+        };
+        let flag_arg = OsString::from(format!("--{}", flag));
+        if set && !args.contains(&flag_arg) {
+            flag_args.push(flag_arg);
+        }
+    }
+    Ok(flag_args)
+}
+
+/// Parses the command line, with the flags set in the environment.
+fn parse_opts() -> Result<Opts> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let flag_args = env_flag_args(&args, |name| std::env::var_os(name))?;
+    let (prog, args) = args.split_at(min(1, args.len()));
+    Ok(Opts::from_iter(prog.iter().cloned().chain(flag_args).chain(args.iter().cloned())))
+}
+
+/// Returns the environment variables, and their values, that provide the options of the TOML
+/// `config`. Lists are comma separated, as on the command line.
+#[cfg(feature = "config")]
+fn config_env_vars(config: &str) -> Result<Vec<(String, String)>> {
+    let config: toml::value::Table = toml::from_str(config)?;
+    let (options, flags) = (config_options(), config_flags());
+    config.into_iter().map(|(option, value)| {
+        if flags.contains(&option.as_str()) {
+            return match value {
+                toml::Value::Boolean(value) => Ok((option_env_var(&option), value.to_string())),
+                _ => bail!("The value of `{}` must be a boolean", option),
+            };
+        }
+        ensure!(options.contains(&option.as_str()), "Unknown option `{}`", option);
+        let to_string = |value: toml::Value| match value {
+            toml::Value::String(value) => Ok(value),
+            toml::Value::Integer(value) => Ok(value.to_string()),
+            _ => bail!("The value of `{}` must be a string, an integer, or a list", option),
+        };
+        let value = match value {
+            toml::Value::Array(values) => values.into_iter()
+                .map(to_string)
+                .collect::<Result<Vec<_>>>()?
+                .join(","),
+            value => to_string(value)?,
+        };
+        Ok((option_env_var(&option), value))
+    }).collect()
+}
+
+/// Loads the config file, when provided with --config or CRIU_IMG_STREAMER_CONFIG, into the
+/// environment variables of its options. Variables that are already set are left untouched, as
+/// the environment takes precedence over the config file. This must be done before parsing the
+/// command line, and before spawning any thread.
+#[cfg(feature = "config")]
+fn load_config() -> Result<()> {
+    // The command line is parsed after the config file is loaded, we look for --config ourselves.
+    let mut args = std::env::args_os().skip(1);
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            path = args.next().map(PathBuf::from);
+        } else if let Some(arg) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            path = Some(PathBuf::from(arg));
+        }
+    }
+    let path = match path.or_else(|| std::env::var_os("CRIU_IMG_STREAMER_CONFIG").map(PathBuf::from)) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    let config = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let env_vars = config_env_vars(&config)
+        .with_context(|| format!("Failed to load {}", path.display()))?;
+    for (name, value) in env_vars {
+        if std::env::var_os(&name).is_none() {
+            std::env::set_var(name, value);
+        }
+    }
+    Ok(())
+}
+
 #[derive(StructOpt, PartialEq, Debug)]
 #[structopt(about,
     // When showing --help, we want to keep the order of arguments defined
@@ -71,34 +295,339 @@ fn parse_port_remap(s: &str) -> Result<(u16, u16)> {
 )]
 struct Opts {
     /// Images directory where the CRIU UNIX socket is created during streaming operations.
+    /// Required by all operations except capabilities and completions.
     // The short option -D mimics CRIU's short option for its --images-dir argument.
-    #[structopt(short = "D", long)]
-    images_dir: PathBuf,
+    #[structopt(short = "D", long, env = "CRIU_IMG_STREAMER_IMAGES_DIR")]
+    images_dir: Option<PathBuf>,
 
     /// File descriptors of shards. Multiple fds may be passed as a comma separated list.
     /// Defaults to 0 or 1 depending on the operation.
     // require_delimiter is set to avoid clap's non-standard way of accepting lists.
-    #[structopt(short, long, require_delimiter = true)]
+    #[structopt(short, long, require_delimiter = true, env = "CRIU_IMG_STREAMER_SHARD_FDS")]
     shard_fds: Vec<i32>,
 
+    /// Create the shards as named pipes at the provided path, and open them, e.g., to share them
+    /// with a sidecar uploader through a volume. `{n}` in the path is replaced by the shard
+    /// index, starting at 0. Existing fifos are reused. Cannot be used with --shard-fds.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_SHARD_FIFOS")]
+    shard_fifos: Option<String>,
+
+    /// Number of shards created with --shard-fifos, --shard-cmd, --rclone, --ssh, or
+    /// --mux-shards. Defaults to 1.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_NUM_SHARDS")]
+    num_shards: Option<usize>,
+
+    /// Pipe the data of each shard through the provided shell command, one process per shard,
+    /// e.g., `zstd` when capturing and `zstd -d` when extracting, to plug in codecs that the
+    /// programs at the other end of the shards don't provide. Cannot be used with the filter and
+    /// merge operations.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_CODEC_CMD")]
+    codec_cmd: Option<String>,
+
+    /// Compress the shards with the provided codec. `gzip` runs on the fastest implementation
+    /// found on the host: QAT (qzip) when a QAT device is present, then ISA-L (igzip), then
+    /// gzip. Images can be extracted with any of them. Cannot be used with --codec-cmd, nor with
+    /// the filter and merge operations.
+    #[structopt(long, possible_values = &["gzip"], env = "CRIU_IMG_STREAMER_CODEC")]
+    codec: Option<String>,
+
+    /// Write the shards to, or read them from, the provided block devices, one shard per device,
+    /// e.g., dedicated NVMe namespaces. Preallocated regular files are accepted as well. Multiple
+    /// devices may be passed as a comma separated list. Cannot be used with --shard-fds nor
+    /// --shard-fifos.
+    #[structopt(long, require_delimiter = true, env = "CRIU_IMG_STREAMER_SHARD_DEVICES")]
+    shard_devices: Vec<PathBuf>,
+
+    /// Multiplex the shards over a single stream, in frames tagged with their shard index, for
+    /// transports that carry a single byte stream, e.g., one ssh connection. The stream is the
+    /// shard fd, which may be any file. The number of shards is set with --num-shards when
+    /// writing the image, and is read from the stream otherwise. Cannot be used with
+    /// --shard-fifos, --shard-devices, --shard-cmd, nor with the merge operation.
+    #[structopt(long)]
+    mux_shards: bool,
+
+    /// Run the provided shell command once per shard to transfer it, e.g., an uploader reading
+    /// the shard on its stdin when capturing, or a downloader writing it on its stdout when
+    /// extracting. `{n}` in the command is replaced by the shard index, starting at 0. Cannot be
+    /// used with --shard-fds, --shard-fifos, nor --shard-devices.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_SHARD_CMD")]
+    shard_cmd: Option<String>,
+
+    /// Store the shards with rclone in the provided remote directory, e.g., s3:bucket/ckpt, as
+    /// shard-0, shard-1, etc. Each shard is uploaded with `rclone rcat` when capturing, and
+    /// downloaded with `rclone cat` when extracting. Cannot be used with --shard-cmd.
+    #[structopt(long, conflicts_with = "shard-cmd", env = "CRIU_IMG_STREAMER_RCLONE")]
+    rclone: Option<String>,
+
+    /// Store the shards with ssh in the provided remote directory, given as
+    /// ssh://[user@]host[:port]/path, as shard-0, shard-1, etc. Each shard is streamed with
+    /// `cat` on the remote host. ssh runs in batch mode, so authentication must not prompt.
+    /// Cannot be used with --shard-cmd nor --rclone.
+    #[structopt(long, parse(try_from_str=SshUrl::parse), conflicts_with_all = &["shard-cmd", "rclone"],
+                env = "CRIU_IMG_STREAMER_SSH")]
+    ssh: Option<SshUrl>,
+
     /// External files to incorporate/extract in/from the image. Format is filename:fd
     /// where filename corresponds to the name of the file, fd corresponds to the pipe
     /// sending or receiving the file content. Multiple external files may be passed as
     /// a comma separated list.
-    #[structopt(short, long, parse(try_from_str=parse_ext_fd), require_delimiter = true)]
+    #[structopt(short, long, parse(try_from_str=parse_ext_fd), require_delimiter = true,
+                env = "CRIU_IMG_STREAMER_EXT_FILE_FDS")]
     ext_file_fds: Vec<(String, i32)>,
 
     /// File descriptor where to report progress. Defaults to 2.
     // The default being 2 is a bit of a lie. We dup(STDOUT_FILENO) due to ownership issues.
-    #[structopt(short, long)]
+    #[structopt(short, long, env = "CRIU_IMG_STREAMER_PROGRESS_FD")]
     progress_fd: Option<i32>,
 
+    /// Format of the progress messages. `json` writes one message per line, the stats being a
+    /// JSON object. `proto` writes length-delimited protobuf messages (proto/stats.proto), the
+    /// stats being a protobuf message. Defaults to json.
+    #[structopt(long, possible_values = &["json", "proto"], env = "CRIU_IMG_STREAMER_PROGRESS_FORMAT")]
+    progress_format: Option<String>,
+
+    /// Where to log warnings and errors. `journald` and `syslog` send them to the local socket of
+    /// the daemon, with the operation, job id, image UUID, and phase as structured fields, and
+    /// log the phases and the completion of the image as well. Defaults to stderr.
+    #[structopt(long, possible_values = &["stderr", "journald", "syslog"], env = "CRIU_IMG_STREAMER_LOG_TARGET")]
+    log_target: Option<String>,
+
     /// When serving the image, remap on the fly the TCP listen socket ports.
     /// Format is old_port:new_port. May only be used with the serve operation.
     /// Multiple tcp port remaps may be passed as a comma separated list.
-    #[structopt(long, parse(try_from_str=parse_port_remap), require_delimiter = true)]
+    #[structopt(long, parse(try_from_str=parse_port_remap), require_delimiter = true,
+                env = "CRIU_IMG_STREAMER_TCP_LISTEN_REMAP")]
     tcp_listen_remap: Vec<(u16, u16)>,
 
+    /// Sign the image manifest with the provided ed25519 private key (PKCS#8 PEM file).
+    /// The manifest lists the size and sha256 digest of each file of the image.
+    /// May only be used with the capture operation.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_SIGN_KEY")]
+    sign_key: Option<PathBuf>,
+
+    /// Verify the image manifest signature with the provided ed25519 public key (PEM file), and
+    /// verify each file of the image against the manifest. Image files are not given to CRIU
+    /// until verification succeeds, and extracted files are removed when it fails. May only be
+    /// used with the serve and extract operations.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_VERIFY_KEY")]
+    verify_key: Option<PathBuf>,
+
+    /// Append an audit record for each file captured or served to the provided file.
+    /// Records are JSON formatted with the filename, size, sha256 digest, timestamp and
+    /// direction of the file.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_AUDIT_LOG")]
+    audit_log: Option<PathBuf>,
+
+    /// Same as --audit-log, with the records emitted on the progress fd instead of a file.
+    /// With --progress-format proto, each record is a `line` progress message.
+    /// Cannot be used with --audit-log.
+    #[structopt(long, conflicts_with = "audit-log")]
+    audit_log_to_progress: bool,
+
+    /// Maximum size in bytes of the protobuf markers exchanged with CRIU and over the shards.
+    /// Larger markers are treated as stream corruption. Defaults to 65536, which accommodates
+    /// long filenames, and must be at least 8192.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_MAX_MARKER_SIZE")]
+    max_marker_size: Option<usize>,
+
+    /// Fail when the shard pipes can't be given at least the provided capacity in bytes, instead
+    /// of streaming slowly through the smaller pipes that the host limits allow. The negotiated
+    /// capacities are reported in the stats. Disabled by default. Must be positive, and at most
+    /// /proc/sys/fs/pipe-max-size when the pipe limits apply.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_MIN_PIPE_CAPACITY")]
+    min_pipe_capacity: Option<i32>,
+
+    /// Maximum number of events returned by a single epoll_wait() when capturing the image.
+    /// Defaults to 8. A larger value reduces syscalls when many external files are streamed.
+    /// May only be used with the capture operation.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_EPOLL_CAPACITY")]
+    epoll_capacity: Option<usize>,
+
+    /// When capturing, make the shard pipes non-blocking and buffer in memory, up to the provided
+    /// number of bytes, the data that doesn't fit in full shards instead of blocking. This
+    /// prevents a stalled shard from freezing CRIU while other shards can take the data.
+    /// Disabled by default. May only be used with the capture operation.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_SHARD_SPILL_SIZE")]
+    shard_spill_size: Option<usize>,
+
+    /// When capturing, pad the chunks written to the shards so that each chunk starts on a
+    /// multiple of the provided number of bytes in the shard streams, e.g., 4096 for uploaders
+    /// writing with O_DIRECT, or to block devices. Must be a power of two up to 65536. The
+    /// padding is recorded in the markers, and skipped when extracting. May only be used with the
+    /// capture operation.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_CHUNK_ALIGNMENT")]
+    chunk_alignment: Option<usize>,
+
+    /// When capturing, write the table of the files of the image (names, sizes, and chunks per
+    /// shard) at the end of the first shard, so that the stored image can be listed with the
+    /// list operation without reading it. May only be used with the capture operation.
+    #[structopt(long)]
+    file_table: bool,
+
+    /// Expected size in bytes of the image, typically the size of the previous checkpoint.
+    /// When provided, the percentage completed and the ETA are emitted on the progress fd.
+    /// May only be used with the capture operation.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_EXPECTED_SIZE")]
+    expected_size: Option<u64>,
+
+    /// Same as --expected-size, with the size of the image listed in the provided manifest of a
+    /// previously captured (and extracted) image. May only be used with the capture operation.
+    #[structopt(long, conflicts_with = "expected-size",
+                env = "CRIU_IMG_STREAMER_EXPECTED_SIZE_MANIFEST")]
+    expected_size_manifest: Option<PathBuf>,
+
+    /// Confine the streamer threads to the provided CPUs, e.g., to keep checkpointing on
+    /// housekeeping cores. Format is a comma separated list of cpus or cpu ranges, e.g., 0-3,8.
+    #[structopt(long, parse(try_from_str=parse_cpu_range), require_delimiter = true,
+                env = "CRIU_IMG_STREAMER_CPUSET")]
+    cpuset: Vec<RangeInclusive<usize>>,
+
+    /// Run the streamer threads with the provided nice value, ranging from -20 (highest priority)
+    /// to 19 (lowest priority).
+    #[structopt(long, allow_hyphen_values = true, env = "CRIU_IMG_STREAMER_NICE")]
+    nice: Option<i32>,
+
+    /// Record the requests, replies, and pipes exchanged with CRIU to the provided file, for
+    /// debugging. The trace can be replayed with the replay operation. File contents are not
+    /// recorded. May only be used with the capture and serve operations.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_CRIU_TRACE")]
+    criu_trace: Option<PathBuf>,
+
+    /// Log every marker written to or read from the shards to the provided file, one line per
+    /// marker with its sequence number, type, filename, size, and shard, for debugging how the
+    /// image files got interleaved across shards. File contents are not logged. May only be used
+    /// with the capture, extract, and serve operations.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_DEBUG_MARKERS")]
+    debug_markers: Option<PathBuf>,
+
+    /// Stream the images of several containers (e.g., the containers of a pod) in a single image.
+    /// Each container has its own CRIU socket in images_dir/<container>, and the names of its
+    /// files are prefixed with <container>/ in the image. Multiple containers may be passed as a
+    /// comma separated list. May only be used with the capture and serve operations.
+    #[structopt(long, parse(try_from_str=parse_container), require_delimiter = true,
+                env = "CRIU_IMG_STREAMER_CONTAINERS")]
+    containers: Vec<String>,
+
+    /// Suffix the names of the CRIU sockets with the provided job id, e.g.,
+    /// streamer-capture-<job-id>.sock, so that concurrent streamers can share the same images_dir.
+    /// May only be used with the capture, serve, and replay operations.
+    #[structopt(long, parse(try_from_str=parse_job_id), env = "CRIU_IMG_STREAMER_JOB_ID")]
+    job_id: Option<String>,
+
+    /// Before creating the CRIU socket, check that the criu binary found in the PATH supports
+    /// image streaming, and fail with guidance otherwise. May only be used with the capture and
+    /// serve operations.
+    #[structopt(long)]
+    check_criu: bool,
+
+    /// CRIU features to check with `criu check --feature`, e.g., mem_dirty_track. Multiple
+    /// features may be passed as a comma separated list. Implies --check-criu.
+    #[structopt(long, require_delimiter = true, env = "CRIU_IMG_STREAMER_CHECK_CRIU_FEATURES")]
+    check_criu_features: Vec<String>,
+
+    /// Fail when CRIU makes no progress (no file request and no data transfer) for the provided
+    /// number of seconds. Regardless of this option, the streamer fails when CRIU dies while its
+    /// socket or pipes are kept open by other processes. May only be used with the capture and
+    /// serve operations.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_CRIU_TIMEOUT")]
+    criu_timeout: Option<u64>,
+
+    /// Cap the rate at which the image is written to the shards, in MB/s, e.g., to leave network
+    /// bandwidth to the application during the pre-copy phase of a live migration. May only be
+    /// used with the capture operation.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_MAX_RATE")]
+    max_rate: Option<u64>,
+
+    /// Accept commands on the control socket (images_dir/streamer-control.sock) to change the
+    /// rate limit while capturing: `rate <MB/s>`, or `rate unlimited`. May only be used with the
+    /// capture operation.
+    #[structopt(long)]
+    rate_control: bool,
+
+    /// Fail when the kernel is known to corrupt data going through splice(), instead of warning
+    /// and copying data through userspace.
+    #[structopt(long)]
+    refuse_splice_bug: bool,
+
+    /// Report the sha256 digest of each external file in the stats, next to its size. When
+    /// extracting or serving, the external files are copied through memory instead of being
+    /// spliced. May only be used with the capture, extract, and serve operations.
+    #[structopt(long)]
+    ext_file_digests: bool,
+
+    /// Report the sha256 digest of every image file in the stats, giving each file a stable
+    /// identity, e.g., to verify or deduplicate images. When capturing an unsigned image, an
+    /// unsigned manifest of the files is added to the image. When extracting or serving, the
+    /// files are copied through memory instead of being spliced. May only be used with the
+    /// capture, extract, and serve operations.
+    #[structopt(long)]
+    file_digests: bool,
+
+    /// Stream the external files one after the other, in the order of --ext-file-fds, e.g., the
+    /// layers of a root filesystem that must be applied lower first. When capturing, each
+    /// external file is captured once the previous one reaches EOF. When extracting or serving,
+    /// each external file is complete, and its fd closed, before the next one gets data. The
+    /// image must be captured and extracted with this option. May only be used with the capture,
+    /// extract, and serve operations.
+    #[structopt(long)]
+    ordered_ext_files: bool,
+
+    /// Extract the ghost files (ghost-file-*.img), which hold the content of files deleted while
+    /// still open and can dominate the image size, into the provided directory instead of
+    /// images_dir. CRIU must be given them back in images_dir to restore. May only be used with
+    /// the extract operation.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_GHOST_FILES_DIR")]
+    ghost_files_dir: Option<PathBuf>,
+
+    /// Once the image is extracted, replace the files that are the same as in the provided
+    /// directory, the extraction of a previous checkpoint, by reflinks of them (or hard links when
+    /// the filesystem has no reflinks), so that retained checkpoints share their unchanged files.
+    /// With --chown or --chmod, files are only hard linked when the previous file already has
+    /// the requested ownership. May only be used with the extract operation.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_REFLINK_FROM")]
+    reflink_from: Option<PathBuf>,
+
+    /// Once the image is extracted and verified, run the provided shell command to snapshot
+    /// images_dir, e.g., `btrfs subvolume snapshot -r {dir} /snapshots/ckpt-1`, or
+    /// `zfs snapshot tank/ckpt@1`. `{dir}` is replaced by images_dir, quoted for the shell, and
+    /// must not be quoted in the command. The extraction fails if the command fails. May only be
+    /// used with the extract operation.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_SNAPSHOT_CMD")]
+    snapshot_cmd: Option<String>,
+
+    /// Give the extracted files, and the directories created, the provided owner, as uid:gid,
+    /// e.g., the root user of the user namespace in which CRIU restores. May only be used with
+    /// the extract operation.
+    #[structopt(long, parse(try_from_str=parse_owner), env = "CRIU_IMG_STREAMER_CHOWN")]
+    chown: Option<(u32, u32)>,
+
+    /// Give the extracted files the provided permissions, in octal, e.g., 640. The directories
+    /// created also get the execute permissions matching their read permissions. May only be used
+    /// with the extract operation.
+    #[structopt(long, parse(try_from_str=parse_mode), env = "CRIU_IMG_STREAMER_CHMOD")]
+    chmod: Option<u32>,
+
+    /// Export spans of the operation, of each file, and of each shard command, to the
+    /// OpenTelemetry collector at the provided OTLP (gRPC) endpoint, e.g., http://localhost:4317.
+    /// Requires the otel feature. Operations that don't read or write shards are not traced.
+    #[structopt(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    /// W3C trace context of the caller, e.g.,
+    /// 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01. The span of the operation becomes
+    /// its child, so that it shows up in the distributed trace of the caller. Ignored without
+    /// --otlp-endpoint.
+    #[structopt(long, env = "TRACEPARENT")]
+    traceparent: Option<String>,
+
+    /// TOML file providing defaults for the options above, keyed by their long name, e.g.,
+    /// `shard-fds = [3, 4]`, or `mux-shards = true` for flags. Options can also be provided with
+    /// CRIU_IMG_STREAMER_<OPTION> environment variables, e.g., CRIU_IMG_STREAMER_IMAGES_DIR, flags
+    /// being set to true or false. The command line takes precedence over the environment, which
+    /// takes precedence over the config file.
+    #[structopt(long, env = "CRIU_IMG_STREAMER_CONFIG")]
+    config: Option<PathBuf>,
+
     #[structopt(subcommand)]
     operation: Operation,
 }
@@ -109,17 +638,282 @@ enum Operation {
     Capture,
 
     /// Serve a captured CRIU image to CRIU
-    Serve,
+    Serve {
+        /// Emit the phase transitions (buffering, verifying, patching, serving, done) on the
+        /// progress fd, and the duration of each phase once CRIU is done.
+        #[structopt(long)]
+        phases: bool,
+
+        /// Emit the file requests of CRIU on the progress fd once CRIU is done, in the order in
+        /// which they were made, with their sizes, and how long CRIU waited on each file.
+        #[structopt(long)]
+        request_stats: bool,
+
+        /// Once the image is received, wait in warm standby with the CRIU socket bound and the
+        /// image locked in memory, until `go` is sent on the control socket
+        /// (images_dir/streamer-control.sock). `standby` is emitted on the progress fd when ready.
+        #[structopt(long)]
+        standby: bool,
+
+        /// Receive successive images from the shards, e.g., periodic checkpoints of an
+        /// application, and serve the last complete one to CRIU. Each image replaces the previous
+        /// one once fully received. `image-ready` is emitted on the progress fd for each image.
+        #[structopt(long, conflicts_with_all = &["phases", "standby"])]
+        rolling: bool,
+    },
 
     /// Extract a captured CRIU image to the specified images_dir
-    Extract,
+    Extract {
+        /// Validate the image without writing anything to images_dir. The image is fully
+        /// decoded, and files are checked against the manifest when present. The file list is
+        /// emitted on the progress fd.
+        #[structopt(long)]
+        dry_run: bool,
+
+        /// Write the image files to stdout instead of images_dir, one after the other, each framed
+        /// as <filename>\0<size>\0<data>, the size being in decimal. Files are buffered in memory
+        /// until they are complete. The image can't be verified with --verify-key.
+        #[structopt(long, conflicts_with = "dry-run")]
+        to_stdout: bool,
+    },
+
+    /// Measure the capture performance with a synthetic workload, simulating CRIU.
+    /// Shards are discarded. A report is emitted on the progress fd.
+    Bench {
+        /// Number of shards to capture into
+        #[structopt(long, default_value = "4")]
+        shards: usize,
+
+        /// Number of small files (4KB) to send
+        #[structopt(long, default_value = "1000")]
+        small_files: usize,
+
+        /// Number of medium files (1MB) to send
+        #[structopt(long, default_value = "100")]
+        medium_files: usize,
+
+        /// Number of large files (64MB) to send
+        #[structopt(long, default_value = "4")]
+        large_files: usize,
+
+        /// Target rate in MB/s at which files are sent. Unlimited by default.
+        #[structopt(long)]
+        rate: Option<u64>,
+    },
+
+    /// Replay a trace recorded with --criu-trace, playing the role of CRIU against a streamer
+    /// running the same operation with the same images_dir and job id. Files are sent with zeroed content.
+    Replay {
+        /// Trace file to replay
+        trace: PathBuf,
+    },
+
+    /// Print the entries of a CRIU image file, read from images_dir. Supported images are
+    /// inventory.img, files.img, and fdinfo-<id>.img.
+    Show {
+        /// Image file to print, for example files.img
+        filename: String,
+
+        /// Read the image file from the captured image on the shards instead of images_dir
+        #[structopt(long)]
+        from_stream: bool,
+    },
+
+    /// Print the files of a captured image, with their size and their number of chunks on each
+    /// shard, from the file table at the end of its first shard. The image must have been
+    /// captured with --file-table. Only the tail of the shard is read.
+    List {
+        /// First shard of the image, stored in a file
+        shard: PathBuf,
+    },
+
+    /// Rewrite a captured image into new shards with files removed or replaced, without
+    /// extracting it. The manifest of the image is dropped, and a new one is signed with
+    /// --sign-key when provided.
+    Filter {
+        /// Files to remove from the image. Multiple files may be passed as a comma separated list.
+        #[structopt(long, require_delimiter = true)]
+        remove: Vec<String>,
+
+        /// Files to replace with the content of a local file. Format is filename:path.
+        /// Multiple files may be passed as a comma separated list.
+        #[structopt(long, parse(try_from_str=parse_replace), require_delimiter = true)]
+        replace: Vec<(String, PathBuf)>,
+
+        /// File descriptors of the output shards. Multiple fds may be passed as a comma
+        /// separated list. Defaults to 1.
+        #[structopt(long, require_delimiter = true)]
+        output_shard_fds: Vec<i32>,
+    },
+
+    /// Combine independently captured images into a single image. The images must not have
+    /// files in common. Manifests are dropped, and a new one is signed with --sign-key when
+    /// provided.
+    Merge {
+        /// File descriptors of the shards of an image to merge, as a comma separated list.
+        /// Pass this option once per image.
+        #[structopt(long, parse(try_from_str=parse_fds), required = true, min_values = 2,
+                    number_of_values = 1)]
+        input_shard_fds: Vec<Vec<i32>>,
+
+        /// File descriptors of the output shards. Multiple fds may be passed as a comma
+        /// separated list. Defaults to 1.
+        #[structopt(long, require_delimiter = true)]
+        output_shard_fds: Vec<i32>,
+    },
+
+    /// Write the files framed as <filename>\0<size>\0<data>, the size being in decimal, read
+    /// from stdin, as an image to the shards. This lets producers other than CRIU use the shards.
+    /// This is the reverse of `extract --to-stdout`.
+    ImportStream,
+
+    /// Convert a kubelet checkpoint archive (tar), read from stdin, into an image written to the
+    /// shards. The CRIU image files of the archive can then be served to CRIU directly.
+    #[cfg(feature = "kubelet")]
+    KubeletImport,
+
+    /// Convert a captured image into a kubelet checkpoint archive (tar), written to stdout.
+    #[cfg(feature = "kubelet")]
+    KubeletExport,
+
+    /// Inspect the host settings that matter to the streamer (pipe size limits, available memory,
+    /// CRIU, and kernel), and print how to fix the issues found.
+    Doctor {
+        /// Size in bytes of the images to serve, which are buffered in memory. Checks that they
+        /// fit in the available memory.
+        #[structopt(long)]
+        image_size: Option<u64>,
+    },
+
+    /// Print the version, image format, operations, and features supported by the streamer, and
+    /// the capabilities of the kernel, as JSON.
+    Capabilities,
+
+    /// Print the completion script of the provided shell
+    Completions {
+        #[structopt(possible_values = &Shell::variants())]
+        shell: String,
+    },
+
+    /// Serve the gRPC control plane. Controllers start captures and restores, follow their
+    /// progress, and abort them with RPCs. Requests that don't specify an images_dir use this one.
+    #[cfg(feature = "grpc")]
+    GrpcServer {
+        /// Unix socket to listen on, for example /run/criu-image-streamer.sock. Only the user
+        /// running the server can connect to it.
+        #[structopt(long)]
+        listen: PathBuf,
+    },
+}
+
+fn output_shard_pipes(output_shard_fds: Vec<i32>) -> Result<Vec<UnixPipe>> {
+    let output_shard_fds = match output_shard_fds {
+        fds if fds.is_empty() => vec![dup(libc::STDOUT_FILENO)?],
+        fds => fds,
+    };
+    output_shard_fds.into_iter()
+        .map(UnixPipe::new)
+        .collect::<Result<_>>()
+        .context("Output shards must be pipes")
+}
+
+/// Name of the operation, as on the command line.
+fn operation_name(operation: &Operation) -> &'static str {
+    use Operation::*;
+    match operation {
+        Capture => "capture",
+        Serve { .. } => "serve",
+        Extract { .. } => "extract",
+        Bench { .. } => "bench",
+        Replay { .. } => "replay",
+        Show { .. } => "show",
+        List { .. } => "list",
+        Filter { .. } => "filter",
+        Merge { .. } => "merge",
+        ImportStream => "import-stream",
+        #[cfg(feature = "kubelet")]
+        KubeletImport => "kubelet-import",
+        #[cfg(feature = "kubelet")]
+        KubeletExport => "kubelet-export",
+        Doctor { .. } => "doctor",
+        Capabilities => "capabilities",
+        Completions { .. } => "completions",
+        #[cfg(feature = "grpc")]
+        GrpcServer { .. } => "grpc-server",
+    }
+}
+
+/// Returns an event callback forwarding the events to each of `on_events`.
+fn forward_events(mut on_events: Vec<EventCallback>) -> impl FnMut(Event<'_>) + Send + 'static {
+    move |event| {
+        for on_event in on_events.iter_mut() {
+            on_event(event);
+        }
+    }
 }
 
 fn do_main() -> Result<()> {
     use Operation::*;
 
-    let opts: Opts = Opts::from_args();
+    #[cfg(feature = "config")]
+    load_config()?;
+    let opts = parse_opts()?;
+    #[cfg(not(feature = "config"))]
+    ensure!(opts.config.is_none(), "--config is not supported by this build of the streamer");
+    #[cfg(not(feature = "otel"))]
+    ensure!(opts.otlp_endpoint.is_none(), "--otlp-endpoint is not supported by this build of the streamer");
+    #[cfg(not(feature = "signing"))]
+    ensure!(opts.sign_key.is_none() && opts.verify_key.is_none(),
+            "--sign-key and --verify-key are not supported by this build of the streamer");
+
+    if let Some(log_target) = &opts.log_target {
+        let mut logger = Logger::new(log_target.parse::<LogTarget>()?)?;
+        logger.context.operation = Some(operation_name(&opts.operation).to_string());
+        logger.context.job_id = opts.job_id.clone();
+        set_logger(logger);
+    }
+
+    // These operations describe the streamer itself, and don't need an images directory.
+    match &opts.operation {
+        Doctor { image_size } => {
+            let checks = doctor(*image_size);
+            print_report(&checks, &mut std::io::stdout().lock())?;
+            ensure!(checks.iter().all(|check| check.status != Status::Error),
+                    "The host is not ready to run the streamer, see the fixes above");
+            return Ok(());
+        }
+        Capabilities => {
+            println!("{}", serde_json::to_string_pretty(&capabilities())?);
+            return Ok(());
+        }
+        List { shard } => {
+            let mut shard = fs::File::open(shard)
+                .with_context(|| format!("Failed to open the shard {}", shard.display()))?;
+            let file_table = read_file_table(&mut shard)?;
+            return print_file_table(&file_table, &mut std::io::stdout().lock());
+        }
+        Completions { shell } => {
+            let shell = shell.parse::<Shell>().map_err(|e| anyhow!(e))?;
+            Opts::clap().gen_completions_to(env!("CARGO_PKG_NAME"), shell, &mut std::io::stdout());
+            return Ok(());
+        }
+        _ => {}
+    }
+    let images_dir = opts.images_dir.ok_or_else(|| anyhow!("--images-dir is required"))?;
+
+    // This must be done before spawning any thread, as threads inherit these attributes.
+    if !opts.cpuset.is_empty() {
+        set_cpu_affinity(&opts.cpuset)?;
+    }
+    if let Some(nice) = opts.nice {
+        set_nice(nice)?;
+    }
 
+    let progress_format = match opts.progress_format.as_deref() {
+        Some("proto") => ProgressFormat::Proto,
+        _ => ProgressFormat::Json,
+    };
+    set_progress_format(progress_format);
     let progress_pipe = {
         let progress_fd = match opts.progress_fd {
             Some(fd) => fd,
@@ -128,37 +922,435 @@ fn do_main() -> Result<()> {
         unsafe { fs::File::from_raw_fd(progress_fd) }
     };
 
-    let shard_pipes =
-        if !opts.shard_fds.is_empty() {
+    ensure!(matches!(opts.operation, Capture | Serve { .. } | Replay { .. }) || opts.job_id.is_none(),
+            "--job-id is only supported when capturing, serving, or replaying");
+
+    if let Some(warning) = KERNEL_CAPS.splice_bug_warning() {
+        ensure!(!opts.refuse_splice_bug, "{}", warning);
+        log(Level::Warning, &warning);
+    }
+
+    // The bench operation plays the role of CRIU, and discards the shards.
+    if let Bench { shards, small_files, medium_files, large_files, rate } = opts.operation {
+        let rate = rate.map(mb_per_sec).transpose().context("Invalid --rate")?;
+        let workload = Workload { small_files, medium_files, large_files, rate };
+        return bench(&images_dir, progress_pipe, shards, workload);
+    }
+
+    // The replay operation plays the role of CRIU, and doesn't use shards.
+    if let Replay { trace } = &opts.operation {
+        return replay(&images_dir, progress_pipe, trace, opts.job_id.as_deref());
+    }
+
+    // The gRPC server runs each operation in its own streamer process.
+    #[cfg(feature = "grpc")]
+    if let GrpcServer { listen } = opts.operation {
+        return serve_grpc(&images_dir, &listen);
+    }
+
+    // When showing an image file from images_dir, we don't need shards.
+    if let Show { filename, from_stream: false } = &opts.operation {
+        let img = fs::read(images_dir.join(filename))
+            .with_context(|| format!("Failed to read {}", filename))?;
+        return show_img(filename, &img, &mut std::io::stdout().lock());
+    }
+
+    // The exporter runs on its own threads, which must come after the thread attributes are set.
+    #[cfg(feature = "otel")]
+    if let Some(endpoint) = &opts.otlp_endpoint {
+        init_telemetry(endpoint, opts.traceparent.as_deref(), operation_name(&opts.operation))?;
+    }
+
+    let shard_cmd = match (opts.shard_cmd, opts.rclone, opts.ssh) {
+        (Some(cmd), _, _) => Some(ShardCmd::Shell(cmd)),
+        (None, Some(remote_dir), _) => Some(ShardCmd::Rclone(remote_dir)),
+        (None, None, Some(url)) => Some(ShardCmd::Ssh(url)),
+        (None, None, None) => None,
+    };
+    let shard_direction = match opts.operation {
+        Capture | ImportStream => FifoDirection::Write,
+        #[cfg(feature = "kubelet")]
+        KubeletImport => FifoDirection::Write,
+        _ => FifoDirection::Read,
+    };
+    ensure!(opts.shard_fifos.is_some() || shard_cmd.is_some() || opts.mux_shards || opts.num_shards.is_none(),
+            "--num-shards is only supported with --shard-fifos, --shard-cmd, --rclone, --ssh, and --mux-shards");
+    ensure!(!opts.mux_shards || shard_direction == FifoDirection::Write || opts.num_shards.is_none(),
+            "--num-shards is read from the stream with --mux-shards when reading the image");
+    ensure!(!opts.mux_shards ||
+            (shard_cmd.is_none() && opts.shard_fifos.is_none() && opts.shard_devices.is_empty()),
+            "--mux-shards cannot be used with --shard-fifos, --shard-devices, --shard-cmd, --rclone, nor --ssh");
+    ensure!(!opts.mux_shards || !matches!(opts.operation, Merge { .. }), "--mux-shards cannot be used with merge");
+    let mut shard_device_threads = Vec::new();
+    let mut shard_mux_thread = None;
+    let mut shard_cmd_processes = Vec::new();
+    let shard_pipes = if let Some(shard_cmd) = &shard_cmd {
+        ensure!(opts.shard_fds.is_empty() && opts.shard_fifos.is_none() && opts.shard_devices.is_empty(),
+                "--shard-cmd, --rclone, and --ssh cannot be used with --shard-fds, --shard-fifos, nor --shard-devices");
+        ensure!(!matches!(opts.operation, Merge { .. }), "--shard-cmd, --rclone, and --ssh cannot be used with merge");
+        #[cfg(feature = "otel")]
+        start_shard_cmd_spans(shard_cmd, opts.num_shards.unwrap_or(1), shard_direction);
+        let (shard_pipes, processes) = spawn_shard_cmds(shard_cmd, opts.num_shards.unwrap_or(1), shard_direction)?;
+        shard_cmd_processes = processes;
+        shard_pipes
+    } else if let Some(pattern) = &opts.shard_fifos {
+        ensure!(opts.shard_fds.is_empty(), "--shard-fds and --shard-fifos cannot be used together");
+        ensure!(opts.shard_devices.is_empty(), "--shard-fifos and --shard-devices cannot be used together");
+        ensure!(!matches!(opts.operation, Merge { .. }), "--shard-fifos cannot be used with merge");
+        open_shard_fifos(pattern, opts.num_shards.unwrap_or(1), shard_direction)?
+    } else if !opts.shard_devices.is_empty() {
+        ensure!(opts.shard_fds.is_empty(), "--shard-fds and --shard-devices cannot be used together");
+        ensure!(!matches!(opts.operation, Merge { .. }), "--shard-devices cannot be used with merge");
+        let (shard_pipes, threads) = open_shard_devices(&opts.shard_devices, shard_direction)?;
+        shard_device_threads = threads;
+        shard_pipes
+    } else {
+        let shard_fds = if !opts.shard_fds.is_empty() {
             opts.shard_fds
         } else {
             match opts.operation {
-                Capture => vec![dup(libc::STDOUT_FILENO)?],
-                Extract | Serve => vec![dup(libc::STDIN_FILENO)?],
+                Capture | ImportStream => vec![dup(libc::STDOUT_FILENO)?],
+                Extract { .. } | Serve { .. } | Show { .. } | Filter { .. } => vec![dup(libc::STDIN_FILENO)?],
+                #[cfg(feature = "kubelet")]
+                KubeletImport => vec![dup(libc::STDOUT_FILENO)?],
+                #[cfg(feature = "kubelet")]
+                KubeletExport => vec![dup(libc::STDIN_FILENO)?],
+                // The input shards of the merge operation are passed with --input-shard-fds
+                Merge { .. } => vec![],
+                Bench { .. } | Replay { .. } | List { .. } | Doctor { .. } | Capabilities | Completions { .. } =>
+                    unreachable!(),
+                #[cfg(feature = "grpc")]
+                GrpcServer { .. } => unreachable!(),
             }
-        }.into_iter()
-            .map(UnixPipe::new)
-            .collect::<Result<_>>()
-            .context("Image shards (input/output) must be pipes. \
-                      You may use `cat` or `pv` (faster) to create one.")?;
+        };
+        if opts.mux_shards {
+            ensure!(shard_fds.len() == 1, "--mux-shards takes a single shard fd");
+            let stream = unsafe { fs::File::from_raw_fd(shard_fds[0]) };
+            let (shard_pipes, thread) = match shard_direction {
+                FifoDirection::Write => mux_shards(stream, opts.num_shards.unwrap_or(1))?,
+                FifoDirection::Read => demux_shards(stream)?,
+            };
+            shard_mux_thread = Some(thread);
+            shard_pipes
+        } else {
+            shard_fds.into_iter()
+                .map(UnixPipe::new)
+                .collect::<Result<_>>()
+                .context("Image shards (input/output) must be pipes. \
+                          You may use `cat` or `pv` (faster) to create one.")?
+        }
+    };
 
     let ext_file_pipes = opts.ext_file_fds.into_iter()
             .map(|(filename, fd)| Ok((filename, UnixPipe::new(fd)?)))
             .collect::<Result<_>>()?;
 
-    ensure!(opts.operation == Serve || opts.tcp_listen_remap.is_empty(),
+    ensure!(matches!(opts.operation, Serve { .. }) || opts.tcp_listen_remap.is_empty(),
             "--tcp-listen-remap is only supported when serving the image");
 
-    match opts.operation {
-        Capture => capture(&opts.images_dir, progress_pipe, shard_pipes, ext_file_pipes),
-        Extract => extract(&opts.images_dir, progress_pipe, shard_pipes, ext_file_pipes),
-        Serve   =>   serve(&opts.images_dir, progress_pipe, shard_pipes, ext_file_pipes, opts.tcp_listen_remap),
+    let writes_new_image = match opts.operation {
+        Capture | Filter { .. } | Merge { .. } | ImportStream => true,
+        #[cfg(feature = "kubelet")]
+        KubeletImport => true,
+        _ => false,
+    };
+    ensure!(writes_new_image || opts.sign_key.is_none(),
+            "--sign-key is only supported when capturing, filtering, merging, or importing images");
+
+    ensure!(matches!(opts.operation, Serve { .. } | Extract { .. }) || opts.verify_key.is_none(),
+            "--verify-key is only supported when serving or extracting the image");
+
+    ensure!(matches!(opts.operation, Capture | Extract { .. } | Serve { .. }) ||
+            opts.max_marker_size.is_none(),
+            "--max-marker-size is only supported when capturing, extracting, or serving the image");
+    ensure!(opts.max_marker_size.is_none_or(|size| size >= MIN_MAX_PB_SIZE),
+            "--max-marker-size must be at least {}", MIN_MAX_PB_SIZE);
+    let max_marker_size = opts.max_marker_size.unwrap_or(DEFAULT_MAX_PB_SIZE);
+
+    ensure!(matches!(opts.operation, Capture | Extract { .. } | Serve { .. }) ||
+            opts.min_pipe_capacity.is_none(),
+            "--min-pipe-capacity is only supported when capturing, extracting, or serving the image");
+    ensure!(opts.min_pipe_capacity.is_none_or(|capacity| capacity > 0),
+            "--min-pipe-capacity must be positive");
+    if let (Some(min_pipe_capacity), Some(pipe_max_size)) = (opts.min_pipe_capacity, pipe_max_size()) {
+        ensure!(min_pipe_capacity as u64 <= pipe_max_size,
+                "--min-pipe-capacity exceeds /proc/sys/fs/pipe-max-size ({} bytes)", pipe_max_size);
+    }
+
+    ensure!(opts.operation == Capture || opts.epoll_capacity.is_none(),
+            "--epoll-capacity is only supported when capturing the image");
+    ensure!(opts.epoll_capacity != Some(0), "--epoll-capacity must be positive");
+    let epoll_capacity = opts.epoll_capacity.unwrap_or(DEFAULT_EPOLL_CAPACITY);
+
+    ensure!(opts.operation == Capture || opts.shard_spill_size.is_none(),
+            "--shard-spill-size is only supported when capturing the image");
+    let shard_spill_size = opts.shard_spill_size.unwrap_or(0);
+
+    ensure!(opts.operation == Capture || !opts.file_table,
+            "--file-table is only supported when capturing the image");
+
+    ensure!(opts.operation == Capture || opts.chunk_alignment.is_none(),
+            "--chunk-alignment is only supported when capturing the image");
+    ensure!(opts.chunk_alignment.is_none_or(|a| a.is_power_of_two() && a <= MAX_CHUNK_ALIGNMENT),
+            "--chunk-alignment must be a power of two up to {}", MAX_CHUNK_ALIGNMENT);
+    let chunk_alignment = opts.chunk_alignment.unwrap_or(0);
+
+    ensure!(opts.operation == Capture ||
+            (opts.expected_size.is_none() && opts.expected_size_manifest.is_none()),
+            "--expected-size and --expected-size-manifest are only supported when capturing the image");
+    let expected_size = match opts.expected_size_manifest {
+        Some(path) => Some(Manifest::load_unverified(&path)?.total_size()),
+        None => opts.expected_size,
+    };
+
+    ensure!(matches!(opts.operation, Capture | Serve { .. }) || opts.criu_trace.is_none(),
+            "--criu-trace is only supported when capturing or serving the image");
+    let criu_trace = opts.criu_trace.as_deref().map(CriuTrace::create).transpose()?;
+
+    ensure!(matches!(opts.operation, Capture | Extract { .. } | Serve { .. }) ||
+            opts.debug_markers.is_none(),
+            "--debug-markers is only supported when capturing, extracting, or serving the image");
+    let marker_log = opts.debug_markers.as_deref().map(MarkerLog::create).transpose()?;
+
+    ensure!(matches!(opts.operation, Capture | Serve { .. }) || opts.containers.is_empty(),
+            "--containers is only supported when capturing or serving the image");
+    ensure!(opts.containers.is_empty() || criu_trace.is_none(),
+            "--criu-trace cannot be used with --containers");
+    ensure!(opts.containers.iter().collect::<HashSet<_>>().len() == opts.containers.len(),
+            "Container names must be unique");
+
+    if opts.check_criu || !opts.check_criu_features.is_empty() {
+        ensure!(matches!(opts.operation, Capture | Serve { .. }),
+                "--check-criu is only supported when capturing or serving the image");
+        check_criu(Path::new("criu"), &opts.check_criu_features)?;
+    }
+
+    ensure!(matches!(opts.operation, Capture | Serve { .. }) || opts.criu_timeout.is_none(),
+            "--criu-timeout is only supported when capturing or serving the image");
+    ensure!(opts.criu_timeout != Some(0), "--criu-timeout must be positive");
+    let criu_timeout = opts.criu_timeout.map(Duration::from_secs);
+
+    ensure!(opts.operation == Capture || (opts.max_rate.is_none() && !opts.rate_control),
+            "--max-rate and --rate-control are only supported when capturing the image");
+    ensure!(opts.max_rate != Some(0), "--max-rate must be positive");
+    let max_rate = opts.max_rate.map(mb_per_sec).transpose().context("Invalid --max-rate")?;
+    let rate_limit = RateLimit::new(max_rate);
+
+    ensure!(matches!(opts.operation, Capture | Extract { dry_run: false, .. } | Serve { .. }) ||
+            !opts.ext_file_digests,
+            "--ext-file-digests is only supported when capturing, extracting, or serving the image");
+    ensure!(matches!(opts.operation, Capture | Extract { dry_run: false, .. } | Serve { .. }) ||
+            !opts.file_digests,
+            "--file-digests is only supported when capturing, extracting, or serving the image");
+    ensure!(matches!(opts.operation, Capture | Extract { dry_run: false, .. } | Serve { .. }) ||
+            !opts.ordered_ext_files,
+            "--ordered-ext-files is only supported when capturing, extracting, or serving the image");
+
+    ensure!(matches!(opts.operation, Extract { dry_run: false, to_stdout: false }) ||
+            opts.ghost_files_dir.is_none(),
+            "--ghost-files-dir is only supported when extracting the image to images_dir");
+    ensure!(matches!(opts.operation, Extract { dry_run: false, to_stdout: false }) ||
+            opts.reflink_from.is_none(),
+            "--reflink-from is only supported when extracting the image to images_dir");
+    ensure!(matches!(opts.operation, Extract { dry_run: false, to_stdout: false }) ||
+            opts.snapshot_cmd.is_none(),
+            "--snapshot-cmd is only supported when extracting the image to images_dir");
+    ensure!(matches!(opts.operation, Extract { dry_run: false, to_stdout: false }) ||
+            (opts.chown.is_none() && opts.chmod.is_none()),
+            "--chown and --chmod are only supported when extracting the image to images_dir");
+
+
+    #[cfg(feature = "signing")]
+    let sign_key = opts.sign_key.as_deref().map(load_signing_key).transpose()?;
+    #[cfg(feature = "signing")]
+    let verify_key = opts.verify_key.as_deref().map(load_verifying_key).transpose()?;
+    #[cfg(not(feature = "signing"))]
+    let (sign_key, verify_key): (Option<SigningKey>, Option<VerifyingKey>) = (None, None);
+
+    let audit_log = match opts.audit_log {
+        Some(path) => Some(AuditLog::open(&path)?),
+        None if opts.audit_log_to_progress => Some(AuditLog::to_progress(progress_pipe.try_clone()?, progress_format)),
+        None => None,
+    };
+
+    let codec = match (&opts.codec_cmd, &opts.codec) {
+        (Some(_), Some(_)) => bail!("--codec-cmd and --codec cannot be used together"),
+        (Some(cmd), None) => Some(ExternalCodec::new(cmd.as_str())),
+        (None, Some(_)) => Some(gzip_codec()?),
+        (None, None) => None,
+    };
+    let (shard_pipes, codec_stages) = match codec {
+        Some(codec) => {
+            ensure!(!matches!(opts.operation, Filter { .. } | Merge { .. }),
+                    "--codec-cmd and --codec cannot be used with filter and merge");
+            if writes_new_image {
+                encode_shards(&codec, shard_pipes)?
+            } else {
+                decode_shards(&codec, shard_pipes)?
+            }
+        }
+        None => (shard_pipes, Vec::new()),
+    };
+
+    let mut on_events = Vec::new();
+    if opts.log_target.is_some() {
+        on_events.push(log_event_callback());
+    }
+    #[cfg(feature = "otel")]
+    on_events.extend(telemetry_event_callback());
+
+    let result = match opts.operation {
+        Capture => CaptureBuilder::new(&images_dir, shard_pipes)
+            .progress_pipe(progress_pipe)
+            .on_event(forward_events(on_events))
+            .ext_files(ext_file_pipes)
+            .sign_key(sign_key)
+            .audit_log(audit_log)
+            .epoll_capacity(epoll_capacity)
+            .shard_spill_size(shard_spill_size)
+            .chunk_alignment(chunk_alignment)
+            .max_marker_size(max_marker_size)
+            .min_pipe_capacity(opts.min_pipe_capacity)
+            .expected_size(expected_size)
+            .criu_trace(criu_trace)
+            .marker_log(marker_log)
+            .containers(opts.containers)
+            .job_id(opts.job_id)
+            .criu_timeout(criu_timeout)
+            .rate_limit(rate_limit)
+            .rate_control(opts.rate_control)
+            .ext_file_digests(opts.ext_file_digests)
+            .file_digests(opts.file_digests)
+            .ordered_ext_files(opts.ordered_ext_files)
+            .file_table(opts.file_table)
+            .run(),
+        Extract { dry_run: true, .. } => {
+            ensure!(ext_file_pipes.is_empty() && audit_log.is_none(),
+                    "--ext-file-fds and --audit-log cannot be used with --dry-run");
+            ExtractBuilder::new(&images_dir, shard_pipes)
+                .progress_pipe(progress_pipe)
+                .on_event(forward_events(on_events))
+                .verify_key(verify_key)
+                .marker_log(marker_log)
+                .max_marker_size(max_marker_size)
+                .min_pipe_capacity(opts.min_pipe_capacity)
+                .extract_dry_run()
+        }
+        Extract { dry_run: false, to_stdout: true } => ExtractBuilder::new(&images_dir, shard_pipes)
+            .progress_pipe(progress_pipe)
+            .on_event(forward_events(on_events))
+            .ext_files(ext_file_pipes)
+            .ext_file_digests(opts.ext_file_digests)
+            .file_digests(opts.file_digests)
+            .ordered_ext_files(opts.ordered_ext_files)
+            .verify_key(verify_key)
+            .audit_log(audit_log)
+            .marker_log(marker_log)
+            .max_marker_size(max_marker_size)
+            .min_pipe_capacity(opts.min_pipe_capacity)
+            .extract_to_stream(BufWriter::new(std::io::stdout().lock())),
+        Extract { dry_run: false, to_stdout: false } => ExtractBuilder::new(&images_dir, shard_pipes)
+            .progress_pipe(progress_pipe)
+            .on_event(forward_events(on_events))
+            .ext_files(ext_file_pipes)
+            .ext_file_digests(opts.ext_file_digests)
+            .file_digests(opts.file_digests)
+            .ordered_ext_files(opts.ordered_ext_files)
+            .ghost_files_dir(opts.ghost_files_dir)
+            .reflink_from(opts.reflink_from)
+            .snapshot_cmd(opts.snapshot_cmd)
+            .ownership(Ownership { owner: opts.chown, mode: opts.chmod })
+            .verify_key(verify_key)
+            .audit_log(audit_log)
+            .marker_log(marker_log)
+            .max_marker_size(max_marker_size)
+            .min_pipe_capacity(opts.min_pipe_capacity)
+            .extract(),
+        Serve { phases, request_stats, standby, rolling } => ExtractBuilder::new(&images_dir, shard_pipes)
+            .progress_pipe(progress_pipe)
+            .on_event(forward_events(on_events))
+            .ext_files(ext_file_pipes)
+            .tcp_listen_remaps(opts.tcp_listen_remap)
+            .verify_key(verify_key)
+            .audit_log(audit_log)
+            .criu_trace(criu_trace)
+            .marker_log(marker_log)
+            .containers(opts.containers)
+            .job_id(opts.job_id)
+            .phases(phases)
+            .request_stats(request_stats)
+            .standby(standby)
+            .rolling(rolling)
+            .criu_timeout(criu_timeout)
+            .ext_file_digests(opts.ext_file_digests)
+            .file_digests(opts.file_digests)
+            .ordered_ext_files(opts.ordered_ext_files)
+            .max_marker_size(max_marker_size)
+            .min_pipe_capacity(opts.min_pipe_capacity)
+            .serve(),
+        Show { filename, from_stream: true } => {
+            ensure!(ext_file_pipes.is_empty() && audit_log.is_none(),
+                    "--ext-file-fds and --audit-log cannot be used with show");
+            let img = extract_img_file(progress_pipe, shard_pipes, &filename)?;
+            show_img(&filename, &img, &mut std::io::stdout().lock())
+        }
+        Filter { remove, replace, output_shard_fds } => {
+            ensure!(ext_file_pipes.is_empty() && audit_log.is_none(),
+                    "--ext-file-fds and --audit-log cannot be used with filter");
+            filter(progress_pipe, shard_pipes, output_shard_pipes(output_shard_fds)?, remove, replace, sign_key)
+        }
+        Merge { input_shard_fds, output_shard_fds } => {
+            ensure!(shard_pipes.is_empty() && ext_file_pipes.is_empty() && audit_log.is_none(),
+                    "--shard-fds, --ext-file-fds, and --audit-log cannot be used with merge");
+            let shard_pipe_sets = input_shard_fds.into_iter()
+                .map(|fds| fds.into_iter().map(UnixPipe::new).collect::<Result<_>>())
+                .collect::<Result<_>>()
+                .context("Image shards must be pipes")?;
+            merge(progress_pipe, shard_pipe_sets, output_shard_pipes(output_shard_fds)?, sign_key)
+        }
+        ImportStream => {
+            ensure!(ext_file_pipes.is_empty() && audit_log.is_none(),
+                    "--ext-file-fds and --audit-log cannot be used with import-stream");
+            let stream = unsafe { fs::File::from_raw_fd(dup(libc::STDIN_FILENO)?) };
+            import_stream(progress_pipe, stream, shard_pipes, sign_key)
+        }
+        #[cfg(feature = "kubelet")]
+        KubeletImport => {
+            ensure!(ext_file_pipes.is_empty() && audit_log.is_none(),
+                    "--ext-file-fds and --audit-log cannot be used with kubelet-import");
+            let archive = unsafe { fs::File::from_raw_fd(dup(libc::STDIN_FILENO)?) };
+            import_kubelet_checkpoint(progress_pipe, archive, shard_pipes, sign_key)
+        }
+        #[cfg(feature = "kubelet")]
+        KubeletExport => {
+            ensure!(ext_file_pipes.is_empty() && audit_log.is_none(),
+                    "--ext-file-fds and --audit-log cannot be used with kubelet-export");
+            let archive = unsafe { fs::File::from_raw_fd(dup(libc::STDOUT_FILENO)?) };
+            export_kubelet_checkpoint(progress_pipe, shard_pipes, archive)
+        }
+        Bench { .. } | Replay { .. } | Show { from_stream: false, .. } |
+        List { .. } | Doctor { .. } | Capabilities | Completions { .. } => unreachable!(),
+        #[cfg(feature = "grpc")]
+        GrpcServer { .. } => unreachable!(),
+    };
+
+    // The shard pipes are closed by now, so the codec processes see EOF and exit, and so do the
+    // shard device threads, the shard mux thread, and shard commands after them.
+    let result = finish_codec_stages(result, codec_stages);
+    let result = finish_shard_cmds(result, shard_cmd_processes);
+    let result = wait_shard_devices(shard_device_threads).and(result);
+    match shard_mux_thread {
+        Some(thread) => wait_shard_mux(thread).and(result),
+        None => result,
     }
 }
 
 fn main() {
-    if let Err(e) = do_main() {
-        eprintln!("criu-image-streamer Error: {:#}", e);
+    let result = do_main();
+    #[cfg(feature = "otel")]
+    finish_telemetry(&result);
+    if let Err(e) = result {
+        log(Level::Error, &format!("{:#}", e));
     }
 }
 
@@ -171,11 +1363,57 @@ mod cli_tests {
     fn test_capture_basic() {
         assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "capture"]),
             Opts {
-                images_dir: PathBuf::from("imgdir"),
+                images_dir: Some(PathBuf::from("imgdir")),
                 shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
                 ext_file_fds: vec![],
                 tcp_listen_remap: vec![],
                 progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
                 operation: Operation::Capture,
             })
     }
@@ -184,12 +1422,58 @@ mod cli_tests {
     fn test_extract_basic() {
         assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "extract"]),
             Opts {
-                images_dir: PathBuf::from("imgdir"),
+                images_dir: Some(PathBuf::from("imgdir")),
                 shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
                 ext_file_fds: vec![],
                 tcp_listen_remap: vec![],
                 progress_fd: None,
-                operation: Operation::Extract,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Extract { dry_run: false, to_stdout: false },
             })
     }
 
@@ -197,66 +1481,2359 @@ mod cli_tests {
     fn test_extract_serve() {
         assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "serve"]),
             Opts {
-                images_dir: PathBuf::from("imgdir"),
+                images_dir: Some(PathBuf::from("imgdir")),
                 shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
                 ext_file_fds: vec![],
                 tcp_listen_remap: vec![],
                 progress_fd: None,
-                operation: Operation::Serve,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Serve { phases: false, request_stats: false, standby: false, rolling: false },
             })
     }
 
 
     #[test]
-    fn test_shards_fds() {
-        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--shard-fds", "1,2,3", "capture"]),
+    fn test_serve_phases() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "serve", "--phases"]),
             Opts {
-                images_dir: PathBuf::from("imgdir"),
-                shard_fds: vec![1,2,3],
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
                 ext_file_fds: vec![],
                 tcp_listen_remap: vec![],
                 progress_fd: None,
-                operation: Operation::Capture,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Serve { phases: true, request_stats: false, standby: false, rolling: false },
             })
     }
 
+
     #[test]
-    fn test_ext_files() {
-        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--ext-file-fds", "file1:1,file2:2", "capture"]),
+    fn test_serve_request_stats() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "serve", "--request-stats", "--rolling"]).operation,
+                   Operation::Serve { phases: false, request_stats: true, standby: false, rolling: true });
+    }
+
+    #[test]
+    fn test_serve_standby() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "serve", "--standby"]),
             Opts {
-                images_dir: PathBuf::from("imgdir"),
+                images_dir: Some(PathBuf::from("imgdir")),
                 shard_fds: vec![],
-                ext_file_fds: vec![(String::from("file1"), 1), (String::from("file2"), 2)],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
                 tcp_listen_remap: vec![],
                 progress_fd: None,
-                operation: Operation::Capture,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Serve { phases: false, request_stats: false, standby: true, rolling: false },
             })
     }
 
+
     #[test]
-    fn test_tcp_listen_remaps() {
-        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--tcp-listen-remap", "2000:3000,5000:6000", "serve"]),
+    fn test_serve_rolling() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "serve", "--rolling"]),
             Opts {
-                images_dir: PathBuf::from("imgdir"),
+                images_dir: Some(PathBuf::from("imgdir")),
                 shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
                 ext_file_fds: vec![],
-                tcp_listen_remap: vec![(2000,3000),(5000,6000)],
+                tcp_listen_remap: vec![],
                 progress_fd: None,
-                operation: Operation::Serve,
-            })
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Serve { phases: false, request_stats: false, standby: false, rolling: true },
+            });
+        assert!(Opts::from_iter_safe(&vec!["prog", "-D", "imgdir", "serve", "--rolling", "--standby"]).is_err());
     }
 
+
     #[test]
-    fn test_progess_fd() {
-        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--progress-fd", "3", "capture"]),
+    fn test_extract_dry_run() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "extract", "--dry-run"]),
             Opts {
-                images_dir: PathBuf::from("imgdir"),
+                images_dir: Some(PathBuf::from("imgdir")),
                 shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
                 ext_file_fds: vec![],
                 tcp_listen_remap: vec![],
-                progress_fd: Some(3),
-                operation: Operation::Capture,
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Extract { dry_run: true, to_stdout: false },
             })
     }
-}
 
+    #[test]
+    fn test_extract_to_stdout() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "extract", "--to-stdout"]).operation,
+                   Operation::Extract { dry_run: false, to_stdout: true });
+        assert!(Opts::from_iter_safe(&vec!["prog", "-D", "imgdir", "extract", "--to-stdout", "--dry-run"]).is_err());
+    }
+
+    #[test]
+    fn test_codec_cmd() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "--codec-cmd", "zstd -d", "extract"]).codec_cmd,
+                   Some("zstd -d".to_string()));
+    }
+
+    #[test]
+    fn test_shard_devices() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "--shard-devices", "/dev/nvme1n1,/dev/nvme2n1", "capture"]).shard_devices,
+                   vec![PathBuf::from("/dev/nvme1n1"), PathBuf::from("/dev/nvme2n1")]);
+    }
+
+    #[test]
+    fn test_mux_shards() {
+        let opts = Opts::from_iter(&vec!["prog", "-D", "imgdir", "--mux-shards", "--num-shards", "4", "capture"]);
+        assert!(opts.mux_shards);
+        assert_eq!(opts.num_shards, Some(4));
+    }
+
+    #[test]
+    fn test_reflink_from() {
+        let opts = Opts::from_iter(&vec!["prog", "-D", "ckpt-2", "--reflink-from", "ckpt-1", "extract"]);
+        assert_eq!(opts.reflink_from, Some(PathBuf::from("ckpt-1")));
+    }
+
+    #[test]
+    fn test_min_pipe_capacity() {
+        let opts = Opts::from_iter(&vec!["prog", "-D", "imgdir", "--min-pipe-capacity", "262144", "capture"]);
+        assert_eq!(opts.min_pipe_capacity, Some(262144));
+    }
+
+    #[test]
+    fn test_snapshot_cmd() {
+        let opts = Opts::from_iter(&vec!["prog", "-D", "/ckpt", "--snapshot-cmd", "zfs snapshot tank/ckpt@1", "extract"]);
+        assert_eq!(opts.snapshot_cmd, Some("zfs snapshot tank/ckpt@1".to_string()));
+    }
+
+    #[test]
+    fn test_ownership() {
+        let opts = Opts::from_iter(&vec!["prog", "-D", "/ckpt", "--chown", "100000:100000", "--chmod", "640", "extract"]);
+        assert_eq!(opts.chown, Some((100000, 100000)));
+        assert_eq!(opts.chmod, Some(0o640));
+        assert!(Opts::from_iter_safe(&vec!["prog", "-D", "/ckpt", "--chown", "100000", "extract"]).is_err());
+        assert!(Opts::from_iter_safe(&vec!["prog", "-D", "/ckpt", "--chmod", "u+r", "extract"]).is_err());
+    }
+
+    #[test]
+    fn test_file_table() {
+        assert!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "--file-table", "capture"]).file_table);
+        assert_eq!(Opts::from_iter(&vec!["prog", "list", "shard-0"]).operation,
+                   Operation::List { shard: PathBuf::from("shard-0") });
+    }
+
+    #[test]
+    fn test_shard_cmd() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "--shard-cmd", "aws s3 cp - s3://bucket/shard-{n}", "capture"]).shard_cmd,
+                   Some("aws s3 cp - s3://bucket/shard-{n}".to_string()));
+        assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "--rclone", "s3:bucket/ckpt", "--num-shards", "4", "serve"]).rclone,
+                   Some("s3:bucket/ckpt".to_string()));
+        assert!(Opts::from_iter_safe(&vec!["prog", "-D", "imgdir", "--shard-cmd", "cat", "--rclone", "s3:bucket", "capture"]).is_err());
+        assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "--ssh", "ssh://user@host/ckpt", "capture"]).ssh,
+                   Some(SshUrl { destination: "user@host".to_string(), port: None, dir: "/ckpt".to_string() }));
+        assert!(Opts::from_iter_safe(&vec!["prog", "-D", "imgdir", "--ssh", "user@host:/ckpt", "capture"]).is_err());
+    }
+
+    #[test]
+    fn test_rate_control() {
+        let opts = Opts::from_iter(&vec!["prog", "-D", "imgdir", "--max-rate", "100", "--rate-control", "capture"]);
+        assert_eq!(opts.max_rate, Some(100));
+        assert!(opts.rate_control);
+    }
+
+    #[test]
+    fn test_progress_format() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "--progress-format", "proto", "capture"]).progress_format,
+                   Some("proto".to_string()));
+        assert!(Opts::from_iter_safe(&vec!["prog", "-D", "imgdir", "--progress-format", "xml", "capture"]).is_err());
+    }
+
+    #[test]
+    fn test_log_target() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "--log-target", "journald", "capture"]).log_target,
+                   Some("journald".to_string()));
+        assert!(Opts::from_iter_safe(&vec!["prog", "-D", "imgdir", "--log-target", "file", "capture"]).is_err());
+    }
+
+    #[test]
+    fn test_codec() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "--codec", "gzip", "capture"]).codec,
+                   Some("gzip".to_string()));
+        assert!(Opts::from_iter_safe(&vec!["prog", "-D", "imgdir", "--codec", "lz4", "capture"]).is_err());
+    }
+
+    #[test]
+    fn test_import_stream() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--shard-fds", "1", "import-stream"]).operation,
+                   Operation::ImportStream);
+        assert_eq!(Opts::from_iter(&vec!["prog", "--shard-fds", "1", "--sign-key", "key.pem", "import-stream"]).sign_key,
+                   Some(PathBuf::from("key.pem")));
+    }
+
+
+    #[test]
+    fn test_shards_fds() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--shard-fds", "1,2,3", "capture"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![1,2,3],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Capture,
+            })
+    }
+
+    #[test]
+    fn test_ext_files() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--ext-file-fds", "file1:1,file2:2", "capture"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![(String::from("file1"), 1), (String::from("file2"), 2)],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Capture,
+            })
+    }
+
+    #[test]
+    fn test_tcp_listen_remaps() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--tcp-listen-remap", "2000:3000,5000:6000", "serve"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![(2000,3000),(5000,6000)],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Serve { phases: false, request_stats: false, standby: false, rolling: false },
+            })
+    }
+
+    #[test]
+    fn test_progess_fd() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--progress-fd", "3", "capture"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: Some(3),
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Capture,
+            })
+    }
+
+    #[test]
+    fn test_sign_key() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--sign-key", "key.pem", "capture"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: Some(PathBuf::from("key.pem")),
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Capture,
+            })
+    }
+
+    #[test]
+    fn test_verify_key() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--verify-key", "pub.pem", "serve"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: Some(PathBuf::from("pub.pem")),
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Serve { phases: false, request_stats: false, standby: false, rolling: false },
+            })
+    }
+
+    #[test]
+    fn test_audit_log() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--audit-log-to-progress", "serve"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: true,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Serve { phases: false, request_stats: false, standby: false, rolling: false },
+            })
+    }
+
+    #[test]
+    fn test_max_marker_size() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--max-marker-size", "1048576", "capture"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: Some(1048576),
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Capture,
+            })
+    }
+
+    #[test]
+    fn test_epoll_capacity() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--epoll-capacity", "64", "capture"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: Some(64),
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Capture,
+            })
+    }
+
+    #[test]
+    fn test_chunk_alignment() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "--chunk-alignment", "4096", "capture"]).chunk_alignment,
+                   Some(4096));
+    }
+
+    #[test]
+    fn test_shard_spill_size() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--shard-spill-size", "67108864", "capture"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: Some(67108864),
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Capture,
+            })
+    }
+
+    #[test]
+    fn test_expected_size() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--expected-size", "1073741824", "capture"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: Some(1073741824),
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Capture,
+            })
+    }
+
+    #[test]
+    fn test_expected_size_manifest() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--expected-size-manifest", "prev/streamer-manifest.json", "capture"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: Some(PathBuf::from("prev/streamer-manifest.json")),
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Capture,
+            })
+    }
+
+    #[test]
+    fn test_cpuset_nice() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--cpuset", "0-3,8", "--nice", "-5", "capture"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![0..=3, 8..=8],
+                nice: Some(-5),
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Capture,
+            })
+    }
+
+    #[test]
+    fn test_bench() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "bench", "--shards", "2", "--large-files", "0", "--rate", "100"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Bench {
+                    shards: 2,
+                    small_files: 1000,
+                    medium_files: 100,
+                    large_files: 0,
+                    rate: Some(100),
+                },
+            })
+    }
+
+    #[test]
+    fn test_criu_trace() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--criu-trace", "trace.json", "serve"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: Some(PathBuf::from("trace.json")),
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Serve { phases: false, request_stats: false, standby: false, rolling: false },
+            })
+    }
+
+    #[test]
+    fn test_debug_markers() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--debug-markers", "markers.log", "extract"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: Some(PathBuf::from("markers.log")),
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Extract { dry_run: false, to_stdout: false },
+            })
+    }
+
+    #[test]
+    fn test_containers() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--containers", "app,sidecar", "capture"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec!["app".to_string(), "sidecar".to_string()],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Capture,
+            });
+        assert!(Opts::from_iter_safe(&vec!["prog", "--images-dir", "imgdir", "--containers", "a/b", "capture"]).is_err());
+    }
+
+    #[test]
+    fn test_job_id() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--job-id", "job-42", "serve"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: Some("job-42".to_string()),
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Serve { phases: false, request_stats: false, standby: false, rolling: false },
+            });
+    }
+
+    #[test]
+    fn test_check_criu() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--check-criu-features", "mem_dirty_track,uffd", "capture"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec!["mem_dirty_track".to_string(), "uffd".to_string()],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Capture,
+            });
+    }
+
+    #[test]
+    fn test_criu_timeout() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--criu-timeout", "30", "serve"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: Some(30),
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Serve { phases: false, request_stats: false, standby: false, rolling: false },
+            });
+    }
+
+    #[test]
+    fn test_refuse_splice_bug() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--refuse-splice-bug", "serve"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: true,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Serve { phases: false, request_stats: false, standby: false, rolling: false },
+            });
+    }
+
+    #[test]
+    fn test_ext_file_digests() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--ext-file-fds", "fs.tar:3", "--ext-file-digests", "extract"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![(String::from("fs.tar"), 3)],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: true,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Extract { dry_run: false, to_stdout: false },
+            });
+    }
+
+    #[test]
+    fn test_file_digests() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--file-digests", "capture"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: true,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Capture,
+            });
+    }
+
+    #[test]
+    fn test_ghost_files_dir() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--ghost-files-dir", "ghosts", "extract"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: Some(PathBuf::from("ghosts")),
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Extract { dry_run: false, to_stdout: false },
+            });
+    }
+
+    #[test]
+    fn test_shard_fifos() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--shard-fifos", "/run/ckpt/shard-{n}", "--num-shards", "4", "capture"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: Some(String::from("/run/ckpt/shard-{n}")),
+                num_shards: Some(4),
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Capture,
+            });
+    }
+
+    #[test]
+    fn test_ordered_ext_files() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "--ext-file-fds", "lower.tar:10,upper.tar:11", "--ordered-ext-files", "capture"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![(String::from("lower.tar"), 10), (String::from("upper.tar"), 11)],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: true,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Capture,
+            });
+    }
+
+    #[test]
+    fn test_replay() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "--images-dir", "imgdir", "replay", "trace.json"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Replay {
+                    trace: PathBuf::from("trace.json"),
+                },
+            })
+    }
+
+    #[test]
+    fn test_show() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "show", "files.img", "--from-stream"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Show {
+                    filename: "files.img".to_string(),
+                    from_stream: true,
+                },
+            })
+    }
+
+    #[test]
+    fn test_filter() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "filter",
+                                         "--remove", "a.img,b.img",
+                                         "--replace", "c.img:/tmp/c.img",
+                                         "--output-shard-fds", "3,4"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Filter {
+                    remove: vec!["a.img".to_string(), "b.img".to_string()],
+                    replace: vec![("c.img".to_string(), PathBuf::from("/tmp/c.img"))],
+                    output_shard_fds: vec![3, 4],
+                },
+            })
+    }
+
+    #[test]
+    fn test_merge() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "merge",
+                                         "--input-shard-fds", "3,4",
+                                         "--input-shard-fds", "5"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: None,
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::Merge {
+                    input_shard_fds: vec![vec![3, 4], vec![5]],
+                    output_shard_fds: vec![],
+                },
+            })
+    }
+
+    #[test]
+    #[cfg(feature = "config")]
+    fn test_config_env_vars() {
+        let config = r#"
+            images-dir = "/tmp/img"
+            shard-fds = [3, 4]
+            nice = -5
+            containers = ["app", "sidecar"]
+        "#;
+        let mut env_vars = config_env_vars(config).unwrap();
+        env_vars.sort();
+        assert_eq!(env_vars, vec![
+            ("CRIU_IMG_STREAMER_CONTAINERS".to_string(), "app,sidecar".to_string()),
+            ("CRIU_IMG_STREAMER_IMAGES_DIR".to_string(), "/tmp/img".to_string()),
+            ("CRIU_IMG_STREAMER_NICE".to_string(), "-5".to_string()),
+            ("CRIU_IMG_STREAMER_SHARD_FDS".to_string(), "3,4".to_string()),
+        ]);
+
+        assert!(config_env_vars("shards = 4").is_err());
+        assert!(config_env_vars("dry-run = true").is_err());
+        assert!(config_env_vars("sign-key = true").is_err());
+        assert!(config_env_vars("mux-shards = \"yes\"").is_err());
+    }
+
+    // Every option of all operations can be provided by the config file, and the environment.
+    #[test]
+    #[cfg(feature = "config")]
+    fn test_config_covers_all_options() {
+        for opt in Opts::clap().p.opts() {
+            let long = opt.s.long.unwrap();
+            if matches!(long, "config" | "otlp-endpoint" | "traceparent") {
+                continue;
+            }
+            assert_eq!(opt.v.env.as_ref().map(|(env, _)| *env), Some(OsStr::new(&option_env_var(long))),
+                       "--{} is not read from the environment", long);
+            assert_eq!(config_env_vars(&format!("{} = \"value\"", long)).unwrap(),
+                       vec![(option_env_var(long), "value".to_string())]);
+        }
+
+        let flags = config_flags();
+        assert!(flags.contains(&"mux-shards") && flags.contains(&"audit-log-to-progress"));
+        for flag in flags {
+            let env_vars = config_env_vars(&format!("{} = true", flag)).unwrap();
+            let env_var = |name: &str| env_vars.iter()
+                .find(|(env_name, _)| env_name == name)
+                .map(|(_, value)| OsString::from(value));
+            assert_eq!(env_flag_args(&[], env_var).unwrap(), vec![OsString::from(format!("--{}", flag))]);
+        }
+    }
+
+    #[test]
+    fn test_env_flag_args() {
+        let env_var = |name: &str| match name {
+            "CRIU_IMG_STREAMER_MUX_SHARDS" => Some(OsString::from("true")),
+            "CRIU_IMG_STREAMER_FILE_TABLE" => Some(OsString::from("false")),
+            "CRIU_IMG_STREAMER_RATE_CONTROL" => Some(OsString::from("true")),
+            _ => None,
+        };
+        let args: Vec<OsString> = vec!["--rate-control".into(), "capture".into()];
+        assert_eq!(env_flag_args(&args, env_var).unwrap(), vec![OsString::from("--mux-shards")]);
+
+        assert!(env_flag_args(&[], |_| Some(OsString::from("1"))).is_err());
+    }
+
+    #[test]
+    fn test_doctor() {
+        let opts = Opts::from_iter(&vec!["prog", "doctor", "--image-size", "1000"]);
+        assert_eq!(opts.images_dir, None);
+        assert_eq!(opts.operation, Operation::Doctor { image_size: Some(1000) });
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let opts = Opts::from_iter(&vec!["prog", "capabilities"]);
+        assert_eq!(opts.images_dir, None);
+        assert_eq!(opts.operation, Operation::Capabilities);
+
+        // The reported operations must be the ones of the command line.
+        for operation in criu_image_streamer::capabilities::OPERATIONS {
+            let err = Opts::clap().get_matches_from_safe(vec!["prog", operation, "--help"]).unwrap_err();
+            assert_eq!(err.kind, structopt::clap::ErrorKind::HelpDisplayed, "{}", operation);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "kubelet")]
+    fn test_kubelet_import() {
+        assert_eq!(Opts::from_iter(&vec!["prog", "-D", "imgdir", "--sign-key", "key.pem",
+                                         "kubelet-import"]),
+            Opts {
+                images_dir: Some(PathBuf::from("imgdir")),
+                shard_fds: vec![],
+                shard_fifos: None,
+                num_shards: None,
+                codec_cmd: None,
+                codec: None,
+                shard_devices: vec![],
+                mux_shards: false,
+                shard_cmd: None,
+                rclone: None,
+                ssh: None,
+                ext_file_fds: vec![],
+                tcp_listen_remap: vec![],
+                progress_fd: None,
+                progress_format: None,
+                log_target: None,
+                sign_key: Some(PathBuf::from("key.pem")),
+                verify_key: None,
+                audit_log: None,
+                audit_log_to_progress: false,
+                max_marker_size: None,
+                min_pipe_capacity: None,
+                epoll_capacity: None,
+                shard_spill_size: None,
+                chunk_alignment: None,
+                file_table: false,
+                reflink_from: None,
+                snapshot_cmd: None,
+                chown: None,
+                chmod: None,
+                expected_size: None,
+                expected_size_manifest: None,
+                cpuset: vec![],
+                nice: None,
+                criu_trace: None,
+                debug_markers: None,
+                containers: vec![],
+                job_id: None,
+                check_criu: false,
+                check_criu_features: vec![],
+                criu_timeout: None,
+                max_rate: None,
+                rate_control: false,
+                refuse_splice_bug: false,
+                ext_file_digests: false,
+                file_digests: false,
+                ordered_ext_files: false,
+                ghost_files_dir: None,
+                otlp_endpoint: None,
+                traceparent: None,
+                config: None,
+                operation: Operation::KubeletImport,
+            })
+    }
+}
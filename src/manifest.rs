@@ -0,0 +1,201 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Write, Result as IoResult},
+    path::Path,
+};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+#[cfg(feature = "signing")]
+use ed25519_dalek::{
+    Signer, Signature,
+    pkcs8::{DecodePrivateKey, DecodePublicKey},
+};
+use anyhow::{Result, Context};
+
+#[cfg(feature = "signing")]
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+/// Builds without the signing feature have no keys, so that images can't be signed nor verified.
+#[cfg(not(feature = "signing"))]
+#[derive(Debug, Clone)]
+pub enum SigningKey {}
+
+#[cfg(not(feature = "signing"))]
+#[derive(Debug, Clone)]
+pub enum VerifyingKey {}
+
+// When capturing an image with a signing key, we produce a manifest listing the size and sha256
+// digest of every file in the image (CRIU files and external files). The manifest is signed with
+// an ed25519 key, and both the manifest and its signature are appended to the image stream as
+// regular files, right before the image EOF marker.
+//
+// On restore, the manifest signature is checked with the corresponding public key, and the
+// digests of the received files are compared against the manifest. The image must contain exactly
+// the files listed in the manifest. This prevents a compromised storage backend from injecting a
+// tampered checkpoint into a restore.
+
+/// These filenames are reserved. CRIU never produces files with such names.
+pub const MANIFEST_FILENAME: &str = "streamer-manifest.json";
+pub const MANIFEST_SIG_FILENAME: &str = "streamer-manifest.sig";
+
+pub fn is_reserved_filename(filename: &str) -> bool {
+    filename == MANIFEST_FILENAME || filename == MANIFEST_SIG_FILENAME
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Manifest {
+    pub files: Vec<ManifestFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestFile {
+    pub filename: String,
+    #[serde(flatten)]
+    pub digest: FileDigest,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileDigest {
+    pub size: u64,
+    /// Hex encoded sha256 digest
+    pub sha256: String,
+}
+
+/// `FileHasher` computes the digest of a file as its content streams through.
+#[derive(Default)]
+pub struct FileHasher {
+    hasher: Sha256,
+    size: u64,
+}
+
+impl FileHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+    }
+
+    pub fn finalize(self) -> FileDigest {
+        FileDigest { size: self.size, sha256: format!("{:x}", self.hasher.finalize()) }
+    }
+}
+
+impl Write for FileHasher {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl Manifest {
+    pub fn add_file(&mut self, filename: &str, digest: FileDigest) {
+        self.files.push(ManifestFile { filename: filename.to_string(), digest });
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    /// Returns the files sorted by filename, e.g., to report them in the stats.
+    pub fn into_sorted_files(mut self) -> Vec<ManifestFile> {
+        self.files.sort_by(|a, b| a.filename.cmp(&b.filename));
+        self.files
+    }
+
+    /// Returns the serialized manifest and its signature.
+    #[cfg(feature = "signing")]
+    pub fn sign(&self, key: &SigningKey) -> Result<(Vec<u8>, Vec<u8>)> {
+        let manifest = self.serialize()?;
+        let sig = key.sign(&manifest).to_bytes().to_vec();
+        Ok((manifest, sig))
+    }
+
+    #[cfg(not(feature = "signing"))]
+    pub fn sign(&self, key: &SigningKey) -> Result<(Vec<u8>, Vec<u8>)> {
+        match *key {}
+    }
+
+    /// Deserializes a manifest, only if its signature is valid.
+    #[cfg(feature = "signing")]
+    pub fn from_signed(manifest: &[u8], sig: &[u8], key: &VerifyingKey) -> Result<Self> {
+        let sig = Signature::from_slice(sig)
+            .map_err(|_| anyhow!("The image manifest signature is malformed"))?;
+        key.verify_strict(manifest, &sig)
+            .map_err(|_| anyhow!("The image manifest signature is invalid"))?;
+        serde_json::from_slice(manifest).context("The image manifest is malformed")
+    }
+
+    #[cfg(not(feature = "signing"))]
+    pub fn from_signed(_manifest: &[u8], _sig: &[u8], key: &VerifyingKey) -> Result<Self> {
+        match *key {}
+    }
+
+    /// Loads a manifest of a previously captured image, without checking its signature. This is
+    /// only meant for estimates, such as the expected size of the next capture.
+    pub fn load_unverified(path: &Path) -> Result<Self> {
+        let manifest = std::fs::read(path)
+            .with_context(|| format!("Failed to read manifest {}", path.display()))?;
+        serde_json::from_slice(&manifest)
+            .with_context(|| format!("The manifest {} is malformed", path.display()))
+    }
+
+    /// Returns the sum of the sizes of the files listed in the manifest.
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|f| f.digest.size).sum()
+    }
+
+    /// Ensures that the received files are exactly the ones listed in the manifest, and that
+    /// their content matches.
+    pub fn check_digests(&self, digests: &HashMap<Box<str>, FileDigest>) -> Result<()> {
+        for file in &self.files {
+            let digest = digests.get(file.filename.as_str())
+                .ok_or_else(|| anyhow!("`{}` is listed in the manifest, but is missing from the image",
+                                       file.filename))?;
+            ensure!(*digest == file.digest,
+                    "`{}` does not match its manifest digest", file.filename);
+        }
+
+        let listed_files: HashSet<&str> = self.files.iter().map(|f| f.filename.as_str()).collect();
+        let unexpected_files = digests.keys()
+            .map(|filename| &**filename)
+            .filter(|filename| !is_reserved_filename(filename) && !listed_files.contains(filename))
+            .collect::<Vec<_>>();
+        ensure!(unexpected_files.is_empty(),
+                "The image contains files that are not listed in the manifest: {:?}",
+                unexpected_files);
+
+        Ok(())
+    }
+}
+
+/// Loads an ed25519 private key in the PKCS#8 PEM format, as generated with
+/// `openssl genpkey -algorithm ed25519`.
+#[cfg(feature = "signing")]
+pub fn load_signing_key(path: &Path) -> Result<SigningKey> {
+    SigningKey::read_pkcs8_pem_file(path)
+        .map_err(|e| anyhow!("Failed to load signing key {}: {}", path.display(), e))
+}
+
+/// Loads an ed25519 public key in the PEM format, as generated with `openssl pkey -pubout`.
+#[cfg(feature = "signing")]
+pub fn load_verifying_key(path: &Path) -> Result<VerifyingKey> {
+    VerifyingKey::read_public_key_pem_file(path)
+        .map_err(|e| anyhow!("Failed to load verifying key {}: {}", path.display(), e))
+}
@@ -0,0 +1,111 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    fmt::Write as _,
+    io::Write,
+    path::Path,
+    fs,
+};
+use crate::image::{self, marker};
+use anyhow::{Result, Context};
+
+// Reconstructing how chunks got interleaved across shards from the raw shard bytes is tedious.
+// With --debug-markers, the markers are logged as they are written to the shards when capturing,
+// and as they are processed when extracting, one line per marker:
+//
+//     seq=12 shard=3 type=file_data filename=pages-1.img size=262144
+//
+// Shard headers carry no sequence number, and are logged with seq=0. File data is not logged.
+
+/// `MarkerLog` records markers in a human-readable form, one line per marker.
+/// Failing to write a line fails the operation, as an incomplete log would be misleading.
+pub struct MarkerLog {
+    file: fs::File,
+    line: String,
+}
+
+impl MarkerLog {
+    pub fn new(file: fs::File) -> Self {
+        Self { file, line: String::new() }
+    }
+
+    /// Creates the log file, truncating it if it exists.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = fs::File::create(path)
+            .with_context(|| format!("Failed to create marker log {}", path.display()))?;
+        Ok(Self::new(file))
+    }
+
+    /// Records a marker going through the shard `shard_index`. `filename` is the file that the
+    /// file data and file EOF markers relate to, as they don't carry it.
+    pub fn record(&mut self, shard_index: usize, marker: &image::Marker, filename: Option<&str>) -> Result<()> {
+        self.line.clear();
+        format_marker(&mut self.line, shard_index, marker, filename);
+        self.line.push('\n');
+        self.file.write_all(self.line.as_bytes()).context("Failed to write to the marker log")?;
+        Ok(())
+    }
+}
+
+fn format_marker(out: &mut String, shard_index: usize, marker: &image::Marker, filename: Option<&str>) {
+    use marker::Body::*;
+
+    let _ = write!(out, "seq={} shard={}", marker.seq, shard_index);
+    let filename = filename.unwrap_or("?");
+    let _ = match &marker.body {
+        Some(ShardHeader(header)) => write!(out, " type=shard_header image_uuid={} shard_index={} num_shards={}",
+                                            header.image_uuid, header.shard_index, header.num_shards),
+        Some(Filename(filename)) if marker.file_size > 0 =>
+            write!(out, " type=filename filename={} file_size={}", filename, marker.file_size),
+        Some(Filename(filename)) => write!(out, " type=filename filename={}", filename),
+        Some(FileData(size)) => write!(out, " type=file_data filename={} size={}", filename, size),
+        Some(FileEof(_)) => write!(out, " type=file_eof filename={}", filename),
+        Some(ImageEof(_)) => write!(out, " type=image_eof"),
+        Some(ImageAborted(_)) => write!(out, " type=image_aborted"),
+        Some(FileTable(size)) => write!(out, " type=file_table size={}", size),
+        Some(FileTableSize(size)) => write!(out, " type=file_table_size size={}", size),
+        None => write!(out, " type=unknown"),
+    };
+    if marker.padding > 0 {
+        let _ = write!(out, " padding={}", marker.padding);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(shard_index: usize, seq: u64, body: marker::Body, filename: Option<&str>) -> String {
+        let mut out = String::new();
+        format_marker(&mut out, shard_index, &image::Marker { seq, body: Some(body), padding: 0, file_size: 0 }, filename);
+        out
+    }
+
+    #[test]
+    fn test_format_marker() {
+        use marker::Body::*;
+
+        assert_eq!(format(1, 0, ShardHeader(image::ShardHeader {
+            image_uuid: "uuid".to_string(), shard_index: 1, num_shards: 4,
+        }), None), "seq=0 shard=1 type=shard_header image_uuid=uuid shard_index=1 num_shards=4");
+        assert_eq!(format(2, 5, Filename("core-1.img".to_string()), None),
+                   "seq=5 shard=2 type=filename filename=core-1.img");
+        assert_eq!(format(2, 6, FileData(42), Some("core-1.img")),
+                   "seq=6 shard=2 type=file_data filename=core-1.img size=42");
+        assert_eq!(format(0, 7, FileEof(true), Some("core-1.img")),
+                   "seq=7 shard=0 type=file_eof filename=core-1.img");
+        assert_eq!(format(3, 8, ImageEof(true), Some("core-1.img")), "seq=8 shard=3 type=image_eof");
+    }
+}
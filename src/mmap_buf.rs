@@ -25,13 +25,16 @@ use core::ffi::c_void;
 ///
 /// We don't use the memmap create because it doesn't offer a len+capacity abstraction. We'd have
 /// to do a wrapper on their `MmapMap` type. That doesn't buy us much code reuse.
-
 pub struct MmapBuf {
     addr: ptr::NonNull<u8>,
     len: usize,
     capacity: usize,
 }
 
+// Like a `Vec<u8>`, the buffer exclusively owns its memory region, so it can be moved to another
+// thread. This is needed to serve the files of multiple containers concurrently.
+unsafe impl Send for MmapBuf {}
+
 #[allow(clippy::len_without_is_empty)]
 impl MmapBuf {
     pub fn with_capacity(capacity: usize) -> Self {
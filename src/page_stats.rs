@@ -0,0 +1,73 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+
+use anyhow::{Context, Result};
+use serde::{Serialize, Deserialize};
+use crate::{
+    image_patcher::read_criu_img_header,
+    util::{pb_read, pb_read_next},
+    criu,
+};
+
+// When CRIU performs iterative pre-dumps with memory tracking, each iteration only dumps the pages
+// that changed since the previous iteration. The other pages are listed in the pagemap images as
+// being in the parent image. By looking at the pagemap images as they stream through, we report
+// the fraction of pages that changed. A migration controller can use it to decide when pre-copy
+// no longer converges, and the final dump should be done.
+
+// These consts are defined in the CRIU project in criu/include/magic.h and criu/include/pagemap.h
+const PAGEMAP_MAGIC: u32 = 0x56084025;
+const PE_PARENT: u32 = 1 << 0;
+
+/// Pagemap images are named pagemap-<pid>.img, or pagemap-shmem-<id>.img for shared memory.
+pub fn is_pagemap_filename(filename: &str) -> bool {
+    filename.starts_with("pagemap-") && filename.ends_with(".img")
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct PageStats {
+    /// Number of pages listed in the pagemap images
+    pub total_pages: u64,
+    /// Number of pages dumped in this image, as opposed to being in the parent image
+    pub dirty_pages: u64,
+    /// dirty_pages / total_pages, or 0 when there are no pages
+    pub dirty_fraction: f64,
+}
+
+impl PageStats {
+    /// Accounts for the pages listed in the content of a pagemap image.
+    pub fn add_pagemap(&mut self, filename: &str, mut pagemap: &[u8]) -> Result<()> {
+        let mut add_entries = || -> Result<()> {
+            read_criu_img_header(&mut pagemap, PAGEMAP_MAGIC)?;
+            let _head: criu::PagemapHead = pb_read(&mut pagemap)?;
+            while let Some((entry, _)) = pb_read_next::<_, criu::PagemapEntry>(&mut pagemap)? {
+                // Older CRIU versions use the in_parent field instead of the PE_PARENT flag.
+                let in_parent = entry.in_parent.unwrap_or(false) ||
+                                entry.flags.unwrap_or(0) & PE_PARENT != 0;
+                self.total_pages += entry.nr_pages as u64;
+                if !in_parent {
+                    self.dirty_pages += entry.nr_pages as u64;
+                }
+            }
+            Ok(())
+        };
+        add_entries().with_context(|| format!("Failed to parse {}", filename))?;
+
+        if self.total_pages > 0 {
+            self.dirty_fraction = self.dirty_pages as f64 / self.total_pages as f64;
+        }
+        Ok(())
+    }
+}
@@ -13,9 +13,11 @@
 //  limitations under the License.
 
 use std::{
+    collections::VecDeque,
     os::unix::io::RawFd,
     convert::TryFrom,
     ops::Drop,
+    time::Duration,
 };
 use slab::Slab;
 use nix::{
@@ -32,22 +34,33 @@ use anyhow::{Context, Result};
 /// object via poll().
 /// There should be a crate with this functionality. Either we didn't look well enough, or we
 /// should publish a crate, because it seems useful beyond this project,
-
 pub struct Poller<T> {
     epoll_fd: RawFd,
     slab: Slab<(RawFd, T)>,
     pending_events: Vec<EpollEvent>,
+    /// Objects that are still ready, but that epoll won't report again as their fds are
+    /// edge-triggered. They are returned in a round-robin fashion, after new events.
+    rescheduled: VecDeque<(Key, EpollFlags)>,
 }
 
 pub type Key = usize;
 
+pub enum PollResult<'a, T> {
+    Ready(Key, &'a mut T, EpollFlags),
+    /// No object became ready within the timeout.
+    TimedOut,
+}
+
+use PollResult::*;
+
 impl<T> Poller<T> {
     pub fn new() -> Result<Self> {
         let epoll_fd = epoll_create().context("Failed to create epoll")?;
         let slab = Slab::new();
         let pending_events = Vec::new();
+        let rescheduled = VecDeque::new();
 
-        Ok(Self { epoll_fd, slab, pending_events })
+        Ok(Self { epoll_fd, slab, pending_events, rescheduled })
     }
 
     pub fn add(&mut self, fd: RawFd, obj: T, flags: EpollFlags) -> Result<Key> {
@@ -64,15 +77,32 @@ impl<T> Poller<T> {
         let (fd, obj) = self.slab.remove(key);
         epoll_ctl(self.epoll_fd, EpollOp::EpollCtlDel, fd, None)
             .context("Failed to remove fd from epoll")?;
+        self.rescheduled.retain(|(k, _)| *k != key);
         Ok(obj)
     }
 
+    /// Makes poll() return the object again, with the given events, once the other ready objects
+    /// had their turn. This is used when an edge-triggered fd has not been fully consumed.
+    pub fn reschedule(&mut self, key: Key, events: EpollFlags) {
+        match self.rescheduled.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, e)) => *e |= events,
+            None => self.rescheduled.push_back((key, events)),
+        }
+    }
+
+    /// Returns the tracked objects.
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.slab.iter().map(|(_key, (_fd, obj))| obj)
+    }
+
     /// Returns None when the poller has no file descriptors to track.
-    /// Otherwise, blocks and returns a reference to the next ready object.
+    /// Otherwise, blocks and returns a reference to the next ready object, along with the
+    /// reported events. When `timeout` is provided, returns `TimedOut` if no object became
+    /// ready in time.
     ///
     /// `capacity` corresponds to the number of file descriptors that can be returned by a single
     /// system call.
-    pub fn poll(&mut self, capacity: usize) -> Result<Option<(Key, &mut T)>> {
+    pub fn poll(&mut self, capacity: usize, timeout: Option<Duration>) -> Result<Option<PollResult<'_, T>>> {
         if self.slab.is_empty() {
             return Ok(None);
         }
@@ -80,21 +110,32 @@ impl<T> Poller<T> {
         if self.pending_events.is_empty() {
             self.pending_events.resize(capacity, EpollEvent::empty());
 
-            let timeout = -1;
-            let num_ready_fds = epoll_wait_no_intr(self.epoll_fd, &mut self.pending_events, timeout)
+            // When objects are rescheduled, we only collect the new events without blocking.
+            let timeout_ms = match timeout {
+                _ if !self.rescheduled.is_empty() => 0,
+                Some(timeout) => timeout.as_millis() as isize,
+                None => -1,
+            };
+            let num_ready_fds = epoll_wait_no_intr(self.epoll_fd, &mut self.pending_events, timeout_ms)
                 .context("Failed to wait on epoll")?;
 
-            // We don't use a timeout (-1), and we have events registered (slab is not empty)
-            // so we should have a least one fd ready.
-            assert!(num_ready_fds > 0);
+            // Without a timeout (-1), and with events registered (slab is not empty),
+            // we should have a least one fd ready.
+            assert!(num_ready_fds > 0 || timeout_ms >= 0);
 
             self.pending_events.truncate(num_ready_fds);
+            if num_ready_fds == 0 && self.rescheduled.is_empty() {
+                return Ok(Some(TimedOut));
+            }
         }
 
-        let event = self.pending_events.pop().unwrap();
-        let key = event.data() as usize;
+        let (key, events) = match self.pending_events.pop() {
+            Some(event) => (event.data() as usize, event.events()),
+            // We have no new events, so we did not block, and `rescheduled` is not empty.
+            None => self.rescheduled.pop_front().unwrap(),
+        };
         let (_fd, obj) = &mut self.slab[key];
-        Ok(Some((key, obj)))
+        Ok(Some(Ready(key, obj, events)))
     }
 }
 
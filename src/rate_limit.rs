@@ -0,0 +1,163 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixListener,
+    sync::{Arc, atomic::{AtomicU64, Ordering}},
+    time::{Duration, Instant},
+};
+use crate::util::MB;
+use anyhow::Result;
+
+// The pre-copy phases of a live migration share the network with the application, and can last
+// long. A migration controller reacting to congestion caps the rate at which the capture writes
+// the shards, and changes the cap as the transfer goes. Library users hold a `RateLimit`, and
+// other controllers send commands on the control socket of the capture:
+//
+//     rate 100        caps the rate to 100 MB/s
+//     rate unlimited  removes the cap
+//
+// Each command gets `ok`, or `error <reason>`, in reply. The capture throttles itself by pausing
+// between image file reads, which makes CRIU block on its full pipes.
+
+/// The longest pause of the capture at once, so that the watchdog, and the control socket, are
+/// serviced while throttled.
+pub const MAX_THROTTLE_PAUSE: Duration = Duration::from_millis(100);
+
+/// The rate limit of a capture, in bytes per second. Clones share the same limit, so that the
+/// caller keeps a handle to change it while the capture runs.
+#[derive(Clone, Default, Debug)]
+pub struct RateLimit {
+    /// 0 when unlimited
+    rate: Arc<AtomicU64>,
+}
+
+impl RateLimit {
+    pub fn new(rate: Option<u64>) -> Self {
+        let limit = Self::default();
+        limit.set(rate);
+        limit
+    }
+
+    /// Sets the rate limit, in bytes per second. `None` removes the limit.
+    pub fn set(&self, rate: Option<u64>) {
+        self.rate.store(rate.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<u64> {
+        Some(self.rate.load(Ordering::Relaxed)).filter(|rate| *rate > 0)
+    }
+}
+
+/// `Throttle` paces a transfer to its rate limit.
+pub struct Throttle {
+    limit: RateLimit,
+    rate: Option<u64>,
+    /// When the current rate started to apply, and the bytes transferred by then.
+    origin: Option<(Instant, u64)>,
+}
+
+impl Throttle {
+    pub fn new(limit: RateLimit) -> Self {
+        Self { limit, rate: None, origin: None }
+    }
+
+    /// Returns how long to pause to stay under the rate limit, given the number of bytes
+    /// transferred so far.
+    pub fn pause(&mut self, transferred: u64) -> Duration {
+        let rate = self.limit.get();
+        if rate != self.rate {
+            // A new rate applies from now on. Time spent under the previous rate earns no credit.
+            self.rate = rate;
+            self.origin = None;
+        }
+        let rate = match rate {
+            Some(rate) => rate,
+            None => return Duration::ZERO,
+        };
+        let (origin_time, origin_transferred) = *self.origin.get_or_insert((Instant::now(), transferred));
+        let due = Duration::from_secs_f64((transferred - origin_transferred) as f64 / rate as f64);
+        due.saturating_sub(origin_time.elapsed())
+    }
+}
+
+/// Returns the rate in bytes per second of `rate`, given in MB/s.
+pub fn mb_per_sec(rate: u64) -> Result<u64> {
+    rate.checked_mul(MB as u64).ok_or_else(|| anyhow!("The rate of {} MB/s is too large", rate))
+}
+
+/// Parses a control command, and returns the new rate limit in bytes per second.
+fn parse_rate_command(command: &str) -> Result<Option<u64>> {
+    match command.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["rate", "unlimited"] => Ok(None),
+        ["rate", rate] => {
+            let rate: u64 = rate.parse().map_err(|_| anyhow!("Invalid rate `{}`, expected MB/s", rate))?;
+            ensure!(rate > 0, "The rate must be positive, use `rate unlimited` to remove the limit");
+            Ok(Some(mb_per_sec(rate)?))
+        }
+        _ => bail!("Unknown command `{}`", command),
+    }
+}
+
+/// Accepts a controller on the control socket, and applies its command to `limit`. Errors of the
+/// controller are reported to it, and don't fail the capture.
+pub fn handle_rate_control(control_listener: &UnixListener, limit: &RateLimit) -> Result<()> {
+    let (socket, _) = control_listener.accept()?;
+    // A controller that connects without sending its command must not stall the capture.
+    socket.set_read_timeout(Some(MAX_THROTTLE_PAUSE))?;
+    let mut command = String::new();
+    if BufReader::new(&socket).read_line(&mut command).is_err() || command.is_empty() {
+        return Ok(());
+    }
+    let reply = match parse_rate_command(command.trim_end_matches('\n')) {
+        Ok(rate) => {
+            limit.set(rate);
+            "ok".to_string()
+        }
+        Err(e) => format!("error {}", e),
+    };
+    let _ = writeln!(&socket, "{}", reply);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate_command() {
+        assert_eq!(parse_rate_command("rate 100").unwrap(), Some(100*MB as u64));
+        assert_eq!(parse_rate_command("rate unlimited").unwrap(), None);
+        assert!(parse_rate_command("rate 0").is_err());
+        assert!(parse_rate_command("rate fast").is_err());
+        assert!(parse_rate_command(&format!("rate {}", u64::MAX)).is_err());
+        assert!(parse_rate_command("go").is_err());
+    }
+
+    #[test]
+    fn test_throttle() {
+        let limit = RateLimit::new(Some(MB as u64));
+        let mut throttle = Throttle::new(limit.clone());
+        assert_eq!(throttle.pause(0), Duration::ZERO);
+        let pause = throttle.pause(MB as u64 / 2);
+        assert!(pause > Duration::from_millis(400) && pause <= Duration::from_millis(500), "{:?}", pause);
+
+        // The data transferred under the previous limit is not paused for.
+        limit.set(None);
+        assert_eq!(throttle.pause(MB as u64), Duration::ZERO);
+        limit.set(Some(MB as u64));
+        assert_eq!(throttle.pause(2*MB as u64), Duration::ZERO);
+    }
+}
@@ -0,0 +1,169 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+use crate::{
+    image_store::fs::Ownership,
+    manifest::{FileDigest, FileHasher},
+};
+use anyhow::{Result, Context};
+
+// Periodic checkpoints of a workload are often retained as successive extractions, e.g., one
+// directory per checkpoint. Most of their files don't change from one checkpoint to the next
+// (e.g., the images of idle processes). With --reflink-from, once the image is extracted, each
+// file whose content is the same as the file of the same name in the previous extraction is
+// replaced by a reflink of it, so that both share their blocks on btrfs or XFS. On filesystems
+// without reflinks, the file is hard linked instead. Retained checkpoints must not be modified
+// in place, as hard linked files are shared.
+//
+// With --chown or --chmod, the reflinks get the ownership like the other extracted files. A hard
+// link shares the owner and permissions of the previous file, so the file is only hard linked
+// when the previous file already has the ownership, and is left as extracted otherwise.
+//
+// Files are compared with their sha256 digests. The digest of the previous file is computed
+// only when its size matches.
+
+nix::ioctl_write_int!(ficlone, 0x94, 9);
+
+/// How an extracted file was made to share the blocks of the previous one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Link {
+    Reflink,
+    Hardlink,
+}
+
+fn previous_digest(path: &Path, size: u64) -> Result<Option<FileDigest>> {
+    let mut file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to open {}", path.display())),
+    };
+    let metadata = file.metadata()?;
+    if !metadata.is_file() || metadata.len() != size {
+        return Ok(None);
+    }
+    let mut hasher = FileHasher::default();
+    io::copy(&mut file, &mut hasher).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(Some(hasher.finalize()))
+}
+
+/// Replaces `path` with a reflink of `src`, or with a hard link when the filesystem can't reflink
+/// it and `src` has the ownership. Returns None when neither can be done, e.g., across
+/// filesystems, leaving `path` as is.
+fn link_file(src: &Path, path: &Path, ownership: &Ownership) -> Result<Option<Link>> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".reflink");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let reflinked = {
+        let src_file = fs::File::open(src)?;
+        let tmp_file = fs::File::create(&tmp_path)
+            .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+        let reflinked = unsafe { ficlone(tmp_file.as_raw_fd(), src_file.as_raw_fd() as libc::c_ulong) }.is_ok();
+        if reflinked {
+            ownership.apply_to_file(&tmp_file, &tmp_path)?;
+        }
+        reflinked
+    };
+    let link = if reflinked {
+        Link::Reflink
+    } else {
+        fs::remove_file(&tmp_path)?;
+        if !ownership.is_applied_to(&fs::metadata(src)?) || fs::hard_link(src, &tmp_path).is_err() {
+            return Ok(None);
+        }
+        Link::Hardlink
+    };
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(Some(link))
+}
+
+/// Links the files of `images_dir` whose `digests` match the files of the same name in
+/// `previous_dir`. The links get `ownership`, as the extracted files did. Returns the files
+/// linked.
+pub fn link_unchanged_files(
+    images_dir: &Path,
+    previous_dir: &Path,
+    digests: &HashMap<Box<str>, FileDigest>,
+    ownership: &Ownership,
+) -> Result<Vec<(Box<str>, Link)>>
+{
+    let mut linked = Vec::new();
+    for (filename, digest) in digests {
+        let path = images_dir.join(&**filename);
+        // External files and diverted ghost files are not in the images directory.
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_file() && metadata.len() == digest.size => {}
+            _ => continue,
+        }
+        let previous_path = previous_dir.join(&**filename);
+        if previous_digest(&previous_path, digest.size)?.as_ref() != Some(digest) {
+            continue;
+        }
+        if let Some(link) = link_file(&previous_path, &path, ownership)? {
+            linked.push((filename.clone(), link));
+        }
+    }
+    Ok(linked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    fn digest(data: &[u8]) -> FileDigest {
+        let mut hasher = FileHasher::default();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    #[test]
+    fn test_link_unchanged_files() -> Result<()> {
+        let dir = PathBuf::from("/tmp/test-criu-image-streamer-reflink");
+        let _ = fs::remove_dir_all(&dir);
+        let (previous_dir, images_dir) = (dir.join("ckpt-1"), dir.join("ckpt-2"));
+        fs::create_dir_all(&previous_dir)?;
+        fs::create_dir_all(&images_dir)?;
+
+        fs::write(previous_dir.join("same.img"), b"same")?;
+        fs::write(previous_dir.join("changed.img"), b"old")?;
+        fs::write(images_dir.join("same.img"), b"same")?;
+        fs::write(images_dir.join("changed.img"), b"new")?;
+        fs::write(images_dir.join("new.img"), b"new")?;
+        let digests = ["same.img", "changed.img", "new.img"].iter()
+            .map(|&filename| Ok((filename.into(), digest(&fs::read(images_dir.join(filename))?))))
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        let linked = link_unchanged_files(&images_dir, &previous_dir, &digests, &Ownership::default())?;
+        assert_eq!(linked.len(), 1);
+        assert_eq!(&*linked[0].0, "same.img");
+        if linked[0].1 == Link::Hardlink {
+            assert_eq!(fs::metadata(images_dir.join("same.img"))?.ino(),
+                       fs::metadata(previous_dir.join("same.img"))?.ino());
+        }
+        assert_eq!(fs::read(images_dir.join("same.img"))?, b"same");
+        assert_eq!(fs::read(images_dir.join("changed.img"))?, b"new");
+        assert!(!images_dir.join("same.img.reflink").exists());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+
+use std::{
+    collections::HashMap,
+    os::unix::net::UnixStream,
+    os::unix::io::AsRawFd,
+    io::{self, Write},
+    path::Path,
+    fs,
+};
+use nix::unistd::pipe;
+use serde::Serialize;
+use crate::{
+    criu,
+    criu_connection::{self, IMG_STREAMER_CAPTURE_SOCKET_NAME, IMG_STREAMER_SERVE_SOCKET_NAME},
+    criu_trace::{CriuTrace, TraceEvent, TraceOperation},
+    unix_pipe::{UnixPipe, UnixPipeImpl},
+    util::*,
+};
+use anyhow::{Result, Context};
+
+// The replay operation plays the role of CRIU, following a trace recorded with --criu-trace,
+// against a streamer running the same operation (capture or serve) with the same images-dir.
+// As the content of the files is not recorded, files are sent with zeroed content of the recorded
+// size. When serving, replies that differ from the recorded ones fail the replay, as it means that
+// the streamer diverged from the recorded session.
+
+/// Data is written to image file pipes in blocks of this size.
+const WRITE_BLOCK_SIZE: usize = 64*KB;
+
+#[derive(Serialize)]
+struct ReplayReport {
+    /// Number of files sent or received
+    files: usize,
+    bytes: u64,
+}
+
+fn new_pipe() -> Result<(UnixPipe, UnixPipe)> {
+    let (fd_r, fd_w) = pipe()?;
+    Ok((UnixPipe::new(fd_r)?, UnixPipe::new(fd_w)?))
+}
+
+fn write_zeroes(pipe: &mut UnixPipe, size: u64) -> Result<()> {
+    let block = [0; WRITE_BLOCK_SIZE];
+    let mut remaining = size;
+    while remaining > 0 {
+        let len = remaining.min(WRITE_BLOCK_SIZE as u64) as usize;
+        pipe.write_all(&block[..len])?;
+        remaining -= len as u64;
+    }
+    Ok(())
+}
+
+fn connected(socket: &mut Option<UnixStream>) -> Result<&mut UnixStream> {
+    socket.as_mut().ok_or_else(|| anyhow!("The CRIU trace has events after the disconnect event"))
+}
+
+/// The description of arguments can be found in main.rs
+pub fn replay(
+    images_dir: &Path,
+    mut progress_pipe: fs::File,
+    trace_path: &Path,
+    job_id: Option<&str>,
+) -> Result<()>
+{
+    let mut events = CriuTrace::load(trace_path)?.into_iter();
+
+    let socket_name = match events.next() {
+        Some(TraceEvent::Connect { operation: TraceOperation::Capture }) => IMG_STREAMER_CAPTURE_SOCKET_NAME,
+        Some(TraceEvent::Connect { operation: TraceOperation::Serve }) => IMG_STREAMER_SERVE_SOCKET_NAME,
+        _ => bail!("The CRIU trace {} does not start with a connect event", trace_path.display()),
+    };
+    let socket_path = images_dir.join(criu_connection::socket_name(socket_name, job_id));
+    let mut socket = Some(UnixStream::connect(&socket_path)
+        .with_context(|| format!("Failed to connect to {}", socket_path.display()))?);
+
+    // When capturing, the write end of the file pipes stay open until their recorded EOF.
+    let mut capture_pipes: HashMap<String, UnixPipe> = HashMap::new();
+    let mut last_filename = None;
+    let mut last_reply = None;
+    let mut report = ReplayReport { files: 0, bytes: 0 };
+
+    for event in events {
+        match &event {
+            TraceEvent::Connect { .. } => bail!("Unexpected connect event"),
+            TraceEvent::Request { filename } => {
+                let req = criu::ImgStreamerRequestEntry { filename: filename.clone() };
+                pb_write(connected(&mut socket)?, &req)?;
+                last_filename = Some(filename.clone());
+                last_reply = None;
+            }
+            TraceEvent::Reply { exists } => {
+                let reply: criu::ImgStreamerReplyEntry = pb_read(connected(&mut socket)?)?;
+                ensure!(reply.exists == *exists,
+                        "`{}` was recorded with exists={}, but the streamer replied exists={}",
+                        last_filename.as_deref().unwrap_or_default(), exists, reply.exists);
+                last_reply = Some(reply.exists);
+            }
+            TraceEvent::RecvPipe => {
+                let filename = last_filename.clone()
+                    .ok_or_else(|| anyhow!("Pipe event without a request"))?;
+                if last_reply.is_some() {
+                    // Serving: the streamer writes the file, we discard it.
+                    let (mut pipe_r, pipe_w) = new_pipe()?;
+                    send_fd(connected(&mut socket)?, pipe_w.as_raw_fd())?;
+                    drop(pipe_w);
+                    report.bytes += io::copy(&mut pipe_r, &mut io::sink())
+                        .with_context(|| format!("Failed to receive `{}`", filename))?;
+                } else {
+                    let (pipe_r, pipe_w) = new_pipe()?;
+                    send_fd(connected(&mut socket)?, pipe_r.as_raw_fd())?;
+                    capture_pipes.insert(filename, pipe_w);
+                }
+                report.files += 1;
+            }
+            TraceEvent::FileEof { filename, size } => {
+                // Files that CRIU didn't send (e.g., external files) are skipped.
+                if let Some(mut pipe) = capture_pipes.remove(filename) {
+                    write_zeroes(&mut pipe, *size)
+                        .with_context(|| format!("Failed to send `{}`", filename))?;
+                    report.bytes += size;
+                }
+            }
+            TraceEvent::Disconnect => {
+                socket = None;
+            }
+        }
+    }
+
+    emit_progress(&mut progress_pipe, &serde_json::to_string(&report)?);
+
+    Ok(())
+}
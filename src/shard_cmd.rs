@@ -0,0 +1,299 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    fmt,
+    os::unix::process::{CommandExt, ExitStatusExt},
+    process::{Child, Command, ExitStatus, Stdio},
+};
+use crate::{
+    unix_pipe::{UnixPipe, new_cloexec_pipe},
+    shard_fifos::{FifoDirection, SHARD_INDEX_PLACEHOLDER},
+    codec::cloexec_inherited_fds,
+    util::shell_quote,
+};
+use anyhow::{Result, Context};
+
+// Moving the shards to and from remote storage is left to the programs at the other end of the
+// shards. When these programs are plain commands, one per shard, the streamer can run them
+// itself, which saves the caller from creating and supervising the pipes. The command reads the
+// shard on its stdin when we write the shard (e.g., capture), and writes it on its stdout when we
+// read the shard (e.g., extract). rclone alone covers dozens of storage providers this way:
+// `rclone rcat` uploads its stdin, and `rclone cat` downloads to its stdout. Hosts without
+// object storage are reached with ssh, running `cat` on the remote host.
+
+/// The command transferring each shard.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ShardCmd {
+    /// A shell command, run with `sh -c`. `{n}` is replaced by the shard index.
+    Shell(String),
+    /// rclone, with the remote directory of the shards, e.g., `s3:bucket/ckpt`. Shards are
+    /// stored as `shard-<n>` in it.
+    Rclone(String),
+    /// ssh, with the remote directory of the shards. Shards are stored as `shard-<n>` in it.
+    Ssh(SshUrl),
+}
+
+/// A directory on a remote host, given as `ssh://[user@]host[:port]/path`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SshUrl {
+    /// `[user@]host`, as passed to ssh.
+    pub destination: String,
+    pub port: Option<u16>,
+    /// The absolute path of the directory.
+    pub dir: String,
+}
+
+impl SshUrl {
+    pub fn parse(url: &str) -> Result<Self> {
+        let rest = url.strip_prefix("ssh://")
+            .ok_or_else(|| anyhow!("The ssh URL `{}` must start with ssh://", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => bail!("The ssh URL `{}` must contain the path of the shard directory", url),
+        };
+        let (destination, port) = match authority.rsplit_once(':') {
+            Some((destination, port)) => {
+                let port = port.parse().with_context(|| format!("Invalid port in the ssh URL `{}`", url))?;
+                (destination, Some(port))
+            }
+            None => (authority, None),
+        };
+        ensure!(!destination.is_empty() && !destination.ends_with('@'),
+                "The ssh URL `{}` must contain the host", url);
+        // ssh would take a destination starting with `-` as an option, e.g., `-oProxyCommand=`.
+        let host = destination.rsplit('@').next().unwrap_or(destination);
+        ensure!(!destination.starts_with('-') && !host.starts_with('-'),
+                "The user and host of the ssh URL `{}` must not start with `-`", url);
+        Ok(Self { destination: destination.to_string(), port, dir: path.to_string() })
+    }
+}
+
+impl fmt::Display for SshUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ssh://{}", self.destination)?;
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+        write!(f, "{}", self.dir)
+    }
+}
+
+fn shard_path(dir: &str, shard_index: usize) -> String {
+    format!("{}/shard-{}", dir.trim_end_matches('/'), shard_index)
+}
+
+impl ShardCmd {
+    fn command(&self, shard_index: usize, direction: FifoDirection) -> Command {
+        match self {
+            ShardCmd::Shell(cmd) => {
+                let mut command = Command::new("sh");
+                command.arg("-c").arg(cmd.replace(SHARD_INDEX_PLACEHOLDER, &shard_index.to_string()));
+                command
+            }
+            ShardCmd::Rclone(remote_dir) => {
+                let mut command = Command::new("rclone");
+                command.arg(match direction {
+                    FifoDirection::Write => "rcat",
+                    FifoDirection::Read => "cat",
+                });
+                command.arg(shard_path(remote_dir, shard_index));
+                command
+            }
+            ShardCmd::Ssh(url) => {
+                let mut command = Command::new("ssh");
+                // There is no one to answer a password prompt.
+                command.args(["-o", "BatchMode=yes"]);
+                if let Some(port) = url.port {
+                    command.arg("-p").arg(port.to_string());
+                }
+                let path = shell_quote(&shard_path(&url.dir, shard_index));
+                command.arg(&url.destination).arg("--").arg(match direction {
+                    FifoDirection::Write => format!("cat > {}", path),
+                    FifoDirection::Read => format!("cat {}", path),
+                });
+                command
+            }
+        }
+    }
+}
+
+impl fmt::Display for ShardCmd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShardCmd::Shell(cmd) => write!(f, "`{}`", cmd),
+            ShardCmd::Rclone(remote_dir) => write!(f, "rclone ({})", remote_dir),
+            ShardCmd::Ssh(url) => write!(f, "ssh ({})", url),
+        }
+    }
+}
+
+/// The failure of the command of a shard.
+#[derive(Debug)]
+pub struct ShardCmdFailed {
+    pub shard_index: usize,
+    pub cmd: ShardCmd,
+    pub status: ExitStatus,
+}
+
+impl ShardCmdFailed {
+    /// A command killed by SIGPIPE was stopped by the streamer closing the shard early, after
+    /// failing.
+    pub fn is_broken_pipe(&self) -> bool {
+        self.status.signal() == Some(libc::SIGPIPE)
+    }
+}
+
+impl fmt::Display for ShardCmdFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The shard command {} of shard {} failed: {}", self.cmd, self.shard_index, self.status)
+    }
+}
+
+impl std::error::Error for ShardCmdFailed {}
+
+/// A running shard command.
+pub struct ShardCmdProcess {
+    shard_index: usize,
+    cmd: ShardCmd,
+    child: Child,
+}
+
+impl ShardCmdProcess {
+    /// Waits for the command to exit, and fails if it didn't succeed.
+    pub fn wait(mut self) -> Result<()> {
+        let status = self.child.wait()
+            .with_context(|| format!("Failed to wait for the shard command {}", self.cmd))?;
+        ensure!(status.success(), ShardCmdFailed { shard_index: self.shard_index, cmd: self.cmd, status });
+        Ok(())
+    }
+}
+
+fn spawn(cmd: &ShardCmd, shard_index: usize, direction: FifoDirection) -> Result<(UnixPipe, ShardCmdProcess)> {
+    let (pipe_r, pipe_w) = new_cloexec_pipe()?;
+    let mut command = cmd.command(shard_index, direction);
+    // The side of the command that doesn't carry the shard gets /dev/null, so that it doesn't
+    // compete with us for our stdin or stdout.
+    let shard_pipe = match direction {
+        FifoDirection::Write => {
+            command.stdin(Stdio::from(pipe_r)).stdout(Stdio::null());
+            pipe_w
+        }
+        FifoDirection::Read => {
+            command.stdin(Stdio::null()).stdout(Stdio::from(pipe_w));
+            pipe_r
+        }
+    };
+    unsafe {
+        command.pre_exec(|| {
+            cloexec_inherited_fds();
+            Ok(())
+        });
+    }
+    let child = command.spawn()
+        .with_context(|| format!("Failed to spawn the shard command {} of shard {}", cmd, shard_index))?;
+    Ok((shard_pipe, ShardCmdProcess { shard_index, cmd: cmd.clone(), child }))
+}
+
+/// Runs the command of each of the `num_shards` shards, in the given direction. Returns the
+/// shard pipes connected to the commands, and the processes to wait for once the pipes are
+/// closed.
+pub fn spawn_shard_cmds(cmd: &ShardCmd, num_shards: usize, direction: FifoDirection)
+    -> Result<(Vec<UnixPipe>, Vec<ShardCmdProcess>)>
+{
+    ensure!(num_shards > 0, "The number of shards must be positive");
+    if let ShardCmd::Shell(shell_cmd) = cmd {
+        ensure!(num_shards == 1 || shell_cmd.contains(SHARD_INDEX_PLACEHOLDER),
+                "The shard command `{}` must contain `{}` when there are multiple shards",
+                shell_cmd, SHARD_INDEX_PLACEHOLDER);
+    }
+
+    let mut shard_pipes = Vec::new();
+    let mut processes = Vec::new();
+    for shard_index in 0..num_shards {
+        match spawn(cmd, shard_index, direction) {
+            Ok((shard_pipe, process)) => {
+                shard_pipes.push(shard_pipe);
+                processes.push(process);
+            }
+            Err(e) => {
+                // The commands already running see EOF once their pipes are dropped.
+                drop(shard_pipes);
+                let _ = wait_shard_cmds(processes);
+                return Err(e);
+            }
+        }
+    }
+    Ok((shard_pipes, processes))
+}
+
+/// Waits for all the shard commands to exit. Their pipes must have been closed beforehand.
+pub fn wait_shard_cmds(processes: Vec<ShardCmdProcess>) -> Result<()> {
+    let mut result = Ok(());
+    for process in processes {
+        // All commands are waited for, so that none is left behind.
+        let process_result = process.wait();
+        if result.is_ok() {
+            result = process_result;
+        }
+    }
+    result
+}
+
+/// Waits for the shard commands, and combines their outcome with the `result` of the operation.
+/// A command that failed leaves its shard truncated, or missing, so its error comes first, unless
+/// it was killed by SIGPIPE, which only tells that the operation failed and stopped reading.
+pub fn finish_shard_cmds(result: Result<()>, processes: Vec<ShardCmdProcess>) -> Result<()> {
+    let cmd_result = wait_shard_cmds(processes);
+    let cmd_is_cause = match &cmd_result {
+        Err(e) => e.downcast_ref::<ShardCmdFailed>().is_none_or(|e| !e.is_broken_pipe()),
+        Ok(()) => false,
+    };
+    if cmd_is_cause {
+        cmd_result
+    } else {
+        result.and(cmd_result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ssh_url() -> Result<()> {
+        assert_eq!(SshUrl::parse("ssh://user@host:2222/var/ckpt")?,
+                   SshUrl { destination: "user@host".to_string(), port: Some(2222), dir: "/var/ckpt".to_string() });
+        assert_eq!(SshUrl::parse("ssh://host/ckpt")?,
+                   SshUrl { destination: "host".to_string(), port: None, dir: "/ckpt".to_string() });
+        assert_eq!(SshUrl::parse("ssh://user@host:2222/var/ckpt")?.to_string(), "ssh://user@host:2222/var/ckpt");
+        assert!(SshUrl::parse("user@host:/ckpt").is_err());
+        assert!(SshUrl::parse("ssh://host").is_err());
+        assert!(SshUrl::parse("ssh://host:port/ckpt").is_err());
+        assert!(SshUrl::parse("ssh://user@/ckpt").is_err());
+        assert!(SshUrl::parse("ssh://-oProxyCommand=sh/ckpt").is_err());
+        assert!(SshUrl::parse("ssh://user@-oProxyCommand=sh/ckpt").is_err());
+        assert!(SshUrl::parse("ssh://us-er@ho-st/ckpt").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ssh_command() -> Result<()> {
+        let cmd = ShardCmd::Ssh(SshUrl::parse("ssh://user@host:2222/it's")?);
+        let command = cmd.command(3, FifoDirection::Write);
+        assert_eq!(command.get_args().collect::<Vec<_>>(),
+                   ["-o", "BatchMode=yes", "-p", "2222", "user@host", "--", "cat > '/it'\\''s/shard-3'"]);
+        Ok(())
+    }
+}
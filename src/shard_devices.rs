@@ -0,0 +1,184 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    fs,
+    io::{self, Read, Write, Seek, SeekFrom},
+    cmp::min,
+    os::unix::fs::{FileExt, FileTypeExt},
+    path::{Path, PathBuf},
+    thread,
+};
+use crate::{
+    unix_pipe::{UnixPipe, new_cloexec_pipe},
+    shard_fifos::FifoDirection,
+    util::MB,
+};
+use anyhow::{Result, Context};
+
+// HPC sites checkpoint to dedicated local NVMe namespaces rather than to filesystems. With
+// --shard-devices, each shard is written to, or read from, a raw block device. A preallocated
+// regular file works the same way.
+//
+// A device has no EOF, so it starts with a header block giving the length of the shard data,
+// which follows from the second block on. Data is written in whole blocks, the last one padded
+// with zeros. The header is invalidated before the data is written, and written once the data
+// is synced, so that a device holding a partial shard is not mistaken for a complete one.
+//
+// The streamer talks to pipes, as with any other shard. A thread per device moves the data
+// between the pipe and the device.
+
+/// Size of the header, and alignment of the writes.
+pub const SHARD_DEVICE_BLOCK_SIZE: usize = 4096;
+
+const SHARD_DEVICE_MAGIC: &[u8; 8] = b"CRIUSHRD";
+const SHARD_DEVICE_VERSION: u32 = 1;
+
+/// Size of the writes to the device, a multiple of the block size.
+#[allow(clippy::identity_op)]
+const WRITE_BUF_SIZE: usize = 1*MB;
+
+/// The thread moving data between a shard pipe and its device.
+pub type ShardDeviceThread = thread::JoinHandle<Result<()>>;
+
+fn encode_header(data_len: u64) -> Vec<u8> {
+    let mut header = vec![0; SHARD_DEVICE_BLOCK_SIZE];
+    header[0..8].copy_from_slice(SHARD_DEVICE_MAGIC);
+    header[8..12].copy_from_slice(&SHARD_DEVICE_VERSION.to_le_bytes());
+    header[12..20].copy_from_slice(&data_len.to_le_bytes());
+    header
+}
+
+/// Returns the length of the shard data.
+fn decode_header(header: &[u8]) -> Result<u64> {
+    ensure!(&header[0..8] == SHARD_DEVICE_MAGIC,
+            "No shard found. The capture may have failed, or written to another device");
+    let mut version = [0; 4];
+    version.copy_from_slice(&header[8..12]);
+    let version = u32::from_le_bytes(version);
+    ensure!(version == SHARD_DEVICE_VERSION, "Unsupported shard device version {}", version);
+    let mut data_len = [0; 8];
+    data_len.copy_from_slice(&header[12..20]);
+    Ok(u64::from_le_bytes(data_len))
+}
+
+/// Writes the data of `pipe` to the device, and the header last.
+fn write_device(mut pipe: UnixPipe, mut device: fs::File) -> Result<()> {
+    device.write_all_at(&vec![0; SHARD_DEVICE_BLOCK_SIZE], 0)?;
+    device.seek(SeekFrom::Start(SHARD_DEVICE_BLOCK_SIZE as u64))?;
+
+    let mut buf = vec![0; WRITE_BUF_SIZE];
+    let mut data_len = 0;
+    loop {
+        // The buffer is filled before being written, so that the writes are whole blocks.
+        let mut filled = 0;
+        while filled < buf.len() {
+            match pipe.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context("Failed to read from the shard pipe"),
+            }
+        }
+        data_len += filled as u64;
+        if filled < buf.len() {
+            let padded_len = filled.div_ceil(SHARD_DEVICE_BLOCK_SIZE) * SHARD_DEVICE_BLOCK_SIZE;
+            buf[filled..padded_len].iter_mut().for_each(|b| *b = 0);
+            device.write_all(&buf[..padded_len])?;
+            break;
+        }
+        device.write_all(&buf)?;
+    }
+
+    device.sync_data()?;
+    device.write_all_at(&encode_header(data_len), 0)?;
+    device.sync_data()?;
+    Ok(())
+}
+
+/// Writes the shard data of the device to `pipe`.
+fn read_device(mut device: fs::File, mut pipe: UnixPipe) -> Result<()> {
+    let mut header = vec![0; SHARD_DEVICE_BLOCK_SIZE];
+    device.read_exact(&mut header).context("Failed to read the header")?;
+    let data_len = decode_header(&header)?;
+
+    // Data is copied through userspace, as with `copy_all()` in unix_pipe.rs.
+    let mut buf = vec![0; WRITE_BUF_SIZE];
+    let mut remaining = data_len;
+    while remaining > 0 {
+        let chunk = &mut buf[..min(remaining, WRITE_BUF_SIZE as u64) as usize];
+        device.read_exact(chunk)
+            .with_context(|| format!("The device is shorter than the {} bytes of its shard", data_len))?;
+        match pipe.write_all(chunk) {
+            // The reader stopped early, and reports why.
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            result => result.context("Failed to write to the shard pipe")?,
+        }
+        remaining -= chunk.len() as u64;
+    }
+    Ok(())
+}
+
+fn open_device(path: &Path, direction: FifoDirection) -> Result<fs::File> {
+    let device = match direction {
+        FifoDirection::Read => fs::File::open(path),
+        FifoDirection::Write => fs::OpenOptions::new().write(true).open(path),
+    }.with_context(|| format!("Failed to open the shard device {}", path.display()))?;
+
+    let file_type = device.metadata()?.file_type();
+    ensure!(file_type.is_block_device() || file_type.is_file(),
+            "The shard device {} is not a block device, nor a regular file", path.display());
+    Ok(device)
+}
+
+/// Opens the shard devices in the given direction. Returns the shard pipes to use in place of
+/// the devices, and the threads to wait for once the pipes are closed.
+pub fn open_shard_devices(paths: &[PathBuf], direction: FifoDirection)
+    -> Result<(Vec<UnixPipe>, Vec<ShardDeviceThread>)>
+{
+    ensure!(!paths.is_empty(), "No shard device provided");
+    let devices = paths.iter()
+        .map(|path| open_device(path, direction))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut shard_pipes = Vec::new();
+    let mut threads = Vec::new();
+    for (path, device) in paths.iter().zip(devices) {
+        let (pipe_r, pipe_w) = new_cloexec_pipe()?;
+        let path = path.clone();
+        let (shard_pipe, thread) = match direction {
+            FifoDirection::Write => (pipe_w, thread::spawn(move || write_device(pipe_r, device)
+                .with_context(|| format!("Failed to write to the shard device {}", path.display())))),
+            FifoDirection::Read => (pipe_r, thread::spawn(move || read_device(device, pipe_w)
+                .with_context(|| format!("Failed to read from the shard device {}", path.display())))),
+        };
+        shard_pipes.push(shard_pipe);
+        threads.push(thread);
+    }
+    Ok((shard_pipes, threads))
+}
+
+/// Waits for the shard device threads. The shard pipes must have been closed beforehand.
+/// A device that failed leaves its shard truncated, so its error should be preferred over the
+/// error of the operation.
+pub fn wait_shard_devices(threads: Vec<ShardDeviceThread>) -> Result<()> {
+    let mut result = Ok(());
+    for thread in threads {
+        let thread_result = thread.join().expect("Failed to join the shard device thread");
+        if result.is_ok() {
+            result = thread_result;
+        }
+    }
+    result
+}
@@ -0,0 +1,93 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    os::unix::fs::FileTypeExt,
+    io::ErrorKind,
+    thread,
+};
+use nix::{
+    sys::stat::Mode,
+    unistd::mkfifo,
+};
+use crate::unix_pipe::UnixPipe;
+use anyhow::{Result, Context};
+
+// Sidecar uploaders (e.g., in another container of the pod) can't inherit our file descriptors,
+// but they can share a volume with us. With --shard-fifos, we create the shards as named pipes on
+// that volume, and the uploader opens them by path.
+//
+// Opening a fifo blocks until the other end is opened. If we were to open the shards one after
+// the other, an uploader that opens them in a different order would deadlock with us. The shards
+// are opened concurrently instead, and we return once all of them are connected.
+
+/// The placeholder replaced by the shard index in the fifo path pattern.
+pub const SHARD_INDEX_PLACEHOLDER: &str = "{n}";
+
+/// Whether we write to the shard fifos (e.g., capture) or read from them (e.g., extract).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FifoDirection {
+    Read,
+    Write,
+}
+
+/// Returns the paths of the `num_shards` shard fifos, `{n}` in `pattern` being replaced by the
+/// shard index, starting at 0.
+pub fn shard_fifo_paths(pattern: &str, num_shards: usize) -> Result<Vec<PathBuf>> {
+    ensure!(num_shards > 0, "The number of shard fifos must be positive");
+    ensure!(num_shards == 1 || pattern.contains(SHARD_INDEX_PLACEHOLDER),
+            "The shard fifo path `{}` must contain `{}` when there are multiple shards",
+            pattern, SHARD_INDEX_PLACEHOLDER);
+    Ok((0..num_shards)
+        .map(|i| PathBuf::from(pattern.replace(SHARD_INDEX_PLACEHOLDER, &i.to_string())))
+        .collect())
+}
+
+/// Creates the fifo at `path`, unless it exists already.
+fn create_fifo(path: &Path) -> Result<()> {
+    match fs::metadata(path) {
+        Ok(metadata) => {
+            ensure!(metadata.file_type().is_fifo(), "{} exists and is not a fifo", path.display());
+            Ok(())
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            mkfifo(path, Mode::S_IRUSR | Mode::S_IWUSR)
+                .with_context(|| format!("Failed to create the fifo {}", path.display()))
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to stat {}", path.display())),
+    }
+}
+
+/// Creates the shard fifos, and opens them in the given direction. Blocks until the other end of
+/// each fifo is opened, in any order.
+pub fn open_shard_fifos(pattern: &str, num_shards: usize, direction: FifoDirection) -> Result<Vec<UnixPipe>> {
+    let paths = shard_fifo_paths(pattern, num_shards)?;
+    for path in &paths {
+        create_fifo(path)?;
+    }
+
+    let openers: Vec<_> = paths.into_iter().map(|path| thread::spawn(move || {
+        let file = match direction {
+            FifoDirection::Read => fs::File::open(&path),
+            FifoDirection::Write => fs::OpenOptions::new().write(true).open(&path),
+        };
+        file.with_context(|| format!("Failed to open the shard fifo {}", path.display()))
+    })).collect();
+
+    openers.into_iter()
+        .map(|opener| opener.join().expect("Failed to join the fifo opener thread"))
+        .collect()
+}
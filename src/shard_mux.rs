@@ -0,0 +1,296 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    os::unix::io::AsRawFd,
+    sync::mpsc,
+    thread,
+};
+use nix::poll::{poll, PollFd, PollFlags};
+use crate::{
+    unix_pipe::{UnixPipe, new_cloexec_pipe},
+    util::MB,
+};
+use anyhow::{Result, Context};
+
+// Some transports carry a single byte stream, e.g., one ssh connection, or the stdin of a pod.
+// With --mux-shards, the shards are multiplexed over that stream, so that the image keeps its
+// shards, and the load balancing between them, on both ends.
+//
+// The stream starts with a header giving the number of shards. Then come frames, each made of
+// the shard index and the length of the data that follows, both u32 little-endian. An empty frame
+// marks the end of its shard. The stream ends once all shards have ended.
+//
+// The streamer talks to pipes, as with any other shard. A thread moves the data between the
+// shard pipes and the stream. When demultiplexing, the frames of each shard are queued in memory
+// until its pipe takes them, so that a shard that isn't read yet doesn't hold back the frames of
+// the other shards behind it.
+
+const SHARD_MUX_MAGIC: &[u8; 8] = b"CRIUSMUX";
+const SHARD_MUX_VERSION: u32 = 1;
+const STREAM_HEADER_SIZE: usize = 16;
+const FRAME_HEADER_SIZE: usize = 8;
+
+/// The largest frame data written, and accepted.
+#[allow(clippy::identity_op)]
+const MAX_FRAME_DATA_SIZE: usize = 1*MB;
+
+/// The most shards a stream may carry. Each shard takes a pipe and a writer thread when
+/// demultiplexing, so a corrupted header must not ask for billions of them.
+const MAX_MUXED_SHARDS: usize = 1024;
+
+/// The thread moving data between the shard pipes and the stream.
+pub type ShardMuxThread = thread::JoinHandle<Result<()>>;
+
+fn encode_stream_header(num_shards: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(STREAM_HEADER_SIZE);
+    header.extend_from_slice(SHARD_MUX_MAGIC);
+    header.extend_from_slice(&SHARD_MUX_VERSION.to_le_bytes());
+    header.extend_from_slice(&(num_shards as u32).to_le_bytes());
+    header
+}
+
+/// Returns the number of shards of the stream.
+fn decode_stream_header(header: &[u8; STREAM_HEADER_SIZE]) -> Result<usize> {
+    ensure!(&header[0..8] == SHARD_MUX_MAGIC,
+            "The stream doesn't carry multiplexed shards. The image may have been written without --mux-shards");
+    let mut version = [0; 4];
+    version.copy_from_slice(&header[8..12]);
+    let version = u32::from_le_bytes(version);
+    ensure!(version == SHARD_MUX_VERSION, "Unsupported multiplexed stream version {}", version);
+    let mut num_shards = [0; 4];
+    num_shards.copy_from_slice(&header[12..16]);
+    let num_shards = u32::from_le_bytes(num_shards) as usize;
+    ensure!(num_shards > 0, "The multiplexed stream has no shards");
+    ensure!(num_shards <= MAX_MUXED_SHARDS,
+            "The multiplexed stream has {} shards, more than the maximum of {}", num_shards, MAX_MUXED_SHARDS);
+    Ok(num_shards)
+}
+
+fn encode_frame_header(buf: &mut [u8], shard_index: usize, data_len: usize) {
+    buf[0..4].copy_from_slice(&(shard_index as u32).to_le_bytes());
+    buf[4..8].copy_from_slice(&(data_len as u32).to_le_bytes());
+}
+
+/// Returns the shard index and the data length of the frame.
+fn decode_frame_header(header: &[u8; FRAME_HEADER_SIZE]) -> (usize, usize) {
+    let mut shard_index = [0; 4];
+    shard_index.copy_from_slice(&header[0..4]);
+    let mut data_len = [0; 4];
+    data_len.copy_from_slice(&header[4..8]);
+    (u32::from_le_bytes(shard_index) as usize, u32::from_le_bytes(data_len) as usize)
+}
+
+/// Writes the data of the shard pipes to `stream` as frames, as it comes.
+fn mux(mut pipes: Vec<UnixPipe>, mut stream: fs::File) -> Result<()> {
+    stream.write_all(&encode_stream_header(pipes.len()))?;
+
+    let mut buf = vec![0; FRAME_HEADER_SIZE + MAX_FRAME_DATA_SIZE];
+    let mut open_shards: Vec<usize> = (0..pipes.len()).collect();
+    while !open_shards.is_empty() {
+        let mut poll_fds: Vec<PollFd> = open_shards.iter()
+            .map(|&i| PollFd::new(pipes[i].as_raw_fd(), PollFlags::POLLIN))
+            .collect();
+        match poll(&mut poll_fds, -1) {
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            result => result.context("Failed to poll the shard pipes")?,
+        };
+
+        let ready: Vec<usize> = open_shards.iter().zip(poll_fds)
+            .filter(|(_, poll_fd)| !poll_fd.revents().unwrap().is_empty())
+            .map(|(&i, _)| i)
+            .collect();
+        for shard_index in ready {
+            let data_len = match pipes[shard_index].read(&mut buf[FRAME_HEADER_SIZE..]) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context("Failed to read from the shard pipe"),
+            };
+            encode_frame_header(&mut buf, shard_index, data_len);
+            stream.write_all(&buf[..FRAME_HEADER_SIZE + data_len])?;
+            if data_len == 0 {
+                open_shards.retain(|&i| i != shard_index);
+            }
+        }
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+/// Writes the frames queued for the shard to its pipe.
+fn write_shard(mut pipe: UnixPipe, frames: mpsc::Receiver<Vec<u8>>) -> Result<()> {
+    for data in frames {
+        match pipe.write_all(&data) {
+            // The reader stopped early, and reports why.
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            result => result.context("Failed to write to the shard pipe")?,
+        }
+    }
+    Ok(())
+}
+
+/// Reads the frames of `stream`, and queues their data to the shard writers.
+fn demux(mut stream: fs::File, mut shards: Vec<Option<mpsc::Sender<Vec<u8>>>>) -> Result<()> {
+    // A shard is done once it ended, or once its reader stopped.
+    while let Some(next_shard) = shards.iter().position(Option::is_some) {
+        let mut header = [0; FRAME_HEADER_SIZE];
+        stream.read_exact(&mut header).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => anyhow!("The stream ended before shard {} did", next_shard),
+            _ => anyhow!(e),
+        })?;
+        let (shard_index, data_len) = decode_frame_header(&header);
+        ensure!(shard_index < shards.len(), "Invalid shard index {} in the stream", shard_index);
+        ensure!(data_len <= MAX_FRAME_DATA_SIZE, "Invalid frame size {} in the stream", data_len);
+
+        let mut data = vec![0; data_len];
+        stream.read_exact(&mut data).context("The stream ended in the middle of a frame")?;
+        if data_len == 0 {
+            // The writer of the shard sees the end of its queue, and closes the pipe.
+            shards[shard_index] = None;
+        } else if let Some(shard) = &shards[shard_index] {
+            if shard.send(data).is_err() {
+                shards[shard_index] = None;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Multiplexes `num_shards` shards over `stream`. Returns the shard pipes to write to in place of
+/// the shards, and the thread to wait for once the pipes are closed.
+pub fn mux_shards(stream: fs::File, num_shards: usize) -> Result<(Vec<UnixPipe>, ShardMuxThread)> {
+    ensure!(num_shards > 0, "The number of shards must be positive");
+    ensure!(num_shards <= MAX_MUXED_SHARDS,
+            "At most {} shards can be multiplexed over a stream", MAX_MUXED_SHARDS);
+    let (shard_pipes, mux_pipes): (Vec<_>, Vec<_>) = (0..num_shards)
+        .map(|_| new_cloexec_pipe().map(|(pipe_r, pipe_w)| (pipe_w, pipe_r)))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .unzip();
+    let thread = thread::spawn(move || mux(mux_pipes, stream)
+        .context("Failed to write the multiplexed shards"));
+    Ok((shard_pipes, thread))
+}
+
+/// Demultiplexes the shards carried by `stream`. Blocks until the stream header is read. Returns
+/// the shard pipes to read from in place of the shards, and the thread to wait for once the pipes
+/// are closed.
+pub fn demux_shards(mut stream: fs::File) -> Result<(Vec<UnixPipe>, ShardMuxThread)> {
+    let mut header = [0; STREAM_HEADER_SIZE];
+    stream.read_exact(&mut header).context("Failed to read the header of the multiplexed shards")?;
+    let num_shards = decode_stream_header(&header)?;
+
+    let mut shard_pipes = Vec::new();
+    let mut senders = Vec::new();
+    let mut writers = Vec::new();
+    for _ in 0..num_shards {
+        let (pipe_r, pipe_w) = new_cloexec_pipe()?;
+        let (sender, receiver) = mpsc::channel();
+        shard_pipes.push(pipe_r);
+        senders.push(Some(sender));
+        writers.push(thread::spawn(move || write_shard(pipe_w, receiver)));
+    }
+
+    let thread = thread::spawn(move || {
+        // The writers see the end of their queue once the senders are dropped, on error as well.
+        let mut result = demux(stream, senders);
+        for writer in writers {
+            let writer_result = writer.join().expect("Failed to join the shard writer thread");
+            if result.is_ok() {
+                result = writer_result;
+            }
+        }
+        result.context("Failed to read the multiplexed shards")
+    });
+    Ok((shard_pipes, thread))
+}
+
+/// Waits for the shard mux thread. The shard pipes must have been closed beforehand.
+pub fn wait_shard_mux(thread: ShardMuxThread) -> Result<()> {
+    thread.join().expect("Failed to join the shard mux thread")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mux_demux() -> Result<()> {
+        let (stream_r, stream_w) = new_cloexec_pipe()?;
+        let (mux_pipes, mux_thread) = mux_shards(stream_w, 3)?;
+        let (demux_pipes, demux_thread) = demux_shards(stream_r)?;
+        assert_eq!(demux_pipes.len(), 3);
+
+        // The shards are written concurrently, and read after one another, so that the frames of
+        // the last shards are queued while the first shard is read.
+        let writers: Vec<_> = mux_pipes.into_iter().enumerate().map(|(i, mut pipe)| {
+            thread::spawn(move || pipe.write_all(&vec![i as u8; (i+1) * MB]))
+        }).collect();
+        for (i, mut pipe) in demux_pipes.into_iter().enumerate() {
+            let mut data = Vec::new();
+            pipe.read_to_end(&mut data)?;
+            assert!(data.len() == (i+1) * MB && data.iter().all(|&b| b == i as u8));
+        }
+        for writer in writers {
+            writer.join().unwrap()?;
+        }
+        wait_shard_mux(mux_thread)?;
+        wait_shard_mux(demux_thread)
+    }
+
+    #[test]
+    fn test_truncated_stream() -> Result<()> {
+        let (stream_r, mut stream_w) = new_cloexec_pipe()?;
+        stream_w.write_all(&encode_stream_header(2))?;
+        let mut frame = [0; FRAME_HEADER_SIZE];
+        encode_frame_header(&mut frame, 0, 0);
+        stream_w.write_all(&frame)?;
+        drop(stream_w);
+
+        let (demux_pipes, demux_thread) = demux_shards(stream_r)?;
+        drop(demux_pipes);
+        let err = wait_shard_mux(demux_thread).unwrap_err();
+        assert!(format!("{:#}", err).contains("The stream ended before shard 1 did"), "{:#}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hostile_header() -> Result<()> {
+        let (stream_r, mut stream_w) = new_cloexec_pipe()?;
+        stream_w.write_all(&encode_stream_header(u32::MAX as usize))?;
+        drop(stream_w);
+
+        let err = demux_shards(stream_r).err().unwrap();
+        assert!(err.to_string().contains("more than the maximum of 1024"), "{:#}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hostile_frame() -> Result<()> {
+        let (stream_r, mut stream_w) = new_cloexec_pipe()?;
+        stream_w.write_all(&encode_stream_header(1))?;
+        let mut frame = [0; FRAME_HEADER_SIZE];
+        encode_frame_header(&mut frame, 0, u32::MAX as usize);
+        stream_w.write_all(&frame)?;
+        drop(stream_w);
+
+        let (demux_pipes, demux_thread) = demux_shards(stream_r)?;
+        drop(demux_pipes);
+        let err = wait_shard_mux(demux_thread).unwrap_err();
+        assert!(format!("{:#}", err).contains("Invalid frame size 4294967295"), "{:#}", err);
+        Ok(())
+    }
+}
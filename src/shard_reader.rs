@@ -0,0 +1,170 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+
+use std::{
+    io::Read,
+    os::unix::io::AsRawFd,
+};
+use nix::poll::{poll, PollFd, PollFlags};
+use anyhow::{Result, Context};
+use crate::{
+    unix_pipe::UnixPipe,
+    util::pb_read_next,
+    image::{self, marker},
+};
+
+// `ShardReader` gives access to the stream of markers of an image, for tools that build on the
+// image format (analytics, converters, etc.). Markers are yielded in sequence order, reassembled
+// from all the shards, along with the data payload of `FileData` markers. The payload is copied
+// in memory, which is why the extraction uses `ImageDeserializer` instead.
+// Shard headers are consumed by the reader, and are not yielded.
+
+/// The data payload following a `FileData` marker. It is empty for other markers.
+pub type ChunkData = Vec<u8>;
+
+struct ShardInput {
+    pipe: UnixPipe,
+    /// The next marker of the shard, when it has been read already
+    pending_marker: Option<image::Marker>,
+    eof: bool,
+}
+
+pub struct ShardReader {
+    shards: Vec<ShardInput>,
+    seq: u64,
+    image_uuid: Option<String>,
+    /// Set once the image EOF marker is yielded, or an error is returned
+    done: bool,
+}
+
+impl ShardReader {
+    pub fn new(shard_pipes: Vec<UnixPipe>) -> Self {
+        let shards = shard_pipes.into_iter()
+            .map(|pipe| ShardInput { pipe, pending_marker: None, eof: false })
+            .collect();
+        Self { shards, seq: 0, image_uuid: None, done: false }
+    }
+
+    /// Returns the UUID of the image, once a shard header has been read. Images captured by older
+    /// versions have no shard headers.
+    pub fn image_uuid(&self) -> Option<&str> {
+        self.image_uuid.as_deref()
+    }
+
+    fn read_marker(&mut self, shard_index: usize) -> Result<()> {
+        let shard = &mut self.shards[shard_index];
+        match pb_read_next::<_, image::Marker>(&mut shard.pipe)? {
+            None => shard.eof = true,
+            Some((image::Marker { body: Some(marker::Body::ShardHeader(header)), padding, .. }, _)) => {
+                skip_padding(&mut shard.pipe, padding)?;
+                match &self.image_uuid {
+                    Some(image_uuid) => ensure!(*image_uuid == header.image_uuid,
+                        "The provided shards belong to different images: {} and {}",
+                        image_uuid, header.image_uuid),
+                    None => self.image_uuid = Some(header.image_uuid),
+                }
+            }
+            Some((image::Marker { body: Some(marker::Body::ImageAborted(true)), .. }, _)) => {
+                bail!("The capture failed, the image is invalid");
+            }
+            // The file table comes after the image EOF marker on the first shard. See file_table.rs.
+            Some((image::Marker { body: Some(marker::Body::FileTable(size)), padding, .. }, _)) => {
+                skip_padding(&mut shard.pipe, size.saturating_add(padding))?;
+            }
+            Some((image::Marker { body: Some(marker::Body::FileTableSize(_)), .. }, _)) => {}
+            Some((marker, _)) => {
+                ensure!(marker.seq >= self.seq, "Unexpected marker sequence number");
+                shard.pending_marker = Some(marker);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the next marker of the shards that don't have one pending. As with the extraction,
+    /// we only read from readable shards, to avoid deadlocking when the shards are directly
+    /// connected to a capture.
+    fn read_markers(&mut self) -> Result<()> {
+        let candidates: Vec<usize> = self.shards.iter().enumerate()
+            .filter(|(_, shard)| shard.pending_marker.is_none() && !shard.eof)
+            .map(|(i, _)| i)
+            .collect();
+
+        match candidates.as_slice() {
+            [] => bail!("The image is incomplete. Missing marker sequence numbers: {} onwards", self.seq),
+            [shard_index] => self.read_marker(*shard_index),
+            _ => {
+                let mut poll_fds: Vec<PollFd> = candidates.iter()
+                    .map(|&i| PollFd::new(self.shards[i].pipe.as_raw_fd(), PollFlags::POLLIN))
+                    .collect();
+                poll(&mut poll_fds, -1)?;
+                for (shard_index, poll_fd) in candidates.into_iter().zip(poll_fds) {
+                    if !poll_fd.revents().unwrap().is_empty() {
+                        self.read_marker(shard_index)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn read_next(&mut self) -> Result<(image::Marker, ChunkData)> {
+        loop {
+            let seq = self.seq;
+            let shard = self.shards.iter_mut()
+                .find(|shard| shard.pending_marker.as_ref().map(|m| m.seq) == Some(seq));
+
+            if let Some(shard) = shard {
+                let marker = shard.pending_marker.take().unwrap();
+                let data = match marker.body {
+                    Some(marker::Body::FileData(size)) => {
+                        let mut data = vec![0; size as usize];
+                        shard.pipe.read_exact(&mut data).context("Failed to read from shard")?;
+                        data
+                    }
+                    _ => Vec::new(),
+                };
+                skip_padding(&mut shard.pipe, marker.padding)?;
+                self.seq += 1;
+                if let Some(marker::Body::ImageEof(true)) = marker.body {
+                    self.done = true;
+                }
+                return Ok((marker, data));
+            }
+
+            self.read_markers()?;
+        }
+    }
+}
+
+fn skip_padding(pipe: &mut UnixPipe, padding: u32) -> Result<()> {
+    let mut buf = vec![0; padding as usize];
+    pipe.read_exact(&mut buf).context("Failed to read from shard")
+}
+
+impl Iterator for ShardReader {
+    type Item = Result<(image::Marker, ChunkData)>;
+
+    /// Returns the next marker in sequence order. The iteration ends after the image EOF marker.
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.read_next();
+        if result.is_err() {
+            self.done = true;
+        }
+        Some(result)
+    }
+}
@@ -0,0 +1,71 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    fmt::Debug,
+    io::Write,
+    mem::size_of,
+};
+use anyhow::{Context, Result};
+use bytes::Buf;
+use prost::Message;
+use crate::{
+    image_patcher::read_criu_img_header,
+    util::{pb_read_next, read_bytes_next},
+    criu,
+};
+
+// Inspecting a few CRIU images is handy when debugging a restore, without having to install
+// `crit`. We only support the images that we know how to decode. The entries are printed with
+// their Debug representation.
+
+// These consts are defined in the CRIU project in criu/include/magic.h
+const INVENTORY_MAGIC: u32 = 0x58313116;
+const FILES_MAGIC: u32 = 0x56303138;
+const FDINFO_MAGIC: u32 = 0x56213732;
+
+/// Prints the entries of the CRIU image `filename`, with content `img`, into `out`.
+pub fn show_img(filename: &str, mut img: &[u8], out: &mut impl Write) -> Result<()> {
+    let mut show = || -> Result<()> {
+        if filename == "inventory.img" {
+            // The inventory image does not have the common magic.
+            read_magic(&mut img, INVENTORY_MAGIC)?;
+            show_entries::<criu::InventoryEntry>(img, out)
+        } else if filename == "files.img" {
+            read_criu_img_header(&mut img, FILES_MAGIC)?;
+            show_entries::<criu::FileEntry>(img, out)
+        } else if filename.starts_with("fdinfo-") && filename.ends_with(".img") {
+            read_criu_img_header(&mut img, FDINFO_MAGIC)?;
+            show_entries::<criu::FdinfoEntry>(img, out)
+        } else {
+            bail!("Unsupported image type. Supported images are \
+                   inventory.img, files.img, and fdinfo-<id>.img")
+        }
+    };
+    show().with_context(|| format!("Failed to show {}", filename))
+}
+
+fn read_magic(img: &mut &[u8], expected_magic: u32) -> Result<()> {
+    let mut magic = read_bytes_next(img, size_of::<u32>())?
+        .ok_or_else(|| anyhow!("Failed to read the CRIU image magic"))?;
+    ensure!(magic.get_u32_le() == expected_magic, "The CRIU image magic is corrupted");
+    Ok(())
+}
+
+fn show_entries<T: Message + Default + Debug>(mut img: &[u8], out: &mut impl Write) -> Result<()> {
+    while let Some((entry, _)) = pb_read_next::<_, T>(&mut img)? {
+        writeln!(out, "{:#?}", entry)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,84 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    os::unix::process::CommandExt,
+    path::Path,
+    process::{Command, Stdio},
+};
+use crate::{
+    codec::cloexec_inherited_fds,
+    util::shell_quote,
+};
+use anyhow::{Result, Context};
+
+// Images extracted to disk are often kept as restore points. When the images directory is a ZFS
+// dataset or a btrfs subvolume, a snapshot of it taken once the extraction is complete holds an
+// image that is complete and verified, whatever happens to the directory afterwards (e.g., the
+// next extraction). With --snapshot-cmd, the streamer runs the snapshot command itself, only once
+// the extraction succeeded, e.g.,
+// `btrfs subvolume snapshot -r {dir} /snapshots/$(date +%s)`, or `zfs snapshot tank/ckpt@$(date +%s)`.
+// A failed snapshot fails the extraction.
+
+/// Replaced by the images directory, quoted, in the snapshot command.
+pub const IMAGES_DIR_PLACEHOLDER: &str = "{dir}";
+
+/// Runs the snapshot `cmd` of `images_dir` with `sh -c`, and waits for it to succeed.
+pub fn run_snapshot_cmd(cmd: &str, images_dir: &Path) -> Result<()> {
+    let mut command = Command::new("sh");
+    // The directory may contain spaces or shell syntax, it must be a single word.
+    let images_dir = shell_quote(&images_dir.to_string_lossy());
+    command.arg("-c").arg(cmd.replace(IMAGES_DIR_PLACEHOLDER, &images_dir));
+    // The command doesn't compete with us for our stdin or stdout.
+    command.stdin(Stdio::null()).stdout(Stdio::null());
+    unsafe {
+        command.pre_exec(|| {
+            cloexec_inherited_fds();
+            Ok(())
+        });
+    }
+    let status = command.status()
+        .with_context(|| format!("Failed to run the snapshot command `{}`", cmd))?;
+    ensure!(status.success(), "The snapshot command `{}` failed: {}", cmd, status);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_snapshot_cmd() -> Result<()> {
+        let dir = Path::new("/tmp/test-criu-image-streamer-snapshot");
+        std::fs::create_dir_all(dir)?;
+        run_snapshot_cmd("touch {dir}/snapshot", dir)?;
+        assert!(dir.join("snapshot").exists());
+
+        let err = run_snapshot_cmd("exit 3", dir).unwrap_err();
+        assert!(err.to_string().contains("failed: exit status: 3"), "{}", err);
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_snapshot_cmd_quotes_dir() -> Result<()> {
+        // The directory name is not interpreted by the shell.
+        let dir = Path::new("/tmp/test-criu-image-streamer-snapshot 'it's $(exit 1)");
+        std::fs::create_dir_all(dir)?;
+        run_snapshot_cmd("touch {dir}/snapshot", dir)?;
+        assert!(dir.join("snapshot").exists());
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+};
+use opentelemetry::{
+    Context, KeyValue, global,
+    propagation::TextMapPropagator,
+    sdk::{self, Resource, propagation::TraceContextPropagator},
+    trace::{Span, StatusCode, TraceContextExt, Tracer},
+};
+use opentelemetry_otlp::WithExportConfig;
+use crate::{
+    events::{Event, EventCallback},
+    shard_cmd::{ShardCmd, ShardCmdFailed},
+    shard_fifos::FifoDirection,
+    util::Phase,
+};
+use anyhow::{Result, Context as _};
+
+// Checkpoints are one step of larger operations, e.g., a migration driven by an orchestrator.
+// With --otlp-endpoint, the streamer exports its spans to an OpenTelemetry collector, so that the
+// checkpoint shows up in the distributed trace of the operation. The caller passes its trace
+// context as a W3C traceparent, which becomes the parent of the span of the streamer operation.
+// Below it, each file streamed gets a span, as do the phases of serving an image, and the shard
+// commands (e.g., uploads with --rclone).
+//
+// The exporter runs on its own tokio runtime, away from the streaming thread. Spans are exported
+// in batches, and flushed when the streamer exits.
+
+struct Telemetry {
+    runtime: tokio::runtime::Runtime,
+    tracer: sdk::trace::Tracer,
+    /// Holds the span of the operation
+    cx: Context,
+    shard_spans: Vec<sdk::trace::Span>,
+}
+
+lazy_static::lazy_static! {
+    static ref TELEMETRY: Mutex<Option<Telemetry>> = Mutex::new(None);
+}
+
+/// Returns the context of the W3C `traceparent`, e.g.,
+/// 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01.
+fn parse_traceparent(traceparent: &str) -> Result<Context> {
+    let carrier = HashMap::from([("traceparent".to_string(), traceparent.to_string())]);
+    let cx = TraceContextPropagator::new().extract(&carrier);
+    ensure!(cx.span().span_context().is_valid(), "Invalid traceparent `{}`", traceparent);
+    Ok(cx)
+}
+
+/// Starts exporting spans to the OTLP (gRPC) collector at `endpoint`, and starts the span of the
+/// `operation`, child of `traceparent` when provided. `finish_telemetry()` must be called once
+/// the operation is done.
+pub fn init_telemetry(endpoint: &str, traceparent: Option<&str>, operation: &str) -> Result<()> {
+    let parent_cx = traceparent.map(parse_traceparent).transpose()?.unwrap_or_default();
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .context("Failed to start the telemetry runtime")?;
+    let tracer = {
+        let _guard = runtime.enter();
+        let resource = Resource::new(vec![KeyValue::new("service.name", env!("CARGO_PKG_NAME"))]);
+        opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint))
+            .with_trace_config(sdk::trace::config().with_resource(resource))
+            .install_batch(opentelemetry::runtime::Tokio)
+            .with_context(|| format!("Failed to export spans to {}", endpoint))?
+    };
+
+    let span = tracer.start_with_context(operation.to_string(), &parent_cx);
+    let cx = parent_cx.with_span(span);
+    *TELEMETRY.lock().unwrap() = Some(Telemetry { runtime, tracer, cx, shard_spans: Vec::new() });
+    Ok(())
+}
+
+/// Returns the event callback that turns the files streamed, and the phases, into spans. `None`
+/// when telemetry is not enabled.
+pub fn telemetry_event_callback() -> Option<EventCallback> {
+    let (tracer, cx) = match TELEMETRY.lock().unwrap().as_ref() {
+        Some(telemetry) => (telemetry.tracer.clone(), telemetry.cx.clone()),
+        None => return None,
+    };
+
+    let mut phase_cx: Option<Context> = None;
+    let mut file_spans: HashMap<String, sdk::trace::Span> = HashMap::new();
+    Some(Box::new(move |event| match event {
+        Event::PhaseStart { phase } => {
+            if let Some(phase_cx) = phase_cx.take() {
+                phase_cx.span().end();
+            }
+            if phase != Phase::Done {
+                let span = tracer.start_with_context(phase.name(), &cx);
+                phase_cx = Some(cx.with_span(span));
+            }
+        }
+        Event::FileStart { filename } => {
+            // Files are received while buffering, they go in the span of the phase.
+            let span = tracer.span_builder("file")
+                .with_attributes(vec![KeyValue::new("file.name", filename.to_string())])
+                .start_with_context(&tracer, phase_cx.as_ref().unwrap_or(&cx));
+            file_spans.insert(filename.to_string(), span);
+        }
+        Event::FileComplete { filename, size } => {
+            if let Some(mut span) = file_spans.remove(filename) {
+                span.set_attribute(KeyValue::new("file.size", size as i64));
+                span.end();
+            }
+        }
+        Event::ImageComplete { stats } => {
+            let span = cx.span();
+            if let Some(image_uuid) = &stats.image_uuid {
+                span.set_attribute(KeyValue::new("image.uuid", image_uuid.clone()));
+            }
+            let size: u64 = stats.shards.iter().map(|s| s.size).sum();
+            span.set_attribute(KeyValue::new("image.size", size as i64));
+            span.set_attribute(KeyValue::new("image.shards", stats.shards.len() as i64));
+        }
+    }))
+}
+
+/// Starts a span for each of the `num_shards` shard commands, which ends in `finish_telemetry()`.
+pub fn start_shard_cmd_spans(cmd: &ShardCmd, num_shards: usize, direction: FifoDirection) {
+    if let Some(telemetry) = TELEMETRY.lock().unwrap().as_mut() {
+        let name = match direction {
+            FifoDirection::Write => "shard-upload",
+            FifoDirection::Read => "shard-download",
+        };
+        telemetry.shard_spans = (0..num_shards).map(|shard_index| {
+            telemetry.tracer.span_builder(name)
+                .with_attributes(vec![
+                    KeyValue::new("shard.index", shard_index as i64),
+                    KeyValue::new("shard.cmd", cmd.to_string()),
+                ])
+                .start_with_context(&telemetry.tracer, &telemetry.cx)
+        }).collect();
+    }
+}
+
+/// Ends the spans with the `result` of the operation, and flushes them to the collector.
+pub fn finish_telemetry(result: &Result<()>) {
+    let telemetry = match TELEMETRY.lock().unwrap().take() {
+        Some(telemetry) => telemetry,
+        None => return,
+    };
+
+    // The shard commands are waited for once the image is written, they are all done by now.
+    let failed_shard = result.as_ref().err()
+        .and_then(|e| e.downcast_ref::<ShardCmdFailed>())
+        .map(|e| e.shard_index);
+    for (shard_index, mut span) in telemetry.shard_spans.into_iter().enumerate() {
+        if Some(shard_index) == failed_shard {
+            span.set_status(StatusCode::Error, format!("{:#}", result.as_ref().unwrap_err()));
+        }
+        span.end();
+    }
+
+    let span = telemetry.cx.span();
+    if let Err(e) = result {
+        span.set_status(StatusCode::Error, format!("{:#}", e));
+    }
+    span.end();
+
+    let _guard = telemetry.runtime.enter();
+    global::shutdown_tracer_provider();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_traceparent() {
+        let cx = parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!(cx.span().span_context().trace_id().to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert!(parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_err());
+        assert!(parse_traceparent("trace").is_err());
+    }
+}
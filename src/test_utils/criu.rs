@@ -19,12 +19,12 @@ use std::{
     path::PathBuf,
 };
 use anyhow::{Result, Context};
-use criu_image_streamer::{
+use crate::{
     criu,
-    util::{pb_read, pb_write},
+    util::{pb_read, pb_write_max},
     unix_pipe::UnixPipe,
 };
-use crate::helpers::util::*;
+use super::{new_pipe, send_fd};
 
 /// For test purposes, we implement a CRIU simulator
 pub struct Criu {
@@ -38,6 +38,12 @@ impl Criu {
         Ok(Self { socket })
     }
 
+    /// CRIU has no limit on the size of its requests, the streamer enforces its own.
+    fn send_request(&mut self, filename: String) -> Result<()> {
+        pb_write_max(&mut self.socket, &criu::ImgStreamerRequestEntry { filename }, usize::MAX)?;
+        Ok(())
+    }
+
     fn read_file_reply(&mut self) -> Result<bool> {
         let reply: criu::ImgStreamerReplyEntry = pb_read(&mut self.socket)?;
         Ok(reply.exists)
@@ -49,16 +55,28 @@ impl Criu {
     }
 
     pub fn write_img_file(&mut self, filename: &str) -> Result<UnixPipe> {
-        let filename = filename.to_string();
-        pb_write(&mut self.socket, &criu::ImgStreamerRequestEntry { filename })?;
         let (pipe_r, pipe_w) = new_pipe();
-        send_fd(&mut self.socket, pipe_r.as_raw_fd())?;
+        self.write_img_file_with_pipe(filename, &pipe_r)?;
         Ok(pipe_w)
     }
 
+    /// Same as `write_img_file()`, with a pipe provided by the caller.
+    pub fn write_img_file_with_pipe(&mut self, filename: &str, pipe_r: &UnixPipe) -> Result<()> {
+        let filename = filename.to_string();
+        self.send_request(filename)?;
+        send_fd(&mut self.socket, pipe_r.as_raw_fd())
+    }
+
+    /// Sends a file request without its pipe, as a stalled CRIU would.
+    pub fn send_file_request(&mut self, filename: &str) -> Result<()> {
+        let filename = filename.to_string();
+        self.send_request(filename)?;
+        Ok(())
+    }
+
     pub fn maybe_read_img_file(&mut self, filename: &str) -> Result<Option<UnixPipe>> {
         let filename = filename.to_string();
-        pb_write(&mut self.socket, &criu::ImgStreamerRequestEntry { filename })?;
+        self.send_request(filename)?;
 
         if self.read_file_reply()? {
             let (pipe_r, pipe_w) = new_pipe();
@@ -0,0 +1,62 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Helpers to write tests against the streamer protocol. `criu::Criu` simulates the CRIU side of
+//! the image streaming protocol. Only compiled with the `test-utils` feature.
+
+pub mod criu;
+
+use std::io::{Read, BufReader, BufRead};
+use nix::unistd;
+use crate::unix_pipe::{UnixPipe, UnixPipeImpl};
+use anyhow::Result;
+
+pub use crate::util::{Stats, ShardStat, ExtFileStat, Progress, Phase, PhaseTransition, PhaseStats, ServeStats,
+                       send_fd};
+
+pub fn new_pipe() -> (UnixPipe, UnixPipe) {
+    let (fd_r, fd_w) = unistd::pipe().expect("Failed to create UNIX pipe");
+    let pipe_r = UnixPipe::new(fd_r).unwrap();
+    let pipe_w = UnixPipe::new(fd_w).unwrap();
+    (pipe_r, pipe_w)
+}
+
+/// Reads a line from the progress pipe, without the trailing '\n'.
+pub fn read_line<R: Read>(progress: &mut BufReader<R>) -> Result<String> {
+    let mut buf = String::new();
+    progress.read_line(&mut buf)?;
+
+    ensure!(!buf.is_empty(), "EOF reached");
+    ensure!(buf.ends_with('\n'), "no trailing \\n found");
+    buf.pop(); // Removes the trailing '\n'
+
+    Ok(buf)
+}
+
+/// Reads the stats emitted on the progress pipe at the end of an operation.
+pub fn read_stats<R: Read>(progress: &mut BufReader<R>) -> Result<Stats> {
+    Ok(serde_json::from_str(&read_line(progress)?)?)
+}
+
+/// Reads the progress reports emitted while capturing, followed by the stats.
+pub fn read_progress_and_stats<R: Read>(progress: &mut BufReader<R>) -> Result<(Vec<Progress>, Stats)> {
+    let mut reports = Vec::new();
+    loop {
+        let line = read_line(progress)?;
+        match serde_json::from_str(&line) {
+            Ok(report) => reports.push(report),
+            Err(_) => return Ok((reports, serde_json::from_str(&line)?)),
+        }
+    }
+}
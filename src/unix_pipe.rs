@@ -14,17 +14,23 @@
 
 use std::{
     os::unix::io::{RawFd, FromRawFd, AsRawFd},
+    io::{Read, Write},
+    cmp::min,
     fs,
 };
 use nix::{
     sys::stat::{fstat, SFlag},
-    fcntl::{fcntl, FcntlArg},
+    fcntl::{fcntl, FcntlArg, OFlag},
+    unistd::pipe2,
     fcntl::{vmsplice, splice, SpliceFFlags},
-    sys::uio::IoVec,
+    sys::uio::{IoVec, writev},
     errno::Errno,
     Error,
 };
-use crate::util::PAGE_SIZE;
+use crate::{
+    util::{PAGE_SIZE, KB},
+    kernel_caps::KERNEL_CAPS,
+};
 use anyhow::{Context, Result};
 
 /// Unix pipes are regular `fs::File`. To add pipe specific functionalities, we have three options:
@@ -35,16 +41,29 @@ use anyhow::{Context, Result};
 /// 3) Define a new trait `UnixPipeImpl`. This has the downside that we need to import the
 ///    `UnixPipeImpl` everywhere we want to use the `UnixPipe` features. Not a terrible downside,
 ///    so we go with this.
-
 pub type UnixPipe = fs::File;
 
+/// When the host limits the pipe sizes, shard pipes fall back to smaller capacities, and the
+/// streaming slows down. With a minimum capacity, the operation fails right away instead.
+pub fn ensure_min_capacity(capacity: i32, min_capacity: i32) -> Result<()> {
+    ensure!(capacity >= min_capacity,
+            "The host limits the shard pipes to {} bytes, below the minimum of {} bytes \
+             (--min-pipe-capacity). Run the doctor operation to find out which limit applies",
+            capacity, min_capacity);
+    Ok(())
+}
+
 pub trait UnixPipeImpl: Sized {
     fn new(fd: RawFd) -> Result<Self>;
     fn fionread(&self) -> Result<i32>;
+    fn capacity(&self) -> Result<i32>;
     fn set_capacity(&mut self, capacity: i32) -> nix::Result<()>;
     fn increase_capacity(pipes: &mut [Self], max_capacity: i32) -> Result<i32>;
     fn splice_all(&mut self, dst: &mut fs::File, len: usize) -> Result<()>;
-    fn vmsplice_all(&mut self, data: &[u8]) -> Result<()>;
+    fn writev_all(&mut self, bufs: &[&[u8]]) -> Result<()>;
+    fn set_nonblocking(&self) -> Result<()>;
+    fn try_writev(&mut self, bufs: &[&[u8]]) -> Result<usize>;
+    fn try_vmsplice(&mut self, data: &[u8]) -> Result<usize>;
 }
 
 impl UnixPipeImpl for UnixPipe {
@@ -71,6 +90,11 @@ impl UnixPipeImpl for UnixPipe {
         Ok(result)
     }
 
+    fn capacity(&self) -> Result<i32> {
+        fcntl(self.as_raw_fd(), FcntlArg::F_GETPIPE_SZ)
+            .with_context(|| format!("Failed to get the capacity of pipe fd {}", self.as_raw_fd()))
+    }
+
     fn set_capacity(&mut self, capacity: i32) -> nix::Result<()> {
         fcntl(self.as_raw_fd(), FcntlArg::F_SETPIPE_SZ(capacity)).map(|_| ())
     }
@@ -78,9 +102,15 @@ impl UnixPipeImpl for UnixPipe {
     /// Sets the capacity of many pipes. /proc/sys/fs/pipe-user-pages-{hard,soft} may be non-zero,
     /// preventing setting the desired capacity. If we can't set the provided `max_capacity`, then
     /// we try with a lower capacity. Eventually we will succeed.
+    /// If the kernel doesn't support resizing pipes, pipes are left untouched.
     /// Returns the actual capacity of the pipes.
     fn increase_capacity(pipes: &mut [Self], max_capacity: i32) -> Result<i32> {
-        let mut capacity = max_capacity;
+        let mut capacity = match KERNEL_CAPS.max_pipe_capacity {
+            Some(kernel_max_capacity) => min(max_capacity, kernel_max_capacity),
+            None => return pipes.iter()
+                .map(|pipe| pipe.capacity())
+                .try_fold(max_capacity, |min_capacity, capacity| Ok(min(min_capacity, capacity?))),
+        };
         loop {
             match pipes.iter_mut().try_for_each(|pipe| pipe.set_capacity(capacity)) {
                 Err(Error::Sys(Errno::EPERM)) => {
@@ -95,13 +125,23 @@ impl UnixPipeImpl for UnixPipe {
     }
 
     fn splice_all(&mut self, dst: &mut fs::File, len: usize) -> Result<()> {
+        if !KERNEL_CAPS.splice {
+            return copy_all(self, dst, len);
+        }
+
         let mut to_write = len;
 
         while to_write > 0 {
-            let written = splice(self.as_raw_fd(), None, dst.as_raw_fd(), None,
-                                 to_write, SpliceFFlags::SPLICE_F_MORE)
-                .with_context(|| format!("splice() failed fd {} -> fd {}",
-                                         self.as_raw_fd(), dst.as_raw_fd()))?;
+            let written = match splice_once(self, dst, to_write) {
+                Err(Error::Sys(Errno::EINTR)) => continue,
+                // Some environments (e.g., gVisor) don't support splice() on all fds. We copy
+                // the data instead. The next call tries splice() again, as it may be supported
+                // on the other fds it involves.
+                Err(Error::Sys(Errno::EINVAL)) | Err(Error::Sys(Errno::ENOSYS)) =>
+                    return copy_all(self, dst, to_write),
+                result => result.with_context(|| format!("splice() failed fd {} -> fd {}",
+                                                         self.as_raw_fd(), dst.as_raw_fd()))?,
+            };
             ensure!(written > 0, "Reached EOF during splice() on fd {}", self.as_raw_fd());
             to_write -= written;
         }
@@ -109,20 +149,122 @@ impl UnixPipeImpl for UnixPipe {
         Ok(())
     }
 
-    fn vmsplice_all(&mut self, data: &[u8]) -> Result<()> {
-        let mut to_write = data.len();
+    fn writev_all(&mut self, mut bufs: &[&[u8]]) -> Result<()> {
+        // `offset` is the number of bytes of bufs[0] that have already been written.
         let mut offset = 0;
 
-        while to_write > 0 {
-            let in_iov = IoVec::from_slice(&data[offset..]);
-            let written = vmsplice(self.as_raw_fd(), &[in_iov], SpliceFFlags::SPLICE_F_GIFT)
-                .with_context(|| format!("vmsplice() failed on fd {}", self.as_raw_fd()))?;
-            assert!(written > 0, "vmsplice() returned 0");
+        while !bufs.is_empty() {
+            let iovs: Vec<_> = std::iter::once(&bufs[0][offset..])
+                .chain(bufs[1..].iter().copied())
+                .map(IoVec::from_slice)
+                .collect();
+            let mut written = match writev(self.as_raw_fd(), &iovs) {
+                Err(Error::Sys(Errno::EINTR)) => continue,
+                result => result.with_context(|| format!("writev() failed on fd {}", self.as_raw_fd()))?,
+            };
+            ensure!(written > 0, "writev() returned 0");
 
-            to_write -= written;
+            // Skip over the buffers that have been fully written
+            while !bufs.is_empty() && written >= bufs[0].len() - offset {
+                written -= bufs[0].len() - offset;
+                bufs = &bufs[1..];
+                offset = 0;
+            }
             offset += written;
         }
 
         Ok(())
     }
+
+    fn set_nonblocking(&self) -> Result<()> {
+        let fd = self.as_raw_fd();
+        fcntl(fd, FcntlArg::F_GETFL)
+            .and_then(|flags| fcntl(fd, FcntlArg::F_SETFL(OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK)))
+            .with_context(|| format!("Failed to make fd {} non-blocking", fd))?;
+        Ok(())
+    }
+
+    /// Writes as much as the pipe accepts without blocking. The pipe must be non-blocking.
+    /// Returns the number of bytes written, which may be 0 when the pipe is full.
+    fn try_writev(&mut self, bufs: &[&[u8]]) -> Result<usize> {
+        let iovs: Vec<_> = bufs.iter()
+            .filter(|buf| !buf.is_empty())
+            .map(|buf| IoVec::from_slice(buf))
+            .collect();
+        if iovs.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            return match writev(self.as_raw_fd(), &iovs) {
+                Err(Error::Sys(Errno::EINTR)) => continue,
+                Err(Error::Sys(Errno::EAGAIN)) => Ok(0),
+                result => result.with_context(|| format!("writev() failed on fd {}", self.as_raw_fd())),
+            };
+        }
+    }
+
+    /// Same as `try_writev()` with vmsplice(). `data` must be backed by memory that is unmapped
+    /// once spliced, and never modified, as the pipe may reference its pages.
+    fn try_vmsplice(&mut self, data: &[u8]) -> Result<usize> {
+        if !KERNEL_CAPS.vmsplice || data.is_empty() {
+            return self.try_writev(&[data]);
+        }
+
+        loop {
+            return match vmsplice_once(self, data) {
+                Err(Error::Sys(Errno::EINTR)) => continue,
+                Err(Error::Sys(Errno::EAGAIN)) => Ok(0),
+                Err(Error::Sys(Errno::EINVAL)) | Err(Error::Sys(Errno::ENOSYS)) =>
+                    self.try_writev(&[data]),
+                result => result.with_context(|| format!("vmsplice() failed on fd {}", self.as_raw_fd())),
+            };
+        }
+    }
+}
+
+fn splice_once(src: &UnixPipe, dst: &fs::File, len: usize) -> nix::Result<usize> {
+    #[cfg(feature = "fault-injection")]
+    crate::fault_injection::splice_fault()?;
+    #[cfg(feature = "fault-injection")]
+    let len = match crate::fault_injection::pipe_fault(len, true)? {
+        0 => return Ok(0),
+        len => len,
+    };
+    splice(src.as_raw_fd(), None, dst.as_raw_fd(), None, len, SpliceFFlags::SPLICE_F_MORE)
+}
+
+fn vmsplice_once(dst: &UnixPipe, data: &[u8]) -> nix::Result<usize> {
+    #[cfg(feature = "fault-injection")]
+    crate::fault_injection::splice_fault()?;
+    #[cfg(feature = "fault-injection")]
+    let data = &data[..crate::fault_injection::pipe_fault(data.len(), false)?];
+    vmsplice(dst.as_raw_fd(), &[IoVec::from_slice(data)],
+             SpliceFFlags::SPLICE_F_GIFT | SpliceFFlags::SPLICE_F_NONBLOCK)
+}
+
+/// Creates a pipe whose ends are not inherited by the processes we spawn.
+pub fn new_cloexec_pipe() -> Result<(UnixPipe, UnixPipe)> {
+    let (pipe_r, pipe_w) = pipe2(OFlag::O_CLOEXEC).context("Failed to create a pipe")?;
+    unsafe { Ok((fs::File::from_raw_fd(pipe_r), fs::File::from_raw_fd(pipe_w))) }
+}
+
+/// Copies data through userspace. This is the fallback when splice() cannot be used, at the cost
+/// of reduced performance.
+/// Note that we don't use `std::io::copy()` as it may use splice() under the hood.
+fn copy_all(src: &mut UnixPipe, dst: &mut fs::File, len: usize) -> Result<()> {
+    let mut buf = vec![0; min(len, 64*KB)];
+    let mut to_write = len;
+
+    while to_write > 0 {
+        let chunk_len = min(to_write, buf.len());
+        let chunk = &mut buf[..chunk_len];
+        src.read_exact(chunk)
+            .with_context(|| format!("Failed to read from fd {}", src.as_raw_fd()))?;
+        dst.write_all(chunk)
+            .with_context(|| format!("Failed to write to fd {}", dst.as_raw_fd()))?;
+        to_write -= chunk.len();
+    }
+
+    Ok(())
 }
@@ -17,23 +17,44 @@ use std::{
     mem::size_of,
     os::unix::net::UnixStream,
     os::unix::io::{RawFd, AsRawFd},
-    io::{Read, Write},
+    io::{self, Read, Write},
+    ops::RangeInclusive,
     path::Path,
+    sync::atomic::{AtomicBool, Ordering},
     fs,
 };
 use nix::{
-    sys::socket::{ControlMessageOwned, MsgFlags, recvmsg},
+    sys::socket::{ControlMessage, ControlMessageOwned, MsgFlags, recvmsg, sendmsg},
     sys::uio::IoVec,
-    unistd::{sysconf, SysconfVar},
+    unistd::{sysconf, SysconfVar, Pid},
+    sched::{CpuSet, sched_setaffinity},
 };
 use bytes::{BytesMut, Buf, BufMut};
-use serde::Serialize;
+use serde::{Serialize, Deserialize};
 use anyhow::{Result, Context};
+use crate::{kernel_caps::KernelCaps, page_stats::PageStats, manifest::ManifestFile, stats as pb};
 
 pub const KB: usize = 1024;
 pub const MB: usize = 1024*1024;
 pub const EOF_ERR_MSG: &str = "EOF unexpectedly reached";
 
+/// Protobuf objects larger than this limit are rejected, as they are most likely the sign of a
+/// corrupted stream. Filenames are the largest fields we deal with, and can get long with deep
+/// ghost file paths, which is why the limit is generous and configurable with
+/// `CaptureBuilder::max_marker_size()` and `ExtractBuilder::max_marker_size()`.
+pub const DEFAULT_MAX_PB_SIZE: usize = 64*KB;
+/// The lowest limit that can be configured. It fits the markers of fixed size, the largest being
+/// the shard headers, and filenames up to PATH_MAX.
+pub const MIN_MAX_PB_SIZE: usize = 8*KB;
+
+fn ensure_pb_size(size: usize, max_size: usize) -> Result<()> {
+    ensure!(size <= max_size,
+            "Protobuf of size {} bytes exceeds the limit of {} bytes. \
+             The stream may be corrupted, otherwise use --max-marker-size to raise the limit",
+            size, max_size);
+    Ok(())
+}
+
 lazy_static::lazy_static! {
     pub static ref PAGE_SIZE: usize = sysconf(SysconfVar::PAGE_SIZE)
         .expect("Failed to determine PAGE_SIZE")
@@ -45,6 +66,9 @@ lazy_static::lazy_static! {
 /// If it can read the number of bytes requested, it returns Ok(bytes_requested).
 /// Otherwise, it returns Err("EOF error").
 pub fn read_bytes_next<S: Read>(src: &mut S, len: usize) -> Result<Option<BytesMut>> {
+    #[cfg(feature = "fault-injection")]
+    let src = &mut crate::fault_injection::FaultyReader(src);
+
     let mut buf = Vec::with_capacity(len);
     src.take(len as u64).read_to_end(&mut buf).context("Failed to read protobuf")?;
     Ok(match buf.len() {
@@ -58,11 +82,16 @@ pub fn read_bytes_next<S: Read>(src: &mut S, len: usize) -> Result<Option<BytesM
 /// It returns Ok(obj) for each object to be read, and Ok(None) when EOF is reached.
 /// It returns an error if an object is only partially read, or any deserialization error.
 pub fn pb_read_next<S: Read, T: Message + Default>(src: &mut S) -> Result<Option<(T, usize)>> {
+    pb_read_next_max(src, DEFAULT_MAX_PB_SIZE)
+}
+
+/// Same as `pb_read_next()`, with objects larger than `max_size` bytes rejected.
+pub fn pb_read_next_max<S: Read, T: Message + Default>(src: &mut S, max_size: usize) -> Result<Option<(T, usize)>> {
     Ok(match read_bytes_next(src, size_of::<u32>())? {
         None => None,
         Some(mut size_buf) => {
             let size = size_buf.get_u32_le() as usize;
-            assert!(size < 10*KB, "Would read a protobuf of size >10KB. Something is wrong");
+            ensure_pb_size(size, max_size)?;
             let buf = read_bytes_next(src, size)?.ok_or_else(|| anyhow!(EOF_ERR_MSG))?;
             let bytes_read = size_of::<u32>() + size_buf.len() + buf.len();
             Some((T::decode(buf)?, bytes_read))
@@ -78,9 +107,14 @@ pub fn pb_read<S: Read, T: Message + Default>(src: &mut S) -> Result<T> {
 }
 
 pub fn pb_write<S: Write, T: Message>(dst: &mut S, msg: &T) -> Result<usize> {
+    pb_write_max(dst, msg, DEFAULT_MAX_PB_SIZE)
+}
+
+/// Same as `pb_write()`, with objects larger than `max_size` bytes rejected.
+pub fn pb_write_max<S: Write, T: Message>(dst: &mut S, msg: &T, max_size: usize) -> Result<usize> {
     let msg_size = msg.encoded_len();
+    ensure_pb_size(msg_size, max_size)?;
     let mut buf = BytesMut::with_capacity(size_of::<u32>() + msg_size);
-    assert!(msg_size < 10*KB, "Would serialize a protobuf of size >10KB. Something is wrong");
     buf.put_u32_le(msg_size as u32);
 
     msg.encode(&mut buf).context("Failed to encode protobuf")?;
@@ -104,10 +138,90 @@ pub fn recv_fd(socket: &mut UnixStream) -> Result<RawFd> {
     })
 }
 
+pub fn send_fd(socket: &mut UnixStream, fd: RawFd) -> Result<()> {
+    sendmsg(socket.as_raw_fd(),
+           &[IoVec::from_slice(&[0])],
+           &[ControlMessage::ScmRights(&[fd])],
+           MsgFlags::empty(),
+           None)
+        .context("Failed to send fd over socket")?;
+    Ok(())
+}
+
+/// Format of the messages written on the progress fd.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressFormat {
+    /// One message per line. The stats are a JSON object.
+    Json,
+    /// Length-delimited `progress` protobuf messages, see proto/stats.proto. The stats are a
+    /// protobuf message, the other messages carry their line.
+    Proto,
+}
+
+static PROGRESS_FORMAT_PROTO: AtomicBool = AtomicBool::new(false);
+
+pub fn set_progress_format(format: ProgressFormat) {
+    PROGRESS_FORMAT_PROTO.store(format == ProgressFormat::Proto, Ordering::Relaxed);
+}
+
+fn progress_format() -> ProgressFormat {
+    match PROGRESS_FORMAT_PROTO.load(Ordering::Relaxed) {
+        true => ProgressFormat::Proto,
+        false => ProgressFormat::Json,
+    }
+}
+
+fn write_progress_pb(progress_pipe: &mut fs::File, body: pb::progress::Body) -> io::Result<()> {
+    progress_pipe.write_all(&pb::Progress { body: Some(body) }.encode_length_delimited_to_vec())
+}
+
+/// Returns the progress message `msg` as written on the progress fd in the given format.
+pub fn progress_line(format: ProgressFormat, msg: &str) -> Vec<u8> {
+    match format {
+        ProgressFormat::Json => format!("{}\n", msg).into_bytes(),
+        ProgressFormat::Proto =>
+            pb::Progress { body: Some(pb::progress::Body::Line(msg.to_string())) }.encode_length_delimited_to_vec(),
+    }
+}
+
 pub fn emit_progress(progress_pipe: &mut fs::File, msg: &str) {
     // Writes to the progress pipe can fail. The parent may have closed that pipe, and we don't
     // need to get upset about failing reporting progress.
-    let _ = writeln!(progress_pipe, "{}", msg);
+    let _ = progress_pipe.write_all(&progress_line(progress_format(), msg));
+}
+
+/// Emits the stats on the progress fd, in the progress format.
+pub fn report_stats(progress_pipe: &mut fs::File, stats: &Stats) -> Result<()> {
+    match progress_format() {
+        ProgressFormat::Json => emit_progress(progress_pipe, &serde_json::to_string(stats)?),
+        ProgressFormat::Proto => {
+            let _ = write_progress_pb(progress_pipe, pb::progress::Body::Stats(stats.into()));
+        }
+    }
+    Ok(())
+}
+
+/// Returns a progress pipe that discards everything, for library users that don't care about the
+/// progress.
+pub fn null_progress_pipe() -> Result<fs::File> {
+    fs::OpenOptions::new().write(true).open("/dev/null")
+        .context("Failed to open /dev/null")
+}
+
+/// Returns a random (version 4) UUID.
+pub fn new_uuid() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes))
+        .context("Failed to read /dev/urandom")?;
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..]))
+}
+
+/// Quotes `s` as a single word for `sh`.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
 }
 
 pub fn create_dir_all(dir: &Path) -> Result<()> {
@@ -115,12 +229,240 @@ pub fn create_dir_all(dir: &Path) -> Result<()> {
         .with_context(|| format!("Failed to create directory {}", dir.display()))
 }
 
-#[derive(Serialize)]
+/// Version of the schema of the stats. It is bumped when fields are removed, or change meaning.
+/// Adding fields doesn't bump it, readers must ignore the fields they don't know.
+pub const STATS_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Stats {
+    /// STATS_SCHEMA_VERSION
+    pub schema_version: u32,
+    /// Generated when capturing, and read from the shard headers when extracting
+    pub image_uuid: Option<String>,
     pub shards: Vec<ShardStat>,
+    pub kernel: KernelCaps,
+    /// Only reported when capturing an image that has pagemap images
+    pub pages: Option<PageStats>,
+    pub ext_files: Vec<ExtFileStat>,
+    /// Digests of all the files, sorted by filename. Only reported with `--file-digests`.
+    pub files: Vec<ManifestFile>,
+    /// Only reported when capturing
+    pub shard_saturation: Option<ShardSaturation>,
+    /// High-water mark of the chunk bytes buffered at once. When capturing, these are the bytes
+    /// in the shard pipes and spilled in memory, sampled when the shards are refreshed. When
+    /// extracting, these are the bytes of the chunks read ahead of their turn, waiting in the
+    /// shard pipes. Useful to size the pipe capacities and the spill budget.
+    pub max_buffered_bytes: Option<u64>,
 }
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct ShardStat {
     pub size: u64,
     pub transfer_duration_millis: u128,
+    /// The following are only reported when writing shards. See `Shard` in capture.rs.
+    pub blocked_millis: Option<u128>,
+    pub splice_millis: Option<u128>,
+    pub marker_bytes: Option<u64>,
+    /// Capacity of the shard pipe, as negotiated with the kernel. Smaller than requested when the
+    /// host limits the pipe sizes. None when it can't be queried.
+    pub pipe_capacity: Option<i32>,
+}
+/// How much the shards held back the capture. See `suggest_num_shards()` in capture.rs.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ShardSaturation {
+    /// Fraction of the transfer spent waiting on full shards, from 0 to 1
+    pub saturation: f64,
+    /// Number of shards that would have kept up with this workload
+    pub suggested_num_shards: usize,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ExtFileStat {
+    pub filename: String,
+    pub size: u64,
+    /// Hex encoded sha256 digest, only reported when digests are computed. See
+    /// `--ext-file-digests`.
+    pub sha256: Option<String>,
+}
+
+impl From<&Stats> for pb::Stats {
+    fn from(stats: &Stats) -> Self {
+        let kernel = &stats.kernel;
+        Self {
+            schema_version: stats.schema_version,
+            image_uuid: stats.image_uuid.clone().unwrap_or_default(),
+            shards: stats.shards.iter().map(|s| pb::ShardStat {
+                size: s.size,
+                transfer_duration_millis: s.transfer_duration_millis as u64,
+                blocked_millis: s.blocked_millis.unwrap_or(0) as u64,
+                splice_millis: s.splice_millis.unwrap_or(0) as u64,
+                marker_bytes: s.marker_bytes.unwrap_or(0),
+                pipe_capacity: s.pipe_capacity.unwrap_or(0),
+            }).collect(),
+            kernel: Some(pb::KernelCaps {
+                release: kernel.release.clone(),
+                max_pipe_capacity: kernel.max_pipe_capacity.unwrap_or(0),
+                splice_bug: kernel.splice_bug,
+                splice: kernel.splice,
+                vmsplice: kernel.vmsplice,
+            }),
+            pages: stats.pages.as_ref().map(|p| pb::PageStats {
+                total_pages: p.total_pages,
+                dirty_pages: p.dirty_pages,
+                dirty_fraction: p.dirty_fraction,
+            }),
+            ext_files: stats.ext_files.iter().map(|f| pb::ExtFileStat {
+                filename: f.filename.clone(),
+                size: f.size,
+                sha256: f.sha256.clone().unwrap_or_default(),
+            }).collect(),
+            files: stats.files.iter().map(|f| pb::FileDigest {
+                filename: f.filename.clone(),
+                size: f.digest.size,
+                sha256: f.digest.sha256.clone(),
+            }).collect(),
+            shard_saturation: stats.shard_saturation.as_ref().map(|s| pb::ShardSaturation {
+                saturation: s.saturation,
+                suggested_num_shards: s.suggested_num_shards as u64,
+            }),
+            max_buffered_bytes: stats.max_buffered_bytes.unwrap_or(0),
+        }
+    }
+}
+
+/// Phases of serving an image, reported when requested. Downloading and decompressing the image
+/// happen before the shards reach the streamer, and are accounted in the buffering phase, as part
+/// of the shard transfer durations.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    /// Receiving the shards into memory
+    Buffering,
+    /// Checking the image against its signed manifest, only with a verifying key
+    Verifying,
+    /// Patching the image, e.g., remapping the TCP listen ports
+    Patching,
+    /// Waiting for the go of the controller, only in warm standby
+    Standby,
+    /// Serving the image to CRIU, until CRIU is done
+    Serving,
+    Done,
+}
+
+impl Phase {
+    /// The name of the phase, as on the progress fd.
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Buffering => "buffering",
+            Phase::Verifying => "verifying",
+            Phase::Patching => "patching",
+            Phase::Standby => "standby",
+            Phase::Serving => "serving",
+            Phase::Done => "done",
+        }
+    }
+}
+
+/// Emitted on the progress fd when entering a phase.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PhaseTransition {
+    pub phase: Phase,
+}
+
+/// Emitted after the done phase, with the duration of each phase that was entered.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PhaseStats {
+    pub phases: Vec<PhaseStat>,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PhaseStat {
+    pub phase: Phase,
+    pub duration_millis: u128,
+}
+
+/// Emitted once CRIU is done, when serving with `--request-stats`. The file requests of CRIU are
+/// listed in the order in which they were made.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServeStats {
+    pub requests: Vec<CriuRequestStat>,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CriuRequestStat {
+    /// With containers, the filename is prefixed with the name of the container, as in the image
+    pub filename: String,
+    /// False when the file is not in the image
+    pub exists: bool,
+    pub size: u64,
+    /// Time of the request, since the CRIU socket is ready
+    pub requested_at_millis: u128,
+    /// Time CRIU waited on the file, from its request until all of it is in the pipe of CRIU
+    pub wait_millis: u128,
+}
+
+/// Emitted while capturing when the expected image size is known, each time the completed
+/// percentage increases.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Progress {
+    pub size: u64,
+    pub expected_size: u64,
+    pub percent: u8,
+    /// None until some data has been captured
+    pub eta_millis: Option<u128>,
+}
+
+/// Confines the calling thread to the given CPUs. Threads spawned afterwards inherit the
+/// affinity, which is why this should be called before any thread is created.
+pub fn set_cpu_affinity(cpus: &[RangeInclusive<usize>]) -> Result<()> {
+    let mut cpu_set = CpuSet::new();
+    for cpu in cpus.iter().cloned().flatten() {
+        cpu_set.set(cpu).with_context(|| format!("Invalid CPU {}", cpu))?;
+    }
+    sched_setaffinity(Pid::from_raw(0), &cpu_set).context("Failed to set the CPU affinity")?;
+    Ok(())
+}
+
+/// Sets the nice value of the calling thread. On Linux, the nice value is a per-thread attribute,
+/// and as with the CPU affinity, threads spawned afterwards inherit it.
+pub fn set_nice(nice: i32) -> Result<()> {
+    let ret = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Failed to set the nice value to {}", nice));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_pb() {
+        let stats = Stats {
+            schema_version: STATS_SCHEMA_VERSION,
+            image_uuid: None,
+            shards: vec![ShardStat { size: 42, transfer_duration_millis: 7, blocked_millis: Some(3),
+                                     splice_millis: None, marker_bytes: Some(12), pipe_capacity: Some(65536) }],
+            kernel: KernelCaps { release: "5.15.0".to_string(), max_pipe_capacity: None,
+                                 splice_bug: false, splice: true, vmsplice: true },
+            pages: None,
+            ext_files: vec![ExtFileStat { filename: "fs.tar".to_string(), size: 3, sha256: None }],
+            files: Vec::new(),
+            shard_saturation: Some(ShardSaturation { saturation: 0.5, suggested_num_shards: 2 }),
+            max_buffered_bytes: None,
+        };
+        let encoded = pb::Progress { body: Some(pb::progress::Body::Stats((&stats).into())) }
+            .encode_length_delimited_to_vec();
+        let decoded = match pb::Progress::decode_length_delimited(encoded.as_slice()).unwrap().body {
+            Some(pb::progress::Body::Stats(stats)) => stats,
+            body => panic!("Unexpected progress message {:?}", body),
+        };
+        assert_eq!(decoded.schema_version, STATS_SCHEMA_VERSION);
+        assert_eq!(decoded.image_uuid, "");
+        assert_eq!(decoded.shards, vec![pb::ShardStat { size: 42, transfer_duration_millis: 7, blocked_millis: 3,
+                                                        splice_millis: 0, marker_bytes: 12, pipe_capacity: 65536 }]);
+        assert_eq!(decoded.kernel.unwrap().max_pipe_capacity, 0);
+        assert_eq!(decoded.ext_files[0].sha256, "");
+        assert_eq!(decoded.pages, None);
+        assert_eq!(decoded.shard_saturation.unwrap().suggested_num_shards, 2);
+        assert_eq!(decoded.max_buffered_bytes, 0);
+    }
 }
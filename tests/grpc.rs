@@ -0,0 +1,251 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+
+// These tests drive the streamer through the gRPC control plane. They need the grpc feature:
+// `cargo test --features grpc`.
+#![cfg(feature = "grpc")]
+
+// Unless we are in release mode, allow dead code, unused imports and variables,
+// it makes development more enjoyable.
+#![cfg_attr(debug_assertions, allow(dead_code, unused_imports, unused_variables))]
+
+#[macro_use]
+extern crate anyhow;
+
+mod helpers;
+
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Child},
+    os::unix::fs::PermissionsExt,
+    io::{Read, Write},
+    time::Duration,
+    thread,
+    fs,
+};
+use nix::{sys::stat::Mode, unistd::mkfifo};
+use tokio::{net::UnixStream, runtime::Runtime};
+use tonic::transport::{Channel, Endpoint, Uri};
+use tower::service_fn;
+use criu_image_streamer::{
+    grpc::control::{
+        control_client::ControlClient,
+        StartCaptureRequest, StartRestoreRequest, GetProgressRequest, GetProgressResponse,
+        AbortRequest, State,
+    },
+    test_utils::criu::Criu,
+};
+use crate::helpers::util::*;
+use anyhow::Result;
+
+/// The gRPC server process, killed when dropped.
+struct Server(Child);
+
+impl Server {
+    fn spawn(images_dir: &Path, listen: &Path) -> Result<Self> {
+        let child = Command::new(env!("CARGO_BIN_EXE_criu-image-streamer"))
+            .arg("--images-dir").arg(images_dir)
+            .arg("grpc-server")
+            .arg("--listen").arg(listen)
+            .spawn()?;
+        Ok(Self(child))
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// A blocking client for the control plane.
+struct Client {
+    runtime: Runtime,
+    client: ControlClient<Channel>,
+}
+
+impl Client {
+    fn connect(socket_path: &Path) -> Result<Self> {
+        let runtime = Runtime::new()?;
+        // The server may not be listening yet.
+        for _ in 0..100 {
+            let socket_path = socket_path.to_path_buf();
+            // The URI is required, but unused. The connector connects to the Unix socket.
+            let endpoint = Endpoint::from_static("http://[::]:50051");
+            let connector = service_fn(move |_: Uri| UnixStream::connect(socket_path.clone()));
+            if let Ok(channel) = runtime.block_on(endpoint.connect_with_connector(connector)) {
+                return Ok(Self { runtime, client: ControlClient::new(channel) });
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        bail!("Failed to connect to the gRPC server");
+    }
+
+    fn start_capture(&mut self, request: StartCaptureRequest) -> Result<u64> {
+        Ok(self.runtime.block_on(self.client.start_capture(request))?.into_inner().id)
+    }
+
+    fn start_restore(&mut self, request: StartRestoreRequest) -> Result<u64> {
+        Ok(self.runtime.block_on(self.client.start_restore(request))?.into_inner().id)
+    }
+
+    fn get_progress(&mut self, id: u64) -> Result<GetProgressResponse> {
+        Ok(self.runtime.block_on(self.client.get_progress(GetProgressRequest { id }))?.into_inner())
+    }
+
+    fn abort(&mut self, id: u64) -> Result<()> {
+        self.runtime.block_on(self.client.abort(AbortRequest { id }))?;
+        Ok(())
+    }
+
+    /// Polls the progress of the operation until `cond` is met.
+    fn wait_for(&mut self, id: u64, cond: impl Fn(&GetProgressResponse) -> bool) -> Result<GetProgressResponse> {
+        for _ in 0..200 {
+            let progress = self.get_progress(id)?;
+            if cond(&progress) {
+                return Ok(progress);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        bail!("Timed out waiting on operation {}", id);
+    }
+}
+
+fn new_images_dir(name: &str) -> Result<PathBuf> {
+    let images_dir = PathBuf::from(format!("/tmp/test-criu-image-streamer-grpc-{}", name));
+    let _ = fs::remove_dir_all(&images_dir);
+    fs::create_dir_all(&images_dir)?;
+    Ok(images_dir)
+}
+
+fn new_fifo(images_dir: &Path, name: &str) -> Result<String> {
+    let path = images_dir.join(name);
+    mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR)?;
+    Ok(path.to_str().unwrap().to_string())
+}
+
+fn has_socket_init(progress: &GetProgressResponse) -> bool {
+    progress.progress.iter().any(|msg| msg == "socket-init")
+}
+
+fn is_done(progress: &GetProgressResponse) -> bool {
+    progress.state != State::Running as i32
+}
+
+#[test]
+fn capture_and_restore() -> Result<()> {
+    let images_dir = new_images_dir("capture-and-restore")?;
+    let socket_path = images_dir.join("grpc.sock");
+    let _server = Server::spawn(&images_dir, &socket_path)?;
+    let mut client = Client::connect(&socket_path)?;
+    // Only the user of the server can connect.
+    assert_eq!(fs::metadata(&socket_path)?.permissions().mode() & 0o777, 0o600);
+    let file_data = get_rand_vec(1024*1024);
+
+    // Capture
+    let shard_path = new_fifo(&images_dir, "capture-shard")?;
+    let id = client.start_capture(StartCaptureRequest {
+        shard_paths: vec![shard_path.clone()],
+        ..Default::default()
+    })?;
+    let shard_thread = thread::spawn(move || -> Result<Vec<u8>> {
+        let mut img = Vec::new();
+        fs::File::open(shard_path)?.read_to_end(&mut img)?;
+        Ok(img)
+    });
+
+    client.wait_for(id, has_socket_init)?;
+    let mut criu = Criu::connect(images_dir.join("streamer-capture.sock"))?;
+    criu.write_img_file("file.img")?.write_all(&file_data)?;
+    criu.finish()?;
+
+    let progress = client.wait_for(id, is_done)?;
+    assert_eq!(progress.state, State::Succeeded as i32, "{}", progress.error);
+    assert!(progress.progress.iter().any(|msg| msg == "checkpoint-start"));
+    let img = shard_thread.join().unwrap()?;
+
+    // Restore
+    let shard_path = new_fifo(&images_dir, "restore-shard")?;
+    let id = client.start_restore(StartRestoreRequest {
+        shard_paths: vec![shard_path.clone()],
+        ..Default::default()
+    })?;
+    let shard_thread = thread::spawn(move || -> Result<()> {
+        fs::OpenOptions::new().write(true).open(shard_path)?.write_all(&img)?;
+        Ok(())
+    });
+
+    client.wait_for(id, has_socket_init)?;
+    let mut criu = Criu::connect(images_dir.join("streamer-serve.sock"))?;
+    assert_eq!(criu.read_img_file_into_vec("file.img")?, file_data);
+    criu.finish()?;
+
+    let progress = client.wait_for(id, is_done)?;
+    assert_eq!(progress.state, State::Succeeded as i32, "{}", progress.error);
+    shard_thread.join().unwrap()?;
+
+    Ok(())
+}
+
+#[test]
+fn abort_and_failure() -> Result<()> {
+    let images_dir = new_images_dir("abort-and-failure")?;
+    let socket_path = images_dir.join("grpc.sock");
+    let _server = Server::spawn(&images_dir, &socket_path)?;
+    let mut client = Client::connect(&socket_path)?;
+
+    // An operation waiting for the controller to open its shards can be aborted.
+    for (i, capture) in [true, false].iter().enumerate() {
+        let shard_path = new_fifo(&images_dir, &format!("unopened-shard-{}", i))?;
+        let id = if *capture {
+            client.start_capture(StartCaptureRequest { shard_paths: vec![shard_path], ..Default::default() })?
+        } else {
+            client.start_restore(StartRestoreRequest { shard_paths: vec![shard_path], ..Default::default() })?
+        };
+        client.abort(id)?;
+        let progress = client.wait_for(id, is_done)?;
+        assert_eq!(progress.state, State::Aborted as i32);
+    }
+
+    // An aborted capture closes its shards.
+    let shard_path = new_fifo(&images_dir, "capture-shard")?;
+    let id = client.start_capture(StartCaptureRequest {
+        shard_paths: vec![shard_path.clone()],
+        ..Default::default()
+    })?;
+    let mut shard = fs::File::open(shard_path)?;
+    client.wait_for(id, has_socket_init)?;
+    client.abort(id)?;
+    let progress = client.wait_for(id, is_done)?;
+    assert_eq!(progress.state, State::Aborted as i32);
+    shard.read_to_end(&mut Vec::new())?;
+
+    // A restore from an empty shard fails, and reports the error.
+    let shard_path = new_fifo(&images_dir, "restore-shard")?;
+    let id = client.start_restore(StartRestoreRequest {
+        shard_paths: vec![shard_path.clone()],
+        ..Default::default()
+    })?;
+    drop(fs::OpenOptions::new().write(true).open(shard_path)?);
+    let progress = client.wait_for(id, is_done)?;
+    assert_eq!(progress.state, State::Failed as i32);
+    assert!(!progress.error.is_empty());
+
+    // Unknown operations are reported as such.
+    assert!(client.get_progress(1000).is_err());
+
+    Ok(())
+}
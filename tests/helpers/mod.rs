@@ -12,5 +12,6 @@
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
 
-pub mod criu;
+// The helpers are shared by several test crates, each using a part of them.
+#[allow(dead_code, unused_imports)]
 pub mod util;
@@ -13,62 +13,18 @@
 //  limitations under the License.
 
 use std::{
-    os::unix::net::UnixStream,
-    io::{Read, BufReader, BufRead},
-    os::unix::io::{RawFd, AsRawFd},
-};
-use nix::{
-    sys::socket::{ControlMessage, MsgFlags, sendmsg},
-    sys::uio::IoVec,
-    unistd,
+    io::{Read, Write},
+    thread,
 };
 use criu_image_streamer::{
-    unix_pipe::{UnixPipe, UnixPipeImpl},
+    unix_pipe::UnixPipe,
     util::{KB, PAGE_SIZE},
 };
-use serde::Deserialize;
 use anyhow::Result;
 
-#[derive(Deserialize, Debug)]
-pub struct Stats {
-    pub shards: Vec<ShardStat>,
-}
-#[derive(Deserialize, Debug)]
-pub struct ShardStat {
-    pub size: u64,
-    pub transfer_duration_millis: u128,
-}
-
-pub fn new_pipe() -> (UnixPipe, UnixPipe) {
-    let (fd_r, fd_w) = unistd::pipe().expect("Failed to create UNIX pipe");
-    let pipe_r = UnixPipe::new(fd_r).unwrap();
-    let pipe_w = UnixPipe::new(fd_w).unwrap();
-    (pipe_r, pipe_w)
-}
-
-pub fn read_line<R: Read>(progress: &mut BufReader<R>) -> Result<String> {
-    let mut buf = String::new();
-    progress.read_line(&mut buf)?;
-
-    ensure!(buf.len() > 0, "EOF reached");
-    ensure!(buf.chars().last() == Some('\n'), "no trailing \\n found");
-    buf.pop(); // Removes the trailing '\n'
-
-    Ok(buf)
-}
-
-pub fn read_stats<R: Read>(progress: &mut BufReader<R>) -> Result<Stats> {
-    Ok(serde_json::from_str(&read_line(progress)?)?)
-}
-
-pub fn send_fd(socket: &mut UnixStream, fd: RawFd) -> Result<()> {
-    sendmsg(socket.as_raw_fd(),
-           &[IoVec::from_slice(&[0])],
-           &[ControlMessage::ScmRights(&[fd])],
-           MsgFlags::empty(),
-           None)?;
-    Ok(())
-}
+pub use criu_image_streamer::test_utils::{Stats, ShardStat, Progress, Phase, PhaseTransition, PhaseStats,
+                                          ServeStats, new_pipe, read_line, read_stats, read_progress_and_stats,
+                                          send_fd};
 
 pub fn get_rand_vec(size: usize) -> Vec<u8> {
     let urandom = std::fs::File::open("/dev/urandom").expect("Failed to open /dev/urandom");
@@ -109,3 +65,29 @@ pub fn read_to_end_rate_limited(src: &mut UnixPipe, dst: &mut Vec<u8>,
 
     Ok(total_size)
 }
+
+/// Returns the content of a shard
+pub type ShardThread = thread::JoinHandle<Result<Vec<u8>>>;
+
+/// Returns shard pipes that stream the provided contents.
+pub fn spawn_shard_writers(contents: Vec<Vec<u8>>) -> Vec<UnixPipe> {
+    contents.into_iter().map(|content| {
+        let (shard_r, mut shard_w) = new_pipe();
+        // The write fails when the reader gives up on the shard early, that's fine.
+        thread::spawn(move || { let _ = shard_w.write_all(&content); });
+        shard_r
+    }).collect()
+}
+
+/// Returns shard pipes, and the threads collecting what is written to them.
+pub fn spawn_shard_readers(num_shards: usize) -> (Vec<UnixPipe>, Vec<ShardThread>) {
+    (0..num_shards).map(|_| {
+        let (mut shard_r, shard_w) = new_pipe();
+        let shard_thread = thread::spawn(move || {
+            let mut buf = Vec::new();
+            shard_r.read_to_end(&mut buf)?;
+            Ok(buf)
+        });
+        (shard_w, shard_thread)
+    }).unzip()
+}
@@ -0,0 +1,168 @@
+//  Copyright 2020 Two Sigma Investments, LP.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+// Unless we are in release mode, allow dead code, unused imports and variables,
+// it makes development more enjoyable.
+#![cfg_attr(debug_assertions, allow(dead_code, unused_imports, unused_variables))]
+
+#[macro_use]
+extern crate anyhow;
+
+mod helpers;
+
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio, Child},
+    os::unix::process::CommandExt,
+    io::{Read, Write, BufReader},
+    thread,
+    fs,
+};
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::{Pid, geteuid},
+};
+use criu_image_streamer::{
+    CaptureBuilder,
+    ExtractBuilder,
+    criu_check::{criu_version, MIN_CRIU_VERSION},
+};
+use crate::helpers::util::*;
+use anyhow::{Result, Context};
+
+// These tests validate the streaming protocol against an actual CRIU, as opposed to the CRIU
+// simulator used in tests.rs. They need a CRIU supporting --stream and root privileges. When
+// these requirements are not met, the tests are skipped.
+
+fn should_skip() -> bool {
+    if !geteuid().is_root() {
+        eprintln!("Skipping real CRIU tests: root privileges are required");
+        return true;
+    }
+    if !matches!(criu_version(Path::new("criu")), Ok(version) if version >= MIN_CRIU_VERSION) {
+        eprintln!("Skipping real CRIU tests: CRIU >= {}.{} is not installed",
+                  MIN_CRIU_VERSION.0, MIN_CRIU_VERSION.1);
+        return true;
+    }
+    false
+}
+
+fn run_criu(images_dir: &Path, args: &[&str]) -> Result<()> {
+    let status = Command::new("criu")
+        .args(args)
+        .arg("--images-dir").arg(images_dir)
+        .arg("--stream")
+        .status()
+        .context("Failed to run criu")?;
+    ensure!(status.success(), "criu {} failed: {}", args[0], status);
+    Ok(())
+}
+
+/// Spawns a process that CRIU can checkpoint. It needs its own session, and no terminal.
+fn spawn_test_process() -> Result<Child> {
+    let mut cmd = Command::new("sleep");
+    cmd.arg("1000")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    unsafe {
+        cmd.pre_exec(|| {
+            libc::setsid();
+            Ok(())
+        });
+    }
+    Ok(cmd.spawn()?)
+}
+
+fn dump(images_dir: &Path, pid: u32) -> Result<Vec<u8>> {
+    let (progress_r, progress_w) = new_pipe();
+    let (shard_r, shard_w) = new_pipe();
+    let mut progress = BufReader::new(progress_r);
+
+    let capture_thread = {
+        let images_dir = images_dir.to_path_buf();
+        thread::spawn(move || {
+            CaptureBuilder::new(images_dir, vec![shard_w])
+                .progress_pipe(progress_w)
+                .run()
+                .expect("capture() failed");
+        })
+    };
+    let shard_thread = thread::spawn(move || {
+        let mut shard_r = shard_r;
+        let mut img = Vec::new();
+        shard_r.read_to_end(&mut img).expect("Failed to read the shard");
+        img
+    });
+
+    assert_eq!(read_line(&mut progress)?, "socket-init");
+    run_criu(images_dir, &["dump", "--tree", &pid.to_string()])?;
+    capture_thread.join().unwrap();
+    Ok(shard_thread.join().unwrap())
+}
+
+fn restore(images_dir: &Path, img: Vec<u8>) -> Result<Pid> {
+    let (progress_r, progress_w) = new_pipe();
+    let (shard_r, shard_w) = new_pipe();
+    let mut progress = BufReader::new(progress_r);
+
+    let serve_thread = {
+        let images_dir = images_dir.to_path_buf();
+        thread::spawn(move || {
+            ExtractBuilder::new(images_dir, vec![shard_r])
+                .progress_pipe(progress_w)
+                .serve()
+                .expect("serve() failed");
+        })
+    };
+    let shard_thread = thread::spawn(move || {
+        let mut shard_w = shard_w;
+        shard_w.write_all(&img).expect("Failed to write the shard");
+    });
+
+    assert_eq!(read_line(&mut progress)?, "socket-init");
+    let pidfile = images_dir.join("restored.pid");
+    let _ = fs::remove_file(&pidfile);
+    run_criu(images_dir, &["restore", "--restore-detached",
+                           "--pidfile", pidfile.to_str().unwrap()])?;
+    serve_thread.join().unwrap();
+    shard_thread.join().unwrap();
+
+    let pid = fs::read_to_string(&pidfile)?.trim().parse()?;
+    Ok(Pid::from_raw(pid))
+}
+
+#[test]
+fn dump_and_restore() -> Result<()> {
+    if should_skip() {
+        return Ok(());
+    }
+
+    let images_dir = PathBuf::from("/tmp/test-criu-image-streamer-real-criu");
+    fs::create_dir_all(&images_dir)?;
+
+    let mut child = spawn_test_process()?;
+    let pid = child.id();
+    let img = dump(&images_dir, pid)?;
+    // CRIU kills the process after dumping it. We reap it so its pid can be reused on restore.
+    child.wait()?;
+    assert!(!img.is_empty(), "The captured image is empty");
+
+    let restored_pid = restore(&images_dir, img)?;
+    assert_eq!(restored_pid.as_raw() as u32, pid, "The process was restored with a different pid");
+    kill(restored_pid, None).context("The restored process is not running")?;
+    kill(restored_pid, Signal::SIGKILL)?;
+
+    Ok(())
+}
@@ -15,9 +15,8 @@
 // Unless we are in release mode, allow dead code, unused imports and variables,
 // it makes development more enjoyable.
 #![cfg_attr(debug_assertions, allow(dead_code, unused_imports, unused_variables))]
-
-#[macro_use]
-extern crate anyhow;
+// We like writing sizes as 1*KB, 1*MB for readability.
+#![allow(clippy::identity_op)]
 
 mod helpers;
 
@@ -29,14 +28,24 @@ use std::{
 };
 use criu_image_streamer::{
     unix_pipe::{UnixPipe, UnixPipeImpl},
-    capture::capture,
-    extract::{extract, serve},
-    util::{KB, MB, PAGE_SIZE},
-};
-use crate::helpers::{
-    criu::Criu,
-    util::*,
+    CaptureBuilder,
+    ExtractBuilder,
+    extract::{filter, merge},
+    util::{KB, MB, PAGE_SIZE, DEFAULT_MAX_PB_SIZE},
+    manifest::{SigningKey, VerifyingKey},
+    audit::AuditLog,
+    criu_trace::CriuTrace,
+    marker_log::MarkerLog,
+    rate_limit::RateLimit,
+    events::{Event, EventCallback},
+    image_store::{DynImageStore, ImageFile, fs::Ownership},
+    criu_connection::{socket_name, IMG_STREAMER_CAPTURE_SOCKET_NAME, IMG_STREAMER_SERVE_SOCKET_NAME,
+                      IMG_STREAMER_CONTROL_SOCKET_NAME},
 };
+#[cfg(feature = "fault-injection")]
+use criu_image_streamer::fault_injection::{Faults, inject_faults};
+use criu_image_streamer::test_utils::criu::Criu;
+use crate::helpers::util::*;
 use anyhow::Result;
 
 // Each test belongs in its separate module. They all follow the same workflow, so we made the
@@ -68,8 +77,44 @@ trait TestImpl {
     fn images_dir(&self) -> PathBuf { PathBuf::from("/tmp/test-criu-image-streamer") }
     fn capture_ext_files(&mut self) -> Vec<(String, UnixPipe)> { Vec::new() }
     fn extract_ext_files(&mut self) -> Vec<(String, UnixPipe)> { Vec::new() }
+    fn ext_file_digests(&self) -> bool { false }
+    fn file_digests(&self) -> bool { false }
+    fn ordered_ext_files(&self) -> bool { false }
     fn serve_image(&mut self) -> bool { true }
+    fn extract_dry_run(&self) -> bool { false } // only used when serve_image() is false
+    fn extract_img_store(&mut self) -> Option<Box<dyn DynImageStore + Send>> { None } // same
+    fn extract_stream(&mut self) -> Option<Box<dyn Write + Send>> { None } // same
+    fn ghost_files_dir(&self) -> Option<PathBuf> { None } // same
+    fn ownership(&self) -> Ownership { Ownership::default() } // same
+    fn reflink_from(&self) -> Option<PathBuf> { None } // same
     fn has_checkpoint_started(&mut self) -> bool { true } // should be true if send_img_files() has sent a file.
+    fn sign_key(&self) -> Option<SigningKey> { None }
+    fn verify_key(&self) -> Option<VerifyingKey> { None }
+    fn capture_audit_log(&mut self) -> Option<AuditLog> { None }
+    fn extract_audit_log(&mut self) -> Option<AuditLog> { None }
+    fn shard_spill_size(&self) -> usize { 0 }
+    fn chunk_alignment(&self) -> usize { 0 }
+    fn max_marker_size(&self) -> usize { DEFAULT_MAX_PB_SIZE }
+    fn file_table(&self) -> bool { false }
+    fn expected_size(&self) -> Option<u64> { None }
+    fn capture_criu_trace(&mut self) -> Option<CriuTrace> { None }
+    fn serve_criu_trace(&mut self) -> Option<CriuTrace> { None }
+    fn capture_marker_log(&mut self) -> Option<MarkerLog> { None }
+    fn extract_marker_log(&mut self) -> Option<MarkerLog> { None }
+    fn containers(&self) -> Vec<String> { Vec::new() }
+    fn job_id(&self) -> Option<String> { None }
+    fn serve_phases(&self) -> bool { false }
+    fn serve_request_stats(&self) -> bool { false }
+    fn serve_standby(&self) -> bool { false }
+    fn serve_rolling(&self) -> bool { false }
+    fn capture_rate_limit(&self) -> RateLimit { RateLimit::default() }
+    fn capture_rate_control(&self) -> bool { false }
+    fn capture_on_event(&mut self) -> Option<EventCallback> { None }
+    fn extract_on_event(&mut self) -> Option<EventCallback> { None }
+    #[cfg(feature = "fault-injection")]
+    fn capture_faults(&self) -> Faults { Faults::default() }
+    #[cfg(feature = "fault-injection")]
+    fn extract_faults(&self) -> Faults { Faults::default() }
 
     fn shards(&mut self)-> Vec<(UnixPipe, UnixPipe)> {
         (0..self.num_shards())
@@ -90,24 +135,129 @@ trait TestImpl {
         let capture_thread = {
             let images_dir = self.images_dir();
             let ext_files = self.capture_ext_files();
+            let ext_file_digests = self.ext_file_digests();
+            let file_digests = self.file_digests();
+            let ordered_ext_files = self.ordered_ext_files();
+            let sign_key = self.sign_key();
+            let audit_log = self.capture_audit_log();
+            let shard_spill_size = self.shard_spill_size();
+            let chunk_alignment = self.chunk_alignment();
+            let max_marker_size = self.max_marker_size();
+            let file_table = self.file_table();
+            let expected_size = self.expected_size();
+            let criu_trace = self.capture_criu_trace();
+            let marker_log = self.capture_marker_log();
+            let containers = self.containers();
+            let job_id = self.job_id();
+            let rate_limit = self.capture_rate_limit();
+            let rate_control = self.capture_rate_control();
+            let on_event = self.capture_on_event();
+            #[cfg(feature = "fault-injection")]
+            let faults = self.capture_faults();
 
             thread::spawn(move || {
-                capture(&images_dir, capture_progress_w, shard_pipes_w, ext_files)
-                    .expect("capture() failed");
+                #[cfg(feature = "fault-injection")]
+                inject_faults(faults);
+                let mut capture = CaptureBuilder::new(images_dir, shard_pipes_w)
+                    .progress_pipe(capture_progress_w)
+                    .ext_files(ext_files)
+                    .ext_file_digests(ext_file_digests)
+                    .file_digests(file_digests)
+                    .ordered_ext_files(ordered_ext_files)
+                    .sign_key(sign_key)
+                    .audit_log(audit_log)
+                    .shard_spill_size(shard_spill_size)
+                    .chunk_alignment(chunk_alignment)
+                    .max_marker_size(max_marker_size)
+                    .file_table(file_table)
+                    .expected_size(expected_size)
+                    .criu_trace(criu_trace)
+                    .marker_log(marker_log)
+                    .containers(containers)
+                    .job_id(job_id)
+                    .rate_limit(rate_limit)
+                    .rate_control(rate_control);
+                if let Some(on_event) = on_event {
+                    capture = capture.on_event(on_event);
+                }
+                capture.run().expect("capture() failed");
             })
         };
 
         let extract_thread = {
             let images_dir = self.images_dir();
             let ext_files = self.extract_ext_files();
+            let ext_file_digests = self.ext_file_digests();
+            let file_digests = self.file_digests();
+            let ordered_ext_files = self.ordered_ext_files();
             let serve_image = self.serve_image();
+            let dry_run = self.extract_dry_run();
+            let img_store = self.extract_img_store();
+            let stream = self.extract_stream();
+            let ghost_files_dir = self.ghost_files_dir();
+            let ownership = self.ownership();
+            let reflink_from = self.reflink_from();
+            let verify_key = self.verify_key();
+            let audit_log = self.extract_audit_log();
+            let criu_trace = self.serve_criu_trace();
+            let marker_log = self.extract_marker_log();
+            let max_marker_size = self.max_marker_size();
+            let containers = self.containers();
+            let job_id = self.job_id();
+            let phases = self.serve_phases();
+            let request_stats = self.serve_request_stats();
+            let standby = self.serve_standby();
+            let rolling = self.serve_rolling();
+            let on_event = self.extract_on_event();
+            #[cfg(feature = "fault-injection")]
+            let faults = self.extract_faults();
 
             thread::spawn(move || {
+                #[cfg(feature = "fault-injection")]
+                inject_faults(faults);
+                let mut extract = ExtractBuilder::new(images_dir, shard_pipes_r)
+                    .progress_pipe(extract_progress_w)
+                    .ext_file_digests(ext_file_digests)
+                    .file_digests(file_digests)
+                    .ordered_ext_files(ordered_ext_files)
+                    .verify_key(verify_key)
+                    .marker_log(marker_log)
+                    .max_marker_size(max_marker_size);
+                if let Some(on_event) = on_event {
+                    extract = extract.on_event(on_event);
+                }
                 if serve_image {
-                    serve(&images_dir, extract_progress_w, shard_pipes_r, ext_files, vec![])
+                    extract.ext_files(ext_files)
+                        .audit_log(audit_log)
+                        .criu_trace(criu_trace)
+                        .containers(containers)
+                        .job_id(job_id)
+                        .phases(phases)
+                        .request_stats(request_stats)
+                        .standby(standby)
+                        .rolling(rolling)
+                        .serve()
                         .expect("serve() failed");
+                } else if dry_run {
+                    extract.extract_dry_run()
+                        .expect("extract_dry_run() failed");
+                } else if let Some(img_store) = img_store {
+                    extract.ext_files(ext_files)
+                        .audit_log(audit_log)
+                        .extract_into(img_store)
+                        .expect("extract_into() failed");
+                } else if let Some(stream) = stream {
+                    extract.ext_files(ext_files)
+                        .audit_log(audit_log)
+                        .extract_to_stream(stream)
+                        .expect("extract_to_stream() failed");
                 } else {
-                    extract(&images_dir, extract_progress_w, shard_pipes_r, ext_files)
+                    extract.ext_files(ext_files)
+                        .audit_log(audit_log)
+                        .ghost_files_dir(ghost_files_dir)
+                        .ownership(ownership)
+                        .reflink_from(reflink_from)
+                        .extract()
                         .expect("extract() failed");
                 }
             })
@@ -130,7 +280,8 @@ trait TestImpl {
     {
         // Wait for CRIU socket for checkpointing to be ready
         assert_eq!(read_line(&mut checkpoint.progress)?, "socket-init");
-        let criu = Criu::connect(self.images_dir().join("streamer-capture.sock"))?;
+        let criu = Criu::connect(self.images_dir().join(
+            socket_name(IMG_STREAMER_CAPTURE_SOCKET_NAME, self.job_id().as_deref())))?;
         Ok(CheckpointContext { streamer: checkpoint, criu })
     }
 
@@ -157,7 +308,7 @@ trait TestImpl {
     }
 
     fn finish_image_extraction(&mut self, restore: &mut StreamerRestoreContext) -> Result<Stats> {
-        Ok(read_stats(&mut restore.progress)?)
+        read_stats(&mut restore.progress)
     }
 
     fn after_finish_image_extraction(&mut self, _restore_stats: &Stats) -> Result<()> {
@@ -169,7 +320,8 @@ trait TestImpl {
     {
         // The image can be served now. Wait for the CRIU socket to be ready.
         assert_eq!(read_line(&mut restore.progress)?, "socket-init");
-        let criu = Criu::connect(self.images_dir().join("streamer-serve.sock"))?;
+        let criu = Criu::connect(self.images_dir().join(
+            socket_name(IMG_STREAMER_SERVE_SOCKET_NAME, self.job_id().as_deref())))?;
         Ok(RestoreContext { streamer: restore, criu })
     }
 
@@ -333,6 +485,260 @@ mod ext_files {
     }
 }
 
+mod ext_file_stats {
+    use super::*;
+    use criu_image_streamer::{manifest::FileHasher, util::ExtFileStat};
+
+    const TEST_DATA: &str = "ext file data";
+
+    struct Test {
+        with_digests: bool,
+        send_ext_pipe: Option<UnixPipe>,
+        recv_ext_pipe: Option<UnixPipe>,
+    }
+
+    impl Test {
+        fn new(with_digests: bool) -> Self {
+            Self { with_digests, send_ext_pipe: None, recv_ext_pipe: None }
+        }
+
+        fn check_stats(&self, stats: &Stats) {
+            let sha256 = if self.with_digests {
+                let mut hasher = FileHasher::default();
+                hasher.update(TEST_DATA.as_bytes());
+                Some(hasher.finalize().sha256)
+            } else {
+                None
+            };
+            assert_eq!(stats.ext_files, vec![ExtFileStat {
+                filename: "file.ext".to_string(),
+                size: TEST_DATA.len() as u64,
+                sha256,
+            }]);
+        }
+    }
+
+    impl TestImpl for Test {
+        fn has_checkpoint_started(&mut self) -> bool { false }
+        fn ext_file_digests(&self) -> bool { self.with_digests }
+
+        fn capture_ext_files(&mut self) -> Vec<(String, UnixPipe)> {
+            let (pipe_r, pipe_w) = new_pipe();
+            self.send_ext_pipe = Some(pipe_w);
+            vec![("file.ext".to_string(), pipe_r)]
+        }
+
+        fn extract_ext_files(&mut self) -> Vec<(String, UnixPipe)> {
+            let (pipe_r, pipe_w) = new_pipe();
+            self.recv_ext_pipe = Some(pipe_r);
+            vec![("file.ext".to_string(), pipe_w)]
+        }
+
+        fn send_img_files(&mut self, _: &mut CheckpointContext) -> Result<()> {
+            self.send_ext_pipe.take().unwrap().write_all(TEST_DATA.as_bytes())?;
+            Ok(())
+        }
+
+        fn after_finish_checkpoint(&mut self, stats: &Stats) -> Result<()> {
+            self.check_stats(stats);
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, stats: &Stats) -> Result<()> {
+            self.check_stats(stats);
+            Ok(())
+        }
+
+        fn recv_img_files(&mut self, _: &mut RestoreContext) -> Result<()> {
+            let mut buf = Vec::new();
+            self.recv_ext_pipe.take().unwrap().read_to_end(&mut buf)?;
+            assert_eq!(buf, TEST_DATA.as_bytes());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_sizes() -> Result<()> {
+        Test::new(false).run()
+    }
+
+    #[test]
+    fn test_digests() -> Result<()> {
+        Test::new(true).run()
+    }
+}
+
+mod file_digests {
+    use super::*;
+    use std::fs;
+    use criu_image_streamer::manifest::{FileHasher, ManifestFile, MANIFEST_FILENAME};
+
+    const TEST_FILES: &[(&str, &str)] = &[("pages-1.img", "page data"), ("core-1.img", "core data")];
+
+    struct Test {
+        serve_image: bool,
+    }
+
+    impl Test {
+        fn new(serve_image: bool) -> Self {
+            Self { serve_image }
+        }
+
+        fn check_stats(&self, stats: &Stats) {
+            let mut files: Vec<ManifestFile> = TEST_FILES.iter().map(|(filename, data)| {
+                let mut hasher = FileHasher::default();
+                hasher.update(data.as_bytes());
+                ManifestFile { filename: filename.to_string(), digest: hasher.finalize() }
+            }).collect();
+            files.sort_by(|a, b| a.filename.cmp(&b.filename));
+            assert_eq!(stats.files, files);
+        }
+    }
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf {
+            let kind = if self.serve_image { "serve" } else { "extract" };
+            PathBuf::from(format!("/tmp/test-criu-image-streamer-file-digests-{}", kind))
+        }
+        fn file_digests(&self) -> bool { true }
+        fn serve_image(&mut self) -> bool { self.serve_image }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            for (filename, data) in TEST_FILES {
+                checkpoint.criu.write_img_file(filename)?
+                    .write_all(data.as_bytes())?;
+            }
+            Ok(())
+        }
+
+        fn after_finish_checkpoint(&mut self, stats: &Stats) -> Result<()> {
+            self.check_stats(stats);
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, stats: &Stats) -> Result<()> {
+            self.check_stats(stats);
+            if !self.serve_image {
+                // The image is not signed, and carries an unsigned manifest.
+                assert!(self.images_dir().join(MANIFEST_FILENAME).exists());
+            }
+            Ok(())
+        }
+
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            for (filename, data) in TEST_FILES {
+                let buf = restore.criu.read_img_file_into_vec(filename)?;
+                assert_eq!(buf, data.as_bytes());
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_serve() -> Result<()> {
+        Test::new(true).run()
+    }
+
+    #[test]
+    fn test_extract() -> Result<()> {
+        let mut test = Test::new(false);
+        let _ = fs::remove_dir_all(test.images_dir());
+        test.run()
+    }
+}
+
+mod ordered_ext_files {
+    use super::*;
+    use std::time::Duration;
+
+    const LOWER_DATA: &str = "lower layer data";
+    const UPPER_DATA: &str = "upper layer data";
+
+    struct Test {
+        send_lower_pipe: Option<UnixPipe>,
+        send_upper_pipe: Option<UnixPipe>,
+
+        recv_lower_pipe: Option<UnixPipe>,
+        recv_upper_pipe: Option<UnixPipe>,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            Self {
+                send_lower_pipe: None, send_upper_pipe: None,
+                recv_lower_pipe: None, recv_upper_pipe: None,
+            }
+        }
+
+        fn check_order(stats: &Stats) {
+            let filenames: Vec<_> = stats.ext_files.iter().map(|f| f.filename.as_str()).collect();
+            assert_eq!(filenames, ["lower.tar", "upper.tar"]);
+        }
+    }
+
+    impl TestImpl for Test {
+        fn has_checkpoint_started(&mut self) -> bool { false }
+        fn ordered_ext_files(&self) -> bool { true }
+
+        fn capture_ext_files(&mut self) -> Vec<(String, UnixPipe)> {
+            let (lower_r, lower_w) = new_pipe();
+            let (upper_r, upper_w) = new_pipe();
+
+            self.send_lower_pipe = Some(lower_w);
+            self.send_upper_pipe = Some(upper_w);
+
+            vec![("lower.tar".to_string(), lower_r),
+                 ("upper.tar".to_string(), upper_r)]
+        }
+
+        fn extract_ext_files(&mut self) -> Vec<(String, UnixPipe)> {
+            let (lower_r, lower_w) = new_pipe();
+            let (upper_r, upper_w) = new_pipe();
+
+            self.recv_lower_pipe = Some(lower_r);
+            self.recv_upper_pipe = Some(upper_r);
+
+            vec![("lower.tar".to_string(), lower_w),
+                 ("upper.tar".to_string(), upper_w)]
+        }
+
+        fn send_img_files(&mut self, _: &mut CheckpointContext) -> Result<()> {
+            // The upper layer is complete first, but must come second in the image.
+            self.send_upper_pipe.take().unwrap().write_all(UPPER_DATA.as_bytes())?;
+            thread::sleep(Duration::from_millis(100));
+            self.send_lower_pipe.take().unwrap().write_all(LOWER_DATA.as_bytes())?;
+            Ok(())
+        }
+
+        fn after_finish_checkpoint(&mut self, stats: &Stats) -> Result<()> {
+            Self::check_order(stats);
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, stats: &Stats) -> Result<()> {
+            Self::check_order(stats);
+            Ok(())
+        }
+
+        fn recv_img_files(&mut self, _: &mut RestoreContext) -> Result<()> {
+            let mut buf = Vec::new();
+            self.recv_lower_pipe.take().unwrap().read_to_end(&mut buf)?;
+            assert_eq!(buf, LOWER_DATA.as_bytes());
+
+            let mut buf = Vec::new();
+            self.recv_upper_pipe.take().unwrap().read_to_end(&mut buf)?;
+            assert_eq!(buf, UPPER_DATA.as_bytes());
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
 mod load_balancing {
     use super::*;
 
@@ -420,8 +826,7 @@ mod load_balancing {
             }
 
             self.shard_threads.take().unwrap()
-                .drain(..).map(|t| t.join().unwrap())
-                .collect::<Result<_>>()?;
+                .drain(..).try_for_each(|t| t.join().unwrap())?;
 
             eprintln!("Shard sizes: {:?} KB", checkpoint_stats.shards.iter()
                       .map(|s| s.size/KB as u64).collect::<Vec<_>>());
@@ -459,87 +864,90 @@ mod load_balancing {
     }
 }
 
-mod restore_mem_usage {
+mod shard_saturation {
     use super::*;
+    use std::time::Duration;
 
-    // We test one large file, and many small ones, simulating a fair CRIU workload.
-    // There are two things to test:
-    // 1) The in-memory store should have low overhead. 200 bytes per file is not exactly good, but
-    //    it's good enough (measured at 129 bytes). I welcome suggestion to make this better.
-    // 2) The in-memory store should free its memory as its transferring a file to CRIU
-    //    It shouldn't be bigger than image_store::mem::MAX_LARGE_CHUNK_SIZE (10MB). We add a bit
-    //    for slack.
+    // A single shard is uploaded at a slow rate, which holds back the capture most of the time.
+    // The capture reports the shards as saturated, and suggests more shards.
 
-    const BIG_FILE_SIZE: usize = 105*MB;
-    const SMALL_FILE_SIZE: usize = 10;
-    const NUM_SMALL_FILES: usize = 100_000;
-    const TOLERABLE_PER_FILE_OVERHEAD: isize = 200 as isize;
-    const TOLERABLE_CRIU_RECEIVE_OVERHEAD: isize = 12*MB as isize;
+    // About 10MB/sec
+    const READ_SIZE: usize = 64*KB;
+    const READ_INTERVAL: Duration = Duration::from_millis(6);
+    const FILE_SIZE: usize = 12*MB; // Larger than what the CRIU and shard pipes can hold
 
     struct Test {
-        start_mem_size: Option<usize>
+        file: Vec<u8>,
+        shard_thread: Option<thread::JoinHandle<Result<()>>>,
     }
 
     impl Test {
         fn new() -> Self {
-            Self { start_mem_size: None }
+            Self { file: get_rand_vec(FILE_SIZE), shard_thread: None }
         }
     }
 
     impl TestImpl for Test {
-        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
-            self.start_mem_size = Some(get_resident_mem_size());
+        fn num_shards(&self) -> usize { 1 }
+        fn images_dir(&self) -> PathBuf { PathBuf::from("/tmp/test-criu-image-streamer-shard-saturation") }
 
-            let small = get_filled_vec(SMALL_FILE_SIZE, 1);
-
-            for i in 0..NUM_SMALL_FILES {
-                let filename = format!("small-{}.img", i);
-                checkpoint.criu.write_img_file(&filename)?.write_all(&small)?;
-            }
-
-            // Writing the big file in small chunks, to prevent blowing up memory with a large
-            // vector that may not get freed.
-            let mut big_file_pipe = checkpoint.criu.write_img_file("big.img")?;
-            let buf = get_filled_vec(1*KB, 1);
-            for _ in 0..(BIG_FILE_SIZE/buf.len()) {
-                big_file_pipe.write_all(&buf)?;
-            }
+        fn shards(&mut self)-> Vec<(UnixPipe, UnixPipe)> {
+            let (mut capture_shard_r, capture_shard_w) = new_pipe();
+            let (extract_shard_r, mut extract_shard_w) = new_pipe();
+            self.shard_thread = Some(thread::spawn(move || {
+                // Unlike read_to_end_rate_limited(), the time waiting for the capture to start
+                // doesn't count, so the upload is slow throughout the capture.
+                let mut buf = Vec::new();
+                while (&mut capture_shard_r).take(READ_SIZE as u64).read_to_end(&mut buf)? > 0 {
+                    thread::sleep(READ_INTERVAL);
+                }
+                extract_shard_w.write_all(&buf)?;
+                Ok(())
+            }));
+            vec![(extract_shard_r, capture_shard_w)]
+        }
 
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("file.img")?
+                .write_all(&self.file)?;
             Ok(())
         }
 
-        fn after_finish_image_extraction(&mut self, _restore_stats: &Stats) -> Result<()> {
-            let extraction_use = get_resident_mem_size() as isize - self.start_mem_size.unwrap() as isize;
-            let overhead = extraction_use  as isize - (BIG_FILE_SIZE + NUM_SMALL_FILES * SMALL_FILE_SIZE) as isize;
-            let overhead_per_file = overhead / (1 + NUM_SMALL_FILES) as isize;
+        fn after_finish_checkpoint(&mut self, stats: &Stats) -> Result<()> {
+            self.shard_thread.take().unwrap().join().unwrap()?;
+            let shard_saturation = stats.shard_saturation.as_ref().expect("Missing shard saturation");
+            eprintln!("Shard saturation: {:?}", shard_saturation);
+            assert!(shard_saturation.saturation > 0.5, "{:?}", shard_saturation);
+            assert!(shard_saturation.suggested_num_shards > 1, "{:?}", shard_saturation);
+
+            // The time blocked on the shard is broken down in its stats.
+            let shard = &stats.shards[0];
+            let blocked_millis = shard.blocked_millis.expect("Missing blocked time");
+            assert!(blocked_millis > shard.transfer_duration_millis / 2, "{:?}", shard);
+            let marker_bytes = shard.marker_bytes.expect("Missing marker bytes");
+            assert_eq!(marker_bytes, shard.size - FILE_SIZE as u64, "{:?}", shard);
+
+            assert!(shard.pipe_capacity.expect("Missing pipe capacity") > 0, "{:?}", shard);
+
+            // The shard pipe filled up.
+            let max_buffered_bytes = stats.max_buffered_bytes.expect("Missing max buffered bytes");
+            assert!(max_buffered_bytes >= 64*KB as u64, "{}", max_buffered_bytes);
+            Ok(())
+        }
 
-            assert!(overhead_per_file < TOLERABLE_PER_FILE_OVERHEAD,
-                    "In-memory image store shows too much memory overhead per file: {} bytes", overhead_per_file);
-            eprintln!("Per file overhead: {} bytes", overhead_per_file);
+        fn after_finish_image_extraction(&mut self, stats: &Stats) -> Result<()> {
+            assert!(stats.shard_saturation.is_none(), "Only the capture reports the shard saturation");
+            assert!(stats.shards[0].blocked_millis.is_none(), "Only the capture reports the shard timings");
+            assert!(stats.shards[0].pipe_capacity.is_some(), "{:?}", stats.shards[0]);
+            // With a single shard, chunks are processed as they are read, one at a time.
+            let max_buffered_bytes = stats.max_buffered_bytes.expect("Missing max buffered bytes");
+            assert!(max_buffered_bytes > 0 && max_buffered_bytes <= FILE_SIZE as u64, "{}", max_buffered_bytes);
             Ok(())
         }
 
         fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
-            let start_recv_mem_usage = get_resident_mem_size();
-
-            let mut big_file = Vec::with_capacity(BIG_FILE_SIZE);
-            let big_file_pipe = &restore.criu.read_img_file("big.img")?;
-            let mut max_overhead = 0;
-            loop {
-                let count = big_file_pipe.take(10*KB as u64).read_to_end(&mut big_file)?;
-
-                let delta_recv_mem_usage = get_resident_mem_size() as isize - start_recv_mem_usage as isize;
-                max_overhead = max(max_overhead, delta_recv_mem_usage);
-
-                if count == 0 {
-                    break; // EOF
-                }
-            }
-
-            assert!(max_overhead < TOLERABLE_CRIU_RECEIVE_OVERHEAD,
-                "Memory budget exceeded while CRIU reads a large file: {} MB", max_overhead/MB as isize);
-            eprintln!("Large file overhead: {} MB", max_overhead/MB as isize);
-
+            let buf = restore.criu.read_img_file_into_vec("file.img")?;
+            assert!(buf == self.file);
             Ok(())
         }
     }
@@ -550,108 +958,3359 @@ mod restore_mem_usage {
     }
 }
 
-mod stress {
+mod stalled_shards {
     use super::*;
-    use crossbeam_utils::thread;
-    use std::sync::Mutex;
+    use std::{sync::mpsc, time::Duration};
 
-    const NUM_THREADS: usize = 5;
-    const NUM_SMALL_FILES: usize = 10000;
-    const NUM_MEDIUM_FILES: usize = 1000;
-    const NUM_MEDIUM_CHUNKED_FILES: usize = 1000;
-    const NUM_LARGE_FILES: usize = 2;
+    // This test simulates uploaders that stall during the checkpoint. With a spill buffer, the
+    // capture keeps draining CRIU's pipes into memory instead of blocking on the shards, and CRIU
+    // can finish its dump. The shards are released only once CRIU is done writing, or after a
+    // timeout, in which case the test fails.
 
-    const SMALL_FILE_SIZE: usize = 10;
-    const MEDIUM_FILE_SIZE: usize = 10*KB;
-    const LARGE_FILE_SIZE: usize = 25*MB; // 25MB to have a few mmap buffers
-    const CHUNK_SIZE: usize = 10;
+    const FILE_SIZE: usize = 16*MB; // Larger than what the CRIU and shard pipes can hold
+    const SHARD_SPILL_SIZE: usize = 32*MB;
+    const STALL_TIMEOUT: Duration = Duration::from_secs(10);
 
     struct Test {
-        small_file: Vec<u8>,
-        medium_file: Vec<u8>,
-        large_file: Vec<u8>,
+        file: Vec<u8>,
+        release_shards: Vec<mpsc::Sender<()>>,
+        shard_threads: Vec<thread::JoinHandle<Result<bool>>>,
     }
 
     impl Test {
         fn new() -> Self {
-            let small_file  = get_rand_vec(SMALL_FILE_SIZE);
-            let medium_file = get_rand_vec(MEDIUM_FILE_SIZE);
-            let large_file  = get_rand_vec(LARGE_FILE_SIZE);
-
-            Self { small_file, medium_file, large_file }
+            Self {
+                file: get_rand_vec(FILE_SIZE),
+                release_shards: Vec::new(),
+                shard_threads: Vec::new(),
+            }
         }
     }
 
     impl TestImpl for Test {
-        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
-            let checkpoint = Mutex::new(checkpoint);
+        fn num_shards(&self) -> usize { 2 }
+        fn shard_spill_size(&self) -> usize { SHARD_SPILL_SIZE }
 
-            let write_img_file = |name: &str, data: &Vec<u8>| -> Result<()> {
-                let mut pipe = checkpoint.lock().unwrap().criu.write_img_file(name)?;
-                pipe.write_all(data)?;
-                Ok(())
-            };
+        fn shards(&mut self)-> Vec<(UnixPipe, UnixPipe)> {
+            (0..self.num_shards()).map(|_| {
+                let (mut capture_shard_r, capture_shard_w) = new_pipe();
+                let (extract_shard_r, mut extract_shard_w) = new_pipe();
+                let (release_tx, release_rx) = mpsc::channel();
 
-            let write_img_file_chunked = |name: &str, data: &Vec<u8>| -> Result<()> {
-                let mut pipe = checkpoint.lock().unwrap().criu.write_img_file(name)?;
+                let shard_thread = thread::spawn(move || {
+                    let released = release_rx.recv_timeout(STALL_TIMEOUT).is_ok();
+                    let mut buf = Vec::new();
+                    capture_shard_r.read_to_end(&mut buf)?;
+                    extract_shard_w.write_all(&buf)?;
+                    Ok(released)
+                });
 
-                let mut offset = 0;
-                while offset < data.len() {
-                    let to_write = min(data.len() - offset, CHUNK_SIZE);
-                    pipe.write_all(&data[offset..offset+to_write])?;
-                    offset += to_write;
+                self.release_shards.push(release_tx);
+                self.shard_threads.push(shard_thread);
+                (extract_shard_r, capture_shard_w)
+            }).collect()
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("file.img")?
+                .write_all(&self.file)?;
+
+            for release_shard in self.release_shards.drain(..) {
+                let _ = release_shard.send(());
+            }
+
+            Ok(())
+        }
+
+        fn after_finish_checkpoint(&mut self, _checkpoint_stats: &Stats) -> Result<()> {
+            for shard_thread in self.shard_threads.drain(..) {
+                assert!(shard_thread.join().unwrap()?, "CRIU was blocked by the stalled shards");
+            }
+            Ok(())
+        }
+
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            let buf = restore.criu.read_img_file_into_vec("file.img")?;
+            assert!(buf == self.file);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod expected_size {
+    use super::*;
+
+    // The previous checkpoint was twice as large. Progress is reported as the image file streams
+    // through, and stops at 50%.
+
+    const FILE_SIZE: usize = 8*MB;
+    const EXPECTED_SIZE: u64 = 2*FILE_SIZE as u64;
+
+    struct Test {
+        file: Vec<u8>,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            Self { file: get_rand_vec(FILE_SIZE) }
+        }
+    }
+
+    impl TestImpl for Test {
+        fn expected_size(&self) -> Option<u64> { Some(EXPECTED_SIZE) }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("file.img")?
+                .write_all(&self.file)?;
+            Ok(())
+        }
+
+        fn finish_checkpoint(&mut self, mut checkpoint: CheckpointContext) -> Result<Stats> {
+            checkpoint.criu.finish()?;
+            let (reports, stats) = read_progress_and_stats(&mut checkpoint.streamer.progress)?;
+            checkpoint.streamer.capture_thread.join().unwrap();
+
+            assert!(reports.len() > 2, "Not enough progress reports");
+            assert!(reports.windows(2).all(|r| r[0].percent < r[1].percent && r[0].size <= r[1].size));
+            assert!(reports.iter().all(|r| r.expected_size == EXPECTED_SIZE));
+
+            let last = reports.last().unwrap();
+            assert_eq!(last.size, FILE_SIZE as u64);
+            assert_eq!(last.percent, 50);
+            assert!(last.eta_millis.is_some());
+
+            Ok(stats)
+        }
+
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            let buf = restore.criu.read_img_file_into_vec("file.img")?;
+            assert!(buf == self.file);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod page_stats {
+    use super::*;
+    use criu_image_streamer::{
+        criu::{PagemapHead, PagemapEntry},
+        image_patcher::write_criu_img_header,
+        util::pb_write,
+    };
+
+    // We simulate a pre-dump iteration where some pages are in the parent image. Pages in the
+    // parent are either flagged with PE_PARENT, or with in_parent on older CRIU versions.
+
+    const PAGEMAP_MAGIC: u32 = 0x56084025;
+    const PE_PARENT: u32 = 1 << 0;
+    const PE_PRESENT: u32 = 1 << 2;
+
+    fn pagemap(entries: &[(u32, Option<bool>, Option<u32>)]) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        write_criu_img_header(&mut buf, PAGEMAP_MAGIC)?;
+        pb_write(&mut buf, &PagemapHead { pages_id: 1 })?;
+        for (i, &(nr_pages, in_parent, flags)) in entries.iter().enumerate() {
+            let vaddr = (i * 1024 * *PAGE_SIZE) as u64;
+            pb_write(&mut buf, &PagemapEntry { vaddr, nr_pages, in_parent, flags })?;
+        }
+        Ok(buf)
+    }
+
+    struct Test {
+        files: Vec<(&'static str, Vec<u8>)>,
+    }
+
+    impl Test {
+        fn new() -> Result<Self> {
+            Ok(Self {
+                files: vec![
+                    ("pagemap-1.img", pagemap(&[(10, None, Some(PE_PRESENT)),
+                                                (30, None, Some(PE_PARENT))])?),
+                    ("pagemap-2.img", pagemap(&[(10, Some(true), None),
+                                                (10, Some(false), None)])?),
+                    ("pages-1.img", get_rand_vec(10 * *PAGE_SIZE)),
+                ],
+            })
+        }
+    }
+
+    impl TestImpl for Test {
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            for (filename, data) in &self.files {
+                checkpoint.criu.write_img_file(filename)?.write_all(data)?;
+            }
+            Ok(())
+        }
+
+        fn after_finish_checkpoint(&mut self, checkpoint_stats: &Stats) -> Result<()> {
+            let pages = checkpoint_stats.pages.as_ref().expect("Missing page stats");
+            assert_eq!(pages.total_pages, 60);
+            assert_eq!(pages.dirty_pages, 20);
+            assert!((pages.dirty_fraction - 1.0/3.0).abs() < 1e-9);
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, restore_stats: &Stats) -> Result<()> {
+            assert!(restore_stats.pages.is_none());
+            Ok(())
+        }
+
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            for (filename, data) in &self.files {
+                let buf = restore.criu.read_img_file_into_vec(filename)?;
+                assert!(&buf == data, "File data content mismatch");
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new()?.run()
+    }
+}
+
+mod criu_trace {
+    use super::*;
+    use criu_image_streamer::{
+        criu_trace::{TraceEvent, TraceOperation},
+        replay::replay,
+    };
+
+    // We record the CRIU traffic of a checkpoint and a restore. Then, we replay both traces
+    // against a new capture and serve. The serve replay fails if the streamer replies differ
+    // from the recorded ones.
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-trace";
+    const FILE_SIZE: usize = 1*MB;
+
+    struct Test {
+        file: Vec<u8>,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            std::fs::create_dir_all(IMAGES_DIR).unwrap();
+            Self { file: get_rand_vec(FILE_SIZE) }
+        }
+
+        fn trace_path(&self, name: &str) -> PathBuf {
+            self.images_dir().join(name)
+        }
+
+        fn check_traces(&self) -> Result<()> {
+            let events = CriuTrace::load(&self.trace_path("capture.trace"))?;
+            assert_eq!(events[0], TraceEvent::Connect { operation: TraceOperation::Capture });
+            let requests = events.iter().filter(|e| matches!(e, TraceEvent::Request { .. })).count();
+            let pipes = events.iter().filter(|e| **e == TraceEvent::RecvPipe).count();
+            assert_eq!((requests, pipes), (2, 2));
+            assert!(events.contains(&TraceEvent::Disconnect));
+            assert!(events.contains(&TraceEvent::FileEof { filename: "a.img".to_string(), size: 5 }));
+            assert!(events.contains(&TraceEvent::FileEof { filename: "b.img".to_string(),
+                                                           size: FILE_SIZE as u64 }));
+
+            let request = |filename: &str| TraceEvent::Request { filename: filename.to_string() };
+            assert_eq!(CriuTrace::load(&self.trace_path("serve.trace"))?, vec![
+                TraceEvent::Connect { operation: TraceOperation::Serve },
+                request("a.img"), TraceEvent::Reply { exists: true }, TraceEvent::RecvPipe,
+                request("b.img"), TraceEvent::Reply { exists: true }, TraceEvent::RecvPipe,
+                request("missing.img"), TraceEvent::Reply { exists: false },
+                TraceEvent::Disconnect,
+            ]);
+            Ok(())
+        }
+
+        fn replay_traces(&self) -> Result<()> {
+            let images_dir = self.images_dir();
+            let (shard_r, shard_w) = new_pipe();
+            let (capture_progress_r, capture_progress_w) = new_pipe();
+            let (serve_progress_r, serve_progress_w) = new_pipe();
+            let (replay_progress_r, replay_progress_w) = new_pipe();
+            let mut capture_progress = BufReader::new(capture_progress_r);
+            let mut serve_progress = BufReader::new(serve_progress_r);
+            let mut replay_progress = BufReader::new(replay_progress_r);
+
+            let capture_thread = {
+                let images_dir = images_dir.clone();
+                thread::spawn(move || {
+                    CaptureBuilder::new(images_dir, vec![shard_w])
+                        .progress_pipe(capture_progress_w)
+                        .run()
+                        .expect("capture() failed");
+                })
+            };
+            let serve_thread = {
+                let images_dir = images_dir.clone();
+                thread::spawn(move || {
+                    ExtractBuilder::new(images_dir, vec![shard_r])
+                        .progress_pipe(serve_progress_w)
+                        .serve()
+                        .expect("serve() failed");
+                })
+            };
+
+            assert_eq!(read_line(&mut capture_progress)?, "socket-init");
+            replay(&images_dir, replay_progress_w.try_clone()?, &self.trace_path("capture.trace"), None)?;
+            assert_eq!(read_line(&mut replay_progress)?, format!(r#"{{"files":2,"bytes":{}}}"#, FILE_SIZE+5));
+            capture_thread.join().unwrap();
+
+            read_stats(&mut serve_progress)?;
+            assert_eq!(read_line(&mut serve_progress)?, "socket-init");
+            replay(&images_dir, replay_progress_w, &self.trace_path("serve.trace"), None)?;
+            assert_eq!(read_line(&mut replay_progress)?, format!(r#"{{"files":2,"bytes":{}}}"#, FILE_SIZE+5));
+            serve_thread.join().unwrap();
+
+            Ok(())
+        }
+    }
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from(IMAGES_DIR) }
+
+        fn capture_criu_trace(&mut self) -> Option<CriuTrace> {
+            Some(CriuTrace::create(&self.trace_path("capture.trace")).unwrap())
+        }
+
+        fn serve_criu_trace(&mut self) -> Option<CriuTrace> {
+            Some(CriuTrace::create(&self.trace_path("serve.trace")).unwrap())
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("a.img")?.write_all(b"hello")?;
+            checkpoint.criu.write_img_file("b.img")?.write_all(&self.file)?;
+            Ok(())
+        }
+
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            assert_eq!(restore.criu.read_img_file_into_vec("a.img")?, b"hello");
+            assert!(restore.criu.read_img_file_into_vec("b.img")? == self.file);
+            assert!(restore.criu.maybe_read_img_file("missing.img")?.is_none());
+            Ok(())
+        }
+
+        fn finish_restore(&mut self, restore: RestoreContext) -> Result<()> {
+            restore.criu.finish()?;
+            restore.streamer.extract_thread.join().unwrap();
+            self.check_traces()?;
+            self.replay_traces()
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod debug_markers {
+    use super::*;
+    use std::fs;
+
+    // The capture logs the markers as it writes them, and the extraction as it processes them,
+    // in sequence order. Both logs must agree on every marker.
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-debug-markers";
+    const FILE_SIZE: usize = 1*MB;
+
+    struct Test {
+        file: Vec<u8>,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            fs::create_dir_all(IMAGES_DIR).unwrap();
+            Self { file: get_rand_vec(FILE_SIZE) }
+        }
+
+        fn log_path(&self, name: &str) -> PathBuf {
+            self.images_dir().join(name)
+        }
+
+        fn read_log(&self, name: &str) -> Result<(Vec<String>, Vec<String>)> {
+            let log = fs::read_to_string(self.log_path(name))?;
+            let (mut headers, markers): (Vec<String>, Vec<String>) = log.lines()
+                .map(String::from)
+                .partition(|line| line.contains("type=shard_header"));
+            headers.sort();
+            Ok((headers, markers))
+        }
+    }
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from(IMAGES_DIR) }
+
+        fn capture_marker_log(&mut self) -> Option<MarkerLog> {
+            Some(MarkerLog::create(&self.log_path("capture.log")).unwrap())
+        }
+
+        fn extract_marker_log(&mut self) -> Option<MarkerLog> {
+            Some(MarkerLog::create(&self.log_path("extract.log")).unwrap())
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("a.img")?.write_all(b"hello")?;
+            checkpoint.criu.write_img_file("b.img")?.write_all(&self.file)?;
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, _restore_stats: &Stats) -> Result<()> {
+            let (capture_headers, capture_markers) = self.read_log("capture.log")?;
+            let (extract_headers, extract_markers) = self.read_log("extract.log")?;
+            assert_eq!(capture_headers.len(), self.num_shards());
+            assert_eq!(capture_headers, extract_headers);
+            assert_eq!(capture_markers, extract_markers);
+
+            assert!(capture_markers.iter().any(|line| line.ends_with("type=filename filename=a.img")));
+            assert!(capture_markers.iter().any(|line| line.ends_with("type=file_data filename=a.img size=5")));
+            let b_size: usize = capture_markers.iter()
+                .filter_map(|line| line.split(" type=file_data filename=b.img size=").nth(1))
+                .map(|size| size.parse::<usize>().unwrap())
+                .sum();
+            assert_eq!(b_size, FILE_SIZE);
+            assert!(capture_markers.last().unwrap().ends_with("type=image_eof"));
+            Ok(())
+        }
+
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            assert_eq!(restore.criu.read_img_file_into_vec("b.img")?, self.file);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod restore_mem_usage {
+    use super::*;
+
+    // We test one large file, and many small ones, simulating a fair CRIU workload.
+    // There are two things to test:
+    // 1) The in-memory store should have low overhead. 200 bytes per file is not exactly good, but
+    //    it's good enough (measured at 129 bytes). I welcome suggestion to make this better.
+    // 2) The in-memory store should free its memory as its transferring a file to CRIU
+    //    It shouldn't be bigger than image_store::mem::MAX_LARGE_CHUNK_SIZE (10MB). We add a bit
+    //    for slack.
+
+    const BIG_FILE_SIZE: usize = 105*MB;
+    const SMALL_FILE_SIZE: usize = 10;
+    const NUM_SMALL_FILES: usize = 100_000;
+    const TOLERABLE_PER_FILE_OVERHEAD: isize = 200;
+    const TOLERABLE_CRIU_RECEIVE_OVERHEAD: isize = 12*MB as isize;
+
+    struct Test {
+        start_mem_size: Option<usize>
+    }
+
+    impl Test {
+        fn new() -> Self {
+            Self { start_mem_size: None }
+        }
+    }
+
+    impl TestImpl for Test {
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            self.start_mem_size = Some(get_resident_mem_size());
+
+            let small = get_filled_vec(SMALL_FILE_SIZE, 1);
+
+            for i in 0..NUM_SMALL_FILES {
+                let filename = format!("small-{}.img", i);
+                checkpoint.criu.write_img_file(&filename)?.write_all(&small)?;
+            }
+
+            // Writing the big file in small chunks, to prevent blowing up memory with a large
+            // vector that may not get freed.
+            let mut big_file_pipe = checkpoint.criu.write_img_file("big.img")?;
+            let buf = get_filled_vec(1*KB, 1);
+            for _ in 0..(BIG_FILE_SIZE/buf.len()) {
+                big_file_pipe.write_all(&buf)?;
+            }
+
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, _restore_stats: &Stats) -> Result<()> {
+            let extraction_use = get_resident_mem_size() as isize - self.start_mem_size.unwrap() as isize;
+            let overhead = extraction_use - (BIG_FILE_SIZE + NUM_SMALL_FILES * SMALL_FILE_SIZE) as isize;
+            let overhead_per_file = overhead / (1 + NUM_SMALL_FILES) as isize;
+
+            assert!(overhead_per_file < TOLERABLE_PER_FILE_OVERHEAD,
+                    "In-memory image store shows too much memory overhead per file: {} bytes", overhead_per_file);
+            eprintln!("Per file overhead: {} bytes", overhead_per_file);
+            Ok(())
+        }
+
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            let start_recv_mem_usage = get_resident_mem_size();
+
+            let mut big_file = Vec::with_capacity(BIG_FILE_SIZE);
+            let big_file_pipe = &restore.criu.read_img_file("big.img")?;
+            let mut max_overhead = 0;
+            loop {
+                let count = big_file_pipe.take(10*KB as u64).read_to_end(&mut big_file)?;
+
+                let delta_recv_mem_usage = get_resident_mem_size() as isize - start_recv_mem_usage as isize;
+                max_overhead = max(max_overhead, delta_recv_mem_usage);
+
+                if count == 0 {
+                    break; // EOF
+                }
+            }
+
+            assert!(max_overhead < TOLERABLE_CRIU_RECEIVE_OVERHEAD,
+                "Memory budget exceeded while CRIU reads a large file: {} MB", max_overhead/MB as isize);
+            eprintln!("Large file overhead: {} MB", max_overhead/MB as isize);
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod stress {
+    use super::*;
+    use crossbeam_utils::thread;
+    use std::sync::Mutex;
+
+    const NUM_THREADS: usize = 5;
+    const NUM_SMALL_FILES: usize = 10000;
+    const NUM_MEDIUM_FILES: usize = 1000;
+    const NUM_MEDIUM_CHUNKED_FILES: usize = 1000;
+    const NUM_LARGE_FILES: usize = 2;
+
+    const SMALL_FILE_SIZE: usize = 10;
+    const MEDIUM_FILE_SIZE: usize = 10*KB;
+    const LARGE_FILE_SIZE: usize = 25*MB; // 25MB to have a few mmap buffers
+    const CHUNK_SIZE: usize = 10;
+
+    struct Test {
+        small_file: Vec<u8>,
+        medium_file: Vec<u8>,
+        large_file: Vec<u8>,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            let small_file  = get_rand_vec(SMALL_FILE_SIZE);
+            let medium_file = get_rand_vec(MEDIUM_FILE_SIZE);
+            let large_file  = get_rand_vec(LARGE_FILE_SIZE);
+
+            Self { small_file, medium_file, large_file }
+        }
+    }
+
+    impl TestImpl for Test {
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            let checkpoint = Mutex::new(checkpoint);
+
+            let write_img_file = |name: &str, data: &Vec<u8>| -> Result<()> {
+                let mut pipe = checkpoint.lock().unwrap().criu.write_img_file(name)?;
+                pipe.write_all(data)?;
+                Ok(())
+            };
+
+            let write_img_file_chunked = |name: &str, data: &Vec<u8>| -> Result<()> {
+                let mut pipe = checkpoint.lock().unwrap().criu.write_img_file(name)?;
+
+                let mut offset = 0;
+                while offset < data.len() {
+                    let to_write = min(data.len() - offset, CHUNK_SIZE);
+                    pipe.write_all(&data[offset..offset+to_write])?;
+                    offset += to_write;
+                }
+
+                Ok(())
+            };
+
+            let write_files = |i: usize| {
+                for j in 0..NUM_MEDIUM_FILES {
+                    write_img_file(&format!("medium-{}-{}.img", i, j), &self.medium_file).unwrap();
+                }
+                for j in 0..NUM_LARGE_FILES {
+                    write_img_file(&format!("large-{}-{}.img", i, j), &self.large_file).unwrap();
+                }
+                for j in 0..NUM_SMALL_FILES {
+                    write_img_file(&format!("small-{}-{}.img", i, j), &self.small_file).unwrap();
+                }
+                for j in 0..NUM_MEDIUM_CHUNKED_FILES {
+                    write_img_file_chunked(&format!("mediumc-{}-{}.img", i, j), &self.medium_file).unwrap();
+                }
+            };
+
+            // Write without threads first. Should be easier
+            write_files(0);
+
+            thread::scope(|s| {
+                for i in 0..NUM_THREADS {
+                    s.spawn(move |_| write_files(i+1));
+                }
+            }).unwrap();
+
+            Ok(())
+        }
+
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            let mut read_img_file = |name: &str| -> Result<Vec<u8>> {
+                restore.criu.read_img_file_into_vec(name)
+            };
+
+            for i in 0..NUM_THREADS+1 {
+                for j in 0..NUM_MEDIUM_FILES {
+                    assert!(read_img_file(&format!("medium-{}-{}.img", i, j))? == self.medium_file);
+                }
+                for j in 0..NUM_LARGE_FILES {
+                    assert!(read_img_file(&format!("large-{}-{}.img", i, j))? == self.large_file);
+                }
+                for j in 0..NUM_SMALL_FILES {
+                    assert!(read_img_file(&format!("small-{}-{}.img", i, j))? == self.small_file);
+                }
+                for j in 0..NUM_MEDIUM_CHUNKED_FILES {
+                    assert!(read_img_file(&format!("mediumc-{}-{}.img", i, j))? == self.medium_file);
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod splice_bug {
+    // The 4.14.67 kernel can corrupt data with splice(), but the 4.14.121 has that bug fixed.
+    // This test makes it obvious. It's a subset of the stress test above, but much faster.
+    // Maybe the fix is https://github.com/torvalds/linux/commit/1bdc347
+    // We should be careful not to use such buggy kernel.
+    use super::*;
+    use std::sync::Mutex;
+
+    const NUM_MEDIUM_CHUNKED_FILES: usize = 100;
+    const MEDIUM_FILE_SIZE: usize = 10*KB;
+    const CHUNK_SIZE: usize = 10;
+
+    struct Test {
+        medium_file: Vec<u8>,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            let medium_file = get_rand_vec(MEDIUM_FILE_SIZE);
+            Self { medium_file }
+        }
+    }
+
+    impl TestImpl for Test {
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            let checkpoint = Mutex::new(checkpoint);
+
+            let write_img_file_chunked = |name: &str, data: &Vec<u8>| -> Result<()> {
+                let mut pipe = checkpoint.lock().unwrap().criu.write_img_file(name)?;
+
+                let mut offset = 0;
+                while offset < data.len() {
+                    let to_write = min(data.len() - offset, CHUNK_SIZE);
+                    pipe.write_all(&data[offset..offset+to_write])?;
+                    offset += to_write;
+                }
+
+                Ok(())
+            };
+
+            let write_files = |i: usize| {
+                for j in 0..NUM_MEDIUM_CHUNKED_FILES {
+                    write_img_file_chunked(&format!("mediumc-{}-{}.img", i, j), &self.medium_file).unwrap();
+                }
+            };
+
+            write_files(0);
+
+            Ok(())
+        }
+
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            let mut read_img_file = |name: &str| -> Result<Vec<u8>> {
+                restore.criu.read_img_file_into_vec(name)
+            };
+
+            let i = 0;
+            for j in 0..NUM_MEDIUM_CHUNKED_FILES {
+                assert!(read_img_file(&format!("mediumc-{}-{}.img", i, j))? == self.medium_file);
+            }
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod extract_to_disk {
+    use super::*;
+    use std::fs::File;
+
+    struct Test {
+        small_file: Vec<u8>,
+        medium_file: Vec<u8>,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            let small_file = get_rand_vec(1*KB);
+            let medium_file = get_rand_vec(100*KB);
+            Self { small_file, medium_file }
+        }
+    }
+
+    impl TestImpl for Test {
+        fn serve_image(&mut self) -> bool { false }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("small.img")?
+                .write_all(&self.small_file)?;
+            checkpoint.criu.write_img_file("medium.img")?
+                .write_all(&self.medium_file)?;
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, _restore_stats: &Stats) -> Result<()> {
+            let read_img_file = |name: &str| -> Result<Vec<u8>> {
+                let mut buf = Vec::new();
+                let mut small_file = File::open(self.images_dir().join(name))?;
+                small_file.read_to_end(&mut buf)?;
+                Ok(buf)
+            };
+
+            assert!(read_img_file("small.img")? == self.small_file);
+            assert!(read_img_file("medium.img")? == self.medium_file);
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod chunk_alignment {
+    use super::*;
+    use std::fs;
+    use criu_image_streamer::{image::{self, marker}, util::pb_read_next};
+
+    // The image is captured with aligned chunks. Each shard stream is walked to check that every
+    // chunk ends on the alignment, and the image is extracted as usual.
+
+    const CHUNK_ALIGNMENT: usize = 4*KB;
+
+    struct Test {
+        files: Vec<(&'static str, Vec<u8>)>,
+        shard_threads: Vec<ShardThread>,
+    }
+
+    /// Returns the number of chunks of the shard, after checking their alignment.
+    fn check_alignment(mut shard: &[u8]) -> Result<usize> {
+        let shard_size = shard.len();
+        let mut num_chunks = 0;
+        while let Some((marker, _)) = pb_read_next::<_, image::Marker>(&mut shard)? {
+            // The filename marker is written along with the chunk that follows.
+            if let Some(marker::Body::Filename(_)) = marker.body {
+                assert_eq!(marker.padding, 0);
+                continue;
+            }
+            let data_size = match marker.body {
+                Some(marker::Body::FileData(size)) => size as usize,
+                _ => 0,
+            };
+            shard = &shard[data_size + marker.padding as usize..];
+            assert_eq!((shard_size - shard.len()) % CHUNK_ALIGNMENT, 0, "Unaligned chunk {:?}", marker);
+            num_chunks += 1;
+        }
+        Ok(num_chunks)
+    }
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from("/tmp/test-criu-image-streamer-chunk-alignment") }
+        fn serve_image(&mut self) -> bool { false }
+        fn num_shards(&self) -> usize { 2 }
+        fn chunk_alignment(&self) -> usize { CHUNK_ALIGNMENT }
+
+        fn shards(&mut self)-> Vec<(UnixPipe, UnixPipe)> {
+            (0..self.num_shards()).map(|_| {
+                let (mut capture_shard_r, capture_shard_w) = new_pipe();
+                let (extract_shard_r, mut extract_shard_w) = new_pipe();
+
+                self.shard_threads.push(thread::spawn(move || {
+                    let mut buf = Vec::new();
+                    capture_shard_r.read_to_end(&mut buf)?;
+                    extract_shard_w.write_all(&buf)?;
+                    Ok(buf)
+                }));
+
+                (extract_shard_r, capture_shard_w)
+            }).collect()
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            for (filename, data) in &self.files {
+                checkpoint.criu.write_img_file(filename)?.write_all(data)?;
+            }
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, _restore_stats: &Stats) -> Result<()> {
+            let mut num_chunks = 0;
+            for shard_thread in self.shard_threads.drain(..) {
+                num_chunks += check_alignment(&shard_thread.join().unwrap()?)?;
+            }
+            assert!(num_chunks > self.files.len());
+
+            for (filename, data) in &self.files {
+                assert!(&fs::read(self.images_dir().join(filename))? == data, "{} content mismatch", filename);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test {
+            files: vec![("empty.img", vec![]), ("small.img", get_rand_vec(10)), ("large.img", get_rand_vec(3*MB))],
+            shard_threads: Vec::new(),
+        }.run()
+    }
+}
+
+mod file_table {
+    use super::*;
+    use std::io::Cursor;
+    use criu_image_streamer::file_table::read_file_table;
+
+    // The image is captured with a file table, on aligned chunks. The table is read from the tail
+    // of the first shard, and the image is served as usual, past the table.
+
+    const CHUNK_ALIGNMENT: usize = 4*KB;
+
+    struct Test {
+        files: Vec<(&'static str, Vec<u8>)>,
+        shard_threads: Vec<ShardThread>,
+    }
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from("/tmp/test-criu-image-streamer-file-table") }
+        fn num_shards(&self) -> usize { 2 }
+        fn chunk_alignment(&self) -> usize { CHUNK_ALIGNMENT }
+        fn file_table(&self) -> bool { true }
+
+        fn shards(&mut self)-> Vec<(UnixPipe, UnixPipe)> {
+            (0..self.num_shards()).map(|_| {
+                let (mut capture_shard_r, capture_shard_w) = new_pipe();
+                let (extract_shard_r, mut extract_shard_w) = new_pipe();
+
+                self.shard_threads.push(thread::spawn(move || {
+                    let mut buf = Vec::new();
+                    capture_shard_r.read_to_end(&mut buf)?;
+                    extract_shard_w.write_all(&buf)?;
+                    Ok(buf)
+                }));
+
+                (extract_shard_r, capture_shard_w)
+            }).collect()
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            for (filename, data) in &self.files {
+                checkpoint.criu.write_img_file(filename)?.write_all(data)?;
+            }
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, _restore_stats: &Stats) -> Result<()> {
+            let shards = self.shard_threads.drain(..)
+                .map(|shard_thread| shard_thread.join().unwrap())
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(shards[0].len() % CHUNK_ALIGNMENT, 0);
+
+            let file_table = read_file_table(&mut Cursor::new(&shards[0]))?;
+            assert!(read_file_table(&mut Cursor::new(&shards[1])).is_err());
+            assert_eq!(file_table.files.len(), self.files.len());
+            for ((filename, data), file) in self.files.iter().zip(&file_table.files) {
+                assert_eq!(&file.filename, filename);
+                assert_eq!(file.size, data.len() as u64);
+                assert_eq!(file.shard_chunks.len(), self.num_shards());
+            }
+            let num_chunks: u32 = file_table.files.iter().flat_map(|f| &f.shard_chunks).sum();
+            assert!(num_chunks as usize > self.files.len());
+            Ok(())
+        }
+
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            for (filename, data) in &self.files {
+                assert!(&restore.criu.read_img_file_into_vec(filename)? == data, "{} content mismatch", filename);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test {
+            files: vec![("empty.img", vec![]), ("small.img", get_rand_vec(10)), ("large.img", get_rand_vec(3*MB))],
+            shard_threads: Vec::new(),
+        }.run()
+    }
+}
+
+mod ghost_files_dir {
+    use super::*;
+    use std::fs;
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-ghost-files";
+    const GHOST_FILES_DIR: &str = "/tmp/test-criu-image-streamer-ghost-files/ghosts";
+
+    struct Test {
+        ghost_file: Vec<u8>,
+    }
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from(IMAGES_DIR) }
+        fn serve_image(&mut self) -> bool { false }
+        fn ghost_files_dir(&self) -> Option<PathBuf> { Some(PathBuf::from(GHOST_FILES_DIR)) }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("files.img")?.write_all(b"files")?;
+            checkpoint.criu.write_img_file("ghost-file-1a.img")?.write_all(&self.ghost_file)?;
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, _restore_stats: &Stats) -> Result<()> {
+            assert_eq!(fs::read(self.images_dir().join("files.img"))?, b"files");
+            assert_eq!(fs::read(PathBuf::from(GHOST_FILES_DIR).join("ghost-file-1a.img"))?, self.ghost_file);
+            assert!(!self.images_dir().join("ghost-file-1a.img").exists());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        let _ = fs::remove_dir_all(IMAGES_DIR);
+        Test { ghost_file: get_rand_vec(100*KB) }.run()
+    }
+}
+
+mod ownership {
+    use super::*;
+    use std::{fs, os::unix::fs::MetadataExt};
+    use nix::unistd::{getuid, getgid};
+
+    // The extracted files, and the directories that the extraction creates (here, the ghost
+    // files directory and its parent), get the requested ownership. The images directory, created
+    // beforehand by the capture, is left as is. Files are chowned to our own uid and gid, which
+    // needs no privileges.
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-ownership";
+
+    struct Test;
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from(IMAGES_DIR) }
+        fn serve_image(&mut self) -> bool { false }
+        fn ghost_files_dir(&self) -> Option<PathBuf> { Some(self.images_dir().join("ghosts/1")) }
+
+        fn ownership(&self) -> Ownership {
+            Ownership { owner: Some((getuid().as_raw(), getgid().as_raw())), mode: Some(0o640) }
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("core-1.img")?.write_all(b"core")?;
+            checkpoint.criu.write_img_file("ghost-file-1.img")?.write_all(b"ghost")?;
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, _restore_stats: &Stats) -> Result<()> {
+            for path in &["core-1.img", "ghosts/1/ghost-file-1.img"] {
+                let file = fs::metadata(self.images_dir().join(path))?;
+                assert_eq!(file.mode() & 0o7777, 0o640, "{}", path);
+                assert_eq!((file.uid(), file.gid()), (getuid().as_raw(), getgid().as_raw()));
+            }
+            for dir in &["ghosts", "ghosts/1"] {
+                assert_eq!(fs::metadata(self.images_dir().join(dir))?.mode() & 0o7777, 0o750, "{}", dir);
+            }
+            assert_ne!(fs::metadata(self.images_dir())?.mode() & 0o7777, 0o750);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        let _ = fs::remove_dir_all(IMAGES_DIR);
+        Test.run()
+    }
+}
+
+mod reflink_ownership {
+    use super::*;
+    use std::{fs, os::unix::fs::{MetadataExt, PermissionsExt}};
+    use nix::unistd::{getuid, getgid};
+
+    // The files that are the same as in the previous checkpoint are linked to it, and still get
+    // the requested ownership. A hard link would share the permissions of the previous file, so
+    // a file whose previous version has other permissions is left as extracted.
+
+    const DIR: &str = "/tmp/test-criu-image-streamer-reflink-ownership";
+
+    struct Test;
+
+    impl Test {
+        fn previous_dir(&self) -> PathBuf { PathBuf::from(DIR).join("ckpt-1") }
+    }
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from(DIR).join("ckpt-2") }
+        fn serve_image(&mut self) -> bool { false }
+        fn reflink_from(&self) -> Option<PathBuf> { Some(self.previous_dir()) }
+
+        fn ownership(&self) -> Ownership {
+            Ownership { owner: Some((getuid().as_raw(), getgid().as_raw())), mode: Some(0o640) }
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("same-mode.img")?.write_all(b"same mode")?;
+            checkpoint.criu.write_img_file("other-mode.img")?.write_all(b"other mode")?;
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, _restore_stats: &Stats) -> Result<()> {
+            for filename in &["same-mode.img", "other-mode.img"] {
+                let file = fs::metadata(self.images_dir().join(filename))?;
+                assert_eq!(file.mode() & 0o7777, 0o640, "{}", filename);
+                assert_eq!((file.uid(), file.gid()), (getuid().as_raw(), getgid().as_raw()));
+            }
+            // The previous checkpoint is left untouched.
+            let previous_file = fs::metadata(self.previous_dir().join("other-mode.img"))?;
+            assert_eq!(previous_file.mode() & 0o7777, 0o600);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        let _ = fs::remove_dir_all(DIR);
+        let previous_dir = Test.previous_dir();
+        fs::create_dir_all(&previous_dir)?;
+        for (filename, content, mode) in &[("same-mode.img", "same mode", 0o640), ("other-mode.img", "other mode", 0o600)] {
+            let path = previous_dir.join(filename);
+            fs::write(&path, content)?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(*mode))?;
+        }
+        Test.run()
+    }
+}
+
+mod shard_fifos {
+    use super::*;
+    use std::fs;
+    use criu_image_streamer::shard_fifos::{open_shard_fifos, FifoDirection};
+
+    const FIFOS_DIR: &str = "/tmp/test-criu-image-streamer-shard-fifos";
+
+    #[test]
+    fn test_open_in_any_order() -> Result<()> {
+        let _ = fs::remove_dir_all(FIFOS_DIR);
+        fs::create_dir_all(FIFOS_DIR)?;
+        let pattern = format!("{}/shard-{{n}}", FIFOS_DIR);
+
+        // The uploader opens the shards in the reverse order, once they exist.
+        let uploader = thread::spawn(|| -> Result<Vec<Vec<u8>>> {
+            let paths: Vec<_> = (0..3).rev()
+                .map(|i| PathBuf::from(format!("{}/shard-{}", FIFOS_DIR, i)))
+                .collect();
+            while !paths.iter().all(|path| path.exists()) {
+                thread::yield_now();
+            }
+            let mut shards = paths.iter().map(fs::File::open).collect::<std::io::Result<Vec<_>>>()?;
+            shards.reverse();
+            shards.iter_mut().map(|shard| {
+                let mut buf = Vec::new();
+                shard.read_to_end(&mut buf)?;
+                Ok(buf)
+            }).collect()
+        });
+
+        let shards = open_shard_fifos(&pattern, 3, FifoDirection::Write)?;
+        for (i, mut shard) in shards.into_iter().enumerate() {
+            write!(shard, "shard {}", i)?;
+        }
+
+        let received = uploader.join().unwrap()?;
+        assert_eq!(received, [b"shard 0", b"shard 1", b"shard 2"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pattern_without_index() {
+        let err = open_shard_fifos("/tmp/test-criu-image-streamer-shard", 2, FifoDirection::Read).unwrap_err();
+        assert!(err.to_string().contains("must contain `{n}`"));
+    }
+}
+
+mod extract_dry_run {
+    use super::*;
+
+    // The image is validated against its signed manifest, but no file is written.
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-dry-run";
+
+    struct Test;
+
+    impl Test {
+        fn new() -> Self {
+            let _ = std::fs::remove_dir_all(IMAGES_DIR);
+            Self
+        }
+    }
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from(IMAGES_DIR) }
+        fn serve_image(&mut self) -> bool { false }
+        fn extract_dry_run(&self) -> bool { true }
+        fn sign_key(&self) -> Option<SigningKey> { Some(SigningKey::from_bytes(&[1; 32])) }
+        fn verify_key(&self) -> Option<VerifyingKey> { self.sign_key().map(|k| k.verifying_key()) }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("a.img")?.write_all(b"hello")?;
+            checkpoint.criu.write_img_file("b.img")?.write_all(&get_rand_vec(100*KB))?;
+            Ok(())
+        }
+
+        fn finish_image_extraction(&mut self, restore: &mut StreamerRestoreContext) -> Result<Stats> {
+            let stats = read_stats(&mut restore.progress)?;
+            let report = read_line(&mut restore.progress)?;
+            assert!(report.contains(r#"{"filename":"a.img","size":5,"sha256":""#), "{}", report);
+            assert!(report.contains(r#"{"filename":"b.img","size":102400,"sha256":""#), "{}", report);
+            assert!(report.contains(r#"{"filename":"streamer-manifest.json","#), "{}", report);
+            assert!(report.ends_with(r#""verification":"signature"}"#), "{}", report);
+            Ok(stats)
+        }
+
+        fn after_finish_image_extraction(&mut self, _restore_stats: &Stats) -> Result<()> {
+            assert!(!self.images_dir().join("a.img").exists(), "Files should not be written");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod custom_img_store {
+    use super::*;
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    // The image is extracted into a store provided by the library user. Files are written into
+    // a shared map, and the store keeps track of the completed files.
+    type Files = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    struct Store {
+        files: Files,
+        completed: Arc<Mutex<Vec<String>>>,
+    }
+
+    struct File {
+        filename: String,
+        files: Files,
+    }
+
+    impl DynImageStore for Store {
+        fn create(&mut self, filename: &str) -> Result<Box<dyn ImageFile>> {
+            self.files.lock().unwrap().insert(filename.to_string(), Vec::new());
+            Ok(Box::new(File { filename: filename.to_string(), files: Arc::clone(&self.files) }))
+        }
+
+        fn insert(&mut self, filename: Box<str>, _file: Box<dyn ImageFile>) {
+            self.completed.lock().unwrap().push(filename.into());
+        }
+    }
+
+    impl ImageFile for File {
+        fn write_all_from_pipe(&mut self, shard_pipe: &mut UnixPipe, size: usize) -> Result<()> {
+            let mut buf = vec![0; size];
+            shard_pipe.read_exact(&mut buf)?;
+            self.write_all_from_slice(&buf)
+        }
+
+        fn write_all_from_slice(&mut self, buf: &[u8]) -> Result<()> {
+            self.files.lock().unwrap().get_mut(&self.filename).unwrap().extend_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    struct Test {
+        file: Vec<u8>,
+        files: Files,
+        completed: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            Self {
+                file: get_rand_vec(1*MB),
+                files: Files::default(),
+                completed: Default::default(),
+            }
+        }
+    }
+
+    impl TestImpl for Test {
+        fn serve_image(&mut self) -> bool { false }
+
+        fn extract_img_store(&mut self) -> Option<Box<dyn DynImageStore + Send>> {
+            Some(Box::new(Store { files: Arc::clone(&self.files), completed: Arc::clone(&self.completed) }))
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("small.img")?
+                .write_all("hello world".as_bytes())?;
+            checkpoint.criu.write_img_file("large.img")?
+                .write_all(&self.file)?;
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, _restore_stats: &Stats) -> Result<()> {
+            let mut completed = self.completed.lock().unwrap().clone();
+            completed.sort();
+            assert_eq!(completed, vec!["large.img", "small.img"]);
+
+            let files = self.files.lock().unwrap();
+            assert_eq!(files["small.img"], "hello world".as_bytes());
+            assert!(files["large.img"] == self.file);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod extract_to_stream {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    // The image is extracted into a stream, each file framed as filename\0size\0data.
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    pub(super) fn parse_stream(mut data: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let next_field = |data: &mut &[u8]| {
+            let end = data.iter().position(|b| *b == 0).expect("Missing field terminator");
+            let field = String::from_utf8(data[..end].to_vec()).unwrap();
+            *data = &data[end+1..];
+            field
+        };
+
+        let mut files = Vec::new();
+        while !data.is_empty() {
+            let filename = next_field(&mut data);
+            let size: usize = next_field(&mut data).parse().unwrap();
+            files.push((filename, data[..size].to_vec()));
+            data = &data[size..];
+        }
+        files
+    }
+
+    struct Test {
+        file: Vec<u8>,
+        stream: SharedBuf,
+    }
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from("/tmp/test-criu-image-streamer-stream") }
+        fn serve_image(&mut self) -> bool { false }
+
+        fn extract_stream(&mut self) -> Option<Box<dyn Write + Send>> {
+            Some(Box::new(self.stream.clone()))
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("small.img")?
+                .write_all("hello world".as_bytes())?;
+            checkpoint.criu.write_img_file("large.img")?
+                .write_all(&self.file)?;
+            checkpoint.criu.write_img_file("empty.img")?;
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, _restore_stats: &Stats) -> Result<()> {
+            // Files come in the order in which they are completed.
+            let files = parse_stream(&self.stream.0.lock().unwrap());
+            assert_eq!(files, vec![
+                ("small.img".to_string(), b"hello world".to_vec()),
+                ("large.img".to_string(), self.file.clone()),
+                ("empty.img".to_string(), vec![]),
+            ]);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test { file: get_rand_vec(1*MB), stream: SharedBuf::default() }.run()
+    }
+}
+
+mod import_stream {
+    use super::*;
+    use criu_image_streamer::extract::import_stream;
+
+    // A framed file stream is imported into an image, which is extracted back into a stream.
+    // No CRIU is involved. Files keep the order of the stream.
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-import-stream";
+
+    fn frame(files: &[(String, Vec<u8>)]) -> Vec<u8> {
+        let mut stream = Vec::new();
+        for (filename, data) in files {
+            write!(stream, "{}\0{}\0", filename, data.len()).unwrap();
+            stream.extend_from_slice(data);
+        }
+        stream
+    }
+
+    fn import(stream: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let (_progress_r, progress_w) = new_pipe();
+        let (output_shard_pipes, output_shard_threads) = spawn_shard_readers(2);
+        import_stream(progress_w, stream, output_shard_pipes, None)?;
+        output_shard_threads.into_iter().map(|t| t.join().unwrap()).collect()
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        let files = vec![
+            ("gpu-state.bin".to_string(), get_rand_vec(1*MB)),
+            ("empty.img".to_string(), vec![]),
+            ("small.img".to_string(), b"hello world".to_vec()),
+        ];
+        let contents = import(&frame(&files))?;
+
+        let mut out = Vec::new();
+        ExtractBuilder::new(IMAGES_DIR, spawn_shard_writers(contents))
+            .extract_to_stream(&mut out)?;
+        assert_eq!(extract_to_stream::parse_stream(&out), files);
+        Ok(())
+    }
+
+    #[test]
+    fn test_preallocate() -> Result<()> {
+        // The sizes of the imported files are recorded in the image, and the files extracted to
+        // disk are preallocated, keeping the size of their content.
+        let files = vec![
+            ("pages-1.img".to_string(), get_rand_vec(3*MB)),
+            ("small.img".to_string(), b"hello world".to_vec()),
+        ];
+        let contents = import(&frame(&files))?;
+
+        let images_dir = PathBuf::from(IMAGES_DIR).join("preallocate");
+        std::fs::create_dir_all(&images_dir)?;
+        let log_path = images_dir.join("extract.log");
+        ExtractBuilder::new(&images_dir, spawn_shard_writers(contents))
+            .marker_log(Some(MarkerLog::create(&log_path)?))
+            .extract()?;
+
+        let log = std::fs::read_to_string(&log_path)?;
+        for (filename, data) in &files {
+            assert!(log.contains(&format!("type=filename filename={} file_size={}", filename, data.len())), "{}", log);
+            assert!(&std::fs::read(images_dir.join(filename))? == data, "{} content mismatch", filename);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_stream() {
+        let mut stream = frame(&[("a.img".to_string(), get_rand_vec(1*KB))]);
+        stream.truncate(stream.len() - 1);
+        let err = import(&stream).expect_err("import_stream() should have failed");
+        assert!(format!("{:#}", err).contains("ends in the middle of a.img"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_invalid_size() {
+        let err = import(b"a.img\0ten\0").expect_err("import_stream() should have failed");
+        assert!(format!("{:#}", err).contains("invalid size for a.img"), "{:#}", err);
+    }
+}
+
+mod codec {
+    use super::*;
+    use criu_image_streamer::{
+        codec::{Codec, ExternalCodec, gzip_codec, encode_shards, decode_shards, wait_codec_stages, finish_codec_stages},
+        extract::import_stream,
+    };
+
+    // Files are imported into an image written through an external codec (gzip), and extracted
+    // back through the decoding command. No CRIU is involved.
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-codec";
+
+    fn frame(files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut stream = Vec::new();
+        for (filename, data) in files {
+            write!(stream, "{}\0{}\0", filename, data.len()).unwrap();
+            stream.extend_from_slice(data);
+        }
+        stream
+    }
+
+    fn encode(codec: &dyn Codec, stream: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let (_progress_r, progress_w) = new_pipe();
+        let (shard_pipes, shard_threads) = spawn_shard_readers(2);
+        let (shard_pipes, codec_stages) = encode_shards(codec, shard_pipes)?;
+        import_stream(progress_w, stream, shard_pipes, None)?;
+        wait_codec_stages(codec_stages)?;
+        shard_threads.into_iter().map(|t| t.join().unwrap()).collect()
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        let files = vec![("a.img", get_rand_vec(1*MB)), ("b.img", b"hello world".to_vec())];
+        let contents = encode(&ExternalCodec::new("gzip -c"), &frame(&files))?;
+        for content in &contents {
+            assert!(content.starts_with(&[0x1f, 0x8b]), "The shard is not gzip encoded");
+        }
+
+        let (shard_pipes, codec_stages) = decode_shards(&ExternalCodec::new("gzip -dc"), spawn_shard_writers(contents))?;
+        let mut out = Vec::new();
+        ExtractBuilder::new(IMAGES_DIR, shard_pipes).extract_to_stream(&mut out)?;
+        wait_codec_stages(codec_stages)?;
+        assert_eq!(out, frame(&files));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gzip_codec() -> Result<()> {
+        // The backend depends on the host, they all write gzip.
+        let files = vec![("a.img", get_rand_vec(100*KB))];
+        let contents = encode(&gzip_codec()?, &frame(&files))?;
+
+        let (shard_pipes, codec_stages) = decode_shards(&ExternalCodec::new("gzip -dc"), spawn_shard_writers(contents))?;
+        let mut out = Vec::new();
+        ExtractBuilder::new(IMAGES_DIR, shard_pipes).extract_to_stream(&mut out)?;
+        wait_codec_stages(codec_stages)?;
+        assert_eq!(out, frame(&files));
+        Ok(())
+    }
+
+    #[test]
+    fn test_failing_codec() {
+        let err = encode(&ExternalCodec::new("cat > /dev/null; exit 3"), &frame(&[("a.img", vec![1, 2, 3])]))
+            .expect_err("The failing codec should have been reported");
+        assert!(format!("{:#}", err).contains("codec command `cat > /dev/null; exit 3` failed"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_failing_decoder() -> Result<()> {
+        let files = vec![("a.img", get_rand_vec(100*KB))];
+        let contents = encode(&ExternalCodec::new("gzip -c"), &frame(&files))?;
+
+        // The extraction sees a truncated shard, the decoder error is the one reported.
+        let decoder = ExternalCodec::new("head -c 10; exit 3");
+        let (shard_pipes, codec_stages) = decode_shards(&decoder, spawn_shard_writers(contents))?;
+        let result = ExtractBuilder::new(IMAGES_DIR, shard_pipes).extract_to_stream(&mut Vec::new());
+        assert!(result.is_err(), "The extraction should have failed");
+        let err = finish_codec_stages(result, codec_stages).expect_err("The failing decoder should have been reported");
+        assert!(format!("{:#}", err).contains("codec command `head -c 10; exit 3` failed"), "{:#}", err);
+        Ok(())
+    }
+}
+
+mod shard_devices {
+    use super::*;
+    use std::{fs, path::Path};
+    use criu_image_streamer::{
+        extract::import_stream,
+        shard_devices::{open_shard_devices, wait_shard_devices, SHARD_DEVICE_BLOCK_SIZE},
+        shard_fifos::FifoDirection,
+    };
+
+    // Files are imported into an image written to shard devices, and extracted back from them.
+    // Regular files stand in for the block devices. No CRIU is involved.
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-shard-devices";
+
+    fn frame(files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut stream = Vec::new();
+        for (filename, data) in files {
+            write!(stream, "{}\0{}\0", filename, data.len()).unwrap();
+            stream.extend_from_slice(data);
+        }
+        stream
+    }
+
+    /// Creates the devices, filled with garbage, as reused devices would be.
+    fn create_devices(name: &str, n: usize) -> Result<Vec<PathBuf>> {
+        let dir = Path::new(IMAGES_DIR).join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        (0..n).map(|i| {
+            let path = dir.join(format!("shard-{}", i));
+            fs::write(&path, vec![0xff; 4*MB])?;
+            Ok(path)
+        }).collect()
+    }
+
+    fn import(devices: &[PathBuf], stream: &[u8]) -> Result<()> {
+        let (_progress_r, progress_w) = new_pipe();
+        let (shard_pipes, threads) = open_shard_devices(devices, FifoDirection::Write)?;
+        let result = import_stream(progress_w, stream, shard_pipes, None);
+        wait_shard_devices(threads).and(result)
+    }
+
+    fn extract(devices: &[PathBuf]) -> Result<Vec<u8>> {
+        let (shard_pipes, threads) = open_shard_devices(devices, FifoDirection::Read)?;
+        let mut out = Vec::new();
+        let result = ExtractBuilder::new(IMAGES_DIR, shard_pipes).extract_to_stream(&mut out);
+        wait_shard_devices(threads).and(result)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        let devices = create_devices("test", 3)?;
+        let files = vec![("a.img", get_rand_vec(2*MB)), ("b.img", b"hello world".to_vec())];
+        import(&devices, &frame(&files))?;
+
+        for device in &devices {
+            let content = fs::read(device)?;
+            assert_eq!(&content[0..8], b"CRIUSHRD");
+        }
+        assert_eq!(extract(&devices)?, frame(&files));
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_shard() -> Result<()> {
+        let devices = create_devices("no-shard", 1)?;
+        fs::write(&devices[0], vec![0; 2*SHARD_DEVICE_BLOCK_SIZE])?;
+        let err = extract(&devices).expect_err("The extraction should have failed");
+        assert!(format!("{:#}", err).contains("No shard found"), "{:#}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_short_device() -> Result<()> {
+        let devices = create_devices("short-device", 1)?;
+        import(&devices, &frame(&[("a.img", get_rand_vec(1*MB))]))?;
+        let device = fs::OpenOptions::new().write(true).open(&devices[0])?;
+        device.set_len(SHARD_DEVICE_BLOCK_SIZE as u64 + 100*KB as u64)?;
+        let err = extract(&devices).expect_err("The extraction should have failed");
+        assert!(format!("{:#}", err).contains("shorter than"), "{:#}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_not_a_device() {
+        let err = open_shard_devices(&[PathBuf::from("/tmp")], FifoDirection::Read)
+            .expect_err("A directory is not a shard device");
+        assert!(format!("{:#}", err).contains("is not a block device"), "{:#}", err);
+    }
+}
+
+mod shard_mux {
+    use super::*;
+    use std::fs;
+    use criu_image_streamer::{
+        extract::import_stream,
+        shard_mux::{mux_shards, demux_shards, wait_shard_mux},
+        unix_pipe::new_cloexec_pipe,
+    };
+
+    // Files are imported into an image whose shards are multiplexed over a single stream, and
+    // extracted back from it. No CRIU is involved.
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-shard-mux";
+
+    fn frame(files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut stream = Vec::new();
+        for (filename, data) in files {
+            write!(stream, "{}\0{}\0", filename, data.len()).unwrap();
+            stream.extend_from_slice(data);
+        }
+        stream
+    }
+
+    fn import(stream: fs::File, num_shards: usize, files: &[u8]) -> Result<()> {
+        let (_progress_r, progress_w) = new_pipe();
+        let (shard_pipes, thread) = mux_shards(stream, num_shards)?;
+        let result = import_stream(progress_w, files, shard_pipes, None);
+        wait_shard_mux(thread).and(result)
+    }
+
+    fn extract(stream: fs::File) -> Result<Vec<u8>> {
+        let (shard_pipes, thread) = demux_shards(stream)?;
+        let mut out = Vec::new();
+        let result = ExtractBuilder::new(IMAGES_DIR, shard_pipes).extract_to_stream(&mut out);
+        wait_shard_mux(thread).and(result)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test_file() -> Result<()> {
+        // The stream doesn't need to be a pipe.
+        fs::create_dir_all(IMAGES_DIR)?;
+        let path = PathBuf::from(IMAGES_DIR).join("stream");
+        let files = vec![("a.img", get_rand_vec(5*MB)), ("b.img", b"hello world".to_vec())];
+        import(fs::File::create(&path)?, 4, &frame(&files))?;
+        assert_eq!(&fs::read(&path)?[0..8], b"CRIUSMUX");
+        assert_eq!(extract(fs::File::open(&path)?)?, frame(&files));
+        Ok(())
+    }
+
+    #[test]
+    fn test_live() -> Result<()> {
+        // The image is extracted as it is written, as over an ssh connection.
+        let (stream_r, stream_w) = new_cloexec_pipe()?;
+        let files = vec![("a.img", get_rand_vec(10*MB)), ("b.img", get_rand_vec(3*MB))];
+        let stream = frame(&files);
+        let importer = thread::spawn(move || import(stream_w, 3, &stream));
+        assert_eq!(extract(stream_r)?, frame(&files));
+        importer.join().unwrap()
+    }
+
+    #[test]
+    fn test_truncated_stream() -> Result<()> {
+        fs::create_dir_all(IMAGES_DIR)?;
+        let path = PathBuf::from(IMAGES_DIR).join("truncated-stream");
+        import(fs::File::create(&path)?, 2, &frame(&[("a.img", get_rand_vec(2*MB))]))?;
+        let len = fs::metadata(&path)?.len();
+        fs::OpenOptions::new().write(true).open(&path)?.set_len(len / 2)?;
+        let err = extract(fs::File::open(&path)?).expect_err("The extraction should have failed");
+        assert!(format!("{:#}", err).contains("Failed to read the multiplexed shards"), "{:#}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_not_multiplexed() -> Result<()> {
+        let (stream_r, mut stream_w) = new_cloexec_pipe()?;
+        stream_w.write_all(&[0; 64])?;
+        let err = demux_shards(stream_r).expect_err("The stream is not multiplexed");
+        assert!(format!("{:#}", err).contains("doesn't carry multiplexed shards"), "{:#}", err);
+        Ok(())
+    }
+}
+
+mod shard_subset {
+    use super::*;
+
+    // The shards are kept aside after the capture. Extracting with all of them works, but
+    // extracting with a subset of them, or with too many, fails with a diagnostic.
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-shard-subset";
+
+    struct Test {
+        shard_threads: Vec<thread::JoinHandle<Result<Vec<u8>>>>,
+        shard_contents: Vec<Vec<u8>>,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            Self { shard_threads: Vec::new(), shard_contents: Vec::new() }
+        }
+
+        fn extract_shards(&self, shard_indexes: &[usize]) -> Result<()> {
+            let (_progress_r, progress_w) = new_pipe();
+            let (shard_pipes, shard_threads): (Vec<_>, Vec<_>) = shard_indexes.iter().map(|&i| {
+                let (shard_r, mut shard_w) = new_pipe();
+                let content = self.shard_contents[i].clone();
+                // The write fails when extract() gives up on the shard early, that's fine.
+                (shard_r, thread::spawn(move || { let _ = shard_w.write_all(&content); }))
+            }).unzip();
+
+            let result = ExtractBuilder::new(self.images_dir(), shard_pipes)
+                .progress_pipe(progress_w)
+                .extract();
+            shard_threads.into_iter().for_each(|t| t.join().unwrap());
+            result
+        }
+    }
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from(IMAGES_DIR) }
+        fn serve_image(&mut self) -> bool { false }
+
+        fn shards(&mut self)-> Vec<(UnixPipe, UnixPipe)> {
+            (0..self.num_shards()).map(|_| {
+                let (mut capture_shard_r, capture_shard_w) = new_pipe();
+                let (extract_shard_r, mut extract_shard_w) = new_pipe();
+
+                self.shard_threads.push(thread::spawn(move || {
+                    let mut buf = Vec::new();
+                    capture_shard_r.read_to_end(&mut buf)?;
+                    extract_shard_w.write_all(&buf)?;
+                    Ok(buf)
+                }));
+
+                (extract_shard_r, capture_shard_w)
+            }).collect()
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            for i in 0..8 {
+                checkpoint.criu.write_img_file(&format!("file-{}.img", i))?
+                    .write_all(&get_rand_vec(8*KB))?;
+            }
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, restore_stats: &Stats) -> Result<()> {
+            for shard_thread in self.shard_threads.drain(..) {
+                self.shard_contents.push(shard_thread.join().unwrap()?);
+            }
+
+            let image_uuid = restore_stats.image_uuid.as_ref().expect("Missing image UUID");
+
+            let err = self.extract_shards(&[0, 1, 3]).expect_err("extract() should have failed");
+            let err = err.to_string();
+            assert!(err.starts_with(&format!(
+                "The image is incomplete. The image {} was captured with 4 shards, and 3 shards were provided. \
+                 Missing marker sequence numbers: ", image_uuid)), "{}", err);
+
+            let err = self.extract_shards(&[0, 1, 2, 3, 0]).expect_err("extract() should have failed");
+            assert_eq!(err.to_string(), format!(
+                "The image {} was captured with 4 shards, but 5 shards were provided", image_uuid));
+
+            self.extract_shards(&[3, 2, 1, 0])
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod filter {
+    use super::*;
+    use std::fs;
+
+    // The captured image is filtered into new shards, with a file removed and another replaced.
+    // The filtered image is then extracted and verified with its new signed manifest.
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-filter";
+
+    struct Test {
+        files: Vec<(&'static str, Vec<u8>)>,
+        replacement: Vec<u8>,
+        shard_threads: Vec<ShardThread>,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            let _ = fs::remove_dir_all(IMAGES_DIR);
+            Self {
+                files: vec![
+                    ("kept.img", get_rand_vec(100*KB)),
+                    ("removed.img", get_rand_vec(1*MB)),
+                    ("replaced.img", get_rand_vec(10*KB)),
+                ],
+                replacement: get_rand_vec(200*KB),
+                shard_threads: Vec::new(),
+            }
+        }
+    }
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from(IMAGES_DIR) }
+        fn serve_image(&mut self) -> bool { false }
+        fn sign_key(&self) -> Option<SigningKey> { Some(SigningKey::from_bytes(&[2; 32])) }
+
+        fn shards(&mut self)-> Vec<(UnixPipe, UnixPipe)> {
+            (0..self.num_shards()).map(|_| {
+                let (mut capture_shard_r, capture_shard_w) = new_pipe();
+                let (extract_shard_r, mut extract_shard_w) = new_pipe();
+
+                self.shard_threads.push(thread::spawn(move || {
+                    let mut buf = Vec::new();
+                    capture_shard_r.read_to_end(&mut buf)?;
+                    extract_shard_w.write_all(&buf)?;
+                    Ok(buf)
+                }));
+
+                (extract_shard_r, capture_shard_w)
+            }).collect()
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            for (filename, data) in &self.files {
+                checkpoint.criu.write_img_file(filename)?.write_all(data)?;
+            }
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, _restore_stats: &Stats) -> Result<()> {
+            let contents = self.shard_threads.drain(..)
+                .map(|t| t.join().unwrap())
+                .collect::<Result<Vec<_>>>()?;
+
+            let replacement_path = self.images_dir().join("replacement");
+            fs::write(&replacement_path, &self.replacement)?;
+
+            // Filter the image into 2 shards
+            let (_progress_r, progress_w) = new_pipe();
+            let (output_shard_pipes, output_shard_threads) = spawn_shard_readers(2);
+            filter(progress_w, spawn_shard_writers(contents), output_shard_pipes,
+                   vec!["removed.img".to_string()],
+                   vec![("replaced.img".to_string(), replacement_path)], self.sign_key())?;
+            let filtered_contents = output_shard_threads.into_iter()
+                .map(|t| t.join().unwrap())
+                .collect::<Result<Vec<_>>>()?;
+
+            // Extract the filtered image
+            let filtered_dir = self.images_dir().join("filtered");
+            let verify_key = self.sign_key().map(|k| k.verifying_key());
+            ExtractBuilder::new(&filtered_dir, spawn_shard_writers(filtered_contents))
+                .verify_key(verify_key)
+                .extract()?;
+
+            assert!(fs::read(filtered_dir.join("kept.img"))? == self.files[0].1);
+            assert!(!filtered_dir.join("removed.img").exists());
+            assert!(fs::read(filtered_dir.join("replaced.img"))? == self.replacement);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod merge {
+    use super::*;
+    use std::fs;
+
+    // A second image is captured next to the one of the test workflow, like a GPU controller
+    // would do next to CRIU. The two images are merged into a single image, which is extracted.
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-merge";
+
+    struct Test {
+        files: Vec<(&'static str, Vec<u8>)>,
+        other_files: Vec<(&'static str, Vec<u8>)>,
+        shard_threads: Vec<ShardThread>,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            let _ = fs::remove_dir_all(IMAGES_DIR);
+            Self {
+                files: vec![("a.img", get_rand_vec(100*KB)), ("b.img", get_rand_vec(1*MB))],
+                other_files: vec![("gpu.img", get_rand_vec(300*KB))],
+                shard_threads: Vec::new(),
+            }
+        }
+
+        fn capture_other_image(&self) -> Result<Vec<Vec<u8>>> {
+            let images_dir = self.images_dir().join("other");
+            let (progress_r, progress_w) = new_pipe();
+            let mut progress = BufReader::new(progress_r);
+            let (shard_pipes, shard_threads) = spawn_shard_readers(2);
+            let capture_thread = thread::spawn(move || {
+                CaptureBuilder::new(images_dir, shard_pipes)
+                    .progress_pipe(progress_w)
+                    .run()
+            });
+
+            assert_eq!(read_line(&mut progress)?, "socket-init");
+            let mut criu = Criu::connect(self.images_dir().join("other/streamer-capture.sock"))?;
+            for (filename, data) in &self.other_files {
+                criu.write_img_file(filename)?.write_all(data)?;
+            }
+            criu.finish()?;
+            capture_thread.join().unwrap()?;
+
+            shard_threads.into_iter().map(|t| t.join().unwrap()).collect()
+        }
+
+        fn merge(&self, images: Vec<Vec<Vec<u8>>>, dir: &str) -> Result<()> {
+            let (_progress_r, progress_w) = new_pipe();
+            let (output_shard_pipes, output_shard_threads) = spawn_shard_readers(3);
+            let shard_pipe_sets = images.into_iter().map(spawn_shard_writers).collect();
+            merge(progress_w, shard_pipe_sets, output_shard_pipes, None)?;
+            let merged_contents = output_shard_threads.into_iter()
+                .map(|t| t.join().unwrap())
+                .collect::<Result<Vec<_>>>()?;
+
+            ExtractBuilder::new(self.images_dir().join(dir), spawn_shard_writers(merged_contents))
+                .extract()
+        }
+    }
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from(IMAGES_DIR) }
+        fn serve_image(&mut self) -> bool { false }
+
+        fn shards(&mut self)-> Vec<(UnixPipe, UnixPipe)> {
+            (0..self.num_shards()).map(|_| {
+                let (mut capture_shard_r, capture_shard_w) = new_pipe();
+                let (extract_shard_r, mut extract_shard_w) = new_pipe();
+
+                self.shard_threads.push(thread::spawn(move || {
+                    let mut buf = Vec::new();
+                    capture_shard_r.read_to_end(&mut buf)?;
+                    extract_shard_w.write_all(&buf)?;
+                    Ok(buf)
+                }));
+
+                (extract_shard_r, capture_shard_w)
+            }).collect()
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            for (filename, data) in &self.files {
+                checkpoint.criu.write_img_file(filename)?.write_all(data)?;
+            }
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, _restore_stats: &Stats) -> Result<()> {
+            let image = self.shard_threads.drain(..)
+                .map(|t| t.join().unwrap())
+                .collect::<Result<Vec<_>>>()?;
+            let other_image = self.capture_other_image()?;
+
+            self.merge(vec![image.clone(), other_image], "merged")?;
+            let merged_dir = self.images_dir().join("merged");
+            for (filename, data) in self.files.iter().chain(&self.other_files) {
+                assert!(&fs::read(merged_dir.join(filename))? == data, "{} content mismatch", filename);
+            }
+
+            let err = self.merge(vec![image.clone(), image], "merged-twice").expect_err("merge() should have failed");
+            assert!(format!("{:#}", err).contains("a.img is in more than one image"), "{:#}", err);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+#[cfg(feature = "kubelet")]
+mod kubelet_checkpoint {
+    use super::*;
+    use std::fs;
+    use criu_image_streamer::extract::{import_kubelet_checkpoint, export_kubelet_checkpoint};
+
+    // The captured image is exported as a kubelet checkpoint archive. The runtime files of a
+    // kubelet archive are added to it, and the archive is imported back into an image, which is
+    // extracted. Exporting that image gives back the same archive entries.
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-kubelet-checkpoint";
+
+    struct Test {
+        files: Vec<(&'static str, Vec<u8>)>,
+        runtime_files: Vec<(&'static str, Vec<u8>)>,
+        shard_threads: Vec<ShardThread>,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            let _ = fs::remove_dir_all(IMAGES_DIR);
+            Self {
+                files: vec![("inventory.img", get_rand_vec(1*KB)), ("pages-1.img", get_rand_vec(1*MB))],
+                runtime_files: vec![("config.dump", get_rand_vec(1*KB)), ("rootfs-diff.tar", get_rand_vec(300*KB))],
+                shard_threads: Vec::new(),
+            }
+        }
+
+        fn export(&self, contents: Vec<Vec<u8>>) -> Result<Vec<u8>> {
+            let (_progress_r, progress_w) = new_pipe();
+            let mut archive = Vec::new();
+            export_kubelet_checkpoint(progress_w, spawn_shard_writers(contents), &mut archive)?;
+            Ok(archive)
+        }
+
+        fn import(&self, archive: &[u8]) -> Result<Vec<Vec<u8>>> {
+            let (_progress_r, progress_w) = new_pipe();
+            let (output_shard_pipes, output_shard_threads) = spawn_shard_readers(2);
+            import_kubelet_checkpoint(progress_w, archive, output_shard_pipes, None)?;
+            output_shard_threads.into_iter().map(|t| t.join().unwrap()).collect()
+        }
+    }
+
+    /// Returns the files of the archive, sorted by path.
+    fn read_archive(archive: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut files = Vec::new();
+        for entry in tar::Archive::new(archive).entries()? {
+            let mut entry = entry?;
+            if entry.header().entry_type().is_file() {
+                let path = entry.path()?.to_str().unwrap().to_string();
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                files.push((path, data));
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from(IMAGES_DIR) }
+        fn serve_image(&mut self) -> bool { false }
+
+        fn shards(&mut self)-> Vec<(UnixPipe, UnixPipe)> {
+            (0..self.num_shards()).map(|_| {
+                let (mut capture_shard_r, capture_shard_w) = new_pipe();
+                let (extract_shard_r, mut extract_shard_w) = new_pipe();
+
+                self.shard_threads.push(thread::spawn(move || {
+                    let mut buf = Vec::new();
+                    capture_shard_r.read_to_end(&mut buf)?;
+                    extract_shard_w.write_all(&buf)?;
+                    Ok(buf)
+                }));
+
+                (extract_shard_r, capture_shard_w)
+            }).collect()
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            for (filename, data) in &self.files {
+                checkpoint.criu.write_img_file(filename)?.write_all(data)?;
+            }
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, _restore_stats: &Stats) -> Result<()> {
+            let contents = self.shard_threads.drain(..)
+                .map(|t| t.join().unwrap())
+                .collect::<Result<Vec<_>>>()?;
+
+            // The CRIU image files go in checkpoint/
+            let archive = self.export(contents)?;
+            let mut expected_files = self.files.iter()
+                .map(|(filename, data)| (format!("checkpoint/{}", filename), data.clone()))
+                .collect::<Vec<_>>();
+            expected_files.sort();
+            assert!(read_archive(&archive)? == expected_files);
+
+            // Add the runtime files, as the kubelet does
+            let mut builder = tar::Builder::new(Vec::new());
+            for (path, data) in &expected_files {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                builder.append_data(&mut header, path, &data[..])?;
+            }
+            for (path, data) in &self.runtime_files {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                builder.append_data(&mut header, path, &data[..])?;
+                expected_files.push((path.to_string(), data.clone()));
+            }
+            let archive = builder.into_inner()?;
+            expected_files.sort();
+
+            // CRIU image files are named as CRIU knows them, runtime files are prefixed
+            let imported = self.import(&archive)?;
+            let imported_dir = self.images_dir().join("imported");
+            ExtractBuilder::new(&imported_dir, spawn_shard_writers(imported.clone())).extract()?;
+            for (filename, data) in &self.files {
+                assert!(&fs::read(imported_dir.join(filename))? == data, "{} content mismatch", filename);
+            }
+            for (path, data) in &self.runtime_files {
+                assert!(&fs::read(imported_dir.join("kubelet").join(path))? == data, "{} content mismatch", path);
+            }
+
+            assert!(read_archive(&self.export(imported)?)? == expected_files);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod show_img {
+    use super::*;
+    use criu_image_streamer::{
+        criu::{InventoryEntry, FileEntry, FdinfoEntry, FdTypes},
+        image_patcher::write_criu_img_header,
+        show::show_img,
+        util::pb_write,
+    };
+
+    // The images are extracted to disk, and then printed.
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-show";
+
+    const INVENTORY_MAGIC: u32 = 0x58313116;
+    const FILES_MAGIC: u32 = 0x56303138;
+    const FDINFO_MAGIC: u32 = 0x56213732;
+
+    struct Test {
+        files: Vec<(&'static str, Vec<u8>)>,
+    }
+
+    impl Test {
+        fn new() -> Result<Self> {
+            let mut inventory = INVENTORY_MAGIC.to_le_bytes().to_vec();
+            pb_write(&mut inventory, &InventoryEntry { img_version: 2, ..Default::default() })?;
+
+            let mut files = Vec::new();
+            write_criu_img_header(&mut files, FILES_MAGIC)?;
+            for id in 1..=2 {
+                pb_write(&mut files, &FileEntry { r#type: FdTypes::Reg as i32, id, ..Default::default() })?;
+            }
+
+            let mut fdinfo = Vec::new();
+            write_criu_img_header(&mut fdinfo, FDINFO_MAGIC)?;
+            pb_write(&mut fdinfo, &FdinfoEntry { id: 2, fd: 3, ..Default::default() })?;
+
+            Ok(Self {
+                files: vec![("inventory.img", inventory), ("files.img", files), ("fdinfo-2.img", fdinfo)],
+            })
+        }
+
+        fn show(&self, filename: &str) -> Result<String> {
+            let img = std::fs::read(self.images_dir().join(filename))?;
+            let mut out = Vec::new();
+            show_img(filename, &img, &mut out)?;
+            Ok(String::from_utf8(out)?)
+        }
+    }
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from(IMAGES_DIR) }
+        fn serve_image(&mut self) -> bool { false }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            for (filename, data) in &self.files {
+                checkpoint.criu.write_img_file(filename)?.write_all(data)?;
+            }
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, _restore_stats: &Stats) -> Result<()> {
+            let inventory = self.show("inventory.img")?;
+            assert!(inventory.starts_with("InventoryEntry {"), "{}", inventory);
+            assert!(inventory.contains("img_version: 2,"), "{}", inventory);
+
+            let files = self.show("files.img")?;
+            assert_eq!(files.matches("FileEntry {").count(), 2, "{}", files);
+            assert!(files.contains("id: 2,"), "{}", files);
+
+            let fdinfo = self.show("fdinfo-2.img")?;
+            assert!(fdinfo.contains("fd: 3,"), "{}", fdinfo);
+
+            let err = show_img("pages-1.img", &[], &mut Vec::new()).expect_err("should not be supported");
+            assert!(format!("{:#}", err).contains("Unsupported image type"), "{:#}", err);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new()?.run()
+    }
+}
+
+mod containers {
+    use super::*;
+
+    // Two containers are captured in the same image. Each container has its own CRIU socket, and
+    // gets its own files back on restore, even when the filenames are the same.
+    const CONTAINERS: [&str; 2] = ["app", "sidecar"];
+
+    struct Test {
+        other_criu: Option<Criu>,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            Self { other_criu: None }
+        }
+
+        fn connect(&self, container: &str, socket_name: &str) -> Result<Criu> {
+            Criu::connect(self.images_dir().join(container).join(socket_name))
+        }
+    }
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from("/tmp/test-criu-image-streamer-containers") }
+        fn containers(&self) -> Vec<String> { CONTAINERS.iter().map(|c| c.to_string()).collect() }
+
+        fn criu_checkpoint_connect(&mut self, mut checkpoint: StreamerCheckpointContext)
+            -> Result<CheckpointContext>
+        {
+            assert_eq!(read_line(&mut checkpoint.progress)?, "socket-init");
+            let criu = self.connect(CONTAINERS[0], "streamer-capture.sock")?;
+            self.other_criu = Some(self.connect(CONTAINERS[1], "streamer-capture.sock")?);
+            Ok(CheckpointContext { streamer: checkpoint, criu })
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            let other_criu = self.other_criu.as_mut().unwrap();
+            checkpoint.criu.write_img_file("file.img")?
+                .write_all(CONTAINERS[0].as_bytes())?;
+            other_criu.write_img_file("file.img")?
+                .write_all(CONTAINERS[1].as_bytes())?;
+            other_criu.write_img_file("other.img")?
+                .write_all("only in the sidecar".as_bytes())?;
+            Ok(())
+        }
+
+        fn finish_checkpoint(&mut self, mut checkpoint: CheckpointContext) -> Result<Stats> {
+            // The capture is done once all containers are done.
+            self.other_criu.take().unwrap().finish()?;
+            checkpoint.criu.finish()?;
+            let stats: Stats = read_stats(&mut checkpoint.streamer.progress)?;
+            checkpoint.streamer.capture_thread.join().unwrap();
+            Ok(stats)
+        }
+
+        fn criu_restore_connect(&mut self, mut restore: StreamerRestoreContext)
+            -> Result<RestoreContext>
+        {
+            assert_eq!(read_line(&mut restore.progress)?, "socket-init");
+            let criu = self.connect(CONTAINERS[0], "streamer-serve.sock")?;
+            self.other_criu = Some(self.connect(CONTAINERS[1], "streamer-serve.sock")?);
+            Ok(RestoreContext { streamer: restore, criu })
+        }
+
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            let other_criu = self.other_criu.as_mut().unwrap();
+            assert_eq!(restore.criu.read_img_file_into_vec("file.img")?, CONTAINERS[0].as_bytes());
+            assert!(restore.criu.maybe_read_img_file("other.img")?.is_none(),
+                    "Files of a container should not be served to other containers");
+            assert_eq!(other_criu.read_img_file_into_vec("file.img")?, CONTAINERS[1].as_bytes());
+            assert_eq!(other_criu.read_img_file_into_vec("other.img")?, "only in the sidecar".as_bytes());
+            Ok(())
+        }
+
+        fn finish_restore(&mut self, restore: RestoreContext) -> Result<()> {
+            self.other_criu.take().unwrap().finish()?;
+            restore.criu.finish()?;
+            restore.streamer.extract_thread.join().unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod events {
+    use super::*;
+    use std::sync::mpsc::{channel, Receiver};
+
+    // The library user follows the files as they are captured and extracted, and the phases of
+    // serving the image. Events are forwarded to the test thread with a channel.
+    struct Test {
+        large_file: Vec<u8>,
+        capture_events: Option<Receiver<String>>,
+        extract_events: Option<Receiver<String>>,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            Self { large_file: get_rand_vec(2*MB), capture_events: None, extract_events: None }
+        }
+
+        fn on_event() -> (EventCallback, Receiver<String>) {
+            let (sender, receiver) = channel();
+            let on_event = Box::new(move |event: Event| {
+                let event = match event {
+                    Event::FileStart { filename } => format!("start {}", filename),
+                    Event::FileComplete { filename, size } => format!("complete {} {}", filename, size),
+                    Event::ImageComplete { .. } => "image complete".to_string(),
+                    Event::PhaseStart { phase } => format!("phase {:?}", phase),
+                };
+                sender.send(event).unwrap();
+            });
+            (on_event, receiver)
+        }
+
+        /// Checks the file events, and returns the phase events.
+        fn check_events(&self, events: &Receiver<String>) -> Vec<String> {
+            let (phases, mut events): (Vec<String>, Vec<String>) = events.try_iter()
+                .partition(|event| event.starts_with("phase "));
+            assert_eq!(events.pop().as_deref(), Some("image complete"));
+            // Files may be interleaved, but a file starts before it completes.
+            let position = |event: &str| events.iter().position(|e| e == event).unwrap();
+            assert!(position("start small.img") < position("complete small.img 11"));
+            assert!(position("start large.img") <
+                    position(&format!("complete large.img {}", self.large_file.len())));
+            assert_eq!(events.len(), 4);
+            phases
+        }
+    }
+
+    impl TestImpl for Test {
+        fn capture_on_event(&mut self) -> Option<EventCallback> {
+            let (on_event, events) = Self::on_event();
+            self.capture_events = Some(events);
+            Some(on_event)
+        }
+
+        fn extract_on_event(&mut self) -> Option<EventCallback> {
+            let (on_event, events) = Self::on_event();
+            self.extract_events = Some(events);
+            Some(on_event)
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("small.img")?
+                .write_all("hello world".as_bytes())?;
+            checkpoint.criu.write_img_file("large.img")?
+                .write_all(&self.large_file)?;
+            Ok(())
+        }
+
+        fn after_finish_checkpoint(&mut self, _checkpoint_stats: &Stats) -> Result<()> {
+            let phases = self.check_events(self.capture_events.as_ref().unwrap());
+            assert!(phases.is_empty(), "Only serving the image has phases");
+            Ok(())
+        }
+
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            // The image is fully extracted before the CRIU socket is ready.
+            let phases = self.check_events(self.extract_events.as_ref().unwrap());
+            assert_eq!(phases, ["phase Buffering", "phase Patching", "phase Serving"]);
+            assert!(restore.criu.read_img_file_into_vec("large.img")? == self.large_file);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod job_id {
+    use super::*;
+
+    // With a job id, the socket names are suffixed, and the default socket names are left alone
+    // for other streamers.
+    struct Test;
+
+    impl Test {
+        fn new() -> Self { Self }
+    }
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from("/tmp/test-criu-image-streamer-job-id") }
+        fn job_id(&self) -> Option<String> { Some("job-42".to_string()) }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            assert!(self.images_dir().join("streamer-capture-job-42.sock").exists());
+            assert!(!self.images_dir().join("streamer-capture.sock").exists());
+            checkpoint.criu.write_img_file("file.img")?
+                .write_all("hello world".as_bytes())?;
+            Ok(())
+        }
+
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            assert!(self.images_dir().join("streamer-serve-job-42.sock").exists());
+            let buf = restore.criu.read_img_file_into_vec("file.img")?;
+            assert_eq!(buf, "hello world".as_bytes(), "File data content mismatch");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod shard_reader {
+    use super::*;
+    use std::collections::HashMap;
+    use criu_image_streamer::{ShardReader, image::marker::Body};
+
+    // The markers of a captured image are read back with the public ShardReader, and the files
+    // are reassembled from them.
+    struct Test {
+        files: Vec<(&'static str, Vec<u8>)>,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            Self {
+                files: vec![
+                    ("small.img", get_rand_vec(1*KB)),
+                    ("large.img", get_rand_vec(3*MB)),
+                ],
+            }
+        }
+    }
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from("/tmp/test-criu-image-streamer-shard-reader") }
+
+        fn run(&mut self) -> Result<()> {
+            let images_dir = self.images_dir();
+            let (progress_r, progress_w) = new_pipe();
+            let mut progress = BufReader::new(progress_r);
+            let (shard_pipes, shard_threads) = spawn_shard_readers(self.num_shards());
+            let capture_thread = thread::spawn(move || {
+                CaptureBuilder::new(images_dir, shard_pipes)
+                    .progress_pipe(progress_w)
+                    .run()
+            });
+
+            assert_eq!(read_line(&mut progress)?, "socket-init");
+            let mut criu = Criu::connect(self.images_dir().join("streamer-capture.sock"))?;
+            for (filename, data) in &self.files {
+                criu.write_img_file(filename)?.write_all(data)?;
+            }
+            criu.finish()?;
+            capture_thread.join().unwrap()?;
+            let contents = shard_threads.into_iter()
+                .map(|t| t.join().unwrap())
+                .collect::<Result<Vec<_>>>()?;
+
+            let mut shard_reader = ShardReader::new(spawn_shard_writers(contents));
+            let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+            let mut current_filename = None;
+            let mut image_eof = false;
+            for (seq, item) in shard_reader.by_ref().enumerate() {
+                let (marker, data) = item?;
+                assert_eq!(marker.seq, seq as u64);
+                match marker.body.unwrap() {
+                    Body::Filename(filename) => current_filename = Some(filename),
+                    Body::FileData(size) => {
+                        assert_eq!(data.len(), size as usize);
+                        files.entry(current_filename.clone().unwrap()).or_default()
+                            .extend_from_slice(&data);
+                    }
+                    Body::ImageEof(_) => image_eof = true,
+                    _ => assert!(data.is_empty()),
+                }
+            }
+
+            assert!(image_eof);
+            assert!(shard_reader.image_uuid().is_some());
+            assert_eq!(files.len(), self.files.len());
+            for (filename, data) in &self.files {
+                assert!(files[*filename] == *data, "File data content mismatch");
+            }
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod signed_manifest {
+    use super::*;
+
+    const TEST_DATA: &str = "ext file data";
+
+    struct Test {
+        send_ext_pipe: Option<UnixPipe>,
+        recv_ext_pipe: Option<UnixPipe>,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            Self { send_ext_pipe: None, recv_ext_pipe: None }
+        }
+    }
+
+    impl TestImpl for Test {
+        fn sign_key(&self) -> Option<SigningKey> { Some(SigningKey::from_bytes(&[1; 32])) }
+        fn verify_key(&self) -> Option<VerifyingKey> { self.sign_key().map(|k| k.verifying_key()) }
+
+        fn capture_ext_files(&mut self) -> Vec<(String, UnixPipe)> {
+            let (pipe_r, pipe_w) = new_pipe();
+            self.send_ext_pipe = Some(pipe_w);
+            vec![("file.ext".to_string(), pipe_r)]
+        }
+
+        fn extract_ext_files(&mut self) -> Vec<(String, UnixPipe)> {
+            let (pipe_r, pipe_w) = new_pipe();
+            self.recv_ext_pipe = Some(pipe_r);
+            vec![("file.ext".to_string(), pipe_w)]
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            self.send_ext_pipe.take().unwrap().write_all(TEST_DATA.as_bytes())?;
+            checkpoint.criu.write_img_file("file.img")?
+                .write_all("hello world".as_bytes())?;
+            Ok(())
+        }
+
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            let mut buf = Vec::new();
+            self.recv_ext_pipe.take().unwrap().read_to_end(&mut buf)?;
+            assert_eq!(buf, TEST_DATA.as_bytes());
+
+            let buf = restore.criu.read_img_file_into_vec("file.img")?;
+            assert_eq!(buf, "hello world".as_bytes(), "File data content mismatch");
+
+            // The manifest is not meant for CRIU
+            let file = restore.criu.maybe_read_img_file("streamer-manifest.json")?;
+            assert!(file.is_none(), "The manifest should not be served");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod serve_phases {
+    use super::*;
+
+    // The phase transitions are reported around the usual progress messages, and the duration of
+    // each phase is reported once CRIU is done.
+    struct Test;
+
+    impl Test {
+        fn new() -> Self { Self }
+    }
+
+    fn read_phase<R: Read>(progress: &mut BufReader<R>) -> Result<Phase> {
+        let transition: PhaseTransition = serde_json::from_str(&read_line(progress)?)?;
+        Ok(transition.phase)
+    }
+
+    impl TestImpl for Test {
+        fn sign_key(&self) -> Option<SigningKey> { Some(SigningKey::from_bytes(&[1; 32])) }
+        fn verify_key(&self) -> Option<VerifyingKey> { self.sign_key().map(|k| k.verifying_key()) }
+        fn serve_phases(&self) -> bool { true }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("file.img")?
+                .write_all("hello world".as_bytes())?;
+            Ok(())
+        }
+
+        fn finish_image_extraction(&mut self, restore: &mut StreamerRestoreContext) -> Result<Stats> {
+            assert_eq!(read_phase(&mut restore.progress)?, Phase::Buffering);
+            read_stats(&mut restore.progress)
+        }
+
+        fn criu_restore_connect(&mut self, mut restore: StreamerRestoreContext)
+            -> Result<RestoreContext>
+        {
+            assert_eq!(read_phase(&mut restore.progress)?, Phase::Verifying);
+            assert_eq!(read_phase(&mut restore.progress)?, Phase::Patching);
+            assert_eq!(read_phase(&mut restore.progress)?, Phase::Serving);
+            assert_eq!(read_line(&mut restore.progress)?, "socket-init");
+            let criu = Criu::connect(self.images_dir().join(IMG_STREAMER_SERVE_SOCKET_NAME))?;
+            Ok(RestoreContext { streamer: restore, criu })
+        }
+
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            let buf = restore.criu.read_img_file_into_vec("file.img")?;
+            assert_eq!(buf, "hello world".as_bytes(), "File data content mismatch");
+            Ok(())
+        }
+
+        fn finish_restore(&mut self, mut restore: RestoreContext) -> Result<()> {
+            restore.criu.finish()?;
+            assert_eq!(read_phase(&mut restore.streamer.progress)?, Phase::Done);
+            let stats: PhaseStats = serde_json::from_str(&read_line(&mut restore.streamer.progress)?)?;
+            let phases: Vec<Phase> = stats.phases.iter().map(|p| p.phase).collect();
+            assert_eq!(phases, [Phase::Buffering, Phase::Verifying, Phase::Patching, Phase::Serving]);
+            restore.streamer.extract_thread.join().unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod serve_request_stats {
+    use super::*;
+
+    // CRIU requests a file that exists, one that doesn't, and another one that exists. The
+    // requests are reported in that order once CRIU is done.
+    struct Test;
+
+    impl Test {
+        fn new() -> Self { Self }
+    }
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from("/tmp/test-criu-image-streamer-request-stats") }
+        fn serve_request_stats(&self) -> bool { true }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("file1.img")?
+                .write_all("hello world".as_bytes())?;
+            checkpoint.criu.write_img_file("file2.img")?
+                .write_all(&get_rand_vec(1*MB))?;
+            Ok(())
+        }
+
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            restore.criu.read_img_file_into_vec("file1.img")?;
+            assert!(restore.criu.maybe_read_img_file("missing.img")?.is_none());
+            restore.criu.read_img_file_into_vec("file2.img")?;
+            Ok(())
+        }
+
+        fn finish_restore(&mut self, mut restore: RestoreContext) -> Result<()> {
+            restore.criu.finish()?;
+            let stats: ServeStats = serde_json::from_str(&read_line(&mut restore.streamer.progress)?)?;
+            let requests: Vec<_> = stats.requests.iter()
+                .map(|r| (r.filename.as_str(), r.exists, r.size))
+                .collect();
+            assert_eq!(requests, [("file1.img", true, 11), ("missing.img", false, 0),
+                                  ("file2.img", true, 1*MB as u64)]);
+            assert!(stats.requests.windows(2).all(|w| w[0].requested_at_millis <= w[1].requested_at_millis));
+            restore.streamer.extract_thread.join().unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod serve_standby {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    // The image is received, and served only once the controller sends go. Connections that
+    // don't send a command are ignored.
+    struct Test;
+
+    impl Test {
+        fn new() -> Self { Self }
+    }
+
+    impl TestImpl for Test {
+        fn serve_standby(&self) -> bool { true }
+        fn serve_phases(&self) -> bool { true }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("file.img")?
+                .write_all("hello world".as_bytes())?;
+            Ok(())
+        }
+
+        fn finish_image_extraction(&mut self, restore: &mut StreamerRestoreContext) -> Result<Stats> {
+            assert_eq!(read_line(&mut restore.progress)?, r#"{"phase":"buffering"}"#);
+            read_stats(&mut restore.progress)
+        }
+
+        fn criu_restore_connect(&mut self, mut restore: StreamerRestoreContext)
+            -> Result<RestoreContext>
+        {
+            assert_eq!(read_line(&mut restore.progress)?, r#"{"phase":"patching"}"#);
+            assert_eq!(read_line(&mut restore.progress)?, r#"{"phase":"standby"}"#);
+            assert_eq!(read_line(&mut restore.progress)?, "standby");
+
+            let control_socket = self.images_dir().join(IMG_STREAMER_CONTROL_SOCKET_NAME);
+            drop(UnixStream::connect(&control_socket)?);
+            UnixStream::connect(&control_socket)?.write_all(b"go\n")?;
+
+            assert_eq!(read_line(&mut restore.progress)?, r#"{"phase":"serving"}"#);
+            assert_eq!(read_line(&mut restore.progress)?, "socket-init");
+            let criu = Criu::connect(self.images_dir().join(IMG_STREAMER_SERVE_SOCKET_NAME))?;
+            Ok(RestoreContext { streamer: restore, criu })
+        }
+
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            let buf = restore.criu.read_img_file_into_vec("file.img")?;
+            assert_eq!(buf, "hello world".as_bytes(), "File data content mismatch");
+            Ok(())
+        }
+
+        fn finish_restore(&mut self, mut restore: RestoreContext) -> Result<()> {
+            restore.criu.finish()?;
+            assert_eq!(read_line(&mut restore.streamer.progress)?, r#"{"phase":"done"}"#);
+            let stats: PhaseStats = serde_json::from_str(&read_line(&mut restore.streamer.progress)?)?;
+            let phases: Vec<Phase> = stats.phases.iter().map(|p| p.phase).collect();
+            assert_eq!(phases, [Phase::Buffering, Phase::Patching, Phase::Standby, Phase::Serving]);
+            restore.streamer.extract_thread.join().unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod serve_rolling {
+    use super::*;
+    use std::fs;
+
+    // The shards carry a previous image followed by the image of the test workflow, as with
+    // periodic checkpoints. CRIU gets the last image.
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-rolling";
+
+    struct Test {
+        previous_image: Vec<Vec<u8>>,
+        socket_init_seen: bool,
+    }
+
+    impl Test {
+        fn new() -> Result<Self> {
+            let _ = fs::remove_dir_all(IMAGES_DIR);
+            Ok(Self {
+                previous_image: Self::capture_previous_image()?,
+                socket_init_seen: false,
+            })
+        }
+
+        fn capture_previous_image() -> Result<Vec<Vec<u8>>> {
+            let images_dir = PathBuf::from(IMAGES_DIR).join("previous");
+            let (progress_r, progress_w) = new_pipe();
+            let mut progress = BufReader::new(progress_r);
+            let (shard_pipes, shard_threads) = spawn_shard_readers(4);
+            let capture_thread = {
+                let images_dir = images_dir.clone();
+                thread::spawn(move || {
+                    CaptureBuilder::new(images_dir, shard_pipes)
+                        .progress_pipe(progress_w)
+                        .run()
+                })
+            };
+
+            assert_eq!(read_line(&mut progress)?, "socket-init");
+            let mut criu = Criu::connect(images_dir.join(IMG_STREAMER_CAPTURE_SOCKET_NAME))?;
+            criu.write_img_file("file.img")?.write_all(b"previous")?;
+            criu.write_img_file("previous.img")?.write_all(b"only in the previous image")?;
+            criu.finish()?;
+            capture_thread.join().unwrap()?;
+
+            shard_threads.into_iter().map(|t| t.join().unwrap()).collect()
+        }
+    }
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from(IMAGES_DIR) }
+        fn serve_rolling(&self) -> bool { true }
+
+        fn shards(&mut self)-> Vec<(UnixPipe, UnixPipe)> {
+            self.previous_image.iter().map(|previous_shard| {
+                let (mut capture_shard_r, capture_shard_w) = new_pipe();
+                let (extract_shard_r, mut extract_shard_w) = new_pipe();
+                let previous_shard = previous_shard.clone();
+
+                thread::spawn(move || -> Result<()> {
+                    extract_shard_w.write_all(&previous_shard)?;
+                    std::io::copy(&mut capture_shard_r, &mut extract_shard_w)?;
+                    Ok(())
+                });
+
+                (extract_shard_r, capture_shard_w)
+            }).collect()
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("file.img")?.write_all(b"latest")?;
+            Ok(())
+        }
+
+        fn finish_image_extraction(&mut self, restore: &mut StreamerRestoreContext) -> Result<Stats> {
+            // The CRIU socket is bound once the first image is ready, concurrently with the
+            // reception of the second image.
+            let mut stats = Vec::new();
+            let mut num_images_ready = 0;
+            while num_images_ready < 2 {
+                match read_line(&mut restore.progress)?.as_str() {
+                    "socket-init" => self.socket_init_seen = true,
+                    "image-ready" => num_images_ready += 1,
+                    line => stats.push(serde_json::from_str::<Stats>(line)?),
                 }
+            }
+            assert_eq!(stats.len(), 2);
+            assert_ne!(stats[0].image_uuid, stats[1].image_uuid);
+            Ok(stats.pop().unwrap())
+        }
+
+        fn criu_restore_connect(&mut self, mut restore: StreamerRestoreContext)
+            -> Result<RestoreContext>
+        {
+            if !self.socket_init_seen {
+                assert_eq!(read_line(&mut restore.progress)?, "socket-init");
+            }
+            let criu = Criu::connect(self.images_dir().join(IMG_STREAMER_SERVE_SOCKET_NAME))?;
+            Ok(RestoreContext { streamer: restore, criu })
+        }
+
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            assert_eq!(restore.criu.read_img_file_into_vec("file.img")?, b"latest");
+            assert!(restore.criu.maybe_read_img_file("previous.img")?.is_none(),
+                    "The file of the previous image shouldn't be served");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new()?.run()
+    }
+}
+
+mod check_criu {
+    use super::*;
+    use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+    use criu_image_streamer::criu_check::check_criu;
+
+    // Fake criu binaries report their version, and fail the checks of unsupported features.
+
+    const DIR: &str = "/tmp/test-criu-image-streamer-check-criu";
+
+    fn fake_criu(name: &str, version: &str) -> Result<PathBuf> {
+        let path = Path::new(DIR).join(name);
+        fs::write(&path, format!(
+            "#!/bin/sh\n\
+             [ \"$1\" = --version ] && echo 'Version: {}' && exit 0\n\
+             [ \"$3\" = uffd ] && echo 'UFFD is not supported' >&2 && exit 1\n\
+             echo 'Looks good.'\n", version))?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+        Ok(path)
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        let _ = fs::remove_dir_all(DIR);
+        fs::create_dir_all(DIR)?;
+
+        let criu = fake_criu("criu-new", "3.17.1")?;
+        assert_eq!(check_criu(&criu, &["mem_dirty_track".to_string()])?, (3, 17));
+        let err = check_criu(&criu, &["uffd".to_string()]).expect_err("check_criu() should have failed");
+        assert!(format!("{:#}", err).contains("UFFD is not supported"), "{:#}", err);
+
+        let criu = fake_criu("criu-old", "3.14")?;
+        let err = check_criu(&criu, &[]).expect_err("check_criu() should have failed");
+        assert!(format!("{:#}", err).contains("CRIU 3.14 does not support image streaming"), "{:#}", err);
+
+        let err = check_criu(&Path::new(DIR).join("no-criu"), &[]).expect_err("check_criu() should have failed");
+        assert!(format!("{:#}", err).contains("Is CRIU installed?"), "{:#}", err);
+        Ok(())
+    }
+}
+
+mod doctor {
+    use super::*;
+    use criu_image_streamer::doctor::{check_pipe_max_size, check_pipe_user_pages, check_memory, Status};
+
+    #[test]
+    fn test_pipe_limits() {
+        let check = check_pipe_max_size(Some(1*MB as u64), true);
+        assert_eq!(check.status, Status::Warning);
+        assert_eq!(check.remediation.as_deref(), Some("sysctl -w fs.pipe-max-size=4194304"));
+        assert_eq!(check_pipe_max_size(Some(1*MB as u64), false).status, Status::Ok);
+        assert_eq!(check_pipe_max_size(Some(4*MB as u64), true).status, Status::Ok);
+
+        let check = check_pipe_user_pages("pipe-user-pages-soft", Some(1), true);
+        assert_eq!(check.status, Status::Warning);
+        assert!(check.remediation.unwrap().starts_with("sysctl -w fs.pipe-user-pages-soft="));
+        assert_eq!(check_pipe_user_pages("pipe-user-pages-hard", Some(0), true).status, Status::Ok);
+    }
+
+    #[test]
+    fn test_memory() {
+        assert_eq!(check_memory(Some(1*MB as u64), Some(2*MB as u64)).status, Status::Error);
+        assert_eq!(check_memory(Some(2*MB as u64), Some(1*MB as u64)).status, Status::Ok);
+        assert_eq!(check_memory(Some(1*MB as u64), None).status, Status::Ok);
+    }
+}
+
+mod criu_watchdog {
+    use super::*;
+    use std::{fs, path::Path, time::Duration};
+    use criu_image_streamer::criu_watchdog::CriuWatchdogError;
+    use nix::{sys::wait::waitpid, unistd::{fork, ForkResult}};
+
+    // CRIU stalls, or dies while its file pipes are kept open by other processes. The streamer
+    // must fail instead of waiting forever.
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-criu-watchdog";
+
+    fn start_capture(images_dir: &Path, criu_timeout: Option<Duration>)
+        -> Result<thread::JoinHandle<Result<()>>>
+    {
+        let _ = fs::remove_dir_all(images_dir);
+        let (progress_r, progress_w) = new_pipe();
+        let (shard_pipes, _shard_threads) = spawn_shard_readers(1);
+        let capture_thread = {
+            let images_dir = images_dir.to_path_buf();
+            thread::spawn(move || {
+                CaptureBuilder::new(images_dir, shard_pipes)
+                    .progress_pipe(progress_w)
+                    .criu_timeout(criu_timeout)
+                    .run()
+            })
+        };
+        assert_eq!(read_line(&mut BufReader::new(progress_r))?, "socket-init");
+        Ok(capture_thread)
+    }
+
+    fn expect_err(thread: thread::JoinHandle<Result<()>>, msg: &str) {
+        let err = thread.join().unwrap().expect_err("The streamer should have failed");
+        assert!(format!("{:#}", err).contains(msg), "{:#}", err);
+    }
+
+    #[test]
+    fn test_stalled_capture() -> Result<()> {
+        let images_dir = Path::new(IMAGES_DIR).join("stalled-capture");
+        let capture_thread = start_capture(&images_dir, Some(Duration::from_secs(1)))?;
+
+        let mut criu = Criu::connect(images_dir.join(IMG_STREAMER_CAPTURE_SOCKET_NAME))?;
+        let mut pipe = criu.write_img_file("file.img")?;
+        pipe.write_all(b"some data, and then nothing")?;
+
+        expect_err(capture_thread, "CRIU made no progress for 1s");
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_without_pipe() -> Result<()> {
+        let images_dir = Path::new(IMAGES_DIR).join("request-without-pipe");
+        let timeout = Duration::from_secs(1);
+        let capture_thread = start_capture(&images_dir, Some(timeout))?;
+
+        let mut criu = Criu::connect(images_dir.join(IMG_STREAMER_CAPTURE_SOCKET_NAME))?;
+        criu.send_file_request("file.img")?;
+
+        let err = capture_thread.join().unwrap().expect_err("The streamer should have failed");
+        assert_eq!(err.downcast_ref::<CriuWatchdogError>(), Some(&CriuWatchdogError::Stalled { timeout }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dead_criu() -> Result<()> {
+        let images_dir = Path::new(IMAGES_DIR).join("dead-criu");
+        let capture_thread = start_capture(&images_dir, None)?;
+
+        // The forked CRIU exits while we keep the write end of its file pipe open.
+        let (pipe_r, pipe_w) = new_pipe();
+        let socket_path = images_dir.join(IMG_STREAMER_CAPTURE_SOCKET_NAME);
+        match fork()? {
+            ForkResult::Child => {
+                let result = Criu::connect(socket_path)
+                    .and_then(|mut criu| criu.write_img_file_with_pipe("file.img", &pipe_r));
+                unsafe { libc::_exit(result.is_err() as i32) };
+            }
+            ForkResult::Parent { child } => {
+                drop(pipe_r);
+                expect_err(capture_thread, &format!("CRIU (pid {}) died", child));
+                waitpid(child, None)?;
+                drop(pipe_w);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_stalled_serve() -> Result<()> {
+        let images_dir = Path::new(IMAGES_DIR).join("stalled-serve");
+        let (shard_pipes, shard_threads) = spawn_shard_readers(1);
+        let capture_thread = {
+            let images_dir = images_dir.clone();
+            let _ = fs::remove_dir_all(&images_dir);
+            let (progress_r, progress_w) = new_pipe();
+            let capture_thread = thread::spawn(move || {
+                CaptureBuilder::new(images_dir, shard_pipes)
+                    .progress_pipe(progress_w)
+                    .run()
+            });
+            assert_eq!(read_line(&mut BufReader::new(progress_r))?, "socket-init");
+            capture_thread
+        };
+        let mut criu = Criu::connect(images_dir.join(IMG_STREAMER_CAPTURE_SOCKET_NAME))?;
+        criu.write_img_file("pages.img")?.write_all(&get_rand_vec(4*MB))?;
+        criu.finish()?;
+        capture_thread.join().unwrap()?;
+        let shards = shard_threads.into_iter().map(|t| t.join().unwrap()).collect::<Result<_>>()?;
+
+        // CRIU gets the pipe of the file, and never reads it.
+        let (progress_r, progress_w) = new_pipe();
+        let mut progress = BufReader::new(progress_r);
+        let shard_pipes = spawn_shard_writers(shards);
+        let serve_thread = {
+            let images_dir = images_dir.clone();
+            thread::spawn(move || {
+                ExtractBuilder::new(images_dir, shard_pipes)
+                    .progress_pipe(progress_w)
+                    .criu_timeout(Some(Duration::from_secs(1)))
+                    .serve()
+            })
+        };
+        read_stats(&mut progress)?;
+        assert_eq!(read_line(&mut progress)?, "socket-init");
+        let mut criu = Criu::connect(images_dir.join(IMG_STREAMER_SERVE_SOCKET_NAME))?;
+        let _pipe = criu.read_img_file("pages.img")?;
+
+        expect_err(serve_thread, "CRIU made no progress for 1s");
+        Ok(())
+    }
+}
+
+mod capture_abort {
+    use super::*;
+    use std::fs;
+    use criu_image_streamer::manifest::MANIFEST_FILENAME;
+
+    // On a live migration, the extraction reads the shards as the capture writes them. When the
+    // capture fails, the extraction must fail right away, even though the shards are kept open
+    // by another process.
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-capture-abort";
+
+    #[test]
+    fn test() -> Result<()> {
+        let _ = fs::remove_dir_all(IMAGES_DIR);
+        let capture_dir = PathBuf::from(IMAGES_DIR).join("capture");
+        let extract_dir = PathBuf::from(IMAGES_DIR).join("extract");
+
+        let (shards_r, shards_w): (Vec<_>, Vec<_>) = (0..4).map(|_| new_pipe()).unzip();
+        let _shards_w_held = shards_w.iter().map(UnixPipe::try_clone).collect::<std::io::Result<Vec<_>>>()?;
+
+        let (progress_r, progress_w) = new_pipe();
+        let capture_thread = {
+            let capture_dir = capture_dir.clone();
+            thread::spawn(move || {
+                CaptureBuilder::new(capture_dir, shards_w)
+                    .progress_pipe(progress_w)
+                    .run()
+            })
+        };
+        let extract_thread = thread::spawn(move || ExtractBuilder::new(extract_dir, shards_r).extract());
+
+        assert_eq!(read_line(&mut BufReader::new(progress_r))?, "socket-init");
+        let mut criu = Criu::connect(capture_dir.join(IMG_STREAMER_CAPTURE_SOCKET_NAME))?;
+        criu.write_img_file("file.img")?.write_all(&get_rand_vec(1*MB))?;
+        // CRIU is not allowed to send this file, which fails the capture. The capture may close
+        // the socket before the pipe of the file is sent.
+        let _ = criu.write_img_file(MANIFEST_FILENAME);
+
+        let err = capture_thread.join().unwrap().expect_err("The capture should have failed");
+        assert!(format!("{:#}", err).contains("reserved filename"), "{:#}", err);
+        let err = extract_thread.join().unwrap().expect_err("The extraction should have failed");
+        assert!(format!("{:#}", err).contains("The capture failed, the image is invalid"), "{:#}", err);
+        Ok(())
+    }
+}
+
+mod signed_manifest_wrong_key {
+    use super::*;
+
+    // The image is signed with a key that doesn't match the verifying key. serve() must refuse
+    // to serve the image to CRIU.
+
+    struct Test;
+
+    impl Test {
+        fn new() -> Self { Self }
+    }
+
+    impl TestImpl for Test {
+        fn sign_key(&self) -> Option<SigningKey> { Some(SigningKey::from_bytes(&[1; 32])) }
+        fn verify_key(&self) -> Option<VerifyingKey> {
+            Some(SigningKey::from_bytes(&[2; 32]).verifying_key())
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("file.img")?
+                .write_all("hello world".as_bytes())?;
+            Ok(())
+        }
+
+        fn run(&mut self) -> Result<()> {
+            let (checkpoint, mut restore) = self.bootstrap()?;
+
+            let mut checkpoint = self.criu_checkpoint_connect(checkpoint)?;
+            self.send_img_files(&mut checkpoint)?;
+            self.read_progress_checkpoint_started(&mut checkpoint)?;
+            self.finish_checkpoint(checkpoint)?;
+
+            self.finish_image_extraction(&mut restore)?;
+            assert!(read_line(&mut restore.progress).is_err(), "The CRIU socket should not be ready");
+            assert!(restore.extract_thread.join().is_err(), "serve() should have failed");
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+mod signed_manifest_wrong_key_extract {
+    use super::*;
+    use std::fs;
+
+    // The image is signed with a key that doesn't match the verifying key. extract() must fail,
+    // and not leave the unverified files in images_dir.
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-wrong-key-extract";
+
+    struct Test;
+
+    impl TestImpl for Test {
+        fn images_dir(&self) -> PathBuf { PathBuf::from(IMAGES_DIR) }
+        fn serve_image(&mut self) -> bool { false }
+        fn sign_key(&self) -> Option<SigningKey> { Some(SigningKey::from_bytes(&[1; 32])) }
+        fn verify_key(&self) -> Option<VerifyingKey> {
+            Some(SigningKey::from_bytes(&[2; 32]).verifying_key())
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("file.img")?
+                .write_all("hello world".as_bytes())?;
+            Ok(())
+        }
+
+        fn run(&mut self) -> Result<()> {
+            let (checkpoint, restore) = self.bootstrap()?;
+
+            let mut checkpoint = self.criu_checkpoint_connect(checkpoint)?;
+            self.send_img_files(&mut checkpoint)?;
+            self.read_progress_checkpoint_started(&mut checkpoint)?;
+            self.finish_checkpoint(checkpoint)?;
+
+            assert!(restore.extract_thread.join().is_err(), "extract() should have failed");
+            for filename in &["file.img", "streamer-manifest.json", "streamer-manifest.sig"] {
+                assert!(!self.images_dir().join(filename).exists(), "{} was left", filename);
+            }
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        let _ = fs::remove_dir_all(IMAGES_DIR);
+        Test.run()
+    }
+}
+
+mod audit_log {
+    use super::*;
+    use std::fs;
+    use serde_json::Value;
+
+    // "hello world" is served in full to CRIU, "unused.img" is never requested by CRIU.
+
+    const HELLO_WORLD_SHA256: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    struct Test {
+        capture_log: PathBuf,
+        extract_log: PathBuf,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            let capture_log = PathBuf::from("/tmp/test-criu-image-streamer-audit-capture.log");
+            let extract_log = PathBuf::from("/tmp/test-criu-image-streamer-audit-extract.log");
+            let _ = fs::remove_file(&capture_log);
+            let _ = fs::remove_file(&extract_log);
+            Self { capture_log, extract_log }
+        }
+
+        fn read_records(path: &PathBuf) -> Result<Vec<Value>> {
+            fs::read_to_string(path)?
+                .lines()
+                .map(|line| Ok(serde_json::from_str(line)?))
+                .collect()
+        }
+    }
+
+    impl TestImpl for Test {
+        fn capture_audit_log(&mut self) -> Option<AuditLog> {
+            Some(AuditLog::open(&self.capture_log).unwrap())
+        }
+
+        fn extract_audit_log(&mut self) -> Option<AuditLog> {
+            Some(AuditLog::open(&self.extract_log).unwrap())
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file("file.img")?
+                .write_all("hello world".as_bytes())?;
+            checkpoint.criu.write_img_file("unused.img")?
+                .write_all("unused".as_bytes())?;
+            Ok(())
+        }
+
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            let buf = restore.criu.read_img_file_into_vec("file.img")?;
+            assert_eq!(buf, "hello world".as_bytes(), "File data content mismatch");
+            Ok(())
+        }
+
+        fn after_finish_image_extraction(&mut self, _restore_stats: &Stats) -> Result<()> {
+            let records = Self::read_records(&self.capture_log)?;
+            assert_eq!(records.len(), 2);
+            assert_eq!(records[0]["filename"], "file.img");
+            assert_eq!(records[0]["direction"], "in");
+            assert_eq!(records[0]["size"], 11);
+            assert_eq!(records[0]["sha256"], HELLO_WORLD_SHA256);
+            assert!(records[0]["timestamp_millis"].is_u64());
+            assert_eq!(records[1]["filename"], "unused.img");
+            Ok(())
+        }
 
-                Ok(())
-            };
+        fn run(&mut self) -> Result<()> {
+            let (checkpoint, mut restore) = self.bootstrap()?;
 
-            let write_files = |i: usize| {
-                for j in 0..NUM_MEDIUM_FILES {
-                    write_img_file(&format!("medium-{}-{}.img", i, j), &self.medium_file).unwrap();
-                }
-                for j in 0..NUM_LARGE_FILES {
-                    write_img_file(&format!("large-{}-{}.img", i, j), &self.large_file).unwrap();
-                }
-                for j in 0..NUM_SMALL_FILES {
-                    write_img_file(&format!("small-{}-{}.img", i, j), &self.small_file).unwrap();
-                }
-                for j in 0..NUM_MEDIUM_CHUNKED_FILES {
-                    write_img_file_chunked(&format!("mediumc-{}-{}.img", i, j), &self.medium_file).unwrap();
-                }
-            };
+            let mut checkpoint = self.criu_checkpoint_connect(checkpoint)?;
+            self.send_img_files(&mut checkpoint)?;
+            self.read_progress_checkpoint_started(&mut checkpoint)?;
+            self.finish_checkpoint(checkpoint)?;
 
-            // Write without threads first. Should be easier
-            write_files(0);
+            let stats = self.finish_image_extraction(&mut restore)?;
+            self.after_finish_image_extraction(&stats)?;
 
-            thread::scope(|s| {
-                for i in 0..NUM_THREADS {
-                    s.spawn(move |_| write_files(i+1));
-                }
-            }).unwrap();
+            let mut restore = self.criu_restore_connect(restore)?;
+            self.recv_img_files(&mut restore)?;
+            self.finish_restore(restore)?;
+
+            // Only the files that CRIU requested are audited when serving
+            let records = Self::read_records(&self.extract_log)?;
+            assert_eq!(records.len(), 1);
+            assert_eq!(records[0]["filename"], "file.img");
+            assert_eq!(records[0]["direction"], "out");
+            assert_eq!(records[0]["sha256"], HELLO_WORLD_SHA256);
 
             Ok(())
         }
+    }
 
-        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
-            let mut read_img_file = |name: &str| -> Result<Vec<u8>> {
-                restore.criu.read_img_file_into_vec(name)
-            };
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
 
-            for i in 0..NUM_THREADS+1 {
-                for j in 0..NUM_MEDIUM_FILES {
-                    assert!(read_img_file(&format!("medium-{}-{}.img", i, j))? == self.medium_file);
-                }
-                for j in 0..NUM_LARGE_FILES {
-                    assert!(read_img_file(&format!("large-{}-{}.img", i, j))? == self.large_file);
-                }
-                for j in 0..NUM_SMALL_FILES {
-                    assert!(read_img_file(&format!("small-{}-{}.img", i, j))? == self.small_file);
-                }
-                for j in 0..NUM_MEDIUM_CHUNKED_FILES {
-                    assert!(read_img_file(&format!("mediumc-{}-{}.img", i, j))? == self.medium_file);
-                }
+    // With the proto progress format, the records must not break the length-delimited messages.
+    #[test]
+    fn test_to_progress_proto() -> Result<()> {
+        use criu_image_streamer::{audit::Direction, manifest::FileDigest, stats::{progress, Progress}, util::ProgressFormat};
+        use prost::Message;
+
+        let path = PathBuf::from("/tmp/test-criu-image-streamer-audit-progress.log");
+        let progress_pipe = fs::File::create(&path)?;
+        let mut audit_log = AuditLog::to_progress(progress_pipe, ProgressFormat::Proto);
+        let digest = FileDigest { size: 11, sha256: HELLO_WORLD_SHA256.to_string() };
+        audit_log.record(Direction::In, "file.img", &digest)?;
+        audit_log.try_clone()?.record(Direction::Out, "file.img", &digest)?;
+
+        let buf = fs::read(&path)?;
+        let mut buf = &buf[..];
+        let mut records = Vec::new();
+        while !buf.is_empty() {
+            match Progress::decode_length_delimited(&mut buf)?.body {
+                Some(progress::Body::Line(line)) => records.push(serde_json::from_str::<Value>(&line)?),
+                body => panic!("Unexpected progress message {:?}", body),
             }
+        }
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["direction"], "in");
+        assert_eq!(records[1]["direction"], "out");
+        assert_eq!(records[1]["sha256"], HELLO_WORLD_SHA256);
+        Ok(())
+    }
+}
+
+mod long_filename {
+    use super::*;
+
+    // Filenames can be long (e.g., deep ghost file paths). They must go through the CRIU
+    // protocol and the image markers.
+
+    struct Test {
+        filename: String,
+        max_marker_size: usize,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            let filename = format!("{}file.img", "deep/".repeat(4*KB));
+            Self { filename, max_marker_size: DEFAULT_MAX_PB_SIZE }
+        }
+
+        // The filename exceeds the default limit, which both sides must raise.
+        fn with_raised_limit() -> Self {
+            let filename = format!("{}file.img", "deep/".repeat(16*KB));
+            Self { filename, max_marker_size: 128*KB }
+        }
+    }
+
+    impl TestImpl for Test {
+        fn max_marker_size(&self) -> usize { self.max_marker_size }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            checkpoint.criu.write_img_file(&self.filename)?
+                .write_all("hello world".as_bytes())?;
+            Ok(())
+        }
 
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            let buf = restore.criu.read_img_file_into_vec(&self.filename)?;
+            assert_eq!(buf, "hello world".as_bytes(), "File data content mismatch");
             Ok(())
         }
     }
@@ -660,68 +4319,184 @@ mod stress {
     fn test() -> Result<()> {
         Test::new().run()
     }
+
+    #[test]
+    fn test_raised_limit() -> Result<()> {
+        Test::with_raised_limit().run()
+    }
 }
 
-mod splice_bug {
-    // The 4.14.67 kernel can corrupt data with splice(), but the 4.14.121 has that bug fixed.
-    // This test makes it obvious. It's a subset of the stress test above, but much faster.
-    // Maybe the fix is https://github.com/torvalds/linux/commit/1bdc347
-    // We should be careful not to use such buggy kernel.
+mod min_pipe_capacity {
     use super::*;
-    use std::sync::Mutex;
 
-    const NUM_MEDIUM_CHUNKED_FILES: usize = 100;
-    const MEDIUM_FILE_SIZE: usize = 10*KB;
-    const CHUNK_SIZE: usize = 10;
+    // Shard pipes that can't be given the minimum capacity fail the operation right away. No pipe
+    // can be given i32::MAX bytes.
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-min-pipe-capacity";
+
+    #[test]
+    fn test_capture() {
+        let (_shard_r, shard_w) = new_pipe();
+        let err = CaptureBuilder::new(IMAGES_DIR, vec![shard_w])
+            .min_pipe_capacity(Some(i32::MAX))
+            .run()
+            .expect_err("The capture should have failed");
+        assert!(format!("{:#}", err).contains("below the minimum"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_extract() {
+        let err = ExtractBuilder::new(IMAGES_DIR, spawn_shard_writers(vec![Vec::new()]))
+            .min_pipe_capacity(Some(i32::MAX))
+            .extract_to_stream(&mut Vec::new())
+            .expect_err("The extraction should have failed");
+        assert!(format!("{:#}", err).contains("below the minimum"), "{:#}", err);
+    }
+}
+
+#[cfg(feature = "fault-injection")]
+mod transient_faults {
+    use super::*;
+
+    // Delays, short reads/writes, and EINTR must be tolerated by both capture and extract.
+    // Run with `cargo test --features fault-injection`.
+
+    const NUM_FILES: usize = 20;
 
     struct Test {
-        medium_file: Vec<u8>,
+        files: Vec<Vec<u8>>,
     }
 
     impl Test {
         fn new() -> Self {
-            let medium_file = get_rand_vec(MEDIUM_FILE_SIZE);
-            Self { medium_file }
+            let files = (0..NUM_FILES).map(|i| get_rand_vec(i*10*KB + 1)).collect();
+            Self { files }
+        }
+
+        fn faults(seed: u64) -> Faults {
+            Faults { seed, delay: 0.1, short_io: 0.3, eintr: 0.3, eof: 0.0, unsupported: 0.0 }
         }
     }
 
     impl TestImpl for Test {
+        fn capture_faults(&self) -> Faults { Self::faults(1) }
+        fn extract_faults(&self) -> Faults { Self::faults(2) }
+
         fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
-            let checkpoint = Mutex::new(checkpoint);
+            for (i, file) in self.files.iter().enumerate() {
+                checkpoint.criu.write_img_file(&format!("file-{}.img", i))?
+                    .write_all(file)?;
+            }
+            Ok(())
+        }
 
-            let write_img_file_chunked = |name: &str, data: &Vec<u8>| -> Result<()> {
-                let mut pipe = checkpoint.lock().unwrap().criu.write_img_file(name)?;
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            for (i, file) in self.files.iter().enumerate() {
+                let buf = restore.criu.read_img_file_into_vec(&format!("file-{}.img", i))?;
+                assert!(buf == *file, "File data content mismatch");
+            }
+            Ok(())
+        }
+    }
 
-                let mut offset = 0;
-                while offset < data.len() {
-                    let to_write = min(data.len() - offset, CHUNK_SIZE);
-                    pipe.write_all(&data[offset..offset+to_write])?;
-                    offset += to_write;
-                }
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
 
-                Ok(())
-            };
+#[cfg(feature = "fault-injection")]
+mod unsupported_splice {
+    use super::*;
 
-            let write_files = |i: usize| {
-                for j in 0..NUM_MEDIUM_CHUNKED_FILES {
-                    write_img_file_chunked(&format!("mediumc-{}-{}.img", i, j), &self.medium_file).unwrap();
-                }
-            };
+    // When splice() and vmsplice() are not supported (e.g., gVisor), data must be copied through
+    // userspace instead. Run with `cargo test --features fault-injection`.
 
-            write_files(0);
+    const NUM_FILES: usize = 10;
+
+    struct Test {
+        files: Vec<Vec<u8>>,
+    }
+
+    impl Test {
+        fn new() -> Self {
+            // Files must be large enough to be spliced rather than copied during capture.
+            let files = (0..NUM_FILES).map(|i| get_rand_vec(i*100*KB + 1)).collect();
+            Self { files }
+        }
+
+        fn faults(seed: u64) -> Faults {
+            Faults { seed, unsupported: 1.0, ..Faults::default() }
+        }
+    }
+
+    impl TestImpl for Test {
+        fn capture_faults(&self) -> Faults { Self::faults(1) }
+        fn extract_faults(&self) -> Faults { Self::faults(2) }
 
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            for (i, file) in self.files.iter().enumerate() {
+                checkpoint.criu.write_img_file(&format!("file-{}.img", i))?
+                    .write_all(file)?;
+            }
             Ok(())
         }
 
         fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
-            let mut read_img_file = |name: &str| -> Result<Vec<u8>> {
-                restore.criu.read_img_file_into_vec(name)
-            };
+            for (i, file) in self.files.iter().enumerate() {
+                let buf = restore.criu.read_img_file_into_vec(&format!("file-{}.img", i))?;
+                assert!(buf == *file, "File data content mismatch");
+            }
+            Ok(())
+        }
+    }
 
-            let i = 0;
-            for j in 0..NUM_MEDIUM_CHUNKED_FILES {
-                assert!(read_img_file(&format!("mediumc-{}-{}.img", i, j))? == self.medium_file);
+    #[test]
+    fn test() -> Result<()> {
+        Test::new().run()
+    }
+}
+
+#[cfg(feature = "fault-injection")]
+mod eof_fault {
+    use super::*;
+
+    // Shards hitting an unexpected EOF during extraction must fail the serve operation with an
+    // error, and must not hang either side. Run with `cargo test --features fault-injection`.
+
+    const NUM_FILES: usize = 20;
+
+    struct Test;
+
+    impl Test {
+        fn new() -> Self { Self }
+    }
+
+    impl TestImpl for Test {
+        fn extract_faults(&self) -> Faults {
+            Faults { seed: 3, eof: 0.5, ..Faults::default() }
+        }
+
+        fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
+            for i in 0..NUM_FILES {
+                checkpoint.criu.write_img_file(&format!("file-{}.img", i))?
+                    .write_all(&get_rand_vec(KB))?;
             }
+            Ok(())
+        }
+
+        fn run(&mut self) -> Result<()> {
+            let (checkpoint, mut restore) = self.bootstrap()?;
+
+            let mut checkpoint = self.criu_checkpoint_connect(checkpoint)?;
+            // Depending on when extraction fails, the capture may fail as well, as its shards
+            // are no longer read. We don't care about the outcome of the capture.
+            let _ = self.send_img_files(&mut checkpoint);
+            drop(checkpoint.criu);
+            let _ = checkpoint.streamer.capture_thread.join();
+
+            assert!(read_line(&mut restore.progress).is_err(), "The CRIU socket should not be ready");
+            assert!(restore.extract_thread.join().is_err(), "serve() should have failed");
 
             Ok(())
         }
@@ -733,51 +4508,172 @@ mod splice_bug {
     }
 }
 
-mod extract_to_disk {
+mod rate_limit {
     use super::*;
-    use std::fs::File;
+    use std::{
+        io::{BufRead, BufReader},
+        os::unix::net::UnixStream,
+        path::Path,
+        time::{Duration, Instant},
+    };
+
+    // The capture is throttled to a rate limit, which a controller may lift with the control
+    // socket while the capture runs.
+
+    const FILE_SIZE: usize = 12*MB; // Larger than what the CRIU and shard pipes can hold
 
     struct Test {
-        small_file: Vec<u8>,
-        medium_file: Vec<u8>,
+        file: Vec<u8>,
+        rate_limit: RateLimit,
+        lift_limit: bool,
+        start_time: Option<Instant>,
+        control_thread: Option<thread::JoinHandle<Result<()>>>,
     }
 
     impl Test {
-        fn new() -> Self {
-            let small_file = get_rand_vec(1*KB);
-            let medium_file = get_rand_vec(100*KB);
-            Self { small_file, medium_file }
+        fn new(rate: usize, lift_limit: bool) -> Self {
+            Self {
+                file: get_rand_vec(FILE_SIZE),
+                rate_limit: RateLimit::new(Some(rate as u64)),
+                lift_limit,
+                start_time: None,
+                control_thread: None,
+            }
         }
     }
 
+    fn send_command(control_socket: &Path, command: &str) -> Result<String> {
+        let mut socket = UnixStream::connect(control_socket)?;
+        socket.write_all(format!("{}\n", command).as_bytes())?;
+        let mut reply = String::new();
+        BufReader::new(socket).read_line(&mut reply)?;
+        Ok(reply)
+    }
+
     impl TestImpl for Test {
-        fn serve_image(&mut self) -> bool { false }
+        fn images_dir(&self) -> PathBuf { PathBuf::from("/tmp/test-criu-image-streamer-rate-limit") }
+        fn capture_rate_limit(&self) -> RateLimit { self.rate_limit.clone() }
+        fn capture_rate_control(&self) -> bool { self.lift_limit }
 
         fn send_img_files(&mut self, checkpoint: &mut CheckpointContext) -> Result<()> {
-            checkpoint.criu.write_img_file("small.img")?
-                .write_all(&self.small_file)?;
-            checkpoint.criu.write_img_file("medium.img")?
-                .write_all(&self.medium_file)?;
+            self.start_time = Some(Instant::now());
+            if self.lift_limit {
+                let control_socket = self.images_dir().join(IMG_STREAMER_CONTROL_SOCKET_NAME);
+                self.control_thread = Some(thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(200));
+                    assert!(send_command(&control_socket, "rate fast")?.starts_with("error "));
+                    assert_eq!(send_command(&control_socket, "rate unlimited")?, "ok\n");
+                    Ok(())
+                }));
+            }
+            checkpoint.criu.write_img_file("file.img")?
+                .write_all(&self.file)?;
             Ok(())
         }
 
-        fn after_finish_image_extraction(&mut self, _restore_stats: &Stats) -> Result<()> {
-            let read_img_file = |name: &str| -> Result<Vec<u8>> {
-                let mut buf = Vec::new();
-                let mut small_file = File::open(self.images_dir().join(name))?;
-                small_file.read_to_end(&mut buf)?;
-                Ok(buf)
-            };
-
-            assert!(read_img_file("small.img")? == self.small_file);
-            assert!(read_img_file("medium.img")? == self.medium_file);
+        fn after_finish_checkpoint(&mut self, _stats: &Stats) -> Result<()> {
+            if let Some(control_thread) = self.control_thread.take() {
+                control_thread.join().unwrap()?;
+            }
+            let duration = self.start_time.unwrap().elapsed();
+            eprintln!("Capture duration: {:?}", duration);
+            if self.lift_limit {
+                // It would take 12s at 1MB/s.
+                assert!(duration < Duration::from_secs(4), "{:?}", duration);
+                assert_eq!(self.rate_limit.get(), None);
+            } else {
+                // 300ms at 40MB/s, what the pipes hold is written without delay.
+                assert!(duration > Duration::from_millis(150), "{:?}", duration);
+            }
+            Ok(())
+        }
 
+        fn recv_img_files(&mut self, restore: &mut RestoreContext) -> Result<()> {
+            let buf = restore.criu.read_img_file_into_vec("file.img")?;
+            assert!(buf == self.file);
             Ok(())
         }
     }
 
     #[test]
     fn test() -> Result<()> {
-        Test::new().run()
+        Test::new(40*MB, false).run()
+    }
+
+    #[test]
+    fn test_lift_limit() -> Result<()> {
+        Test::new(1*MB, true).run()
+    }
+}
+
+mod shard_cmd {
+    use super::*;
+    use std::{fs, path::Path};
+    use criu_image_streamer::{
+        extract::import_stream,
+        shard_cmd::{ShardCmd, spawn_shard_cmds, finish_shard_cmds},
+        shard_fifos::FifoDirection,
+    };
+
+    // Files are imported into an image uploaded by shard commands, and extracted back through
+    // download commands. A local directory stands in for the remote storage. No CRIU is involved.
+
+    const IMAGES_DIR: &str = "/tmp/test-criu-image-streamer-shard-cmd";
+
+    fn frame(files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut stream = Vec::new();
+        for (filename, data) in files {
+            write!(stream, "{}\0{}\0", filename, data.len()).unwrap();
+            stream.extend_from_slice(data);
+        }
+        stream
+    }
+
+    fn import(cmd: &str, num_shards: usize, stream: &[u8]) -> Result<()> {
+        let (_progress_r, progress_w) = new_pipe();
+        let cmd = ShardCmd::Shell(cmd.to_string());
+        let (shard_pipes, processes) = spawn_shard_cmds(&cmd, num_shards, FifoDirection::Write)?;
+        let result = import_stream(progress_w, stream, shard_pipes, None);
+        finish_shard_cmds(result, processes)
+    }
+
+    fn extract(cmd: &str, num_shards: usize) -> Result<Vec<u8>> {
+        let cmd = ShardCmd::Shell(cmd.to_string());
+        let (shard_pipes, processes) = spawn_shard_cmds(&cmd, num_shards, FifoDirection::Read)?;
+        let mut out = Vec::new();
+        let result = ExtractBuilder::new(IMAGES_DIR, shard_pipes).extract_to_stream(&mut out);
+        finish_shard_cmds(result, processes)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test() -> Result<()> {
+        let remote_dir = format!("{}/remote", IMAGES_DIR);
+        let _ = fs::remove_dir_all(&remote_dir);
+        fs::create_dir_all(&remote_dir)?;
+
+        let files = vec![("a.img", get_rand_vec(1*MB)), ("b.img", b"hello world".to_vec())];
+        import(&format!("cat > {}/shard-{{n}}", remote_dir), 3, &frame(&files))?;
+        for i in 0..3 {
+            assert!(Path::new(&format!("{}/shard-{}", remote_dir, i)).exists());
+        }
+
+        assert_eq!(extract(&format!("cat {}/shard-{{n}}", remote_dir), 3)?, frame(&files));
+        Ok(())
+    }
+
+    #[test]
+    fn test_failing_download() -> Result<()> {
+        let remote_dir = format!("{}/missing", IMAGES_DIR);
+        let cmd = format!("cat {}/shard-{{n}}", remote_dir);
+        let err = extract(&cmd, 2).expect_err("The failing download should have been reported");
+        assert!(format!("{:#}", err).contains(&format!("The shard command `{}` of shard 0 failed", cmd)), "{:#}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_placeholder() {
+        let err = import("cat > /dev/null", 2, &[]).expect_err("The command should have been rejected");
+        assert!(format!("{:#}", err).contains("must contain `{n}`"), "{:#}", err);
     }
 }